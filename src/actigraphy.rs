@@ -0,0 +1,65 @@
+//! Activity counts per epoch from raw accelerometer samples, the usual input for Cole-Kripke style
+//! sleep/wake scoring.
+
+// Gravity in mg, the magnitude of a resting accelerometer
+const GRAVITY_MG: f32 = 1000.0;
+
+/// Sums how far the acceleration magnitude deviates from gravity over each epoch, ignoring
+/// deviations below `threshold_mg` (sensor noise). This is the proportional integration mode of
+/// classic actigraphs, so the counts are comparable between epochs but not between devices.
+pub struct ActivityCounter {
+    epoch_ms: u64,
+    threshold_mg: f32,
+    epoch_start: Option<u64>,
+    count: f32,
+}
+
+impl ActivityCounter {
+    pub fn new(epoch_sec: u32, threshold_mg: f32) -> Self {
+        ActivityCounter {
+            epoch_ms: epoch_sec.max(1) as u64 * 1000,
+            threshold_mg,
+            epoch_start: None,
+            count: 0.0,
+        }
+    }
+
+    /// Adds a sample in mg, returns the start (in ms) and count of an epoch once it's complete.
+    pub fn push(&mut self, time_ms: u64, [x, y, z]: [f32; 3]) -> Option<(u64, f32)> {
+        let start = *self.epoch_start.get_or_insert(time_ms);
+        let mut finished = None;
+        if time_ms - start >= self.epoch_ms {
+            finished = Some((start, self.count));
+            self.epoch_start = Some(start + (time_ms - start) / self.epoch_ms * self.epoch_ms);
+            self.count = 0.0;
+        }
+        let deviation = ((x * x + y * y + z * z).sqrt() - GRAVITY_MG).abs();
+        if deviation > self.threshold_mg {
+            self.count += deviation - self.threshold_mg;
+        }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_movement_per_epoch() {
+        let mut counter = ActivityCounter::new(30, 20.0);
+        let still = [0.0, 0.0, 1000.0];
+        let moving = [0.0, 300.0, 1000.0];
+        // 25 Hz, one still epoch, then one with a second of movement
+        let mut epochs = Vec::new();
+        for i in 0..=1500u64 {
+            let sample = if (750..775).contains(&i) { moving } else { still };
+            epochs.extend(counter.push(i * 40, sample));
+        }
+        assert_eq!(epochs.len(), 2);
+        assert_eq!(epochs[0], (0, 0.0));
+        assert_eq!(epochs[1].0, 30_000);
+        // Each moving sample deviates by ~44 mg, 24 above the threshold
+        assert!((epochs[1].1 - 25.0 * 24.0).abs() < 10.0, "{}", epochs[1].1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Alerts: events that shouldn't wait for the next upload, like CO2 going over its threshold or
+//! the room becoming occupied. A round with an alert in it goes out right away, ahead of the
+//! upload jitter of aligned rounds and, to downsampled uplinks, as it is besides the average.
+//! Watchers running between rounds, like the reed switch, raise theirs with `pipeline::Alerts`.
+//! The schedule of the rounds stays as it is either way.
+
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::config::Config;
+use crate::sensors::Measurement;
+
+/// Picks the alerts out of the rounds.
+#[derive(Default)]
+pub struct AlertWatch {
+    /// Metrics with a threshold, whether they're outside it
+    breached: HashMap<String, bool>,
+    /// Last value of each of `alerts.change_metrics`
+    last: HashMap<String, f32>,
+}
+
+impl AlertWatch {
+    /// True if the round has an alert: a metric going outside its `thresholds`, or any of
+    /// `alerts.change_metrics` changing. Only the crossing is one, a metric staying outside its
+    /// threshold isn't, nor the first value of a metric.
+    pub fn check(&mut self, config: &Config, measurements: &[Measurement]) -> bool {
+        let mut alert = false;
+        for measurement in measurements.iter().filter(|measurement| measurement.value.is_finite()) {
+            let name = measurement.name.as_ref();
+            let value = measurement.value;
+            if let Some(threshold) = config.threshold(name) {
+                let breached =
+                    threshold.min.is_some_and(|min| value < min) || threshold.max.is_some_and(|max| value > max);
+                let was_breached = self.breached.insert(name.to_string(), breached).unwrap_or(false);
+                if breached && !was_breached {
+                    info!("Alert: {} is outside its threshold at {}", name, value);
+                    alert = true;
+                }
+            }
+            let watched = config.alerts.change_metrics.iter().any(|metric| metric == name);
+            if watched && self.last.insert(name.to_string(), value).is_some_and(|last| last != value) {
+                info!("Alert: {} changed to {}", name, value);
+                alert = true;
+            }
+        }
+        alert
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Threshold;
+
+    fn round(values: &[(&'static str, f32)]) -> Vec<Measurement> {
+        values
+            .iter()
+            .map(|(name, value)| Measurement {
+                name: (*name).into(),
+                value: *value,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alerts_on_crossings_and_changes() {
+        let mut config = Config::default();
+        config.thresholds.insert(
+            "co2".to_string(),
+            Threshold {
+                min: None,
+                max: Some(1400.0),
+            },
+        );
+        let mut watch = AlertWatch::default();
+        assert!(!watch.check(&config, &round(&[("co2", 900.0), ("room_occupied", 0.0)])));
+        assert!(watch.check(&config, &round(&[("co2", 1500.0), ("room_occupied", 0.0)])));
+        // Still above, and the occupancy is the same
+        assert!(!watch.check(&config, &round(&[("co2", 1600.0), ("room_occupied", 0.0)])));
+        assert!(watch.check(&config, &round(&[("co2", 1600.0), ("room_occupied", 1.0)])));
+        assert!(!watch.check(&config, &round(&[("co2", 1000.0), ("lux", 200.0)])));
+        assert!(watch.check(&config, &round(&[("co2", 1450.0)])));
+    }
+}
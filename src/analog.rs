@@ -0,0 +1,82 @@
+//! Conversions for the analog inputs, kept apart from the ADC driver so they can be tested on the
+//! host.
+
+use crate::config::ThermistorModel;
+
+const KELVIN: f32 = 273.15;
+
+/// Resistance of the sensor in a voltage divider with `series_ohm`, from the voltage across the
+/// sensor's end of it. `None` at either rail, the sensor is disconnected or shorted.
+pub fn divider_resistance(mv: f32, supply_mv: f32, series_ohm: f32, to_ground: bool) -> Option<f32> {
+    if mv <= 0.0 || mv >= supply_mv {
+        return None;
+    }
+    Some(if to_ground {
+        series_ohm * mv / (supply_mv - mv)
+    } else {
+        series_ohm * (supply_mv - mv) / mv
+    })
+}
+
+/// Where `mv` is between the readings at 0% and 100%, clamped to that range.
+pub fn percentage(mv: f32, zero_mv: f32, full_mv: f32) -> Option<f32> {
+    if zero_mv == full_mv {
+        return None;
+    }
+    Some(((mv - zero_mv) / (full_mv - zero_mv) * 100.0).clamp(0.0, 100.0))
+}
+
+/// Temperature in °C of an NTC thermistor at `ohm`.
+pub fn thermistor_temperature(ohm: f32, model: &ThermistorModel) -> f32 {
+    let inverse_kelvin = match *model {
+        ThermistorModel::Beta { beta, r0_ohm, t0 } => 1.0 / (t0 + KELVIN) + (ohm / r0_ohm).ln() / beta,
+        ThermistorModel::SteinhartHart { a, b, c } => {
+            // In f64, the coefficients span six orders of magnitude
+            let ln = (ohm as f64).ln();
+            (a + b * ln + c * ln.powi(3)) as f32
+        }
+    };
+    1.0 / inverse_kelvin - KELVIN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_a_divider_reading() {
+        assert_eq!(divider_resistance(1650.0, 3300.0, 10_000.0, true), Some(10_000.0));
+        assert_eq!(divider_resistance(1100.0, 3300.0, 10_000.0, true), Some(5_000.0));
+        assert_eq!(divider_resistance(1100.0, 3300.0, 10_000.0, false), Some(20_000.0));
+        assert_eq!(divider_resistance(3300.0, 3300.0, 10_000.0, true), None);
+        assert_eq!(divider_resistance(0.0, 3300.0, 10_000.0, true), None);
+    }
+
+    #[test]
+    fn maps_readings_to_percentages() {
+        assert_eq!(percentage(1500.0, 1000.0, 2000.0), Some(50.0));
+        // A capacitive probe, lower when wet
+        assert_eq!(percentage(1250.0, 2000.0, 1000.0), Some(75.0));
+        assert_eq!(percentage(2500.0, 1000.0, 2000.0), Some(100.0));
+        assert_eq!(percentage(1500.0, 1000.0, 1000.0), None);
+    }
+
+    #[test]
+    fn converts_thermistor_resistance() {
+        let beta = ThermistorModel::Beta {
+            beta: 3950.0,
+            r0_ohm: 10_000.0,
+            t0: 25.0,
+        };
+        assert!((thermistor_temperature(10_000.0, &beta) - 25.0).abs() < 0.01);
+        assert!(thermistor_temperature(33_620.0, &beta).abs() < 0.05);
+
+        // Common coefficients for a 10 kΩ NTC
+        let steinhart_hart = ThermistorModel::SteinhartHart {
+            a: 1.009_249_5e-3,
+            b: 2.378_405_4e-4,
+            c: 2.019_202_7e-7,
+        };
+        assert!((thermistor_temperature(10_000.0, &steinhart_hart) - 24.7).abs() < 0.1);
+    }
+}
@@ -0,0 +1,86 @@
+//! Baseline handling for the metal-oxide gas sensors. They calibrate against the cleanest air
+//! they've seen, which takes hours to days to learn and is forgotten on every power cycle, so the
+//! learned baseline is kept in the sensor memory and written back after a reboot.
+
+/// Decides when a sensor's baseline is written back and when it's read out to be kept.
+pub struct BaselineKeeper {
+    stored: Option<u32>,
+    restore_after_sec: u64,
+    burn_in_sec: u64,
+    save_every_sec: u64,
+    restored: bool,
+    last_save_sec: Option<u64>,
+}
+
+impl BaselineKeeper {
+    /// `stored` is restored once the sensor has run for `restore_after_sec`. Without one the
+    /// first baseline is only kept after `burn_in_sec`, before that it isn't worth much.
+    pub fn new(stored: Option<u32>, restore_after_sec: u64, burn_in_sec: u64, save_every_sec: u64) -> Self {
+        BaselineKeeper {
+            stored,
+            restore_after_sec,
+            burn_in_sec,
+            save_every_sec,
+            restored: false,
+            last_save_sec: None,
+        }
+    }
+
+    /// The stored baseline, once, when it's time to write it to the sensor.
+    pub fn restore(&mut self, running_sec: u64) -> Option<u32> {
+        if self.restored || running_sec < self.restore_after_sec {
+            return None;
+        }
+        self.restored = true;
+        self.stored
+    }
+
+    /// Whether the sensor's current baseline should be read out and kept.
+    pub fn save_due(&self, running_sec: u64) -> bool {
+        let learned = match self.stored {
+            Some(_) => self.restored,
+            None => running_sec >= self.burn_in_sec,
+        };
+        learned && self.last_save_sec.is_none_or(|last| running_sec >= last + self.save_every_sec)
+    }
+
+    pub fn saved(&mut self, running_sec: u64, baseline: u32) {
+        self.stored = Some(baseline);
+        self.last_save_sec = Some(running_sec);
+    }
+
+    /// What goes into the sensor memory.
+    pub fn memory(&self) -> Vec<(&'static str, u32)> {
+        self.stored.map(|baseline| vec![("baseline", baseline)]).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn restores_then_saves_hourly() {
+        let mut keeper = BaselineKeeper::new(Some(0x1234), 20 * 60, 24 * HOUR, HOUR);
+        assert_eq!(keeper.restore(60), None);
+        assert!(!keeper.save_due(60));
+        assert_eq!(keeper.restore(20 * 60), Some(0x1234));
+        assert_eq!(keeper.restore(21 * 60), None);
+        assert!(keeper.save_due(21 * 60));
+        keeper.saved(21 * 60, 0x1300);
+        assert!(!keeper.save_due(30 * 60));
+        assert!(keeper.save_due(81 * 60));
+        assert_eq!(keeper.memory(), [("baseline", 0x1300)]);
+    }
+
+    #[test]
+    fn waits_for_the_burn_in_without_a_stored_baseline() {
+        let mut keeper = BaselineKeeper::new(None, 0, 12 * HOUR, HOUR);
+        assert_eq!(keeper.restore(0), None);
+        assert!(!keeper.save_due(11 * HOUR));
+        assert!(keeper.memory().is_empty());
+        assert!(keeper.save_due(12 * HOUR));
+    }
+}
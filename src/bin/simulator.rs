@@ -0,0 +1,117 @@
+//! Runs the measurement pipeline on the host with simulated sensors. The target has to be given,
+//! `.cargo/config.toml` builds for the chip otherwise:
+//!
+//! ```text
+//! cargo run --target x86_64-unknown-linux-gnu --no-default-features --features simulator \
+//!     --bin simulator -- --fake-server
+//! ```
+
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{info, LevelFilter, Log, Metadata, Record};
+use sleep_thing::config::Config;
+use sleep_thing::identity::Identity;
+use sleep_thing::pipeline::PipelineBuilder;
+use sleep_thing::registry::SensorRegistry;
+use sleep_thing::sim::{SimulatedClimate, SimulatedLight, SimulatedNetwork};
+use sleep_thing::sinks::GraphiteSink;
+
+const USAGE: &str = concat!(
+    "Usage: simulator [--config <file>] [--interval <seconds>] [--failure-rate <0..1>] ",
+    "[--fake-server [port]]"
+);
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Accepts Graphite plaintext connections and prints every received line.
+fn spawn_fake_server(port: u16) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    info!("Fake Graphite server listening on 127.0.0.1:{}", port);
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                println!("graphite <- {}", line);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    log::set_logger(&LOGGER).map_err(|err| anyhow::anyhow!("{}", err))?;
+    log::set_max_level(LevelFilter::Info);
+
+    let mut config = Config::default();
+    let mut interval = None;
+    let mut failure_rate = 0.0;
+    let mut fake_server = None;
+
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config = Config::load_from(&args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?),
+            "--interval" => interval = Some(args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.parse()?),
+            "--failure-rate" => failure_rate = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.parse()?,
+            "--fake-server" => {
+                let port = match args.peek().and_then(|port| port.parse::<u16>().ok()) {
+                    Some(port) => {
+                        args.next();
+                        port
+                    }
+                    None => 2003,
+                };
+                fake_server = Some(port);
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(interval) = interval {
+        config.interval_sec = interval;
+    }
+    if let Some(port) = fake_server {
+        spawn_fake_server(port)?;
+        config.graphite.host = "127.0.0.1".to_string();
+        config.graphite.port = port;
+    }
+    // Locally administered MAC, so simulated data never collides with a real unit
+    config.identity = Identity::from_mac([0x02, 0x00, 0x00, 0x51, 0x4d, 0x01]);
+    info!(
+        "Simulating device {}, sending to {}:{} every {} s",
+        config.device_id(),
+        config.graphite.host,
+        config.graphite.port,
+        config.interval_sec
+    );
+
+    let mut sensors = SensorRegistry::default();
+    sensors.add(Box::new(SimulatedClimate::new()));
+    sensors.add(Box::new(SimulatedLight));
+    PipelineBuilder::new(Arc::new(Mutex::new(config)))
+        .sensors(sensors)
+        .network(Box::new(SimulatedNetwork::new(failure_rate)))
+        .uplink(Box::new(GraphiteSink::default()))
+        .build()?
+        .run();
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! The BME280's trimming parameters and the datasheet's floating point compensation, for reading
+//! it over SPI, which the driver crate doesn't do.
+
+/// Trimming parameters, from registers `0x88..=0xA1` and `0xE1..=0xE7`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+/// Compensated sample: temperature in °C, pressure in Pa and relative humidity in %.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub temperature: f32,
+    pub pressure: f32,
+    pub humidity: f32,
+}
+
+impl Calibration {
+    pub fn parse(low: &[u8; 26], high: &[u8; 7]) -> Self {
+        let unsigned = |i: usize| u16::from_le_bytes([low[i], low[i + 1]]) as f64;
+        let signed = |i: usize| i16::from_le_bytes([low[i], low[i + 1]]) as f64;
+        let mut p = [0.0; 9];
+        p[0] = unsigned(6);
+        for (n, value) in p.iter_mut().enumerate().skip(1) {
+            *value = signed(6 + 2 * n);
+        }
+        // H4 and H5 are 12 bit values sharing a nibble of 0xE5
+        Calibration {
+            t1: unsigned(0),
+            t2: signed(2),
+            t3: signed(4),
+            p,
+            h1: low[25] as f64,
+            h2: i16::from_le_bytes([high[0], high[1]]) as f64,
+            h3: high[2] as f64,
+            h4: ((high[3] as i8 as i16) << 4 | (high[4] & 0x0F) as i16) as f64,
+            h5: ((high[5] as i8 as i16) << 4 | (high[4] >> 4) as i16) as f64,
+            h6: high[6] as i8 as f64,
+        }
+    }
+
+    /// Compensates the 20 bit temperature and pressure and the 16 bit humidity readings.
+    pub fn compensate(&self, adc_t: u32, adc_p: u32, adc_h: u16) -> Sample {
+        let (adc_t, adc_p, adc_h) = (adc_t as f64, adc_p as f64, adc_h as f64);
+        let var1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        Sample {
+            temperature: (t_fine / 5120.0) as f32,
+            pressure: self.pressure(t_fine, adc_p) as f32,
+            humidity: self.humidity(t_fine, adc_h) as f32,
+        }
+    }
+
+    fn pressure(&self, t_fine: f64, adc_p: f64) -> f64 {
+        let p = &self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p[5] / 32768.0;
+        var2 += var1 * p[4] * 2.0;
+        var2 = var2 / 4.0 + p[3] * 65536.0;
+        var1 = (p[2] * var1 * var1 / 524288.0 + p[1] * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p[0];
+        if var1 == 0.0 {
+            // Avoids a division by zero, the trimming is broken
+            return 0.0;
+        }
+        let pressure = (1048576.0 - adc_p - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = p[8] * pressure * pressure / 2147483648.0;
+        let var2 = pressure * p[7] / 32768.0;
+        pressure + (var1 + var2 + p[6]) / 16.0
+    }
+
+    fn humidity(&self, t_fine: f64, adc_h: f64) -> f64 {
+        let var = t_fine - 76800.0;
+        let var = (adc_h - (self.h4 * 64.0 + self.h5 / 16384.0 * var))
+            * (self.h2 / 65536.0 * (1.0 + self.h6 / 67108864.0 * var * (1.0 + self.h3 / 67108864.0 * var)));
+        (var * (1.0 - self.h1 * var / 524288.0)).clamp(0.0, 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensates_the_datasheet_example() {
+        // Trimming and readings of the example in the BMP280 datasheet, which shares the formulas
+        let words: [u16; 12] = [
+            27504, 26435, -1000i16 as u16, 36477, -10685i16 as u16, 3024, 2855, 140, -7i16 as u16, 15500,
+            -14600i16 as u16, 6000,
+        ];
+        let mut low = [0u8; 26];
+        for (i, word) in words.iter().enumerate() {
+            low[2 * i..2 * i + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        let calibration = Calibration::parse(&low, &[0; 7]);
+        let sample = calibration.compensate(519888, 415148, 0);
+        assert!((sample.temperature - 25.08).abs() < 0.01, "{}", sample.temperature);
+        assert!((sample.pressure - 100653.27).abs() < 0.1, "{}", sample.pressure);
+        assert_eq!(sample.humidity, 0.0);
+    }
+}
@@ -0,0 +1,225 @@
+//! The boards the firmware runs on and where they wire things, so a port to another board is a
+//! definition here rather than a fork of the defaults. The board is picked at build time with a
+//! `board_*` feature, a board stored in NVS (`board <name>` on the console) wins over it. Its pins
+//! are the defaults of the configuration, pins set in the configuration file still override them.
+
+use std::sync::OnceLock;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(target_os = "espidf")]
+use log::error;
+
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase, Result};
+
+#[cfg(all(feature = "board_xiao_esp32c3", feature = "board_sleep_thing"))]
+compile_error!("Only one `board_*` feature can be enabled");
+#[cfg(all(feature = "board_xiao_esp32c3", target_os = "espidf", not(esp32c3)))]
+compile_error!("The XIAO ESP32C3 needs `MCU=esp32c3`");
+#[cfg(all(feature = "board_sleep_thing", target_os = "espidf", not(esp32c6)))]
+compile_error!("The sleep-thing PCB needs `MCU=esp32c6`");
+
+#[cfg(target_os = "espidf")]
+const NVS_NAMESPACE: &str = "sleep_thing";
+#[cfg(target_os = "espidf")]
+const NVS_BOARD_KEY: &str = "board";
+
+static SELECTED: OnceLock<Board> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Board {
+    /// Espressif's ESP32-C6-DevKitC-1
+    DevkitC6,
+    /// Espressif's ESP32-S3-DevKitC-1
+    DevkitS3,
+    /// Seeed Studio XIAO ESP32C3, pins after the silkscreen's D4/D5 (I2C), D6/D7 (UART) and A0
+    XiaoEsp32c3,
+    /// Our own PCB
+    SleepThing,
+}
+
+/// Where a board wires the parts the configuration has pins for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pins {
+    pub sda: i32,
+    pub scl: i32,
+    pub sclk: i32,
+    pub mosi: i32,
+    pub miso: i32,
+    /// Self-test and factory reset, active low
+    pub button: Option<i32>,
+    pub led: Option<i32>,
+    /// The first ADC input, for the thermistor
+    pub adc: i32,
+    /// The UART of the LD2410 or the modem
+    pub uart_tx: i32,
+    pub uart_rx: i32,
+}
+
+impl Board {
+    pub const ALL: [Board; 4] = [Board::DevkitC6, Board::DevkitS3, Board::XiaoEsp32c3, Board::SleepThing];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Board::DevkitC6 => "devkitc6",
+            Board::DevkitS3 => "devkits3",
+            Board::XiaoEsp32c3 => "xiao_esp32c3",
+            Board::SleepThing => "sleep_thing",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Board> {
+        Board::ALL.into_iter().find(|board| board.name() == name)
+    }
+
+    /// The one the `board_*` feature picks, without one the dev board of the chip built for.
+    pub fn compiled() -> Board {
+        if cfg!(feature = "board_xiao_esp32c3") || cfg!(esp32c3) {
+            Board::XiaoEsp32c3
+        } else if cfg!(feature = "board_sleep_thing") {
+            Board::SleepThing
+        } else if cfg!(esp32s3) {
+            Board::DevkitS3
+        } else {
+            Board::DevkitC6
+        }
+    }
+
+    /// The board the defaults are for, the compiled one unless another was selected at boot.
+    pub fn current() -> Board {
+        SELECTED.get().copied().unwrap_or_else(Board::compiled)
+    }
+
+    /// Only takes before the configuration is loaded, false if one was selected already.
+    pub fn select(board: Board) -> bool {
+        SELECTED.set(board).is_ok()
+    }
+
+    pub fn pins(self) -> Pins {
+        match self {
+            Board::DevkitC6 => Pins {
+                sda: 19,
+                scl: 20,
+                sclk: 6,
+                mosi: 7,
+                miso: 2,
+                // BOOT
+                button: Some(9),
+                // GPIO8 drives the RGB LED, which needs RMT
+                led: None,
+                adc: 0,
+                uart_tx: 22,
+                uart_rx: 23,
+            },
+            Board::DevkitS3 => Pins {
+                sda: 8,
+                scl: 9,
+                sclk: 12,
+                mosi: 11,
+                miso: 13,
+                // BOOT
+                button: Some(0),
+                // GPIO48 drives the RGB LED
+                led: None,
+                adc: 1,
+                uart_tx: 17,
+                uart_rx: 18,
+            },
+            Board::XiaoEsp32c3 => Pins {
+                sda: 6,
+                scl: 7,
+                sclk: 8,
+                mosi: 10,
+                miso: 9,
+                // BOOT, shared with MISO, so only without an SPI peripheral
+                button: Some(9),
+                led: None,
+                adc: 2,
+                uart_tx: 21,
+                uart_rx: 20,
+            },
+            Board::SleepThing => Pins {
+                sda: 4,
+                scl: 5,
+                sclk: 6,
+                mosi: 7,
+                miso: 2,
+                button: Some(9),
+                led: Some(1),
+                adc: 3,
+                uart_tx: 22,
+                uart_rx: 23,
+            },
+        }
+    }
+}
+
+/// Reads the board selected from the console, None if there's none or it's unknown.
+#[cfg(target_os = "espidf")]
+pub fn load(nvs: &EspDefaultNvsPartition) -> Option<Board> {
+    let nvs = match EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, err);
+            return None;
+        }
+    };
+    let mut buf = [0u8; 32];
+    match nvs.get_str(NVS_BOARD_KEY, &mut buf) {
+        Ok(name) => {
+            let name = name?;
+            let board = Board::parse(name);
+            if board.is_none() {
+                error!("Unknown board '{}' in NVS, ignoring it", name);
+            }
+            board
+        }
+        Err(err) => {
+            error!("Failed to read the board from NVS: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Persists the board selection in NVS, `None` goes back to the compiled one.
+#[cfg(target_os = "espidf")]
+pub fn store(nvs: &EspDefaultNvsPartition, board: Option<Board>) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true)
+        .context(Phase::Config, "Failed to open the NVS namespace")?;
+    match board {
+        Some(board) => nvs
+            .set_str(NVS_BOARD_KEY, board.name())
+            .context(Phase::Config, "Failed to store the board")?,
+        None => {
+            nvs.remove(NVS_BOARD_KEY)
+                .context(Phase::Config, "Failed to clear the board")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boards_wire_every_part_to_its_own_pin() {
+        for board in Board::ALL {
+            assert_eq!(Board::parse(board.name()), Some(board));
+            let pins = board.pins();
+            let mut used = vec![pins.sda, pins.scl, pins.sclk, pins.mosi, pins.adc, pins.uart_tx, pins.uart_rx];
+            used.extend(pins.led);
+            // The XIAO's button doubles as MISO
+            if board != Board::XiaoEsp32c3 {
+                used.push(pins.miso);
+                used.extend(pins.button);
+            }
+            let count = used.len();
+            used.sort();
+            used.dedup();
+            assert_eq!(used.len(), count, "{} uses a pin twice", board.name());
+        }
+        assert_eq!(Board::parse("devkit"), None);
+    }
+}
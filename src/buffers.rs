@@ -0,0 +1,192 @@
+//! The measurement buffer split by priority, so while the uplink is down diagnostics are dropped
+//! first and never evict sensor data. Every round is split across the classes, each a ring buffer
+//! of its own that only drops its own oldest batches.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+
+use crate::compact::CompactBatch;
+use crate::config::{BufferConfig, Config};
+use crate::pipeline::{Batch, SharedBuffer};
+use crate::schedule;
+use crate::sensors::Measurement;
+
+// About 150 KB of RAM for the ring buffers at 32 bytes a batch, the values stored in the batches
+// take another 8 bytes each
+pub const MAX_BATCHES: u64 = 4800;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+/// The buffers of the three classes, clones share them.
+#[derive(Clone)]
+pub struct PriorityBuffers {
+    high: SharedBuffer,
+    normal: SharedBuffer,
+    low: SharedBuffer,
+    classes: Arc<BufferConfig>,
+}
+
+impl PriorityBuffers {
+    /// Sized for the retention of each class at the shortest interval, scaled down to
+    /// `MAX_BATCHES` in all if that's more.
+    pub fn from_config(config: &Config) -> Self {
+        let interval_sec = schedule::shortest_interval_sec(config);
+        let capacities = capacities(&config.buffers, interval_sec);
+        let total = capacities.iter().sum::<u64>();
+        if total > MAX_BATCHES {
+            warn!(
+                "The buffers hold {} of the {} batches their retention needs at an interval of {} s",
+                MAX_BATCHES, total, interval_sec
+            );
+        }
+        let total = total.max(MAX_BATCHES);
+        let capacities = capacities.map(|capacity| (capacity * MAX_BATCHES / total).max(1) as usize);
+        PriorityBuffers::new(&config.buffers, capacities)
+    }
+
+    /// `capacities` in batches, highest priority first.
+    pub fn new(classes: &BufferConfig, [high, normal, low]: [usize; 3]) -> Self {
+        let buffer = |capacity| Arc::new(Mutex::new(AllocRingBuffer::new(capacity)));
+        PriorityBuffers {
+            high: buffer(high),
+            normal: buffer(normal),
+            low: buffer(low),
+            classes: Arc::new(classes.clone()),
+        }
+    }
+
+    pub fn priority(&self, name: &str) -> Priority {
+        let matches = |patterns: &[String]| patterns.iter().any(|pattern| matches(pattern, name));
+        if matches(&self.classes.high_metrics) {
+            Priority::High
+        } else if matches(&self.classes.low_metrics) {
+            Priority::Low
+        } else {
+            Priority::Normal
+        }
+    }
+
+    /// Splits the round across the classes, a batch dropped to make room counts in `dropped`.
+    pub fn push(&self, batch: Batch, dropped: &AtomicU64) {
+        self.push_seqs(batch, dropped);
+    }
+
+    /// Like `push`, returns the `seq` of each batch the round was split into.
+    pub fn push_seqs(&self, (timestamp_ms, measurements): Batch, dropped: &AtomicU64) -> Vec<u64> {
+        let mut seqs = Vec::new();
+        let mut classes: [Vec<Measurement>; 3] = Default::default();
+        for measurement in measurements {
+            let class = match self.priority(&measurement.name) {
+                Priority::High => 0,
+                Priority::Normal => 1,
+                Priority::Low => 2,
+            };
+            classes[class].push(measurement);
+        }
+        for (buffer, measurements) in self.all().iter().zip(classes) {
+            if measurements.is_empty() {
+                continue;
+            }
+            let mut buffer = buffer.lock().expect("Measurement buffer lock poisoned");
+            if buffer.is_full() {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            let batch = CompactBatch::encode(timestamp_ms, &measurements);
+            seqs.push(batch.seq());
+            buffer.push(batch);
+        }
+        seqs
+    }
+
+    /// Highest priority first, the order they're uploaded in.
+    pub fn all(&self) -> [SharedBuffer; 3] {
+        [self.high.clone(), self.normal.clone(), self.low.clone()]
+    }
+
+    /// Batches in all classes.
+    pub fn len(&self) -> usize {
+        self.all()
+            .iter()
+            .map(|buffer| buffer.lock().expect("Measurement buffer lock poisoned").len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Exact, or a prefix with a trailing `*`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// Batches each class needs for its retention at `interval_sec`, highest priority first.
+fn capacities(classes: &BufferConfig, interval_sec: u32) -> [u64; 3] {
+    let interval_sec = interval_sec.max(1) as u64;
+    [classes.high_hours, classes.normal_hours, classes.low_hours]
+        .map(|hours| (hours as u64 * 3600 / interval_sec).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(timestamp_ms: u64) -> Batch {
+        let measurement = |name: &'static str| Measurement {
+            name: name.into(),
+            value: 1.0,
+        };
+        (timestamp_ms, vec![measurement("co2"), measurement("temperature"), measurement("heap_free")])
+    }
+
+    #[test]
+    fn classifies_metrics() {
+        let buffers = PriorityBuffers::from_config(&Config::default());
+        assert_eq!(buffers.priority("co2"), Priority::High);
+        assert_eq!(buffers.priority("presence_moving"), Priority::High);
+        assert_eq!(buffers.priority("temperature"), Priority::Normal);
+        assert_eq!(buffers.priority("uplink_bytes"), Priority::Low);
+        assert_eq!(buffers.priority("stack_free.console"), Priority::Low);
+    }
+
+    #[test]
+    fn drops_diagnostics_first() {
+        let buffers = PriorityBuffers::new(&BufferConfig::default(), [4, 3, 1]);
+        let dropped = AtomicU64::new(0);
+        for timestamp_ms in 0..4 {
+            buffers.push(round(timestamp_ms), &dropped);
+        }
+        let [high, normal, low] = buffers.all();
+        assert_eq!(high.lock().unwrap().len(), 4);
+        assert_eq!(normal.lock().unwrap().len(), 3);
+        assert_eq!(low.lock().unwrap().len(), 1);
+        assert_eq!(low.lock().unwrap().back().unwrap().timestamp_ms, 3);
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn stays_within_the_budget() {
+        let config = Config {
+            interval_sec: 10,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        let capacities = PriorityBuffers::from_config(&config)
+            .all()
+            .map(|buffer| buffer.lock().unwrap().capacity() as u64);
+        assert!(capacities.iter().sum::<u64>() <= MAX_BATCHES);
+        assert!(capacities[0] > capacities[2]);
+    }
+}
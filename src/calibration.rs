@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(target_os = "espidf")]
+use log::error;
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "espidf")]
+use crate::config::SharedConfig;
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase, Result};
+use crate::sensors::Measurement;
+
+#[cfg(target_os = "espidf")]
+const NVS_NAMESPACE: &str = "calibration";
+#[cfg(target_os = "espidf")]
+const NVS_OFFSETS_KEY: &str = "offsets";
+
+/// Per-board corrections, kept in NVS rather than the configuration file because they belong to
+/// the unit (self-heating, enclosure) and have to survive a configuration upload.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    /// Added to the reading, keyed by `<sensor>.<metric>`, e.g. `bme280.temperature`
+    pub offsets: BTreeMap<String, f32>,
+    /// Readings of the analog inputs at 0% and 100%, keyed by the input's metric
+    #[serde(default)]
+    pub ranges: BTreeMap<String, AnalogRange>,
+    /// Linear corrections keyed by metric, applied to every reading of it before the filters
+    #[serde(default)]
+    pub corrections: BTreeMap<String, Correction>,
+}
+
+/// E.g. a moisture pad read dry and soaked. Capacitive probes read lower when wet, `full_mv` can
+/// be below `zero_mv`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AnalogRange {
+    pub zero_mv: f32,
+    pub full_mv: f32,
+}
+
+/// `value * slope + offset`, from two readings taken next to a reference instrument.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Correction {
+    pub slope: f32,
+    pub offset: f32,
+}
+
+impl Correction {
+    /// From `(reading, reference)` pairs, `None` unless the readings differ.
+    pub fn from_points((low, low_reference): (f32, f32), (high, high_reference): (f32, f32)) -> Option<Self> {
+        let slope = (high_reference - low_reference) / (high - low);
+        let offset = low_reference - low * slope;
+        (slope.is_finite() && offset.is_finite() && slope != 0.0).then_some(Correction { slope, offset })
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        value * self.slope + self.offset
+    }
+}
+
+/// `<metric> <reading> <reference> <reading> <reference>` or `<metric> clear`, as taken by the
+/// console and the MQTT command topic.
+pub fn parse_correction<'a>(args: &[&'a str]) -> Option<(&'a str, Option<Correction>)> {
+    match *args {
+        [metric, "clear"] => Some((metric, None)),
+        [metric, low, low_reference, high, high_reference] => {
+            let [low, low_reference, high, high_reference] =
+                [low, low_reference, high, high_reference].map(|value| value.parse::<f32>().ok());
+            let correction = Correction::from_points((low?, low_reference?), (high?, high_reference?))?;
+            Some((metric, Some(correction)))
+        }
+        _ => None,
+    }
+}
+
+impl Calibration {
+    pub fn offset(&self, sensor: &str, metric: &str) -> f32 {
+        self.offsets
+            .get(&format!("{}.{}", sensor, metric))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    pub fn range(&self, metric: &str) -> Option<AnalogRange> {
+        self.ranges.get(metric).copied()
+    }
+
+    /// Sets the range of an analog input, `None` removes it.
+    pub fn set_range(&mut self, metric: &str, range: Option<AnalogRange>) {
+        match range {
+            Some(range) => self.ranges.insert(metric.to_string(), range),
+            None => self.ranges.remove(metric),
+        };
+    }
+
+    /// Sets the correction of a metric, `None` removes it.
+    pub fn set_correction(&mut self, metric: &str, correction: Option<Correction>) {
+        match correction {
+            Some(correction) => self.corrections.insert(metric.to_string(), correction),
+            None => self.corrections.remove(metric),
+        };
+    }
+
+    /// Applies the corrections to the readings of the metrics that have one.
+    pub fn correct(&self, measurements: &mut [Measurement]) {
+        if self.corrections.is_empty() {
+            return;
+        }
+        for measurement in measurements {
+            if let Some(correction) = self.corrections.get(measurement.name.as_ref()) {
+                measurement.value = correction.apply(measurement.value);
+            }
+        }
+    }
+
+    /// Sets the offset for `<sensor>.<metric>`, zero removes it.
+    pub fn set_offset(&mut self, key: &str, offset: f32) {
+        if offset == 0.0 {
+            self.offsets.remove(key);
+        } else {
+            self.offsets.insert(key.to_string(), offset);
+        }
+    }
+}
+
+/// Reads the calibration from NVS, a missing or broken entry means no corrections.
+#[cfg(target_os = "espidf")]
+pub fn load(nvs: &EspDefaultNvsPartition) -> Calibration {
+    let nvs = match EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, err);
+            return Calibration::default();
+        }
+    };
+    // Sized to what's stored, the two-point corrections have no fixed size
+    let len = match nvs.str_len(NVS_OFFSETS_KEY) {
+        Ok(Some(len)) => len,
+        Ok(None) => return Calibration::default(),
+        Err(err) => {
+            error!("Failed to read the calibration from NVS: {:?}", err);
+            return Calibration::default();
+        }
+    };
+    let mut buf = vec![0u8; len];
+    match nvs.get_str(NVS_OFFSETS_KEY, &mut buf) {
+        Ok(Some(json)) => serde_json::from_str(json).unwrap_or_else(|err| {
+            error!("Failed to parse the calibration stored in NVS: {}", err);
+            Calibration::default()
+        }),
+        Ok(None) => Calibration::default(),
+        Err(err) => {
+            error!("Failed to read the calibration from NVS: {:?}", err);
+            Calibration::default()
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn store(nvs: &EspDefaultNvsPartition, calibration: &Calibration) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true)
+        .context(Phase::Config, "Failed to open the NVS namespace")?;
+    nvs.set_str(NVS_OFFSETS_KEY, &serde_json::to_string(calibration)?)
+        .context(Phase::Config, "Failed to store the calibration")?;
+    Ok(())
+}
+
+/// Changes the calibration of the running configuration and stores it, it's left as it was if
+/// storing fails.
+#[cfg(target_os = "espidf")]
+pub fn update(
+    nvs: &EspDefaultNvsPartition,
+    config: &SharedConfig,
+    change: impl FnOnce(&mut Calibration),
+) -> Result<()> {
+    let mut config = config.lock().expect("Config lock poisoned");
+    let mut calibration = config.calibration.clone();
+    change(&mut calibration);
+    store(nvs, &calibration)?;
+    config.calibration = calibration;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_offset_clears_the_entry() {
+        let mut calibration = Calibration::default();
+        calibration.set_offset("bme280.temperature", -1.25);
+        assert_eq!(calibration.offset("bme280", "temperature"), -1.25);
+        assert_eq!(calibration.offset("scd4x", "temperature"), 0.0);
+
+        calibration.set_offset("bme280.temperature", 0.0);
+        assert!(calibration.offsets.is_empty());
+    }
+
+    #[test]
+    fn reads_entries_stored_before_the_ranges() {
+        let calibration: Calibration = serde_json::from_str(r#"{"offsets":{"bme280.temperature":-1.0}}"#).unwrap();
+        assert_eq!(calibration.offset("bme280", "temperature"), -1.0);
+        assert_eq!(calibration.range("bed_moisture"), None);
+    }
+
+    #[test]
+    fn corrects_through_two_points() {
+        // Reads 2 % low at 30 % and 6 % low at 70 %
+        let (metric, correction) = parse_correction(&["humidity", "28", "30", "64", "70"]).unwrap();
+        let mut calibration = Calibration::default();
+        calibration.set_correction(metric, correction);
+        let mut measurements = [("humidity", 46.0), ("temperature", 21.0)].map(|(name, value)| Measurement {
+            name: name.into(),
+            value,
+        });
+        calibration.correct(&mut measurements);
+        assert!((measurements[0].value - 50.0).abs() < 1e-4);
+        assert_eq!(measurements[1].value, 21.0);
+
+        assert_eq!(parse_correction(&["humidity", "30", "30", "30", "70"]), None);
+        assert_eq!(parse_correction(&["humidity", "clear"]), Some(("humidity", None)));
+    }
+}
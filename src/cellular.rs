@@ -0,0 +1,95 @@
+//! AT command responses of the SIM7000/SIM7600 modems and the signal metrics taken from them,
+//! kept apart from the UART driver in `modem` so they can be tested on the host.
+
+use std::sync::{Arc, Mutex};
+
+use crate::pipeline::Filter;
+use crate::sensors::Measurement;
+
+/// What the modem reported the last time it was brought up.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Signal {
+    pub rssi_dbm: Option<i32>,
+    pub registered: bool,
+}
+
+pub type SharedSignal = Arc<Mutex<Option<Signal>>>;
+
+/// How a command ended, none while the response is still coming in.
+pub fn finished(response: &str) -> Option<bool> {
+    let lines = || response.lines().map(str::trim);
+    if lines().any(|line| line == "OK" || line.starts_with("CONNECT")) {
+        Some(true)
+    } else if lines().any(|line| line == "ERROR" || line == "NO CARRIER" || line.starts_with("+CME ERROR")) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// The value after `<prefix>: ` on the line starting with it, e.g. `18,99` of `+CSQ: 18,99`.
+pub fn field<'a>(response: &'a str, prefix: &str) -> Option<&'a str> {
+    response
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(prefix))
+        .and_then(|rest| rest.strip_prefix(':'))
+        .map(str::trim)
+}
+
+/// The received signal strength of `AT+CSQ`, none while the modem can't tell.
+pub fn rssi_dbm(response: &str) -> Option<i32> {
+    let rssi: i32 = field(response, "+CSQ")?.split(',').next()?.trim().parse().ok()?;
+    match rssi {
+        0..=31 => Some(-113 + 2 * rssi),
+        _ => None,
+    }
+}
+
+/// Whether `AT+CREG?`, `AT+CGREG?` or `AT+CEREG?` says the modem is registered, at home or
+/// roaming.
+pub fn registered(response: &str) -> bool {
+    ["+CEREG", "+CGREG", "+CREG"]
+        .iter()
+        .filter_map(|prefix| field(response, prefix))
+        .filter_map(|value| value.split(',').nth(1))
+        .any(|status| matches!(status.trim(), "1" | "5"))
+}
+
+/// Adds `cellular_rssi` and `cellular_registered` to every round, once the modem has been up.
+pub struct SignalMetrics(pub SharedSignal);
+
+impl Filter for SignalMetrics {
+    fn apply(&mut self, mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+        if let Some(signal) = *self.0.lock().expect("Cellular signal lock poisoned") {
+            measurements.push(Measurement {
+                name: "cellular_rssi".into(),
+                value: signal.rssi_dbm.map_or(f32::NAN, |rssi| rssi as f32),
+            });
+            measurements.push(Measurement {
+                name: "cellular_registered".into(),
+                value: if signal.registered { 1.0 } else { 0.0 },
+            });
+        }
+        measurements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_responses() {
+        assert_eq!(finished("AT+CSQ\r\n+CSQ: 18,99\r\n"), None);
+        assert_eq!(finished("\r\n+CSQ: 18,99\r\n\r\nOK\r\n"), Some(true));
+        assert_eq!(finished("\r\n+CME ERROR: SIM not inserted\r\n"), Some(false));
+        assert_eq!(finished("\r\nCONNECT 150000000\r\n"), Some(true));
+
+        assert_eq!(rssi_dbm("\r\n+CSQ: 18,99\r\n\r\nOK\r\n"), Some(-77));
+        assert_eq!(rssi_dbm("\r\n+CSQ: 99,99\r\n\r\nOK\r\n"), None);
+
+        assert!(registered("\r\n+CEREG: 0,5\r\n\r\nOK\r\n"));
+        assert!(!registered("\r\n+CREG: 0,2\r\n\r\nOK\r\n"));
+        assert_eq!(field("\r\n+CPIN: READY\r\n", "+CPIN"), Some("READY"));
+    }
+}
@@ -0,0 +1,81 @@
+//! Psychrometric helpers shared by the derived metrics and sensor compensation.
+
+// Magnus formula coefficients for water, valid from -45 to 60 °C
+const MAGNUS_B: f32 = 17.62;
+const MAGNUS_C: f32 = 243.12;
+
+/// Saturation vapour pressure in hPa at the given temperature in °C.
+pub fn saturation_vapour_pressure(temperature: f32) -> f32 {
+    6.112 * (MAGNUS_B * temperature / (MAGNUS_C + temperature)).exp()
+}
+
+/// Dew point in °C from temperature in °C and relative humidity in %.
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_B * temperature / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+/// Water vapour density in g/m³ from temperature in °C and relative humidity in %.
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    216.7 * (humidity / 100.0 * saturation_vapour_pressure(temperature)) / (273.15 + temperature)
+}
+
+/// Heat index in °C after the US National Weather Service, equal to the temperature in mild
+/// conditions.
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+    // Steadman's simple formula is accurate enough below 80 °F
+    let mut index = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (index + t) / 2.0 >= 80.0 {
+        // Rothfusz regression
+        index = -42.379 + 2.049_015_2 * t + 10.143_332 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+    }
+    (index - 32.0) * 5.0 / 9.0
+}
+
+/// Humidex after Environment Canada, in °C.
+pub fn humidex(temperature: f32, humidity: f32) -> f32 {
+    let vapour_pressure = humidity / 100.0 * saturation_vapour_pressure(temperature);
+    temperature + 0.5555 * (vapour_pressure - 10.0)
+}
+
+/// Relative humidity the same air has at another temperature, e.g. the room instead of a
+/// self-heated sensor.
+pub fn relative_humidity_at(humidity: f32, measured_at: f32, temperature: f32) -> f32 {
+    let humidity = humidity * saturation_vapour_pressure(measured_at) / saturation_vapour_pressure(temperature);
+    humidity.clamp(0.0, 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_reference_values() {
+        assert!((dew_point(20.0, 50.0) - 9.26).abs() < 0.05);
+        assert!((absolute_humidity(20.0, 50.0) - 8.63).abs() < 0.05);
+        // 1.5 °C of self-heating makes the sensor read roughly 5 % RH too low at room temperature
+        assert!((relative_humidity_at(45.0, 23.0, 21.5) - 49.2).abs() < 0.2);
+    }
+
+    #[test]
+    fn perceived_temperature() {
+        // NWS table: 90 °F at 60 % RH feels like 100 °F
+        assert!((heat_index(32.2, 60.0) - 37.8).abs() < 0.5);
+        assert!((heat_index(20.0, 50.0) - 19.7).abs() < 0.5);
+        // Environment Canada: 30 °C with a 15 °C dew point is a humidex of 34
+        assert!((humidex(30.0, 39.8) - 34.0).abs() < 0.5);
+    }
+}
@@ -0,0 +1,184 @@
+//! Switches IR controlled appliances (AC, fan, heater) by rules on the measurements, e.g. the AC
+//! on above 26 °C while the bed is occupied.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+
+use crate::config::{ClimateRule, IrDevice};
+use crate::error::Result;
+use crate::pipeline::Filter;
+use crate::sensors::Measurement;
+
+/// Manual on/off per device, taking precedence over the rules until it's cleared.
+pub type SharedOverrides = Arc<Mutex<BTreeMap<String, bool>>>;
+
+/// Sets the override of `device` from `on`, `off` or `auto` to hand it back to the rules. False
+/// for any other state.
+pub fn set_override(overrides: &SharedOverrides, device: &str, state: &str) -> bool {
+    let mut overrides = overrides.lock().expect("Override lock poisoned");
+    match state {
+        "on" => overrides.insert(device.to_string(), true),
+        "off" => overrides.insert(device.to_string(), false),
+        "auto" => overrides.remove(device),
+        _ => return false,
+    };
+    true
+}
+
+/// Sends a code given as alternating mark and space durations in µs, starting with a mark.
+pub trait Transmitter {
+    fn transmit(&mut self, code: &[u32]) -> Result<()>;
+}
+
+/// Runs the rules on every round and adds a `<device>_on` metric, 1 or 0, for every device with
+/// a known state. A code is only sent when the wanted state changes, a failed transmission is
+/// retried on the next round.
+pub struct ClimateControl<'a> {
+    devices: BTreeMap<String, IrDevice>,
+    rules: Vec<ClimateRule>,
+    overrides: SharedOverrides,
+    transmitter: Box<dyn Transmitter + 'a>,
+    // What the appliances were last told, unknown until the first code is sent
+    states: BTreeMap<String, bool>,
+}
+
+impl<'a> ClimateControl<'a> {
+    pub fn new(
+        devices: BTreeMap<String, IrDevice>,
+        rules: Vec<ClimateRule>,
+        overrides: SharedOverrides,
+        transmitter: Box<dyn Transmitter + 'a>,
+    ) -> Self {
+        ClimateControl {
+            devices,
+            rules,
+            overrides,
+            transmitter,
+            states: BTreeMap::new(),
+        }
+    }
+
+    fn wanted(&self, rule: &ClimateRule, measurements: &[Measurement]) -> Option<bool> {
+        let value = |name: &str| measurements.iter().find(|measurement| measurement.name == name).map(|m| m.value);
+        if let Some(occupancy) = &rule.when_occupied {
+            // Whatever the rules switched on goes off once the room or bed is empty
+            if value(occupancy).is_none_or(|occupied| occupied <= 0.0) {
+                return Some(false);
+            }
+        }
+        let value = value(&rule.metric)?;
+        let on = self.states.get(&rule.device).copied().unwrap_or(false);
+        // `on` above `off` is cooling, below is heating
+        let cooling = rule.on >= rule.off;
+        let past = |threshold: f32| if cooling { value > threshold } else { value < threshold };
+        let back = |threshold: f32| if cooling { value < threshold } else { value > threshold };
+        Some(if on { !back(rule.off) } else { past(rule.on) })
+    }
+
+    fn switch(&mut self, device: &str, on: bool) {
+        if self.states.get(device) == Some(&on) {
+            return;
+        }
+        let Some(codes) = self.devices.get(device) else {
+            error!("No IR codes for '{}'", device);
+            return;
+        };
+        let code = if on { &codes.on } else { &codes.off };
+        match self.transmitter.transmit(code) {
+            Ok(_) => {
+                info!("Switched {} {}", device, if on { "on" } else { "off" });
+                self.states.insert(device.to_string(), on);
+            }
+            Err(err) => error!("Failed to switch {}: {}", device, err),
+        }
+    }
+}
+
+impl Filter for ClimateControl<'_> {
+    fn apply(&mut self, mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+        let overrides = self.overrides.lock().expect("Override lock poisoned").clone();
+        let mut wanted: BTreeMap<String, bool> = BTreeMap::new();
+        for rule in &self.rules {
+            if let Some(on) = self.wanted(rule, &measurements) {
+                // Any rule wanting a device on keeps it on
+                *wanted.entry(rule.device.clone()).or_default() |= on;
+            }
+        }
+        wanted.extend(overrides);
+        for (device, on) in wanted {
+            self.switch(&device, on);
+        }
+        for (device, on) in &self.states {
+            measurements.push(Measurement {
+                name: format!("{}_on", device).into(),
+                value: if *on { 1.0 } else { 0.0 },
+            });
+        }
+        measurements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockTransmitter {
+        sent: Arc<Mutex<Vec<Vec<u32>>>>,
+    }
+
+    impl Transmitter for MockTransmitter {
+        fn transmit(&mut self, code: &[u32]) -> Result<()> {
+            self.sent.lock().unwrap().push(code.to_vec());
+            Ok(())
+        }
+    }
+
+    fn round(temperature: f32, occupied: f32) -> Vec<Measurement> {
+        [("temperature", temperature), ("bed_occupied", occupied)]
+            .into_iter()
+            .map(|(name, value)| Measurement {
+                name: name.into(),
+                value,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn switches_the_ac_with_hysteresis_while_occupied() {
+        let devices = BTreeMap::from([(
+            "ac".to_string(),
+            IrDevice {
+                on: vec![1],
+                off: vec![0],
+            },
+        )]);
+        let rules = vec![ClimateRule {
+            device: "ac".to_string(),
+            metric: "temperature".to_string(),
+            on: 26.0,
+            off: 24.0,
+            when_occupied: Some("bed_occupied".to_string()),
+        }];
+        let overrides = SharedOverrides::default();
+        let transmitter = MockTransmitter::default();
+        let mut control = ClimateControl::new(devices, rules, overrides.clone(), Box::new(transmitter.clone()));
+
+        let ac_on = |measurements: Vec<Measurement>| {
+            measurements.iter().find(|measurement| measurement.name == "ac_on").map(|m| m.value)
+        };
+        assert_eq!(ac_on(control.apply(round(27.0, 0.0))), Some(0.0));
+        assert_eq!(ac_on(control.apply(round(27.0, 1.0))), Some(1.0));
+        assert_eq!(ac_on(control.apply(round(25.0, 1.0))), Some(1.0));
+        assert_eq!(ac_on(control.apply(round(23.5, 1.0))), Some(0.0));
+
+        assert!(set_override(&overrides, "ac", "on"));
+        assert_eq!(ac_on(control.apply(round(23.5, 1.0))), Some(1.0));
+        assert!(!set_override(&overrides, "ac", "maybe"));
+        assert!(set_override(&overrides, "ac", "auto"));
+        assert_eq!(ac_on(control.apply(round(23.5, 1.0))), Some(0.0));
+        assert_eq!(*transmitter.sent.lock().unwrap(), vec![vec![0], vec![1], vec![0], vec![1], vec![0]]);
+    }
+}
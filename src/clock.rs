@@ -0,0 +1,70 @@
+//! Local wall-clock helpers. The system clock runs on UTC, local time is derived from the
+//! configured offset since there's no time zone database on the device.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const DAY: u32 = 24 * 60 * 60;
+
+/// Whether the system clock has been set, by SNTP or the RTC. Clones share it.
+#[derive(Clone, Default)]
+pub struct ClockSync(Arc<AtomicBool>);
+
+impl ClockSync {
+    /// For a clock that's right from the start, like the host's.
+    pub fn synced() -> Self {
+        ClockSync(Arc::new(AtomicBool::new(true)))
+    }
+
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Seconds since local midnight for a Unix timestamp.
+pub fn seconds_of_day(timestamp: u64, utc_offset_min: i32) -> u32 {
+    let local = timestamp as i64 + utc_offset_min as i64 * 60;
+    local.rem_euclid(DAY as i64) as u32
+}
+
+/// Parses `HH:MM` into seconds since midnight.
+pub fn parse_time_of_day(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
+
+/// Whether `time` falls into `[start, end)`, windows can wrap around midnight.
+pub fn in_window(time: u32, start: u32, end: u32) -> bool {
+    if start <= end {
+        (start..end).contains(&time)
+    } else {
+        time >= start || time < end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_offsets_and_midnight() {
+        // 2024-01-01 23:30 UTC
+        assert_eq!(seconds_of_day(1_704_151_800, 0), 23 * 3600 + 1800);
+        assert_eq!(seconds_of_day(1_704_151_800, 60), 1800);
+        assert_eq!(seconds_of_day(1_704_151_800, -120), 21 * 3600 + 1800);
+
+        assert_eq!(parse_time_of_day("07:30"), Some(7 * 3600 + 1800));
+        assert_eq!(parse_time_of_day("24:00"), None);
+        assert_eq!(parse_time_of_day("7"), None);
+
+        assert!(in_window(23 * 3600, 22 * 3600, 7 * 3600));
+        assert!(in_window(3600, 22 * 3600, 7 * 3600));
+        assert!(!in_window(12 * 3600, 22 * 3600, 7 * 3600));
+        assert!(in_window(12 * 3600, 9 * 3600, 17 * 3600));
+    }
+}
@@ -0,0 +1,194 @@
+//! Sharing the radio of the single-antenna chips between BLE and Wi-Fi. ESP-IDF's coexistence
+//! arbitrates packet by packet, which keeps both alive but lets BLE advertising eat into the
+//! upload and the upload starve the advertising. Instead, anything advertising over BLE
+//! registers here and is paused for as long as Wi-Fi is up for an upload, then resumed.
+
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::pipeline::Network;
+
+/// Something advertising over BLE that can step aside for Wi-Fi.
+pub trait Advertiser {
+    fn pause(&mut self) -> Result<()>;
+    fn resume(&mut self) -> Result<()>;
+}
+
+#[derive(Default)]
+struct State {
+    advertisers: Vec<Box<dyn Advertiser + Send>>,
+    // Windows can overlap, e.g. a reconnect within an upload
+    windows: usize,
+}
+
+/// Shared between the networks and the BLE features.
+#[derive(Clone, Default)]
+pub struct RadioScheduler {
+    state: Arc<Mutex<State>>,
+}
+
+impl RadioScheduler {
+    /// Paused right away if Wi-Fi is up.
+    pub fn register(&self, mut advertiser: Box<dyn Advertiser + Send>) {
+        let mut state = self.state.lock().unwrap();
+        if state.windows > 0 {
+            if let Err(err) = advertiser.pause() {
+                warn!("Failed to pause BLE advertising: {}", err);
+            }
+        }
+        state.advertisers.push(advertiser);
+    }
+
+    /// Pauses the advertising for Wi-Fi, with the first window.
+    pub fn open_window(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.windows += 1;
+        if state.windows == 1 {
+            for advertiser in &mut state.advertisers {
+                if let Err(err) = advertiser.pause() {
+                    warn!("Failed to pause BLE advertising: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Resumes the advertising, with the last window.
+    pub fn close_window(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.windows == 0 {
+            return;
+        }
+        state.windows -= 1;
+        if state.windows == 0 {
+            for advertiser in &mut state.advertisers {
+                if let Err(err) = advertiser.resume() {
+                    warn!("Failed to resume BLE advertising: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// A network that holds a window open from connecting until disconnecting.
+pub struct Scheduled<'a> {
+    network: Box<dyn Network + Send + 'a>,
+    scheduler: RadioScheduler,
+    open: bool,
+}
+
+impl<'a> Scheduled<'a> {
+    pub fn new(network: Box<dyn Network + Send + 'a>, scheduler: RadioScheduler) -> Self {
+        Scheduled {
+            network,
+            scheduler,
+            open: false,
+        }
+    }
+
+    fn open(&mut self) {
+        if !self.open {
+            self.scheduler.open_window();
+            self.open = true;
+        }
+    }
+
+    fn close(&mut self) {
+        if self.open {
+            self.scheduler.close_window();
+            self.open = false;
+        }
+    }
+}
+
+impl Network for Scheduled<'_> {
+    fn connect(&mut self, config: &Config) -> Result<()> {
+        self.open();
+        let result = self.network.connect(config);
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        let result = self.network.disconnect();
+        self.close();
+        result
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.network.restart()
+    }
+}
+
+impl Drop for Scheduled<'_> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Error, Phase};
+
+    struct MockAdvertiser {
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Advertiser for MockAdvertiser {
+        fn pause(&mut self) -> Result<()> {
+            self.log.lock().unwrap().push("pause");
+            Ok(())
+        }
+
+        fn resume(&mut self) -> Result<()> {
+            self.log.lock().unwrap().push("resume");
+            Ok(())
+        }
+    }
+
+    struct MockNetwork {
+        fail: bool,
+    }
+
+    impl Network for MockNetwork {
+        fn connect(&mut self, _config: &Config) -> Result<()> {
+            if self.fail {
+                Err(Error::failed(Phase::Connect, "No access point"))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn disconnect(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pauses_advertising_while_wifi_is_up() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = RadioScheduler::default();
+        scheduler.register(Box::new(MockAdvertiser { log: log.clone() }));
+        let config = Config::default();
+
+        let mut network = Scheduled::new(Box::new(MockNetwork { fail: false }), scheduler.clone());
+        network.connect(&config).unwrap();
+        // Already up, so nothing changes
+        network.connect(&config).unwrap();
+        scheduler.register(Box::new(MockAdvertiser { log: log.clone() }));
+        network.disconnect().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["pause", "pause", "resume", "resume"]);
+
+        log.lock().unwrap().clear();
+        let mut failing = Scheduled::new(Box::new(MockNetwork { fail: true }), scheduler.clone());
+        assert!(failing.connect(&config).is_err());
+        assert_eq!(log.lock().unwrap().len(), 4);
+        scheduler.close_window();
+        assert_eq!(log.lock().unwrap().len(), 4);
+    }
+}
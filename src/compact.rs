@@ -0,0 +1,150 @@
+//! Buffered rounds as compact records: the metric name as an index into a table of every name
+//! seen so far and the value in fixed point, 8 bytes per value instead of a `Measurement` and its
+//! name on the heap.
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::pipeline::Batch;
+use crate::sensors::Measurement;
+
+// Fixed metric names plus the computed ones, a few hundred at most
+static NAMES: Mutex<Vec<Cow<'static, str>>> = Mutex::new(Vec::new());
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+const MAX_DECIMALS: u8 = 3;
+const NAN: i32 = i32::MIN;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Record {
+    name: u16,
+    decimals: u8,
+    value: i32,
+}
+
+/// A round of measurements as stored in the buffers, see [`CompactBatch::decode`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactBatch {
+    pub timestamp_ms: u64,
+    records: Box<[Record]>,
+    seq: u64,
+}
+
+impl CompactBatch {
+    pub fn encode(timestamp_ms: u64, measurements: &[Measurement]) -> Self {
+        let mut names = NAMES.lock().expect("Metric name lock poisoned");
+        let records = measurements
+            .iter()
+            .filter_map(|measurement| {
+                let name = intern(&mut names, measurement)?;
+                let (decimals, value) = fixed_point(measurement.value);
+                Some(Record { name, decimals, value })
+            })
+            .collect();
+        CompactBatch {
+            timestamp_ms,
+            records,
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Goes up with every batch encoded, so in the order they were pushed to a buffer. How far
+    /// each uplink got in a buffer is kept as the last one it sent.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn decode(&self) -> Vec<Measurement> {
+        let names = NAMES.lock().expect("Metric name lock poisoned");
+        self.records
+            .iter()
+            .map(|record| Measurement {
+                name: names[record.name as usize].clone(),
+                value: match record.value {
+                    NAN => f32::NAN,
+                    value => (value as f64 / 10f64.powi(record.decimals as i32)) as f32,
+                },
+            })
+            .collect()
+    }
+
+    /// Number of measurements.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl From<Batch> for CompactBatch {
+    fn from((timestamp_ms, measurements): Batch) -> Self {
+        CompactBatch::encode(timestamp_ms, &measurements)
+    }
+}
+
+/// Index of the measurement's name, a static name is kept without a copy.
+fn intern(names: &mut Vec<Cow<'static, str>>, measurement: &Measurement) -> Option<u16> {
+    if let Some(index) = names.iter().position(|known| *known == measurement.name) {
+        return Some(index as u16);
+    }
+    // Only runs out if names are made up from values, those get dropped rather than panicking
+    let index = u16::try_from(names.len()).ok()?;
+    names.push(measurement.name.clone());
+    Some(index)
+}
+
+/// As many decimals as fit, values too large even without any are clamped.
+fn fixed_point(value: f32) -> (u8, i32) {
+    if value.is_nan() {
+        return (0, NAN);
+    }
+    let value = value as f64;
+    for decimals in (1..=MAX_DECIMALS).rev() {
+        let scaled = (value * 10f64.powi(decimals as i32)).round();
+        if scaled > NAN as f64 && scaled <= i32::MAX as f64 {
+            return (decimals, scaled as i32);
+        }
+    }
+    (0, value.round().clamp(NAN as f64 + 1.0, i32::MAX as f64) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_values_to_three_decimals() {
+        let measurements: Vec<Measurement> = [
+            ("temperature", 21.37),
+            ("lux", 88_000.5),
+            ("light_visible_ir_ratio", 0.4567),
+            ("co2", f32::NAN),
+            ("pressure", 1e12),
+        ]
+        .into_iter()
+        .map(|(name, value)| Measurement {
+            name: name.into(),
+            value,
+        })
+        .chain([Measurement {
+            name: format!("{}_on", "ac").into(),
+            value: 1.0,
+        }])
+        .collect();
+
+        let batch = CompactBatch::encode(1700000000, &measurements);
+        assert_eq!(batch.len(), 6);
+        let decoded = batch.decode();
+        let values: Vec<(&str, f32)> = decoded.iter().map(|m| (m.name.as_ref(), m.value)).collect();
+        assert_eq!(values[0], ("temperature", 21.37));
+        assert_eq!(values[1], ("lux", 88_000.5));
+        assert_eq!(values[2], ("light_visible_ir_ratio", 0.457));
+        assert!(values[3].1.is_nan());
+        assert_eq!(values[4], ("pressure", i32::MAX as f32));
+        assert_eq!(values[5], ("ac_on", 1.0));
+        assert_eq!(std::mem::size_of::<Record>(), 8);
+    }
+}
@@ -0,0 +1,2375 @@
+use std::collections::BTreeMap;
+#[cfg(target_os = "espidf")]
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::{esp, esp_spiffs_format, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::board::Board;
+use crate::calibration::Calibration;
+use crate::crash_loop;
+use crate::error::{Context, Error, Phase, Result};
+use crate::identity::Identity;
+use crate::lorawan;
+use crate::schedule;
+use crate::sensor_memory::SensorMemory;
+use crate::sensors::Measurement;
+
+#[cfg(target_os = "espidf")]
+const STORAGE_PARTITION: &CStr = c"storage";
+#[cfg(target_os = "espidf")]
+const STORAGE_MOUNT_POINT: &CStr = c"/storage";
+const CONFIG_PATH: &str = "/storage/config.json";
+
+pub const MIN_INTERVAL_SEC: u32 = 1;
+pub const MAX_INTERVAL_SEC: u32 = 24 * 60 * 60;
+pub const MAX_JITTER_PERCENT: f32 = 50.0;
+// As many as lwIP is built for, see `CONFIG_LWIP_SNTP_MAX_SERVERS`
+pub const MAX_SNTP_SERVERS: usize = 4;
+// OT_OPERATIONAL_DATASET_MAX_LENGTH
+const MAX_DATASET_LEN: usize = 254;
+// Sensors with an SPI variant that's supported
+const SPI_SENSORS: [&str; 1] = ["bme280"];
+// configMAX_PRIORITIES - 1
+const MAX_TASK_PRIORITY: u8 = 24;
+
+pub type SharedConfig = Arc<Mutex<Config>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub wifi: WifiConfig,
+    pub cellular: CellularConfig,
+    pub lora: LoraConfig,
+    pub lorawan: LorawanConfig,
+    pub thread: ThreadConfig,
+    /// Where the buffered measurements go, applies after a reboot
+    pub uplink: Uplink,
+    /// Further uplinks, every batch goes to each of them and one that's down doesn't hold up the
+    /// others
+    pub extra_uplinks: Vec<Uplink>,
+    /// Uplinks sent averages over this many minutes rather than every round, by name, e.g.
+    /// `{"datadog": 15}`
+    pub downsample_min: BTreeMap<String, u32>,
+    pub graphite: GraphiteConfig,
+    pub datadog: DatadogConfig,
+    pub coap: CoapConfig,
+    pub mqtt: MqttConfig,
+    pub error_reporting: ErrorReportingConfig,
+    pub insights: InsightsConfig,
+    pub fleet: FleetConfig,
+    pub interval_sec: u32,
+    /// Other intervals for local time windows, `interval_sec` applies outside of them
+    pub schedule: Vec<IntervalWindow>,
+    pub jitter: JitterConfig,
+    /// Starts the rounds on multiples of the interval by the local time once the clock is set, e.g.
+    /// at :00, :05 and so on every 5 min, so the data of several nodes lines up. The jitter then
+    /// only holds back the uploads.
+    pub align_to_clock: bool,
+    /// Hours without a successful upload before the network is restarted, after twice as long the
+    /// node reboots, 0 to never intervene
+    pub delivery_watchdog_hours: u32,
+    pub buffers: BufferConfig,
+    /// Local time offset from UTC in minutes, for everything scheduled by the time of day
+    pub utc_offset_min: i32,
+    pub sntp: SntpConfig,
+    pub ds3231: Ds3231Config,
+    /// Of the timestamps written to the SD card and dumped from the console, Graphite always
+    /// gets seconds
+    pub timestamp_resolution: TimestampResolution,
+    /// Placeholders: `{prefix}`, `{id}` and `{name}`, the prefix can use `{id}` as well
+    pub metric_template: String,
+    /// Overrides the ID derived from the MAC address, empty to derive it
+    pub device_id: String,
+    pub i2c: I2cPins,
+    /// SPI bus, shared by the SD card, the e-paper panel and SPI sensors, `cs` is the SD card's
+    pub sdcard: SpiPins,
+    pub sensors: SensorsConfig,
+    /// Sensors wired to the SPI bus instead of I2C, by name with their chip select GPIO, e.g.
+    /// `{"bme280": 10}`, only the BME280 so far
+    pub spi_sensors: BTreeMap<String, i32>,
+    pub scd4x: Scd4xConfig,
+    pub tsl2591: Tsl2591Config,
+    pub hdc1080: Hdc1080Config,
+    pub ccs811: Ccs811Config,
+    pub max44009: Max44009Config,
+    pub dht22: Dht22Config,
+    pub reed_switch: ReedSwitchConfig,
+    /// NTC thermistors on ADC pins, e.g. on the mattress surface
+    pub thermistors: Vec<ThermistorConfig>,
+    /// Generic analog transducers, e.g. a moisture pad under the sheet
+    pub analog_inputs: Vec<AnalogInputConfig>,
+    pub ld2410: Ld2410Config,
+    pub snore: SnoreConfig,
+    pub actigraphy: ActigraphyConfig,
+    pub ir: IrConfig,
+    pub display: DisplayConfig,
+    pub epaper: EpaperConfig,
+    /// Windows in which the listed outputs stay off, e.g. the LED between 22:00 and 07:00
+    pub quiet_hours: Vec<QuietHours>,
+    pub thresholds: BTreeMap<String, Threshold>,
+    /// Applied in order to every round of measurements before it's archived and buffered
+    pub filters: Vec<FilterConfig>,
+    /// Profile used when neither NVS nor the strapping pin select one, empty for none
+    pub profile: String,
+    pub profile_strap: Option<ProfileStrap>,
+    pub profiles: BTreeMap<String, Profile>,
+    pub selftest: SelfTestConfig,
+    pub remote_console: RemoteConsoleConfig,
+    pub modbus: ModbusConfig,
+    pub snmp: SnmpConfig,
+    pub history: HistoryConfig,
+    pub daily: DailySummaryConfig,
+    pub disturbances: DisturbancesConfig,
+    pub alerts: AlertsConfig,
+    pub shutdown: ShutdownConfig,
+    pub consistency: ConsistencyConfig,
+    pub metric_names: MetricNamesConfig,
+    /// Adds heap and per-task stack usage to every round, applies after a reboot
+    pub system_metrics: bool,
+    pub tasks: TaskConfig,
+    pub crash_loop: CrashLoopConfig,
+    #[serde(skip)]
+    pub active_profile: Option<String>,
+    #[serde(skip)]
+    pub identity: Identity,
+    #[serde(skip)]
+    pub calibration: Calibration,
+    /// Trusted by the TLS sinks instead of the CA bundle, from NVS
+    #[serde(skip)]
+    pub tls_certificate: Option<String>,
+    #[serde(skip)]
+    pub sensor_memory: SensorMemory,
+    /// Booted in safe mode after a crash loop, see `crash_loop.rs`: the configuration as it was
+    /// before the restrictions, which is what gets saved
+    #[serde(skip)]
+    pub safe_mode: Option<Box<Config>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterConfig {
+    /// Drops values more than `threshold` median absolute deviations away from the median of the
+    /// last `window` values, `metrics` empty for all
+    Outlier {
+        #[serde(default)]
+        metrics: Vec<String>,
+        #[serde(default = "default_outlier_window")]
+        window: usize,
+        #[serde(default = "default_outlier_threshold")]
+        threshold: f32,
+    },
+    /// Replaces values by the mean of the last `window` values
+    Average {
+        #[serde(default)]
+        metrics: Vec<String>,
+        window: usize,
+    },
+    /// Adds metrics computed from the measured ones: `dew_point`, `absolute_humidity`,
+    /// `heat_index`, `humidex`, `iaq` (0-100 air quality score) and `melanopic_edi`, the last one
+    /// needs `tsl2591.raw_channels`
+    Derived { metrics: Vec<String> },
+    /// Summarizes the night between `bedtime` and `morning` (local `HH:MM`) into `sleep_score.*`
+    /// metrics, added to the first round after `morning`
+    SleepScore {
+        #[serde(default = "default_bedtime")]
+        bedtime: String,
+        #[serde(default = "default_morning")]
+        morning: String,
+    },
+    /// Fuses presence metrics into debounced `bed_occupied` and `room_occupied` metrics
+    Occupancy {
+        sources: Vec<OccupancySource>,
+        /// Weighted share of the sources that has to report presence
+        #[serde(default = "default_occupancy_threshold")]
+        threshold: f32,
+        /// How long an area has to look empty before it counts as empty
+        #[serde(default = "default_occupancy_hold_sec")]
+        hold_sec: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OccupancySource {
+    pub metric: String,
+    #[serde(default = "default_occupancy_weight")]
+    pub weight: f32,
+    /// Values above this count as presence
+    #[serde(default)]
+    pub above: f32,
+    pub area: OccupancyArea,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OccupancyArea {
+    Bed,
+    Room,
+}
+
+fn default_outlier_window() -> usize {
+    10
+}
+
+fn default_outlier_threshold() -> f32 {
+    5.0
+}
+
+fn default_bedtime() -> String {
+    "22:00".to_string()
+}
+
+fn default_morning() -> String {
+    "07:00".to_string()
+}
+
+fn default_occupancy_threshold() -> f32 {
+    0.5
+}
+
+fn default_occupancy_hold_sec() -> u64 {
+    300
+}
+
+fn default_occupancy_weight() -> f32 {
+    1.0
+}
+
+/// Per-room overrides on top of the base configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Profile {
+    pub prefix: Option<String>,
+    pub sensors: Option<SensorsConfig>,
+    pub thresholds: BTreeMap<String, Threshold>,
+    pub schedule: Option<Vec<IntervalWindow>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntervalWindow {
+    /// Local `HH:MM`, the window can wrap around midnight
+    pub start: String,
+    pub end: String,
+    pub interval_sec: u32,
+}
+
+/// Selects a profile by the level of a GPIO, read once at boot with the internal pull-up enabled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProfileStrap {
+    pub pin: i32,
+    pub high: String,
+    pub low: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SelfTestConfig {
+    /// Runs the self-test on every boot
+    pub on_boot: bool,
+    /// Button (active low) that starts the self-test when held during the first seconds after boot,
+    /// held for 10 s it does a factory reset instead. GPIO9 is the BOOT button on the dev boards,
+    /// it's a strapping pin, so press it after the reset.
+    pub button: Option<i32>,
+    /// LED showing the result: on for a few seconds on success, rapid blinking on failure, dark
+    /// during its quiet hours
+    pub led: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WifiConfig {
+    pub ssid: String,
+    pub password: String,
+    /// Stays connected between uploads instead of only bringing the link up for them, so the
+    /// remote console can be reached. Costs power.
+    pub always_on: bool,
+    /// Brings the link up while the sensors measure rather than after, so it's usually up by the
+    /// time the round is ready. Only when not `always_on`.
+    pub prewarm: bool,
+    /// ISO 3166 country code for the regulatory domain, like "DE", or "01" for the world-safe
+    /// one. Empty leaves the driver's default.
+    pub country: String,
+    /// Only scans this channel, 1 to 14, connects quicker when the access point never moves
+    pub channel: Option<u8>,
+    /// Only connects to the access point with this MAC, "aa:bb:cc:dd:ee:ff"
+    pub bssid: Option<String>,
+    pub provisioning: ProvisioningConfig,
+}
+
+/// Getting the credentials onto a node booting without any, see `provisioning.rs`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProvisioningConfig {
+    /// Espressif's BLE provisioning, for their phone apps. Needs the `ble_provisioning` feature.
+    pub ble: bool,
+    /// Proof of possession the app asks for, empty for none
+    pub pop: String,
+    /// ESP-Touch v2 (SmartConfig) after BLE, the last resort for enclosures without a button or a
+    /// BLE app at hand
+    pub smartconfig: bool,
+    /// 16 characters the ESP-Touch app encrypts the credentials with, empty to send them as they are
+    pub esptouch_key: String,
+    /// How long each method waits for credentials before the next, or booting without a network
+    pub timeout_sec: u32,
+}
+
+/// A SIM7000 or SIM7600 modem on UART1 (UART2 on the S3) instead of Wi-Fi, for sites without it.
+/// Needs the `cellular` feature, applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CellularConfig {
+    pub enabled: bool,
+    pub model: ModemModel,
+    pub tx: i32,
+    pub rx: i32,
+    pub baudrate: u32,
+    /// GPIO on the modem's PWRKEY, the modem is then switched off between uploads rather than
+    /// only put in flight mode
+    pub power_key: Option<i32>,
+    /// Of the data connection, empty for the network's default
+    pub apn: String,
+    /// Of the SIM card, empty if it has none
+    pub pin: String,
+    /// How long to wait for the modem to register with the network
+    pub register_timeout_sec: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModemModel {
+    /// LTE-M/NB-IoT
+    #[default]
+    Sim7000,
+    /// LTE Cat-1/4
+    Sim7600,
+}
+
+/// An SX1276 or SX1262 radio on the SPI bus for the `lora` uplink. Needs the `lora` feature,
+/// applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoraConfig {
+    pub chip: LoraChip,
+    pub cs: i32,
+    pub reset: i32,
+    /// The SX1262's BUSY, the SX1276's DIO0
+    pub busy: i32,
+    pub frequency_hz: u32,
+    /// 7 to 12, each step doubles the time on air and adds about 2.5 dB of range
+    pub spreading_factor: u8,
+    /// 125, 250 or 500
+    pub bandwidth_khz: u32,
+    /// The denominator of the 4/5 to 4/8 coding rate
+    pub coding_rate: u8,
+    pub tx_power_dbm: i8,
+    /// Has to match the receiver's, 0x12 for private networks
+    pub sync_word: u8,
+    /// Of the time on air over the last hour, 1% in most of the EU868 sub-bands
+    pub duty_cycle_percent: f32,
+    /// Largest frame sent, a batch is split over as many frames as it takes
+    pub max_payload: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoraChip {
+    #[default]
+    Sx1276,
+    Sx1262,
+}
+
+/// The node as a LoRaWAN device, joining over the air. The radio settings come from `lora`, the
+/// spreading factor there is the data rate. Needs the `lorawan` feature, applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LorawanConfig {
+    /// As in the network's console, most significant byte first
+    pub dev_eui: String,
+    /// Also known as AppEUI, zeros on TTN if there's none
+    pub join_eui: String,
+    pub app_key: String,
+    /// Acknowledged uplinks, a batch stays buffered until one is. TTN allows ten downlinks a day
+    pub confirmed: bool,
+    pub port: u8,
+    pub codec: LorawanCodec,
+    /// Channels the node joins on, more can come with the join
+    pub channels_hz: Vec<u32>,
+    pub rx2_frequency_hz: u32,
+    /// Of the RX2 window until the network says otherwise, 9 on TTN's EU868
+    pub rx2_spreading_factor: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LorawanCodec {
+    /// TTN decodes it without a formatter of our own
+    #[default]
+    CayenneLpp,
+    /// Every metric with the time of the round, see `lorawan`
+    Compact,
+}
+
+/// A Thread network instead of Wi-Fi, on chips with an 802.15.4 radio (ESP32-C6/H2). The node
+/// joins as a minimal end device with the network's active dataset, as `ot-ctl dataset active -x`
+/// prints it on the border router. Needs the `thread` feature, applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ThreadConfig {
+    pub enabled: bool,
+    /// Hex
+    pub dataset: String,
+    /// How long to wait for the node to attach to the network
+    pub attach_timeout_sec: u32,
+}
+
+/// The console commands over TCP (telnet or netcat), applies after a reboot. Needs
+/// `wifi.always_on`, there's no link between uploads otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RemoteConsoleConfig {
+    pub port: u16,
+    /// Asked for before any command, empty disables the remote console
+    pub password: String,
+}
+
+/// Modbus TCP server with the latest readings, the register map is in `modbus.rs`. Applies after
+/// a reboot and needs `wifi.always_on`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ModbusConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+/// Read-only SNMP v2c agent, the OIDs are listed in `snmp.rs`. Applies after a reboot and needs
+/// `wifi.always_on`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SnmpConfig {
+    pub enabled: bool,
+    pub port: u16,
+    pub community: String,
+    /// Private enterprise number the readings are under, the default is the one reserved for
+    /// documentation (RFC 5612)
+    pub enterprise: u32,
+}
+
+/// Recent rounds served as JSON on `/history`, see `history.rs`. Applies after a reboot and needs
+/// `wifi.always_on`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// How far back it goes, the node keeps at most 512 rounds
+    pub minutes: u32,
+}
+
+/// `daily.*` aggregates uploaded after local midnight, see `daily.rs`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DailySummaryConfig {
+    pub enabled: bool,
+    /// Get a min, max and mean
+    pub metrics: Vec<String>,
+    /// In ppm, each gets the hours CO2 spent above it
+    pub co2_thresholds: Vec<f32>,
+}
+
+/// Discrete events for overlaying on sleep charts, kept apart from the rounds, see
+/// `disturbances.rs`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DisturbancesConfig {
+    pub enabled: bool,
+    /// `lux` going above this is a light disturbance, unless `tsl2591.interrupt_pin` catches
+    /// them as they happen
+    pub light_lux: f32,
+    /// `co2` going above this, in ppm
+    pub co2_ppm: f32,
+    /// Presence metrics whose rise counts as motion
+    pub motion_metrics: Vec<String>,
+    /// Graphite's events API, e.g. `http://graphite.local/events/`
+    pub graphite_url: String,
+    /// Gets a JSON object per disturbance
+    pub webhook_url: String,
+    /// Also publishes them on `<mqtt.base_topic>/disturbance`
+    pub mqtt: bool,
+}
+
+/// Events uploaded right away rather than on the schedule, see `alerts.rs`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    /// Metrics whose every change is an alert, besides any metric crossing its threshold
+    pub change_metrics: Vec<String>,
+}
+
+/// How long the buffer holds on to each class of metrics while the uplink is down, see
+/// `buffers.rs`. Patterns are exact names, or prefixes with a trailing `*`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BufferConfig {
+    /// Kept longest, e.g. CO2 and occupancy
+    pub high_metrics: Vec<String>,
+    /// Dropped first, e.g. the uplink and system diagnostics
+    pub low_metrics: Vec<String>,
+    pub high_hours: u32,
+    /// Everything that isn't high or low
+    pub normal_hours: u32,
+    pub low_hours: u32,
+}
+
+/// Time servers, tried in order. Rounds measured before the first sync are held back for up to
+/// `timeout_sec` and go out with the clock as it is after that, SNTP keeps trying in the
+/// background.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SntpConfig {
+    /// Names or addresses, e.g. the router on a network without a way out, at most 4
+    pub servers: Vec<String>,
+    pub timeout_sec: u32,
+}
+
+/// Battery-backed clock on the I2C bus, see `rtc.rs`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Ds3231Config {
+    pub enabled: bool,
+    pub address: u8,
+}
+
+/// Orderly shutdown on a flat battery, see `shutdown.rs`. The console and MQTT `shutdown` command
+/// work without it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Metric with the battery level, e.g. `battery_mv` from an analog input, empty to never shut
+    /// down on a low battery
+    pub battery_metric: String,
+    pub low_battery_below: f32,
+    /// Rounds in a row below `low_battery_below` before shutting down, a dip under load isn't enough
+    pub low_battery_rounds: u32,
+}
+
+/// FreeRTOS priorities of the sampling and upload tasks, see `tasks.rs`. Applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TaskConfig {
+    /// Threads sampling sensors on core 1 and the uplink on core 0 of dual-core chips, off to let
+    /// the scheduler move them
+    pub pinned: bool,
+    pub sensing_priority: u8,
+    pub network_priority: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CrashLoopConfig {
+    /// Boots in a row that each end within `stable_sec` before the next one is in safe mode, 0 to
+    /// never boot into safe mode
+    pub boots: u32,
+    pub stable_sec: u32,
+}
+
+/// Metrics more than one sensor reports, e.g. the temperature of the BME280 and the SCD4x, see
+/// `consistency.rs`. Applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConsistencyConfig {
+    /// How far a sensor can be from the median of all sensors reporting the metric, keyed by
+    /// metric. Metrics without a tolerance aren't compared.
+    pub tolerances: BTreeMap<String, f32>,
+    /// Rounds in a row further off before the sensor counts as suspect
+    pub rounds: u32,
+}
+
+/// Keeps metrics of different sensors apart, the BME280 and the SCD4x both report `temperature`
+/// and `humidity`. Applies after a reboot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MetricNamesConfig {
+    /// Every sensor metric as `<sensor>.<metric>`
+    pub namespaced: bool,
+    /// Otherwise one sensor keeps the plain name of a metric several report, the first of these
+    /// that reported it this round or the first registered one. The others report it as
+    /// `<sensor>.<metric>`.
+    pub preferred: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GraphiteConfig {
+    /// Name, IPv4 or IPv6 address
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+    pub units: Units,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Uplink {
+    #[default]
+    Graphite,
+    /// Datadog's series API over HTTPS
+    Datadog,
+    /// The broker in `mqtt.url`, with every upload rather than as the rounds come in
+    Mqtt,
+    /// Frames to a receiver of our own over the radio in `lora`
+    Lora,
+    /// The Things Network or another LoRaWAN network over the radio in `lora`, with the keys in
+    /// `lorawan`
+    Lorawan,
+    /// POSTs to `coap.path` over UDP, e.g. through a Thread border router
+    Coap,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CoapConfig {
+    /// Name, IPv4 or IPv6 address, on Thread usually the address of a border router's proxy or one
+    /// behind its NAT64 prefix
+    pub host: String,
+    pub port: u16,
+    /// With the device ID for `{id}`
+    pub path: String,
+    /// Resent until acknowledged, or sent once
+    pub confirmable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DatadogConfig {
+    /// E.g. `datadoghq.eu` or `us5.datadoghq.com`
+    pub site: String,
+    pub api_key: String,
+    /// Of the metric names, no placeholders
+    pub prefix: String,
+    /// Added to every series next to `device:<id>`, e.g. `room:bedroom`
+    pub tags: Vec<String>,
+    pub units: Units,
+    /// Deflates request bodies of 1 KB or more, a flushed backlog goes out in a fraction of the
+    /// airtime. Takes about 200 KB of heap while compressing.
+    pub compress: bool,
+}
+
+/// Where logged errors and panics are sent, both empty to keep them on the serial console only
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ErrorReportingConfig {
+    /// Sentry (or compatible) project DSN
+    pub sentry_dsn: String,
+    /// Gets a JSON object per event
+    pub webhook_url: String,
+}
+
+/// Self-hosted fleet diagnostics: reset reason and crash once per boot, system metrics on an
+/// interval, POSTed as JSON while the network is up for an upload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InsightsConfig {
+    /// Collector endpoint, empty disables the reports
+    pub url: String,
+    pub interval_min: u32,
+}
+
+/// Pulls this node's configuration from a fleet manager while the network is up for an upload.
+/// The document is `{"version": <n>, "config": {...}, "commands": [...]}`, the `config` part is
+/// merged into the configuration and saved, see `fleet` for the commands.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FleetConfig {
+    /// `{id}` is replaced by the device ID, empty disables the pull
+    pub url: String,
+    /// Sent as a bearer token if set
+    pub token: String,
+}
+
+/// Publishes every round to an MQTT broker next to the uplink, applies after a reboot. Needs
+/// `wifi.always_on`, the broker marks the node offline whenever the link drops otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// E.g. `mqtt://192.168.24.1:1883`, empty disables MQTT
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /// Topic everything is published under, `{id}` is replaced by the device ID
+    pub base_topic: String,
+    pub layout: MqttLayout,
+    /// 0, 1 or 2, for everything published including the Last Will
+    pub qos: u8,
+    /// Of the measurements, the status topic is always retained
+    pub retain: bool,
+    /// Between the uptime messages on `<base_topic>/heartbeat`
+    pub heartbeat_sec: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttLayout {
+    /// One JSON object per round on `<base_topic>/state`
+    #[default]
+    State,
+    /// Every value on a topic of its own, `<base_topic>/<name>`
+    PerMetric,
+}
+
+/// Units a sink reports in, measurements stay metric everywhere else
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Units {
+    /// °C and mmHg, as measured
+    #[default]
+    Metric,
+    /// °F and inHg
+    Imperial,
+}
+
+/// Spreads the wait between rounds, or with `align_to_clock` the uploads, so nodes started together
+/// don't all upload at the same time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct JitterConfig {
+    pub mode: JitterMode,
+    /// Furthest a wait may be off the interval, in % of it
+    pub percent: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JitterMode {
+    /// Exactly the interval
+    None,
+    /// Every wait picked at random
+    #[default]
+    Uniform,
+    /// Every wait a random step away from the last one, so nodes that happen to line up drift
+    /// apart again instead of staying in step for a while
+    Decorrelated,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampResolution {
+    #[default]
+    Seconds,
+    Milliseconds,
+}
+
+impl TimestampResolution {
+    pub fn convert(self, timestamp_ms: u64) -> u64 {
+        match self {
+            TimestampResolution::Seconds => timestamp_ms / 1000,
+            TimestampResolution::Milliseconds => timestamp_ms,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct I2cPins {
+    pub sda: i32,
+    pub scl: i32,
+    pub baudrate_khz: u32,
+    /// Longest a transaction may take, including waiting for the bus, before it's given up and
+    /// counted as an error of the sensor
+    pub timeout_ms: u32,
+    /// Per sensor overrides of `timeout_ms`, by name
+    pub sensor_timeout_ms: BTreeMap<String, u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpiPins {
+    pub sclk: i32,
+    pub mosi: i32,
+    pub miso: i32,
+    pub cs: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SensorsConfig {
+    pub bme280: bool,
+    pub scd4x: bool,
+    pub tsl2591: bool,
+    pub as7341: bool,
+    pub ld2410: bool,
+    pub hdc1080: bool,
+    pub ccs811: bool,
+    pub sgp30: bool,
+    pub max44009: bool,
+    pub dht22: bool,
+    pub reed_switch: bool,
+    pub thermistor: bool,
+    pub analog: bool,
+    pub snore: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Scd4xConfig {
+    pub mode: Scd4xMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scd4xMode {
+    /// Woken up for every measurement and powered down in between, for battery powered nodes
+    #[default]
+    SingleShot,
+    /// Measures every 30 s on its own, smoother data for mains powered nodes
+    LowPowerPeriodic,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Tsl2591Config {
+    /// Also report `light_full`, `light_ir` and `light_visible_ir_ratio` next to `lux`
+    pub raw_channels: bool,
+    /// GPIO wired to INT, keeps the sensor integrating and records a `light` disturbance whenever
+    /// the light changes by more than `change_percent` between rounds
+    pub interrupt_pin: Option<i32>,
+    pub change_percent: f32,
+}
+
+impl Default for Tsl2591Config {
+    fn default() -> Self {
+        Tsl2591Config {
+            raw_channels: false,
+            interrupt_pin: None,
+            // Twice or half as bright, a lamp switched on or off rather than a passing cloud
+            change_percent: 100.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Hdc1080Config {
+    /// Humidity that triggers the heater, to recover from condensation, `null` to never heat
+    pub heater_rh: Option<f32>,
+    pub heater_sec: u32,
+}
+
+impl Default for Hdc1080Config {
+    fn default() -> Self {
+        Hdc1080Config {
+            heater_rh: Some(98.0),
+            heater_sec: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Ccs811Config {
+    /// 0x5A, or 0x5B with ADDR pulled high
+    pub address: u8,
+}
+
+impl Default for Ccs811Config {
+    fn default() -> Self {
+        Ccs811Config { address: 0x5A }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Max44009Config {
+    /// 0x4A, or 0x4B with A0 pulled high
+    pub address: u8,
+}
+
+impl Default for Max44009Config {
+    fn default() -> Self {
+        Max44009Config { address: 0x4A }
+    }
+}
+
+/// DHT22/AM2302 on a single-wire GPIO, it needs a pull-up if the module doesn't have one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Dht22Config {
+    pub pin: i32,
+}
+
+impl Default for Dht22Config {
+    fn default() -> Self {
+        Dht22Config { pin: 21 }
+    }
+}
+
+/// Door or window contact, a reed switch between a GPIO and ground
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReedSwitchConfig {
+    pub pin: i32,
+    /// The switch opens when the magnet moves away, which the pull-up reads as high. False for
+    /// normally open switches.
+    pub open_when_high: bool,
+    pub debounce_ms: u32,
+}
+
+impl Default for ReedSwitchConfig {
+    fn default() -> Self {
+        ReedSwitchConfig {
+            pin: 10,
+            open_when_high: true,
+            debounce_ms: 50,
+        }
+    }
+}
+
+/// An NTC thermistor in a voltage divider on an ADC pin, reported as `<name>.temperature`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ThermistorConfig {
+    /// Tells the thermistors apart, e.g. `mattress`
+    pub name: String,
+    pub pin: i32,
+    /// The fixed resistor of the divider
+    pub series_ohm: f32,
+    /// Divider supply, usually the 3.3 V rail
+    pub supply_mv: f32,
+    /// The thermistor is the low side of the divider, between the pin and ground
+    pub to_ground: bool,
+    pub model: ThermistorModel,
+}
+
+impl Default for ThermistorConfig {
+    fn default() -> Self {
+        ThermistorConfig {
+            name: String::new(),
+            pin: Board::current().pins().adc,
+            series_ohm: 10_000.0,
+            supply_mv: 3300.0,
+            to_ground: true,
+            model: ThermistorModel::Beta {
+                beta: 3950.0,
+                r0_ohm: 10_000.0,
+                t0: 25.0,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ThermistorModel {
+    /// From the datasheet: β and the resistance at `t0` °C
+    Beta { beta: f32, r0_ohm: f32, t0: f32 },
+    /// 1/T = a + b ln R + c (ln R)³, more accurate over a wide range
+    SteinhartHart { a: f64, b: f64, c: f64 },
+}
+
+/// An analog transducer on an ADC pin, reported as `<name>_mv` and, once its range is calibrated
+/// with `calibrate range`, as `<name>` in %
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalogInputConfig {
+    /// The metric, e.g. `bed_moisture`
+    pub name: String,
+    pub pin: i32,
+}
+
+/// LD2410 presence radar on a UART
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Ld2410Config {
+    pub tx: i32,
+    pub rx: i32,
+    /// Needed for the `breathing_rate` estimate
+    pub engineering_mode: bool,
+    /// Stillness needed for a breathing rate estimate
+    pub breathing_window_sec: u32,
+}
+
+/// I2S MEMS microphone, e.g. an INMP441, for `snore_events`, disabled without pins
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SnoreConfig {
+    pub bclk: Option<i32>,
+    pub ws: Option<i32>,
+    pub din: Option<i32>,
+    /// Classifier trained off the node, see [`crate::snore`]
+    pub model_path: String,
+    /// Score from which a frame counts as snoring
+    pub threshold: f32,
+    /// Snoring needed for an event
+    pub min_event_ms: u32,
+    /// Frames quieter than this aren't scored
+    pub min_level_db: f32,
+}
+
+/// Movement epochs from a LIS3DH accelerometer sampled in the background, e.g. on the mattress
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ActigraphyConfig {
+    pub enabled: bool,
+    pub address: u8,
+    pub epoch_sec: u32,
+    /// Deviations from gravity below this are treated as noise
+    pub threshold_mg: f32,
+}
+
+/// SSD1306 OLED on the I2C bus
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub enabled: bool,
+    pub address: u8,
+    pub pages: Vec<Page>,
+    /// Shows the next page every `page_sec`, 0 to only switch pages with the button
+    pub page_sec: u32,
+    /// Button (active low) showing the next page, it also wakes a dark display for `wake_sec`
+    pub button: Option<i32>,
+    pub wake_sec: u32,
+    /// Dims below this light level and goes dark below `blank_below_lux`, so the display doesn't
+    /// light up the bedroom at night
+    pub dim_below_lux: f32,
+    pub blank_below_lux: f32,
+    /// Metric graphed on the history page
+    pub history_metric: String,
+}
+
+/// SSD1680 e-paper panel (2.13", 250x122) on the SPI bus showing a summary of the day
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EpaperConfig {
+    pub enabled: bool,
+    pub cs: i32,
+    pub dc: i32,
+    pub rst: i32,
+    pub busy: i32,
+    /// Every refresh flashes the panel, so it's only redrawn this often
+    pub refresh_min: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Page {
+    /// Temperature, humidity, pressure and light
+    Climate,
+    /// CO2, IAQ score, TVOC and particulates
+    AirQuality,
+    /// Device ID, uptime, free heap and buffered batches
+    Network,
+    /// Day graph of `history_metric`
+    History,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuietHours {
+    pub outputs: Vec<Output>,
+    /// Local `HH:MM`, the window can wrap around midnight
+    pub start: String,
+    pub end: String,
+}
+
+/// Anything the device can disturb the room with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    Led,
+    Display,
+    Buzzer,
+    WakeLight,
+}
+
+/// IR transmitter switching appliances by `rules`, disabled without a pin
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IrConfig {
+    pub pin: Option<i32>,
+    pub carrier_khz: u32,
+    pub devices: BTreeMap<String, IrDevice>,
+    pub rules: Vec<ClimateRule>,
+}
+
+/// Raw codes as alternating mark and space durations in µs, e.g. captured with an IR receiver
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IrDevice {
+    pub on: Vec<u32>,
+    pub off: Vec<u32>,
+}
+
+/// Switches `device` on once `metric` goes past `on` and off once it's back past `off`, with `on`
+/// above `off` for cooling and below it for heating
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClimateRule {
+    pub device: String,
+    pub metric: String,
+    pub on: f32,
+    pub off: f32,
+    /// Only switched on while this metric is above 0, e.g. `bed_occupied`
+    #[serde(default)]
+    pub when_occupied: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Threshold {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            wifi: WifiConfig::default(),
+            cellular: CellularConfig::default(),
+            lora: LoraConfig::default(),
+            lorawan: LorawanConfig::default(),
+            thread: ThreadConfig::default(),
+            uplink: Uplink::default(),
+            extra_uplinks: Vec::new(),
+            downsample_min: BTreeMap::new(),
+            graphite: GraphiteConfig::default(),
+            datadog: DatadogConfig::default(),
+            coap: CoapConfig::default(),
+            mqtt: MqttConfig::default(),
+            error_reporting: ErrorReportingConfig::default(),
+            insights: InsightsConfig::default(),
+            fleet: FleetConfig::default(),
+            interval_sec: 300,
+            schedule: Vec::new(),
+            jitter: JitterConfig::default(),
+            align_to_clock: false,
+            delivery_watchdog_hours: 0,
+            buffers: BufferConfig::default(),
+            utc_offset_min: 0,
+            sntp: SntpConfig::default(),
+            ds3231: Ds3231Config::default(),
+            timestamp_resolution: TimestampResolution::default(),
+            metric_template: "{prefix}{name}".to_string(),
+            device_id: String::new(),
+            i2c: I2cPins::default(),
+            sdcard: SpiPins::default(),
+            sensors: SensorsConfig::default(),
+            spi_sensors: BTreeMap::new(),
+            scd4x: Scd4xConfig::default(),
+            tsl2591: Tsl2591Config::default(),
+            hdc1080: Hdc1080Config::default(),
+            ccs811: Ccs811Config::default(),
+            max44009: Max44009Config::default(),
+            dht22: Dht22Config::default(),
+            reed_switch: ReedSwitchConfig::default(),
+            thermistors: Vec::new(),
+            analog_inputs: Vec::new(),
+            ld2410: Ld2410Config::default(),
+            snore: SnoreConfig::default(),
+            actigraphy: ActigraphyConfig::default(),
+            ir: IrConfig::default(),
+            display: DisplayConfig::default(),
+            epaper: EpaperConfig::default(),
+            quiet_hours: Vec::new(),
+            thresholds: BTreeMap::new(),
+            filters: Vec::new(),
+            profile: String::new(),
+            profile_strap: None,
+            profiles: BTreeMap::new(),
+            selftest: SelfTestConfig::default(),
+            remote_console: RemoteConsoleConfig::default(),
+            modbus: ModbusConfig::default(),
+            snmp: SnmpConfig::default(),
+            history: HistoryConfig::default(),
+            daily: DailySummaryConfig::default(),
+            disturbances: DisturbancesConfig::default(),
+            alerts: AlertsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            consistency: ConsistencyConfig::default(),
+            metric_names: MetricNamesConfig::default(),
+            system_metrics: false,
+            tasks: TaskConfig::default(),
+            crash_loop: CrashLoopConfig::default(),
+            active_profile: None,
+            identity: Identity::default(),
+            calibration: Calibration::default(),
+            tls_certificate: None,
+            sensor_memory: SensorMemory::default(),
+            safe_mode: None,
+        }
+    }
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        let pins = Board::current().pins();
+        SelfTestConfig {
+            on_boot: false,
+            button: pins.button,
+            led: pins.led,
+        }
+    }
+}
+
+impl Default for WifiConfig {
+    fn default() -> Self {
+        // Credentials baked in at build time are optional now that they can come from the config file
+        WifiConfig {
+            ssid: option_env!("SSID").unwrap_or_default().to_string(),
+            password: option_env!("WIFI_PASSWORD").unwrap_or_default().to_string(),
+            always_on: false,
+            prewarm: true,
+            country: String::new(),
+            channel: None,
+            bssid: None,
+            provisioning: ProvisioningConfig::default(),
+        }
+    }
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        ProvisioningConfig {
+            ble: true,
+            pop: String::new(),
+            smartconfig: false,
+            esptouch_key: String::new(),
+            timeout_sec: 10 * 60,
+        }
+    }
+}
+
+impl WifiConfig {
+    /// The BSSID as bytes, `None` without one or if it isn't a MAC.
+    pub fn bssid_bytes(&self) -> Option<[u8; 6]> {
+        let bssid = self.bssid.as_deref()?;
+        let mut bytes = [0u8; 6];
+        let mut parts = bssid.split(':');
+        for byte in &mut bytes {
+            let part = parts.next().filter(|part| part.len() == 2)?;
+            *byte = u8::from_str_radix(part, 16).ok()?;
+        }
+        parts.next().is_none().then_some(bytes)
+    }
+}
+
+impl Default for RemoteConsoleConfig {
+    fn default() -> Self {
+        RemoteConsoleConfig {
+            port: 23,
+            password: String::new(),
+        }
+    }
+}
+
+impl Default for ModbusConfig {
+    fn default() -> Self {
+        ModbusConfig {
+            enabled: false,
+            port: 502,
+        }
+    }
+}
+
+impl Default for SnmpConfig {
+    fn default() -> Self {
+        SnmpConfig {
+            enabled: false,
+            port: 161,
+            community: "public".to_string(),
+            enterprise: 32473,
+        }
+    }
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        JitterConfig {
+            mode: JitterMode::default(),
+            percent: 10.0,
+        }
+    }
+}
+
+impl Default for GraphiteConfig {
+    fn default() -> Self {
+        GraphiteConfig {
+            host: "192.168.24.1".to_string(),
+            port: 2003,
+            prefix: option_env!("DATA_PREFIX").unwrap_or("sleep_thing.{id}.").to_string(),
+            units: Units::default(),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: false,
+            port: 80,
+            minutes: 12 * 60,
+        }
+    }
+}
+
+impl Default for DailySummaryConfig {
+    fn default() -> Self {
+        DailySummaryConfig {
+            enabled: false,
+            metrics: ["temperature", "humidity", "co2", "lux"].map(String::from).to_vec(),
+            co2_thresholds: vec![1000.0, 1400.0],
+        }
+    }
+}
+
+impl Default for DisturbancesConfig {
+    fn default() -> Self {
+        DisturbancesConfig {
+            enabled: false,
+            light_lux: 5.0,
+            co2_ppm: 1400.0,
+            motion_metrics: vec!["presence_moving".to_string(), "bed_motion".to_string()],
+            graphite_url: String::new(),
+            webhook_url: String::new(),
+            mqtt: false,
+        }
+    }
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            enabled: true,
+            change_metrics: vec!["bed_occupied".to_string(), "room_occupied".to_string(), "window_open".to_string()],
+        }
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        let patterns = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect();
+        BufferConfig {
+            high_metrics: patterns(&["co2", "bed_occupied", "room_occupied", "presence_*", "window_open"]),
+            low_metrics: patterns(&[
+                "buffer_*",
+                "uplink_*",
+                "heap_*",
+                "stack_free.*",
+                "out_of_range_total",
+                "fleet_config_version",
+            ]),
+            high_hours: 48,
+            normal_hours: 24,
+            low_hours: 6,
+        }
+    }
+}
+
+impl Default for SntpConfig {
+    fn default() -> Self {
+        SntpConfig {
+            servers: (0..4).map(|index| format!("{}.pool.ntp.org", index)).collect(),
+            timeout_sec: 30,
+        }
+    }
+}
+
+impl Default for Ds3231Config {
+    fn default() -> Self {
+        Ds3231Config {
+            enabled: true,
+            address: 0x68,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            battery_metric: String::new(),
+            low_battery_below: 0.0,
+            low_battery_rounds: 3,
+        }
+    }
+}
+
+impl Default for TaskConfig {
+    fn default() -> Self {
+        TaskConfig {
+            pinned: true,
+            // Above lwIP (18) on its own core, below the Wi-Fi driver (23) on a single core
+            sensing_priority: 20,
+            network_priority: 5,
+        }
+    }
+}
+
+impl Default for CrashLoopConfig {
+    fn default() -> Self {
+        CrashLoopConfig {
+            boots: 5,
+            stable_sec: 120,
+        }
+    }
+}
+
+impl Default for MetricNamesConfig {
+    fn default() -> Self {
+        // The SCD4x runs warm and its humidity follows, the dedicated sensors read closer
+        let climate: Vec<String> = ["bme280", "hdc1080", "dht22", "scd4x"].map(String::from).to_vec();
+        MetricNamesConfig {
+            namespaced: false,
+            preferred: BTreeMap::from([
+                ("temperature".to_string(), climate.clone()),
+                ("humidity".to_string(), climate),
+            ]),
+        }
+    }
+}
+
+impl Default for ConsistencyConfig {
+    fn default() -> Self {
+        ConsistencyConfig {
+            tolerances: BTreeMap::from([("temperature".to_string(), 1.0), ("humidity".to_string(), 5.0)]),
+            rounds: 10,
+        }
+    }
+}
+
+impl GraphiteConfig {
+    /// `host:port`, an IPv6 address in brackets, for messages.
+    pub fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl CoapConfig {
+    /// `host:port`, an IPv6 address in brackets, for messages.
+    pub fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl Default for CoapConfig {
+    fn default() -> Self {
+        CoapConfig {
+            host: String::new(),
+            port: 5683,
+            path: "sleep_thing/{id}".to_string(),
+            confirmable: true,
+        }
+    }
+}
+
+impl Default for DatadogConfig {
+    fn default() -> Self {
+        DatadogConfig {
+            site: "datadoghq.com".to_string(),
+            api_key: String::new(),
+            prefix: "sleep_thing.".to_string(),
+            tags: Vec::new(),
+            units: Units::default(),
+            compress: false,
+        }
+    }
+}
+
+impl Default for InsightsConfig {
+    fn default() -> Self {
+        InsightsConfig {
+            url: String::new(),
+            interval_min: 60,
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            base_topic: "sleep_thing/{id}".to_string(),
+            layout: MqttLayout::default(),
+            qos: 0,
+            retain: false,
+            heartbeat_sec: 60,
+        }
+    }
+}
+
+impl Default for I2cPins {
+    fn default() -> Self {
+        let pins = Board::current().pins();
+        I2cPins {
+            sda: pins.sda,
+            scl: pins.scl,
+            baudrate_khz: 100,
+            timeout_ms: 100,
+            sensor_timeout_ms: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for SpiPins {
+    fn default() -> Self {
+        let pins = Board::current().pins();
+        SpiPins {
+            sclk: pins.sclk,
+            mosi: pins.mosi,
+            miso: pins.miso,
+            cs: 18,
+        }
+    }
+}
+
+impl Default for CellularConfig {
+    fn default() -> Self {
+        CellularConfig {
+            enabled: false,
+            model: ModemModel::default(),
+            tx: Board::current().pins().uart_tx,
+            rx: Board::current().pins().uart_rx,
+            baudrate: 115_200,
+            power_key: None,
+            apn: String::new(),
+            pin: String::new(),
+            register_timeout_sec: 120,
+        }
+    }
+}
+
+impl Default for LoraConfig {
+    fn default() -> Self {
+        LoraConfig {
+            chip: LoraChip::default(),
+            cs: 14,
+            reset: 15,
+            busy: 3,
+            frequency_hz: 868_100_000,
+            spreading_factor: 9,
+            bandwidth_khz: 125,
+            coding_rate: 5,
+            tx_power_dbm: 14,
+            sync_word: 0x12,
+            duty_cycle_percent: 1.0,
+            max_payload: 51,
+        }
+    }
+}
+
+impl Default for LorawanConfig {
+    fn default() -> Self {
+        LorawanConfig {
+            dev_eui: String::new(),
+            join_eui: "0000000000000000".to_string(),
+            app_key: String::new(),
+            confirmed: false,
+            port: 1,
+            codec: LorawanCodec::default(),
+            // The three every EU868 device has
+            channels_hz: vec![868_100_000, 868_300_000, 868_500_000],
+            rx2_frequency_hz: 869_525_000,
+            rx2_spreading_factor: 9,
+        }
+    }
+}
+
+impl ThreadConfig {
+    /// The dataset's TLVs, none if it isn't hex or longer than OpenThread takes.
+    pub fn dataset_tlvs(&self) -> Option<Vec<u8>> {
+        let dataset = self.dataset.trim();
+        if dataset.len() % 2 != 0 || dataset.len() > 2 * MAX_DATASET_LEN || !dataset.is_ascii() {
+            return None;
+        }
+        (0..dataset.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&dataset[index..index + 2], 16).ok())
+            .collect()
+    }
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig {
+            enabled: false,
+            dataset: String::new(),
+            attach_timeout_sec: 60,
+        }
+    }
+}
+
+impl Default for Ld2410Config {
+    fn default() -> Self {
+        let pins = Board::current().pins();
+        Ld2410Config {
+            tx: pins.uart_tx,
+            rx: pins.uart_rx,
+            engineering_mode: true,
+            breathing_window_sec: 60,
+        }
+    }
+}
+
+impl Default for SnoreConfig {
+    fn default() -> Self {
+        SnoreConfig {
+            bclk: None,
+            ws: None,
+            din: None,
+            model_path: "/storage/snore_model.json".to_string(),
+            threshold: 0.5,
+            min_event_ms: 300,
+            min_level_db: -50.0,
+        }
+    }
+}
+
+impl Default for ActigraphyConfig {
+    fn default() -> Self {
+        ActigraphyConfig {
+            enabled: false,
+            address: 0x18,
+            epoch_sec: 30,
+            threshold_mg: 20.0,
+        }
+    }
+}
+
+impl Default for IrConfig {
+    fn default() -> Self {
+        IrConfig {
+            pin: None,
+            carrier_khz: 38,
+            devices: BTreeMap::new(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            enabled: false,
+            address: 0x3C,
+            pages: vec![Page::Climate, Page::AirQuality, Page::Network, Page::History],
+            page_sec: 10,
+            button: None,
+            wake_sec: 30,
+            dim_below_lux: 20.0,
+            blank_below_lux: 2.0,
+            history_metric: "co2".to_string(),
+        }
+    }
+}
+
+impl Default for EpaperConfig {
+    fn default() -> Self {
+        EpaperConfig {
+            enabled: false,
+            cs: 10,
+            dc: 11,
+            rst: 1,
+            busy: 0,
+            refresh_min: 5,
+        }
+    }
+}
+
+impl Default for SensorsConfig {
+    fn default() -> Self {
+        SensorsConfig {
+            bme280: true,
+            scd4x: true,
+            tsl2591: true,
+            // Optional extras, most boards don't carry them
+            as7341: false,
+            ld2410: false,
+            hdc1080: false,
+            ccs811: false,
+            sgp30: false,
+            max44009: false,
+            dht22: false,
+            reed_switch: false,
+            thermistor: false,
+            analog: false,
+            snore: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file, falling back to the built-in defaults if it's missing or broken.
+    pub fn load() -> Self {
+        Config::load_from(CONFIG_PATH)
+    }
+
+    pub fn load_from(path: &str) -> Self {
+        match fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Config>(&contents) {
+                Ok(config) => match config.validate() {
+                    Ok(_) => {
+                        info!("Loaded configuration from {}", path);
+                        config
+                    }
+                    Err(err) => {
+                        error!("Invalid configuration in {}, using defaults: {}", path, err);
+                        Config::default()
+                    }
+                },
+                Err(err) => {
+                    error!("Failed to parse {}, using defaults: {:?}", path, err);
+                    Config::default()
+                }
+            },
+            Err(_) => {
+                info!("No configuration file at {}, using defaults", path);
+                Config::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let contents = serde_json::to_string_pretty(&self.saved()?)?;
+        // Write to a temporary file first, so a power loss can't leave a half-written config behind
+        let temp_path = format!("{}.tmp", CONFIG_PATH);
+        fs::write(&temp_path, contents).with_context(Phase::Storage, || format!("Failed to write {}", temp_path))?;
+        // SPIFFS can't rename over an existing file
+        match fs::remove_file(CONFIG_PATH) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).context(Phase::Storage, "Failed to replace the configuration file");
+            }
+            _ => {}
+        }
+        fs::rename(&temp_path, CONFIG_PATH)
+            .with_context(Phase::Storage, || format!("Failed to rename {}", temp_path))?;
+        info!("Configuration saved to {}", CONFIG_PATH);
+        Ok(())
+    }
+
+    /// What `save` writes. In safe mode that's the configuration from before the restrictions
+    /// with the changes made since, so saving doesn't wipe the sensors and profiles.
+    fn saved(&self) -> Result<Value> {
+        let current = serde_json::to_value(self)?;
+        let Some(unrestricted) = &self.safe_mode else {
+            return Ok(current);
+        };
+        let mut restricted = (**unrestricted).clone();
+        crash_loop::restrict(&mut restricted);
+        let mut saved = serde_json::to_value(&**unrestricted)?;
+        merge(&mut saved, &changes(&serde_json::to_value(&restricted)?, &current));
+        Ok(saved)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Value> {
+        let json = serde_json::to_value(self)?;
+        json.pointer(&pointer(key))
+            .cloned()
+            .ok_or_else(|| Error::UnknownKey(key.to_string()))
+    }
+
+    /// Sets a dotted key (e.g. `graphite.port` or `thresholds.co2.max`), the value is parsed as JSON
+    /// and taken as a plain string if that fails.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut json = serde_json::to_value(&*self)?;
+        let mut slot = &mut json;
+        for part in key.split('.') {
+            if slot.is_null() {
+                // Allows adding new entries to maps like `thresholds`
+                *slot = Value::Object(Map::new());
+            }
+            slot = match slot {
+                Value::Object(map) => map.entry(part).or_insert(Value::Null),
+                _ => return Err(Error::UnknownKey(key.to_string())),
+            };
+        }
+        *slot = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+        let updated: Config = serde_json::from_value(json).map_err(|source| Error::InvalidValue {
+            key: key.to_string(),
+            source,
+        })?;
+        // Unknown keys are silently dropped by serde, catch them before touching the active config
+        if updated.get(key).is_err() {
+            return Err(Error::UnknownKey(key.to_string()));
+        }
+        updated.validate()?;
+        self.replace(updated);
+        Ok(())
+    }
+
+    /// Merges a JSON document into the configuration the way a JSON merge patch does: objects are
+    /// merged key by key, anything else replaces what's there and `null` resets a key to its
+    /// default. Nothing changes if a key is unknown or the result is invalid.
+    pub fn merge(&mut self, patch: &Value) -> Result<()> {
+        let mut json = serde_json::to_value(&*self)?;
+        merge(&mut json, patch);
+        let updated: Config = serde_json::from_value(json).map_err(|source| Error::InvalidValue {
+            key: "config".to_string(),
+            source,
+        })?;
+        let mut keys = Vec::new();
+        leaves(patch, "", &mut keys);
+        if let Some(key) = keys.into_iter().find(|key| updated.get(key).is_err()) {
+            return Err(Error::UnknownKey(key));
+        }
+        updated.validate()?;
+        self.replace(updated);
+        Ok(())
+    }
+
+    /// Back to the defaults, keeping what doesn't come from the configuration file.
+    pub fn reset(&mut self) {
+        // Also what a save in safe mode starts from
+        if let Some(unrestricted) = &mut self.safe_mode {
+            **unrestricted = Config::default();
+        }
+        self.replace(Config::default());
+    }
+
+    /// Keeps what doesn't come from the configuration file.
+    fn replace(&mut self, mut updated: Config) {
+        // Unless the profile is gone
+        updated.active_profile = self.active_profile.take().filter(|name| updated.profiles.contains_key(name));
+        updated.identity = std::mem::take(&mut self.identity);
+        updated.calibration = std::mem::take(&mut self.calibration);
+        updated.tls_certificate = self.tls_certificate.take();
+        updated.sensor_memory = std::mem::take(&mut self.sensor_memory);
+        updated.safe_mode = self.safe_mode.take();
+        *self = updated;
+    }
+
+    /// Checks the values serde can't, like ranges.
+    pub fn validate(&self) -> Result<()> {
+        let windows: Vec<&IntervalWindow> = self
+            .schedule
+            .iter()
+            .chain(self.profiles.values().filter_map(|profile| profile.schedule.as_ref()).flatten())
+            .collect();
+        let mut intervals = std::iter::once(self.interval_sec).chain(windows.iter().map(|window| window.interval_sec));
+        if !intervals.all(|interval| (MIN_INTERVAL_SEC..=MAX_INTERVAL_SEC).contains(&interval)) {
+            return Err(Error::failed(
+                Phase::Config,
+                format!("Intervals must be between {} and {} s", MIN_INTERVAL_SEC, MAX_INTERVAL_SEC),
+            ));
+        }
+        if let Some(window) = windows.iter().find(|window| schedule::window_bounds(window).is_none()) {
+            return Err(Error::failed(
+                Phase::Config,
+                format!("Invalid schedule window {}-{}, expected HH:MM", window.start, window.end),
+            ));
+        }
+        if !(0.0..=MAX_JITTER_PERCENT).contains(&self.jitter.percent) {
+            return Err(Error::failed(
+                Phase::Config,
+                format!("jitter.percent must be between 0 and {}", MAX_JITTER_PERCENT),
+            ));
+        }
+        if self.uplinks().any(|uplink| uplink == Uplink::Datadog) && self.datadog.api_key.is_empty() {
+            return Err(Error::failed(Phase::Config, "The Datadog uplink needs datadog.api_key"));
+        }
+        if self.uplinks().any(|uplink| uplink == Uplink::Mqtt) && self.mqtt.url.is_empty() {
+            return Err(Error::failed(Phase::Config, "The MQTT uplink needs mqtt.url"));
+        }
+        if self.uplinks().enumerate().any(|(index, uplink)| self.uplinks().take(index).any(|other| other == uplink)) {
+            return Err(Error::failed(Phase::Config, "Every uplink can only be used once"));
+        }
+        if !self.cellular.pin.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Error::failed(Phase::Config, "cellular.pin must be digits only"));
+        }
+        if self.cellular.register_timeout_sec == 0 {
+            return Err(Error::failed(Phase::Config, "cellular.register_timeout_sec must be at least 1"));
+        }
+        if !(7..=12).contains(&self.lora.spreading_factor) {
+            return Err(Error::failed(Phase::Config, "lora.spreading_factor must be between 7 and 12"));
+        }
+        if ![125, 250, 500].contains(&self.lora.bandwidth_khz) {
+            return Err(Error::failed(Phase::Config, "lora.bandwidth_khz must be 125, 250 or 500"));
+        }
+        if !(5..=8).contains(&self.lora.coding_rate) {
+            return Err(Error::failed(Phase::Config, "lora.coding_rate must be between 5 and 8"));
+        }
+        if !(-9..=22).contains(&self.lora.tx_power_dbm) {
+            return Err(Error::failed(Phase::Config, "lora.tx_power_dbm must be between -9 and 22"));
+        }
+        if !(0.0..=100.0).contains(&self.lora.duty_cycle_percent) {
+            return Err(Error::failed(Phase::Config, "lora.duty_cycle_percent must be between 0 and 100"));
+        }
+        // One measurement past the header, at most what the FIFO holds
+        if !(17..=255).contains(&self.lora.max_payload) {
+            return Err(Error::failed(Phase::Config, "lora.max_payload must be between 17 and 255"));
+        }
+        if self.uplinks().any(|uplink| uplink == Uplink::Coap) && self.coap.host.is_empty() {
+            return Err(Error::failed(Phase::Config, "The CoAP uplink needs coap.host"));
+        }
+        if self.thread.enabled && self.thread.dataset_tlvs().is_none_or(|tlvs| tlvs.is_empty()) {
+            return Err(Error::failed(Phase::Config, "thread.dataset must be the active dataset in hex"));
+        }
+        for priority in [self.tasks.sensing_priority, self.tasks.network_priority] {
+            if !(1..=MAX_TASK_PRIORITY).contains(&priority) {
+                return Err(Error::failed(
+                    Phase::Config,
+                    format!("Task priorities must be between 1 and {}", MAX_TASK_PRIORITY),
+                ));
+            }
+        }
+        if self.history.enabled && self.history.minutes == 0 {
+            return Err(Error::failed(Phase::Config, "history.minutes must be positive"));
+        }
+        if self.crash_loop.boots > 0 && self.crash_loop.stable_sec == 0 {
+            return Err(Error::failed(Phase::Config, "crash_loop.stable_sec must be positive"));
+        }
+        if self.thread.enabled && self.cellular.enabled {
+            return Err(Error::failed(Phase::Config, "Only one of thread and cellular can be enabled"));
+        }
+        if self.uplinks().any(|uplink| uplink == Uplink::Lorawan) {
+            let lorawan = &self.lorawan;
+            if lorawan::hex::<8>(&lorawan.dev_eui).is_none() || lorawan::hex::<8>(&lorawan.join_eui).is_none() {
+                return Err(Error::failed(Phase::Config, "lorawan.dev_eui and join_eui must be 16 hex digits"));
+            }
+            if lorawan::hex::<16>(&lorawan.app_key).is_none() {
+                return Err(Error::failed(Phase::Config, "lorawan.app_key must be 32 hex digits"));
+            }
+            if self.uplinks().any(|uplink| uplink == Uplink::Lora) {
+                return Err(Error::failed(Phase::Config, "The LoRa and LoRaWAN uplinks share the radio"));
+            }
+        }
+        if !(1..=223).contains(&self.lorawan.port) {
+            return Err(Error::failed(Phase::Config, "lorawan.port must be between 1 and 223"));
+        }
+        if self.lorawan.channels_hz.is_empty() {
+            return Err(Error::failed(Phase::Config, "lorawan.channels_hz needs at least one channel"));
+        }
+        if !(7..=12).contains(&self.lorawan.rx2_spreading_factor) {
+            return Err(Error::failed(Phase::Config, "lorawan.rx2_spreading_factor must be between 7 and 12"));
+        }
+        for (uplink, minutes) in &self.downsample_min {
+            if serde_json::from_value::<Uplink>(Value::String(uplink.clone())).is_err() {
+                return Err(Error::failed(Phase::Config, format!("Unknown uplink '{}' in downsample_min", uplink)));
+            }
+            if !(1..=MAX_INTERVAL_SEC / 60).contains(minutes) {
+                return Err(Error::failed(
+                    Phase::Config,
+                    format!("downsample_min must be between 1 and {}", MAX_INTERVAL_SEC / 60),
+                ));
+            }
+        }
+        if self.mqtt.qos > 2 {
+            return Err(Error::failed(Phase::Config, "mqtt.qos must be 0, 1 or 2"));
+        }
+        if let Some(name) = self.spi_sensors.keys().find(|name| !SPI_SENSORS.contains(&name.as_str())) {
+            return Err(Error::failed(Phase::Config, format!("{} can't be used over SPI", name)));
+        }
+        if self.spi_sensors.values().any(|cs| *cs == self.sdcard.cs) {
+            return Err(Error::failed(Phase::Config, "SPI sensors need a chip select of their own"));
+        }
+        if self.buffers.high_hours == 0 || self.buffers.normal_hours == 0 || self.buffers.low_hours == 0 {
+            return Err(Error::failed(Phase::Config, "Buffer retention must be at least an hour"));
+        }
+        if self.i2c.timeout_ms == 0 || self.i2c.sensor_timeout_ms.values().any(|ms| *ms == 0) {
+            return Err(Error::failed(Phase::Config, "I2C timeouts must be positive"));
+        }
+        if self.wifi.channel.is_some_and(|channel| !(1..=14).contains(&channel)) {
+            return Err(Error::failed(Phase::Config, "wifi.channel must be between 1 and 14"));
+        }
+        if self.wifi.bssid.is_some() && self.wifi.bssid_bytes().is_none() {
+            return Err(Error::failed(Phase::Config, "wifi.bssid must look like aa:bb:cc:dd:ee:ff"));
+        }
+        let country = &self.wifi.country;
+        if !country.is_empty() && (country.len() != 2 || !country.bytes().all(|byte| byte.is_ascii_alphanumeric())) {
+            return Err(Error::failed(Phase::Config, "wifi.country must be a two letter code"));
+        }
+        if self.wifi.provisioning.timeout_sec == 0 {
+            return Err(Error::failed(Phase::Config, "wifi.provisioning.timeout_sec must be positive"));
+        }
+        if ![0, 16].contains(&self.wifi.provisioning.esptouch_key.len()) {
+            return Err(Error::failed(Phase::Config, "wifi.provisioning.esptouch_key must be 16 characters"));
+        }
+        if self.sntp.servers.is_empty() || self.sntp.servers.len() > MAX_SNTP_SERVERS {
+            return Err(Error::failed(
+                Phase::Config,
+                format!("sntp.servers needs 1 to {} servers", MAX_SNTP_SERVERS),
+            ));
+        }
+        if self.sntp.timeout_sec == 0 {
+            return Err(Error::failed(Phase::Config, "sntp.timeout_sec must be positive"));
+        }
+        if self.consistency.rounds == 0 || self.consistency.tolerances.values().any(|tolerance| *tolerance <= 0.0) {
+            return Err(Error::failed(Phase::Config, "consistency needs positive tolerances and rounds"));
+        }
+        if self.tsl2591.change_percent <= 0.0 {
+            return Err(Error::failed(Phase::Config, "tsl2591.change_percent must be positive"));
+        }
+        if !(0.0..=1.0).contains(&self.snore.threshold) || self.snore.min_event_ms == 0 {
+            return Err(Error::failed(Phase::Config, "snore needs a threshold from 0 to 1 and a positive min_event_ms"));
+        }
+        for thermistor in &self.thermistors {
+            if !metric_part(&thermistor.name) {
+                return Err(Error::failed(
+                    Phase::Config,
+                    format!("Invalid thermistor name {:?}, use lowercase letters, digits and _", thermistor.name),
+                ));
+            }
+            if thermistor.series_ohm <= 0.0 || thermistor.supply_mv <= 0.0 {
+                return Err(Error::failed(Phase::Config, "thermistors need a positive series_ohm and supply_mv"));
+            }
+        }
+        if let Some(input) = self.analog_inputs.iter().find(|input| !metric_part(&input.name)) {
+            return Err(Error::failed(
+                Phase::Config,
+                format!("Invalid analog input name {:?}, use lowercase letters, digits and _", input.name),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_active_profile(&mut self, name: Option<String>) -> Result<()> {
+        if let Some(name) = &name {
+            if !self.profiles.contains_key(name) {
+                return Err(Error::UnknownProfile(name.clone()));
+            }
+        }
+        self.active_profile = name;
+        Ok(())
+    }
+
+    fn active(&self) -> Option<&Profile> {
+        self.active_profile.as_ref().and_then(|name| self.profiles.get(name))
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.active()
+            .and_then(|profile| profile.prefix.as_deref())
+            .unwrap_or(&self.graphite.prefix)
+    }
+
+    pub fn sensors(&self) -> &SensorsConfig {
+        self.active()
+            .and_then(|profile| profile.sensors.as_ref())
+            .unwrap_or(&self.sensors)
+    }
+
+    pub fn schedule(&self) -> &[IntervalWindow] {
+        self.active()
+            .and_then(|profile| profile.schedule.as_deref())
+            .unwrap_or(&self.schedule)
+    }
+
+    pub fn threshold(&self, name: &str) -> Option<&Threshold> {
+        self.active()
+            .and_then(|profile| profile.thresholds.get(name))
+            .or_else(|| self.thresholds.get(name))
+    }
+
+    /// `uplink` followed by `extra_uplinks`.
+    pub fn uplinks(&self) -> impl Iterator<Item = Uplink> + '_ {
+        std::iter::once(self.uplink).chain(self.extra_uplinks.iter().copied())
+    }
+
+    pub fn device_id(&self) -> &str {
+        if self.device_id.is_empty() {
+            &self.identity.id
+        } else {
+            &self.device_id
+        }
+    }
+
+    pub fn hostname(&self) -> String {
+        format!("sleep-thing-{}", self.device_id())
+    }
+
+    /// `metric_template` with the prefix and ID filled in, only `{name}` is left.
+    pub fn metric_pattern(&self) -> String {
+        self.metric_template
+            .replace("{prefix}", self.prefix())
+            .replace("{id}", self.device_id())
+    }
+
+    pub fn check_thresholds(&self, measurements: &[Measurement]) {
+        for measurement in measurements {
+            let Some(threshold) = self.threshold(&measurement.name) else {
+                continue;
+            };
+            if threshold.min.is_some_and(|min| measurement.value < min) {
+                warn!("{} is below threshold: {}", measurement.name, measurement.value);
+            }
+            if threshold.max.is_some_and(|max| measurement.value > max) {
+                warn!("{} is above threshold: {}", measurement.name, measurement.value);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn mount_storage() -> Result<()> {
+    let conf = esp_vfs_spiffs_conf_t {
+        base_path: STORAGE_MOUNT_POINT.as_ptr(),
+        partition_label: STORAGE_PARTITION.as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+    esp!(unsafe { esp_vfs_spiffs_register(&conf) }).context(Phase::Storage, "Failed to mount the storage partition")?;
+    info!("Storage partition mounted at {:?}", STORAGE_MOUNT_POINT);
+    Ok(())
+}
+
+/// Erases the storage partition, it stays mounted.
+#[cfg(target_os = "espidf")]
+pub fn format_storage() -> Result<()> {
+    esp!(unsafe { esp_spiffs_format(STORAGE_PARTITION.as_ptr()) })
+        .context(Phase::Storage, "Failed to format the storage partition")
+}
+
+fn pointer(key: &str) -> String {
+    format!("/{}", key.replace('.', "/"))
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// The merge patch turning `from` into `to`.
+fn changes(from: &Value, to: &Value) -> Value {
+    let (Value::Object(from), Value::Object(to)) = (from, to) else {
+        return to.clone();
+    };
+    let mut patch = Map::new();
+    for (key, value) in to {
+        match from.get(key) {
+            Some(old) if old == value => {}
+            Some(old) => {
+                patch.insert(key.clone(), changes(old, value));
+            }
+            None => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for key in from.keys().filter(|key| !to.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+    Value::Object(patch)
+}
+
+/// The dotted keys a merge patch sets, to tell the ones serde silently dropped.
+fn leaves(patch: &Value, prefix: &str, keys: &mut Vec<String>) {
+    match patch {
+        Value::Object(map) if !map.is_empty() || prefix.is_empty() => {
+            for (key, value) in map {
+                leaves(value, &format!("{}{}.", prefix, key), keys);
+            }
+        }
+        Value::Null => {}
+        _ => keys.push(prefix.trim_end_matches('.').to_string()),
+    }
+}
+
+/// Usable in a metric name, e.g. `mattress` in `mattress.temperature` or `bed_moisture`.
+fn metric_part(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Whether `validate` turns the patch down, rather than the keys being unknown or mistyped.
+    fn invalid(patch: Value) -> bool {
+        matches!(
+            Config::default().merge(&patch),
+            Err(Error::Failed {
+                phase: Phase::Config,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn sets_known_keys_only() {
+        let mut config = Config::default();
+        assert!(matches!(config.set("graphite.no_such_key", "1"), Err(Error::UnknownKey(_))));
+        assert!(matches!(config.set("no_such_section.port", "1"), Err(Error::UnknownKey(_))));
+        assert!(matches!(config.set("graphite.port", "\"port\""), Err(Error::InvalidValue { .. })));
+        assert!(config.set("interval_sec", "0").is_err());
+        assert_eq!(config.interval_sec, Config::default().interval_sec);
+
+        config.set("graphite.port", "2004").unwrap();
+        assert_eq!(config.graphite.port, 2004);
+        assert_eq!(config.get("graphite.port").unwrap(), json!(2004));
+    }
+
+    #[test]
+    fn sets_map_entries_that_dont_exist_yet() {
+        let mut config = Config::default();
+        config.set("thresholds.co2.max", "1200").unwrap();
+        assert_eq!(config.thresholds["co2"].max, Some(1200.0));
+        assert_eq!(config.thresholds["co2"].min, None);
+        config.set("i2c.sensor_timeout_ms.scd4x", "500").unwrap();
+        assert_eq!(config.i2c.sensor_timeout_ms["scd4x"], 500);
+    }
+
+    #[test]
+    fn merges_with_null_resetting_to_the_default() {
+        let mut config = Config::default();
+        config.set("graphite.port", "2004").unwrap();
+        config.set("thresholds.co2.max", "1200").unwrap();
+        config.merge(&json!({"graphite": {"port": null}, "interval_sec": 60})).unwrap();
+        assert_eq!(config.graphite.port, Config::default().graphite.port);
+        assert_eq!(config.interval_sec, 60);
+        assert_eq!(config.thresholds["co2"].max, Some(1200.0));
+
+        assert!(matches!(config.merge(&json!({"graphite": {"prot": 1}})), Err(Error::UnknownKey(_))));
+        assert!(config.merge(&json!({"interval_sec": 120, "mqtt": {"qos": 3}})).is_err());
+        assert_eq!(config.interval_sec, 60);
+    }
+
+    #[test]
+    fn keeps_what_isnt_from_the_file_across_changes() {
+        let mut config = Config::default();
+        config.profiles.insert("nursery".to_string(), Profile::default());
+        config.set_active_profile(Some("nursery".to_string())).unwrap();
+        config.tls_certificate = Some("certificate".to_string());
+        config.safe_mode = Some(Box::default());
+
+        config.set("graphite.port", "2004").unwrap();
+        config.merge(&json!({"interval_sec": 60})).unwrap();
+        assert_eq!(config.active_profile.as_deref(), Some("nursery"));
+        assert_eq!(config.tls_certificate.as_deref(), Some("certificate"));
+        assert!(config.safe_mode.is_some());
+    }
+
+    #[test]
+    fn rejects_values_out_of_bounds() {
+        assert!(Config::default().validate().is_ok());
+        let window = |start: &str, interval_sec: u32| {
+            json!([{"start": start, "end": "06:00", "interval_sec": interval_sec}])
+        };
+        let lorawan = json!({
+            "dev_eui": "0011223344556677",
+            "join_eui": "0011223344556677",
+            "app_key": "00112233445566778899aabbccddeeff",
+        });
+        let mut short_key = lorawan.clone();
+        short_key["app_key"] = json!("00");
+        let sdcard_cs = Config::default().sdcard.cs;
+        let patches = [
+            json!({"interval_sec": 0}),
+            json!({"interval_sec": MAX_INTERVAL_SEC + 1}),
+            json!({"schedule": window("22:00", 0)}),
+            json!({"schedule": window("25:00", 60)}),
+            json!({"jitter": {"percent": -1.0}}),
+            json!({"jitter": {"percent": MAX_JITTER_PERCENT + 1.0}}),
+            json!({"uplink": "datadog"}),
+            json!({"uplink": "mqtt"}),
+            json!({"uplink": "coap"}),
+            json!({"extra_uplinks": ["graphite"]}),
+            json!({"cellular": {"pin": "12a4"}}),
+            json!({"cellular": {"register_timeout_sec": 0}}),
+            json!({"lora": {"spreading_factor": 6}}),
+            json!({"lora": {"spreading_factor": 13}}),
+            json!({"lora": {"bandwidth_khz": 100}}),
+            json!({"lora": {"coding_rate": 9}}),
+            json!({"lora": {"tx_power_dbm": 23}}),
+            json!({"lora": {"duty_cycle_percent": 101.0}}),
+            json!({"lora": {"max_payload": 16}}),
+            json!({"thread": {"enabled": true, "dataset": "0x"}}),
+            json!({"thread": {"enabled": true, "dataset": "0102"}, "cellular": {"enabled": true}}),
+            json!({"tasks": {"sensing_priority": 0}}),
+            json!({"tasks": {"network_priority": MAX_TASK_PRIORITY + 1}}),
+            json!({"history": {"enabled": true, "minutes": 0}}),
+            json!({"crash_loop": {"boots": 3, "stable_sec": 0}}),
+            json!({"uplink": "lorawan"}),
+            json!({"uplink": "lorawan", "lorawan": short_key}),
+            json!({"uplink": "lorawan", "lorawan": lorawan, "extra_uplinks": ["lora"]}),
+            json!({"lorawan": {"port": 0}}),
+            json!({"lorawan": {"port": 224}}),
+            json!({"lorawan": {"channels_hz": []}}),
+            json!({"lorawan": {"rx2_spreading_factor": 6}}),
+            json!({"downsample_min": {"pigeon": 5}}),
+            json!({"downsample_min": {"graphite": 0}}),
+            json!({"mqtt": {"qos": 3}}),
+            json!({"spi_sensors": {"scd4x": 5}}),
+            json!({"spi_sensors": {"bme280": sdcard_cs}}),
+            json!({"buffers": {"low_hours": 0}}),
+            json!({"i2c": {"timeout_ms": 0}}),
+            json!({"i2c": {"sensor_timeout_ms": {"scd4x": 0}}}),
+            json!({"wifi": {"channel": 15}}),
+            json!({"wifi": {"bssid": "aa:bb:cc"}}),
+            json!({"wifi": {"country": "USA"}}),
+            json!({"wifi": {"provisioning": {"timeout_sec": 0}}}),
+            json!({"wifi": {"provisioning": {"esptouch_key": "short"}}}),
+            json!({"sntp": {"servers": []}}),
+            json!({"sntp": {"servers": vec!["pool.ntp.org"; MAX_SNTP_SERVERS + 1]}}),
+            json!({"sntp": {"timeout_sec": 0}}),
+            json!({"consistency": {"rounds": 0}}),
+            json!({"consistency": {"tolerances": {"co2": 0.0}}}),
+            json!({"tsl2591": {"change_percent": 0.0}}),
+            json!({"snore": {"threshold": 1.5}}),
+            json!({"snore": {"min_event_ms": 0}}),
+            json!({"thermistors": [{"name": "Mattress"}]}),
+            json!({"thermistors": [{"name": "mattress", "series_ohm": 0.0}]}),
+            json!({"analog_inputs": [{"name": "bed moisture", "pin": 0}]}),
+        ];
+        for patch in patches {
+            assert!(invalid(patch.clone()), "{} was accepted", patch);
+        }
+        assert!(Config::default().merge(&json!({"uplink": "lorawan", "lorawan": lorawan})).is_ok());
+    }
+
+    #[test]
+    fn saves_the_unrestricted_configuration_in_safe_mode() {
+        let mut config = Config::default();
+        config.sensors.scd4x = true;
+        config.profiles.insert("nursery".to_string(), Profile::default());
+        crash_loop::restrict(&mut config);
+        config.set("graphite.port", "2004").unwrap();
+        config.set("sensors.bme280", "true").unwrap();
+        assert!(config.safe_mode.is_some());
+
+        let saved: Config = serde_json::from_value(config.saved().unwrap()).unwrap();
+        assert!(saved.sensors.scd4x);
+        assert!(saved.sensors.bme280);
+        assert!(saved.profiles.contains_key("nursery"));
+        assert_eq!(saved.graphite.port, 2004);
+    }
+
+    #[test]
+    fn resets_to_the_defaults_keeping_the_runtime_fields() {
+        let mut config = Config::default();
+        config.profiles.insert("nursery".to_string(), Profile::default());
+        config.set_active_profile(Some("nursery".to_string())).unwrap();
+        config.tls_certificate = Some("certificate".to_string());
+        config.set("graphite.port", "2004").unwrap();
+        config.reset();
+        assert_eq!(config.graphite.port, Config::default().graphite.port);
+        assert!(config.profiles.is_empty());
+        assert_eq!(config.active_profile, None);
+        assert_eq!(config.tls_certificate.as_deref(), Some("certificate"));
+    }
+}
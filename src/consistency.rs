@@ -0,0 +1,128 @@
+//! Compares the metrics more than one sensor reports, e.g. the temperature of the BME280 and the
+//! SCD4x. The spread between the sensors goes out as `<metric>_spread` with every round, and a
+//! sensor further than the tolerance from the median of all of them for `rounds` rounds in a row
+//! is suspect until it's back in line. Two sensors are always equally far off, they turn suspect
+//! together and it takes a third to tell which one drifted.
+
+use std::collections::{BTreeMap, HashMap};
+
+use log::{info, warn};
+
+use crate::config::ConsistencyConfig;
+use crate::filters::median;
+use crate::sensors::Measurement;
+
+pub struct ConsistencyCheck {
+    config: ConsistencyConfig,
+    /// Rounds in a row a sensor was off, keyed by sensor and metric
+    streaks: HashMap<(&'static str, String), u32>,
+}
+
+impl ConsistencyCheck {
+    pub fn new(config: ConsistencyConfig) -> Self {
+        ConsistencyCheck {
+            config,
+            streaks: HashMap::new(),
+        }
+    }
+
+    /// Takes what each sensor measured this round, returns the spread of the compared metrics
+    /// more than one of them reported.
+    pub fn check(&mut self, readings: &[(&'static str, &[Measurement])]) -> Vec<Measurement> {
+        let mut spreads = Vec::new();
+        for (metric, tolerance) in &self.config.tolerances {
+            let values: Vec<(&'static str, f32)> = readings
+                .iter()
+                .flat_map(|(sensor, measurements)| {
+                    measurements
+                        .iter()
+                        .filter(|measurement| measurement.name == metric.as_str() && !measurement.value.is_nan())
+                        .map(move |measurement| (*sensor, measurement.value))
+                })
+                .collect();
+            if values.len() < 2 {
+                continue;
+            }
+            let (min, max) = values
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), (_, value)| (min.min(*value), max.max(*value)));
+            spreads.push(Measurement {
+                name: format!("{}_spread", metric).into(),
+                value: max - min,
+            });
+
+            let median = median(values.iter().map(|(_, value)| *value));
+            for (sensor, value) in values {
+                let streak = self.streaks.entry((sensor, metric.clone())).or_default();
+                if (value - median).abs() > *tolerance {
+                    *streak += 1;
+                    if *streak == self.config.rounds {
+                        warn!(
+                            "{} {} off the other sensors by {:+.2} for {} rounds, suspect",
+                            sensor,
+                            metric,
+                            value - median,
+                            streak
+                        );
+                    }
+                } else {
+                    if *streak >= self.config.rounds {
+                        info!("{} {} back in line with the other sensors", sensor, metric);
+                    }
+                    *streak = 0;
+                }
+            }
+        }
+        spreads
+    }
+
+    /// The suspect sensors with the metrics they're off in.
+    pub fn suspects(&self) -> BTreeMap<&'static str, Vec<&str>> {
+        let mut suspects: BTreeMap<&'static str, Vec<&str>> = BTreeMap::new();
+        for ((sensor, metric), streak) in &self.streaks {
+            if *streak >= self.config.rounds {
+                suspects.entry(sensor).or_default().push(metric);
+            }
+        }
+        for metrics in suspects.values_mut() {
+            metrics.sort_unstable();
+        }
+        suspects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temperature(value: f32) -> Vec<Measurement> {
+        vec![Measurement {
+            name: "temperature".into(),
+            value,
+        }]
+    }
+
+    #[test]
+    fn flags_the_sensor_drifting_away_from_the_others() {
+        let mut check = ConsistencyCheck::new(ConsistencyConfig {
+            rounds: 3,
+            ..ConsistencyConfig::default()
+        });
+        let (bme280, scd4x, hdc1080) = (temperature(21.0), temperature(21.4), temperature(23.5));
+        let round = [("bme280", &bme280[..]), ("scd4x", &scd4x[..]), ("hdc1080", &hdc1080[..])];
+        for _ in 0..2 {
+            let spreads = check.check(&round);
+            assert_eq!(spreads[0].name, "temperature_spread");
+            assert_eq!(spreads[0].value, 2.5);
+            assert!(check.suspects().is_empty());
+        }
+        check.check(&round);
+        assert_eq!(check.suspects(), BTreeMap::from([("hdc1080", vec!["temperature"])]));
+
+        let hdc1080 = temperature(21.2);
+        check.check(&[("bme280", &bme280[..]), ("scd4x", &scd4x[..]), ("hdc1080", &hdc1080[..])]);
+        assert!(check.suspects().is_empty());
+        // A metric only one sensor reports has nothing to be compared with
+        assert!(check.check(&[("bme280", &bme280[..])]).is_empty());
+    }
+}
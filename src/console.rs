@@ -0,0 +1,744 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv6Addr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{error, info, warn};
+use ringbuffer::RingBuffer;
+
+use crate::board::{self, Board};
+use crate::calibration::{self, AnalogRange, Calibration};
+use crate::climate_control::{self, SharedOverrides};
+use crate::config::{RemoteConsoleConfig, SharedConfig, TimestampResolution};
+use crate::dead_letter::{self, DEAD_LETTER_PATH};
+use crate::diagnostics;
+use crate::factory_reset;
+use crate::buffers::PriorityBuffers;
+use crate::profile;
+use crate::registry::SharedSensorStates;
+use crate::shutdown::Shutdown;
+use crate::tls;
+use crate::watchdog;
+
+#[cfg(feature = "sdcard")]
+use crate::sdcard;
+
+const STACK_SIZE: usize = 8 * 1024;
+const POLL_INTERVAL_MS: u64 = 50;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const LOGIN_ATTEMPTS: usize = 3;
+const LOGIN_DELAY: Duration = Duration::from_secs(2);
+// Room for a certificate pasted on one line, a client sending more is dropped
+const MAX_LINE: u64 = 8 * 1024;
+
+// Telnet option negotiation
+const IAC: u8 = 0xFF;
+const SB: u8 = 0xFA;
+const SE: u8 = 0xF0;
+const WILL: u8 = 0xFB;
+const DONT: u8 = 0xFE;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+/// What the commands work on, shared by the serial and the remote sessions.
+#[derive(Clone)]
+struct Shell {
+    buffers: PriorityBuffers,
+    config: SharedConfig,
+    sensors: SharedSensorStates,
+    overrides: SharedOverrides,
+    nvs: EspDefaultNvsPartition,
+    shutdown: Shutdown,
+}
+
+/// Starts a thread reading commands from the serial console (stdin) and, with a
+/// `remote_console.password` set, one serving the same commands over TCP.
+pub fn spawn(
+    buffers: PriorityBuffers,
+    config: SharedConfig,
+    sensors: SharedSensorStates,
+    overrides: SharedOverrides,
+    nvs: EspDefaultNvsPartition,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let remote = config.lock().expect("Config lock poisoned").remote_console.clone();
+    let shell = Shell {
+        buffers,
+        config,
+        sensors,
+        overrides,
+        nvs,
+        shutdown,
+    };
+    if !remote.password.is_empty() {
+        let shell = shell.clone();
+        thread::Builder::new()
+            .name("remote-console".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || serve(shell, remote))?;
+    }
+    thread::Builder::new()
+        .name("console".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(shell))?;
+    Ok(())
+}
+
+fn run(shell: Shell) {
+    info!("Console started, type 'help' for a list of commands");
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        // stdin is non-blocking on ESP-IDF, so partial lines are kept until the newline arrives
+        match stdin.lock().read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => {
+                if let Err(err) = shell.execute(line.trim(), &mut io::stdout()) {
+                    error!("Error writing to console: {:?}", err);
+                }
+                line.clear();
+            }
+            Ok(_) => thread::sleep(Duration::from_millis(POLL_INTERVAL_MS)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS))
+            }
+            Err(err) => {
+                error!("Error reading from console: {:?}", err);
+                line.clear();
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+    }
+}
+
+/// Accepts one session at a time, the password and commands travel in plain text, so it's meant
+/// for the local network only.
+fn serve(shell: Shell, config: RemoteConsoleConfig) {
+    // Dual stack, lwIP takes IPv4 connections on the IPv6 wildcard as well
+    let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, config.port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to start the remote console on port {}: {:?}", config.port, err);
+            return;
+        }
+    };
+    info!("Remote console listening on port {}", config.port);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Failed to accept a remote console connection: {:?}", err);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "?".to_string());
+        info!("Remote console connection from {}", peer);
+        match session(&shell, stream, &config.password) {
+            Ok(_) => info!("Remote console session from {} closed", peer),
+            Err(err) => warn!("Remote console session from {} ended: {:?}", peer, err),
+        }
+    }
+}
+
+fn session(shell: &Shell, stream: TcpStream, password: &str) -> io::Result<()> {
+    // A forgotten session would keep everyone else out
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut out = stream;
+
+    let mut attempts = 0;
+    loop {
+        write!(out, "Password: ")?;
+        match read_line(&mut reader)? {
+            None => return Ok(()),
+            Some(line) if line == password => break,
+            Some(_) => {
+                attempts += 1;
+                if attempts == LOGIN_ATTEMPTS {
+                    warn!("Remote console login failed");
+                    return writeln!(out, "Access denied");
+                }
+                thread::sleep(LOGIN_DELAY);
+            }
+        }
+    }
+
+    let device_id = shell.config.lock().expect("Config lock poisoned").device_id().to_string();
+    writeln!(out, "Sleep Thing {}, type 'help' for a list of commands, 'exit' to disconnect", device_id)?;
+    loop {
+        write!(out, "> ")?;
+        match read_line(&mut reader)?.as_deref() {
+            None | Some("exit") => return Ok(()),
+            Some(line) => shell.execute(line, &mut out)?,
+        }
+    }
+}
+
+/// Next line with telnet option negotiation and control characters stripped, `None` once the
+/// client hung up. A line longer than `MAX_LINE` is an error, which ends the session.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut bytes = Vec::new();
+    if (&mut *reader).take(MAX_LINE).read_until(b'\n', &mut bytes)? == 0 {
+        return Ok(None);
+    }
+    if bytes.len() as u64 == MAX_LINE && bytes.last() != Some(&b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+    }
+    let mut line = String::new();
+    let mut bytes = bytes.into_iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            IAC => match bytes.next() {
+                Some(WILL..=DONT) => {
+                    bytes.next();
+                }
+                Some(SB) => while bytes.next().is_some_and(|byte| byte != SE) {},
+                _ => {}
+            },
+            0x20..=0x7E => line.push(char::from(byte)),
+            _ => {}
+        }
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+impl Shell {
+    fn execute(&self, command: &str, out: &mut dyn Write) -> io::Result<()> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        match args.as_slice() {
+            [] => Ok(()),
+            ["help"] => print_help(out),
+            ["status"] => print_status(out, &self.buffers, &self.config, &self.nvs),
+            ["stats"] => print_stats(out),
+            ["dump", rest @ ..] => {
+                let format = if rest.contains(&"json") { Format::Json } else { Format::Csv };
+                if rest.contains(&"history") {
+                    dump_history(out, format)
+                } else {
+                    let resolution = self.config.lock().expect("Config lock poisoned").timestamp_resolution;
+                    dump_buffer(out, format, resolution, &self.buffers)
+                }
+            }
+            ["config", rest @ ..] => configure(out, rest, &self.config),
+            ["profile", rest @ ..] => select_profile(out, rest, &self.config, &self.nvs),
+            ["board", rest @ ..] => select_board(out, rest, &self.nvs),
+            ["sensors", rest @ ..] => control_sensors(out, rest, &self.sensors),
+            ["deadletters", rest @ ..] => show_dead_letters(out, rest),
+            ["calibrate", "linear", rest @ ..] => calibrate_linear(out, rest, &self.config, &self.nvs),
+            ["calibrate", "range", rest @ ..] => calibrate_range(out, rest, &self.config, &self.sensors, &self.nvs),
+            ["calibrate", rest @ ..] => calibrate(out, rest, &self.config, &self.sensors, &self.nvs),
+            ["cert", rest @ ..] => set_certificate(out, rest, &self.config, &self.nvs),
+            ["ir", rest @ ..] => override_device(out, rest, &self.config, &self.overrides),
+            ["factory-reset"] => writeln!(
+                out,
+                "Erases the configuration, Wi-Fi credentials, calibration and stored data, \
+                 'factory-reset confirm' to go ahead"
+            ),
+            ["factory-reset", "confirm"] => {
+                writeln!(out, "Erasing everything and rebooting")?;
+                out.flush()?;
+                factory_reset::run();
+            }
+            ["shutdown"] => {
+                self.shutdown.request("console");
+                writeln!(out, "Shutting down after the buffered measurements are sent or kept on flash")
+            }
+            ["reboot"] => {
+                writeln!(out, "Rebooting")?;
+                out.flush()?;
+                esp_idf_svc::hal::reset::restart();
+            }
+            _ => writeln!(out, "Unknown command '{}', type 'help' for a list of commands", command),
+        }
+    }
+}
+
+fn print_help(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "Available commands:")?;
+    writeln!(out, "  help                       Show this message")?;
+    writeln!(out, "  status                     Show device identity and runtime status")?;
+    writeln!(out, "  stats                      Show heap usage and the least free stack of every task")?;
+    writeln!(out, "  dump [csv|json]            Dump measurements waiting in the send buffer")?;
+    #[cfg(feature = "sdcard")]
+    writeln!(out, "  dump [csv|json] history    Dump measurement history stored on the SD card")?;
+    writeln!(out, "  config show                Print the active configuration")?;
+    writeln!(out, "  config get <key>           Print a single value, e.g. 'config get graphite.host'")?;
+    writeln!(out, "  config set <key> <value>   Change a value, pins, Wi-Fi and filters apply after a reboot")?;
+    writeln!(out, "  config save                Write the active configuration to flash")?;
+    writeln!(out, "  config reset               Restore the built-in defaults (not saved)")?;
+    writeln!(out, "  profile                    List profiles and show the active one")?;
+    writeln!(out, "  profile <name>|clear       Select a profile and remember it across reboots")?;
+    writeln!(out, "  board                      List boards and show the one whose pins are the defaults")?;
+    writeln!(out, "  board <name>|clear         Select a board, its pins apply after a reboot")?;
+    writeln!(out, "  sensors                    List sensors and their state")?;
+    writeln!(out, "  sensors enable <name>      Switch a sensor on until the next reboot")?;
+    writeln!(out, "  sensors disable <name>     Switch a sensor off until the next reboot")?;
+    writeln!(out, "  sensors reinit <name>      Run the sensor setup again, e.g. after reseating it")?;
+    writeln!(out, "  deadletters [clear]        List or drop the batches an uplink turned down")?;
+    writeln!(out, "  calibrate                  List the calibration offsets and ranges stored on this board")?;
+    writeln!(out, "  calibrate <sensor.metric> <offset>")?;
+    writeln!(out, "                             Store an offset, e.g. 'calibrate bme280.temperature -1.2'")?;
+    writeln!(out, "  calibrate range <input> <zero_mv> <full_mv>|clear")?;
+    writeln!(out, "                             Store the readings of an analog input at 0% and 100%")?;
+    writeln!(out, "  calibrate linear <metric> <reading> <reference> <reading> <reference>|clear")?;
+    writeln!(out, "                             Correct a metric through two readings next to a reference")?;
+    writeln!(out, "  cert [clear|<PEM>]         Show, store or clear the CA or self-signed server certificate")?;
+    writeln!(out, "                             trusted by HTTPS and MQTT instead of the CA bundle")?;
+    writeln!(out, "  ir                         List IR devices and their overrides")?;
+    writeln!(out, "  ir <device> on|off|auto    Switch a device by hand until set back to 'auto'")?;
+    writeln!(out, "  factory-reset [confirm]    Erase the configuration, NVS and stored data, then reboot")?;
+    writeln!(out, "  shutdown                   Flush the buffer, power the sensors down and halt")?;
+    writeln!(out, "  reboot                     Restart the device")
+}
+
+fn print_status(
+    out: &mut dyn Write,
+    buffers: &PriorityBuffers,
+    config: &SharedConfig,
+    nvs: &EspDefaultNvsPartition,
+) -> io::Result<()> {
+    let [high, normal, low] = buffers
+        .all()
+        .map(|buffer| buffer.lock().expect("Measurement buffer lock poisoned").len());
+    let config = config.lock().expect("Config lock poisoned");
+    let uptime = unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000_000;
+    let free_heap = unsafe { esp_idf_svc::sys::esp_get_free_heap_size() };
+    writeln!(out, "Device ID:    {}", config.device_id())?;
+    writeln!(out, "MAC:          {}", config.identity.mac_string())?;
+    writeln!(out, "Hostname:     {}", config.hostname())?;
+    writeln!(out, "Profile:      {}", config.active_profile.as_deref().unwrap_or("-"))?;
+    writeln!(out, "Board:        {}", Board::current().name())?;
+    if config.safe_mode.is_some() {
+        writeln!(out, "Safe mode:    after a crash loop, sensors and extras are off until a reboot")?;
+    }
+    writeln!(out, "Buffered:     {} high, {} normal, {} low priority batches", high, normal, low)?;
+    writeln!(out, "Uptime:       {} s", uptime)?;
+    writeln!(out, "Free heap:    {} bytes", free_heap)?;
+    writeln!(out, "Watchdog:     {} reboots", watchdog::reboots(nvs))
+}
+
+fn print_stats(out: &mut dyn Write) -> io::Result<()> {
+    let heap = diagnostics::heap();
+    writeln!(out, "Heap free:     {} bytes", heap.free)?;
+    writeln!(out, "Heap min free: {} bytes", heap.minimum_free)?;
+    writeln!(out, "Largest block: {} bytes ({:.0}% fragmented)", heap.largest_block, heap.fragmentation())?;
+    writeln!(out)?;
+    writeln!(out, "  {:<16} {:>4} {:>10}", "task", "prio", "stack free")?;
+    for task in diagnostics::tasks() {
+        writeln!(out, "  {:<16} {:>4} {:>10}", task.name, task.priority, task.stack_free)?;
+    }
+    Ok(())
+}
+
+fn configure(out: &mut dyn Write, args: &[&str], config: &SharedConfig) -> io::Result<()> {
+    let mut config = config.lock().expect("Config lock poisoned");
+    match args {
+        ["show"] => match serde_json::to_string_pretty(&*config) {
+            Ok(json) => writeln!(out, "{}", json),
+            Err(err) => writeln!(out, "Failed to serialize configuration: {:?}", err),
+        },
+        ["get", key] => match config.get(key) {
+            Ok(value) => writeln!(out, "{} = {}", key, value),
+            Err(err) => writeln!(out, "{}", err),
+        },
+        ["set", key, value @ ..] if !value.is_empty() => match config.set(key, &value.join(" ")) {
+            Ok(_) => writeln!(out, "{} = {}", key, config.get(key).unwrap_or_default()),
+            Err(err) => writeln!(out, "{}", err),
+        },
+        ["save"] => match config.save() {
+            Ok(_) => writeln!(out, "Configuration saved"),
+            Err(err) => writeln!(out, "{}", err),
+        },
+        ["reset"] => {
+            config.reset();
+            writeln!(out, "Configuration reset to defaults, use 'config save' to persist")
+        }
+        _ => writeln!(out, "Usage: config show|get <key>|set <key> <value>|save|reset"),
+    }
+}
+
+fn dump_buffer(
+    out: &mut dyn Write,
+    format: Format,
+    resolution: TimestampResolution,
+    buffers: &PriorityBuffers,
+) -> io::Result<()> {
+    let buffers = buffers.all();
+    let locked: Vec<_> = buffers
+        .iter()
+        .map(|buffer| buffer.lock().expect("Measurement buffer lock poisoned"))
+        .collect();
+    let batches = || locked.iter().flat_map(|buffer| buffer.iter());
+    writeln!(out, "--- BEGIN DUMP ({} batches) ---", batches().count())?;
+    match format {
+        Format::Csv => {
+            writeln!(out, "timestamp,name,value")?;
+            for batch in batches() {
+                for measurement in batch.decode() {
+                    let timestamp = resolution.convert(batch.timestamp_ms);
+                    writeln!(out, "{},{},{}", timestamp, measurement.name, measurement.value)?;
+                }
+            }
+        }
+        Format::Json => {
+            write!(out, "[")?;
+            let mut first = true;
+            for batch in batches() {
+                for measurement in batch.decode() {
+                    write!(
+                        out,
+                        "{}\n{}",
+                        if first { "" } else { "," },
+                        json_record(resolution.convert(batch.timestamp_ms), &measurement.name, measurement.value)
+                    )?;
+                    first = false;
+                }
+            }
+            writeln!(out, "\n]")?;
+        }
+    }
+    writeln!(out, "--- END DUMP ---")
+}
+
+fn select_profile(
+    out: &mut dyn Write,
+    args: &[&str],
+    config: &SharedConfig,
+    nvs: &EspDefaultNvsPartition,
+) -> io::Result<()> {
+    let mut config = config.lock().expect("Config lock poisoned");
+    let name = match args {
+        [] => {
+            for name in config.profiles.keys() {
+                let marker = if config.active_profile.as_ref() == Some(name) { "*" } else { " " };
+                writeln!(out, "{} {}", marker, name)?;
+            }
+            if config.active_profile.is_none() {
+                writeln!(out, "No profile active")?;
+            }
+            return Ok(());
+        }
+        ["clear"] => None,
+        [name] => Some(name.to_string()),
+        _ => return writeln!(out, "Usage: profile [<name>|clear]"),
+    };
+    if let Err(err) = config.set_active_profile(name.clone()) {
+        return writeln!(out, "{}", err);
+    }
+    match profile::store(nvs, name.as_deref()) {
+        Ok(_) => writeln!(out, "Profile selection saved, the sensor set changes after a reboot"),
+        Err(err) => writeln!(out, "Failed to store the profile selection: {}", err),
+    }
+}
+
+fn select_board(out: &mut dyn Write, args: &[&str], nvs: &EspDefaultNvsPartition) -> io::Result<()> {
+    let board = match args {
+        [] => {
+            for board in Board::ALL {
+                let marker = if board == Board::current() { "*" } else { " " };
+                writeln!(out, "{} {}", marker, board.name())?;
+            }
+            return Ok(());
+        }
+        ["clear"] => None,
+        [name] => match Board::parse(name) {
+            Some(board) => Some(board),
+            None => return writeln!(out, "Unknown board '{}'", name),
+        },
+        _ => return writeln!(out, "Usage: board [<name>|clear]"),
+    };
+    match board::store(nvs, board) {
+        Ok(_) => writeln!(out, "Board selection saved, pins not set in the configuration change after a reboot"),
+        Err(err) => writeln!(out, "Failed to store the board selection: {}", err),
+    }
+}
+
+fn control_sensors(out: &mut dyn Write, args: &[&str], sensors: &SharedSensorStates) -> io::Result<()> {
+    let mut sensors = sensors.lock().expect("Sensor state lock poisoned");
+    let (command, name) = match args {
+        [] => {
+            for (name, state) in sensors.iter() {
+                let mut status = match (&state.error, state.enabled) {
+                    (Some(error), _) => format!("failed: {}", error),
+                    (None, true) => "enabled".to_string(),
+                    (None, false) => "disabled".to_string(),
+                };
+                if let Some(metrics) = &state.suspect {
+                    status.push_str(&format!(", suspect {}", metrics));
+                }
+                if state.errors > 0 {
+                    writeln!(out, "  {:<10} {}, {} bus timeout(s)", name, status, state.errors)?;
+                } else {
+                    writeln!(out, "  {:<10} {}", name, status)?;
+                }
+            }
+            return Ok(());
+        }
+        [command, name] => (*command, *name),
+        _ => return writeln!(out, "Usage: sensors [enable|disable|reinit <name>]"),
+    };
+    let Some(state) = sensors.get_mut(name) else {
+        return writeln!(out, "Unknown sensor '{}'", name);
+    };
+    match command {
+        "enable" => state.enabled = true,
+        "disable" => state.enabled = false,
+        "reinit" => state.reinit_requested = true,
+        _ => return writeln!(out, "Usage: sensors [enable|disable|reinit <name>]"),
+    }
+    writeln!(out, "Applied with the next measurement, use 'config set sensors.{} ...' to make it permanent", name)
+}
+
+fn show_dead_letters(out: &mut dyn Write, args: &[&str]) -> io::Result<()> {
+    let path = Path::new(DEAD_LETTER_PATH);
+    match args {
+        [] => {
+            let letters = dead_letter::read(path)?;
+            for letter in &letters {
+                writeln!(out, "{} {}: {}", letter.timestamp_ms, letter.uplink, letter.reason)?;
+                let values: Vec<String> = letter
+                    .measurements
+                    .iter()
+                    .map(|(name, value)| match value {
+                        Some(value) => format!("{}={}", name, value),
+                        None => format!("{}=null", name),
+                    })
+                    .collect();
+                writeln!(out, "  {}", values.join(" "))?;
+            }
+            writeln!(out, "{} dead letter(s)", letters.len())
+        }
+        ["clear"] => {
+            dead_letter::clear(path)?;
+            writeln!(out, "Dead letters cleared")
+        }
+        _ => writeln!(out, "Usage: deadletters [clear]"),
+    }
+}
+
+fn calibrate(
+    out: &mut dyn Write,
+    args: &[&str],
+    config: &SharedConfig,
+    sensors: &SharedSensorStates,
+    nvs: &EspDefaultNvsPartition,
+) -> io::Result<()> {
+    let mut config = config.lock().expect("Config lock poisoned");
+    let (key, offset) = match args {
+        [] => {
+            for (key, offset) in &config.calibration.offsets {
+                writeln!(out, "  {:<24} {:+}", key, offset)?;
+            }
+            for (input, range) in &config.calibration.ranges {
+                writeln!(out, "  {:<24} {} mV - {} mV", input, range.zero_mv, range.full_mv)?;
+            }
+            for (metric, correction) in &config.calibration.corrections {
+                writeln!(out, "  {:<24} x {} {:+}", metric, correction.slope, correction.offset)?;
+            }
+            if config.calibration == Calibration::default() {
+                writeln!(out, "No calibration stored")?;
+            }
+            return Ok(());
+        }
+        [key, offset] => match (key.split_once('.'), offset.parse::<f32>()) {
+            (Some(_), Ok(offset)) if offset.is_finite() => (*key, offset),
+            _ => return writeln!(out, "Usage: calibrate [<sensor.metric> <offset>]"),
+        },
+        _ => return writeln!(out, "Usage: calibrate [<sensor.metric> <offset>]"),
+    };
+    let mut calibration = config.calibration.clone();
+    calibration.set_offset(key, offset);
+    if let Err(err) = calibration::store(nvs, &calibration) {
+        return writeln!(out, "Failed to store the calibration: {}", err);
+    }
+    config.calibration = calibration;
+    let sensor = key.split('.').next().unwrap_or_default();
+    // Sensors pick up their offsets during setup
+    if let Some(state) = sensors.lock().expect("Sensor state lock poisoned").get_mut(sensor) {
+        state.reinit_requested = true;
+    }
+    writeln!(out, "{} offset set to {:+}, applied with the next measurement", key, offset)
+}
+
+fn calibrate_range(
+    out: &mut dyn Write,
+    args: &[&str],
+    config: &SharedConfig,
+    sensors: &SharedSensorStates,
+    nvs: &EspDefaultNvsPartition,
+) -> io::Result<()> {
+    let mut config = config.lock().expect("Config lock poisoned");
+    let (input, range) = match args {
+        [input, "clear"] => (*input, None),
+        [input, zero, full] => match (zero.parse::<f32>(), full.parse::<f32>()) {
+            (Ok(zero_mv), Ok(full_mv)) if zero_mv.is_finite() && full_mv.is_finite() && zero_mv != full_mv => {
+                (*input, Some(AnalogRange { zero_mv, full_mv }))
+            }
+            _ => return writeln!(out, "Usage: calibrate range <input> <zero_mv> <full_mv>|clear"),
+        },
+        _ => return writeln!(out, "Usage: calibrate range <input> <zero_mv> <full_mv>|clear"),
+    };
+    if !config.analog_inputs.iter().any(|analog| analog.name == input) {
+        return writeln!(out, "No analog input {}", input);
+    }
+    let mut calibration = config.calibration.clone();
+    calibration.set_range(input, range);
+    if let Err(err) = calibration::store(nvs, &calibration) {
+        return writeln!(out, "Failed to store the calibration: {}", err);
+    }
+    config.calibration = calibration;
+    if let Some(state) = sensors.lock().expect("Sensor state lock poisoned").get_mut("analog") {
+        state.reinit_requested = true;
+    }
+    writeln!(out, "{} range updated, applied with the next measurement", input)
+}
+
+fn calibrate_linear(
+    out: &mut dyn Write,
+    args: &[&str],
+    config: &SharedConfig,
+    nvs: &EspDefaultNvsPartition,
+) -> io::Result<()> {
+    let Some((metric, correction)) = calibration::parse_correction(args) else {
+        return writeln!(out, "Usage: calibrate linear <metric> <reading> <reference> <reading> <reference>|clear");
+    };
+    if let Err(err) = calibration::update(nvs, config, |calibration| calibration.set_correction(metric, correction)) {
+        return writeln!(out, "Failed to store the calibration: {}", err);
+    }
+    match correction {
+        Some(correction) => writeln!(
+            out,
+            "{} corrected to x {} {:+} from the next measurement",
+            metric, correction.slope, correction.offset
+        ),
+        None => writeln!(out, "{} correction cleared", metric),
+    }
+}
+
+fn set_certificate(
+    out: &mut dyn Write,
+    args: &[&str],
+    config: &SharedConfig,
+    nvs: &EspDefaultNvsPartition,
+) -> io::Result<()> {
+    let mut config = config.lock().expect("Config lock poisoned");
+    let pem = match args {
+        [] => {
+            return match &config.tls_certificate {
+                Some(pem) => writeln!(out, "{}", pem.trim_end()),
+                None => writeln!(out, "No certificate stored, servers are checked against the CA bundle"),
+            };
+        }
+        ["clear"] => None,
+        pem => match tls::normalize_pem(&pem.join(" ")) {
+            Some(pem) => Some(pem),
+            None => return writeln!(out, "Usage: cert [clear|<PEM certificate>]"),
+        },
+    };
+    if let Err(err) = tls::store(nvs, pem.as_deref()) {
+        return writeln!(out, "Failed to store the certificate: {}", err);
+    }
+    config.tls_certificate = pem;
+    writeln!(out, "Applied to HTTPS with the next upload, to MQTT after a reboot")
+}
+
+fn override_device(
+    out: &mut dyn Write,
+    args: &[&str],
+    config: &SharedConfig,
+    overrides: &SharedOverrides,
+) -> io::Result<()> {
+    let devices: Vec<String> = config.lock().expect("Config lock poisoned").ir.devices.keys().cloned().collect();
+    let (device, state) = match args {
+        [] => {
+            let overrides = overrides.lock().expect("Override lock poisoned");
+            for device in &devices {
+                let state = match overrides.get(device) {
+                    Some(true) => "on",
+                    Some(false) => "off",
+                    None => "auto",
+                };
+                writeln!(out, "  {:<10} {}", device, state)?;
+            }
+            if devices.is_empty() {
+                writeln!(out, "No IR devices configured")?;
+            }
+            return Ok(());
+        }
+        [device, state] => (*device, *state),
+        _ => return writeln!(out, "Usage: ir [<device> on|off|auto]"),
+    };
+    if !devices.iter().any(|name| name == device) {
+        return writeln!(out, "Unknown IR device '{}'", device);
+    }
+    if !climate_control::set_override(overrides, device, state) {
+        return writeln!(out, "Usage: ir [<device> on|off|auto]");
+    }
+    writeln!(out, "{} set to {}, applied with the next measurement", device, state)
+}
+
+#[cfg(feature = "sdcard")]
+fn dump_history(out: &mut dyn Write, format: Format) -> io::Result<()> {
+    let files = match sdcard::list_files() {
+        Ok(files) => files,
+        Err(err) => return writeln!(out, "Failed to list files on the SD card: {:?}", err),
+    };
+
+    writeln!(out, "--- BEGIN DUMP ({} files) ---", files.len())?;
+    if format == Format::Csv {
+        writeln!(out, "timestamp,name,value")?;
+    } else {
+        write!(out, "[")?;
+    }
+    let mut first = true;
+    for name in files {
+        let file = match std::fs::File::open(sdcard::path(&name)) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Failed to open {}: {:?}", name, err);
+                continue;
+            }
+        };
+        for line in io::BufReader::new(file).lines().map_while(Result::ok) {
+            let fields: Vec<&str> = line.split(',').collect();
+            let [timestamp, metric, value] = fields.as_slice() else {
+                continue;
+            };
+            let (Ok(timestamp), Ok(value)) = (timestamp.parse::<u64>(), value.parse::<f32>()) else {
+                continue; // Header line or a record cut short by a power loss
+            };
+            match format {
+                Format::Csv => writeln!(out, "{}", line)?,
+                Format::Json => {
+                    write!(out, "{}\n{}", if first { "" } else { "," }, json_record(timestamp, metric, value))?;
+                    first = false;
+                }
+            }
+        }
+    }
+    if format == Format::Json {
+        writeln!(out, "\n]")?;
+    }
+    writeln!(out, "--- END DUMP ---")
+}
+
+#[cfg(not(feature = "sdcard"))]
+fn dump_history(out: &mut dyn Write, _format: Format) -> io::Result<()> {
+    writeln!(out, "No history storage available, firmware was built without the sdcard feature")
+}
+
+fn json_record(timestamp: u64, name: &str, value: f32) -> String {
+    // JSON has no representation for NaN/inf
+    let value = if value.is_finite() { value.to_string() } else { "null".to_string() };
+    format!(
+        "{{\"timestamp\":{},\"name\":\"{}\",\"value\":{}}}",
+        timestamp,
+        name.replace('\\', "\\\\").replace('"', "\\\""),
+        value
+    )
+}
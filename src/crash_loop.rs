@@ -0,0 +1,153 @@
+//! Crash-loop detection for remote nodes: boots are counted in RTC memory, which survives
+//! everything short of a power cycle, and the count is cleared once the node has been up for
+//! `crash_loop.stable_sec`. After `crash_loop.boots` boots in a row that didn't get that far,
+//! e.g. a sensor that hangs the bus or a configuration that panics, the node comes up in safe
+//! mode: no sensors, filters or extras, only the network, the primary uplink and the console to
+//! fix it from. The next reboot tries the full configuration again.
+
+use crate::config::{Config, SensorsConfig};
+
+// Tells a counter left by a previous boot from whatever RTC memory holds after a power cycle
+const MAGIC: u32 = 0x5AFE_B007;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootCounter {
+    magic: u32,
+    boots: u32,
+}
+
+impl BootCounter {
+    pub const fn new() -> Self {
+        BootCounter { magic: 0, boots: 0 }
+    }
+
+    /// Counts this boot, returns the boots in a row so far including it.
+    pub fn boot(&mut self) -> u32 {
+        if self.magic != MAGIC {
+            *self = BootCounter { magic: MAGIC, boots: 0 };
+        }
+        self.boots = self.boots.saturating_add(1);
+        self.boots
+    }
+
+    pub fn stable(&mut self) {
+        self.boots = 0;
+    }
+}
+
+impl Default for BootCounter {
+    fn default() -> Self {
+        BootCounter::new()
+    }
+}
+
+/// Whether the boots in a row call for safe mode, `limit` 0 never does.
+pub fn looping(boots: u32, limit: u32) -> bool {
+    limit > 0 && boots > limit
+}
+
+/// Strips the configuration down to what safe mode keeps, what it was before is kept for
+/// `Config::save`.
+pub fn restrict(config: &mut Config) {
+    if config.safe_mode.is_none() {
+        config.safe_mode = Some(Box::new(config.clone()));
+    }
+    config.sensors = SensorsConfig {
+        bme280: false,
+        scd4x: false,
+        tsl2591: false,
+        as7341: false,
+        ld2410: false,
+        hdc1080: false,
+        ccs811: false,
+        sgp30: false,
+        max44009: false,
+        dht22: false,
+        reed_switch: false,
+        thermistor: false,
+        analog: false,
+        snore: false,
+    };
+    config.profiles.clear();
+    config.profile.clear();
+    config.active_profile = None;
+    config.spi_sensors.clear();
+    config.thermistors.clear();
+    config.analog_inputs.clear();
+    config.tsl2591.interrupt_pin = None;
+    config.filters.clear();
+    config.extra_uplinks.clear();
+    config.downsample_min.clear();
+    config.actigraphy.enabled = false;
+    config.ir.pin = None;
+    config.display.enabled = false;
+    config.epaper.enabled = false;
+    config.modbus.enabled = false;
+    config.snmp.enabled = false;
+    config.history.enabled = false;
+    config.daily.enabled = false;
+    config.disturbances.enabled = false;
+    config.selftest.on_boot = false;
+}
+
+#[cfg(target_os = "espidf")]
+mod rtc {
+    use std::ptr;
+
+    use super::BootCounter;
+
+    // Left alone by the bootloader and the startup code
+    #[link_section = ".rtc_noinit"]
+    static mut COUNTER: BootCounter = BootCounter::new();
+
+    /// Counts this boot, see `BootCounter::boot`.
+    pub fn boot() -> u32 {
+        // Only touched by the main task while booting and the timer once the node is stable
+        unsafe {
+            let mut counter = ptr::read_volatile(ptr::addr_of!(COUNTER));
+            let boots = counter.boot();
+            ptr::write_volatile(ptr::addr_of_mut!(COUNTER), counter);
+            boots
+        }
+    }
+
+    pub fn stable() {
+        unsafe {
+            let mut counter = ptr::read_volatile(ptr::addr_of!(COUNTER));
+            counter.stable();
+            ptr::write_volatile(ptr::addr_of_mut!(COUNTER), counter);
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use rtc::{boot, stable};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_boots_until_the_node_is_stable() {
+        // Whatever RTC memory holds after a power cycle
+        let mut counter = BootCounter {
+            magic: 0xDEAD_BEEF,
+            boots: 1000,
+        };
+        assert_eq!(counter.boot(), 1);
+        assert!(!looping(counter.boot(), 2));
+        assert!(looping(counter.boot(), 2));
+        assert!(!looping(counter.boot(), 0));
+        counter.stable();
+        assert_eq!(counter.boot(), 1);
+
+        let mut config = Config::default();
+        config.extra_uplinks.push(config.uplink);
+        restrict(&mut config);
+        assert!(config.safe_mode.is_some());
+        assert!(!config.sensors().bme280);
+        assert!(config.extra_uplinks.is_empty());
+        assert!(config.validate().is_ok());
+    }
+}
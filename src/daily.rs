@@ -0,0 +1,198 @@
+//! Daily aggregates computed on the device, so long-term dashboards don't need rollups on the
+//! server: min, max and mean of `daily.metrics`, the hours CO2 spent above each of
+//! `daily.co2_thresholds` and the light exposure in lux hours. The day is the local one, it's
+//! summed up with the first round after midnight and sent with the next upload, stamped with the
+//! midnight it started at.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+
+use crate::compact::CompactBatch;
+use crate::config::Config;
+use crate::error::Result;
+use crate::pipeline::{Batch, SharedBuffer, Sink};
+use crate::sensors::Measurement;
+
+const DAY_SEC: i64 = 24 * 60 * 60;
+// Summaries kept while the uplink is down
+const MAX_DAYS: usize = 7;
+// A round doesn't count for longer than this, so a node that was off doesn't rack up hours
+const MAX_GAP_MS: u64 = 60 * 60 * 1000;
+
+struct Stats {
+    min: f32,
+    max: f32,
+    sum: f64,
+    count: u32,
+}
+
+/// Archive sink collecting the day's rounds, the summaries go out through its side buffer.
+pub struct DailySummary {
+    buffer: SharedBuffer,
+    day: Option<i64>,
+    last_ms: Option<u64>,
+    stats: BTreeMap<String, Stats>,
+    // In s, one per CO2 threshold
+    co2_above: Vec<f64>,
+    lux_seconds: f64,
+}
+
+impl DailySummary {
+    pub fn new() -> Self {
+        DailySummary {
+            buffer: Arc::new(Mutex::new(AllocRingBuffer::new(MAX_DAYS))),
+            day: None,
+            last_ms: None,
+            stats: BTreeMap::new(),
+            co2_above: Vec::new(),
+            lux_seconds: 0.0,
+        }
+    }
+
+    pub fn buffer(&self) -> SharedBuffer {
+        self.buffer.clone()
+    }
+
+    /// Start of the local day `timestamp_ms` falls into, in ms since the Unix epoch.
+    pub fn start_of_day_ms(config: &Config, timestamp_ms: u64) -> u64 {
+        day_start_ms(config, local_day(config, timestamp_ms))
+    }
+
+    /// Adds a round, returns the summary of the previous day once a new one has begun.
+    pub fn record(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Option<Batch> {
+        let day = local_day(config, timestamp_ms);
+        let summary = match self.day {
+            Some(previous) if previous != day => Some(self.summarize(config, previous)),
+            _ => None,
+        };
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.stats.clear();
+            self.co2_above = vec![0.0; config.daily.co2_thresholds.len()];
+            self.lux_seconds = 0.0;
+        }
+        let elapsed_sec = match self.last_ms {
+            Some(last_ms) => timestamp_ms.saturating_sub(last_ms).min(MAX_GAP_MS) as f64 / 1000.0,
+            None => 0.0,
+        };
+        self.last_ms = Some(timestamp_ms);
+
+        for measurement in measurements.iter().filter(|measurement| measurement.value.is_finite()) {
+            let value = measurement.value;
+            match measurement.name.as_ref() {
+                "co2" => {
+                    for (above, limit) in self.co2_above.iter_mut().zip(&config.daily.co2_thresholds) {
+                        if value > *limit {
+                            *above += elapsed_sec;
+                        }
+                    }
+                }
+                "lux" => self.lux_seconds += value as f64 * elapsed_sec,
+                _ => {}
+            }
+            if !config.daily.metrics.iter().any(|metric| *metric == measurement.name) {
+                continue;
+            }
+            let stats = self.stats.entry(measurement.name.to_string()).or_insert(Stats {
+                min: value,
+                max: value,
+                sum: 0.0,
+                count: 0,
+            });
+            stats.min = stats.min.min(value);
+            stats.max = stats.max.max(value);
+            stats.sum += value as f64;
+            stats.count += 1;
+        }
+        summary
+    }
+
+    fn summarize(&self, config: &Config, day: i64) -> Batch {
+        let mut measurements = Vec::new();
+        let mut push = |name: String, value: f32| {
+            measurements.push(Measurement {
+                name: name.into(),
+                value,
+            })
+        };
+        for (name, stats) in &self.stats {
+            push(format!("daily.{}.min", name), stats.min);
+            push(format!("daily.{}.max", name), stats.max);
+            push(format!("daily.{}.mean", name), (stats.sum / stats.count as f64) as f32);
+        }
+        for (above, limit) in self.co2_above.iter().zip(&config.daily.co2_thresholds) {
+            push(format!("daily.co2_above_{}_hours", limit), (above / 3600.0) as f32);
+        }
+        push("daily.light_exposure".to_string(), (self.lux_seconds / 3600.0) as f32);
+        (day_start_ms(config, day), measurements)
+    }
+}
+
+impl Default for DailySummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for DailySummary {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        if let Some((timestamp_ms, summary)) = self.record(config, timestamp_ms, measurements) {
+            let batch = CompactBatch::encode(timestamp_ms, &summary);
+            self.buffer.lock().expect("Buffer lock poisoned").push(batch);
+        }
+        Ok(())
+    }
+}
+
+fn local_day(config: &Config, timestamp_ms: u64) -> i64 {
+    ((timestamp_ms / 1000) as i64 + config.utc_offset_min as i64 * 60).div_euclid(DAY_SEC)
+}
+
+fn day_start_ms(config: &Config, day: i64) -> u64 {
+    ((day * DAY_SEC - config.utc_offset_min as i64 * 60) * 1000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(co2: f32, lux: f32) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "co2".into(),
+                value: co2,
+            },
+            Measurement {
+                name: "lux".into(),
+                value: lux,
+            },
+        ]
+    }
+
+    #[test]
+    fn sums_up_the_local_day_after_midnight() {
+        let config = Config {
+            utc_offset_min: 60,
+            ..Config::default()
+        };
+        // 2024-01-01 22:00 UTC, 23:00 local
+        let start_ms = 1_704_146_400_000;
+        let mut summary = DailySummary::new();
+        assert!(summary.record(&config, start_ms, &round(800.0, 0.0)).is_none());
+        assert!(summary.record(&config, start_ms + 1_800_000, &round(1200.0, 100.0)).is_none());
+        // Midnight local time
+        let (timestamp_ms, measurements) = summary.record(&config, start_ms + 3_600_000, &round(600.0, 0.0)).unwrap();
+        assert_eq!(timestamp_ms, DailySummary::start_of_day_ms(&config, start_ms));
+        assert_eq!(timestamp_ms, 1_704_063_600_000);
+
+        let value = |name: &str| measurements.iter().find(|measurement| measurement.name == name).unwrap().value;
+        assert_eq!(value("daily.co2.min"), 800.0);
+        assert_eq!(value("daily.co2.max"), 1200.0);
+        assert_eq!(value("daily.co2.mean"), 1000.0);
+        assert_eq!(value("daily.co2_above_1000_hours"), 0.5);
+        assert_eq!(value("daily.co2_above_1400_hours"), 0.0);
+        assert_eq!(value("daily.light_exposure"), 50.0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Batches an uplink turned down for good, e.g. with an HTTP 400 or for a bad API key. They're
+//! kept on flash to be looked at from the console instead of being sent again and again, the
+//! oldest make room once there are `MAX_LETTERS`.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::compact::CompactBatch;
+use crate::error::Error;
+use crate::spool::{self, Values};
+
+/// On the storage partition, next to the spool.
+pub const DEAD_LETTER_PATH: &str = "/storage/dead_letters.jsonl";
+// A few KiB, the partition is shared with the configuration and the spool
+const MAX_LETTERS: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub uplink: String,
+    pub reason: String,
+    pub timestamp_ms: u64,
+    pub measurements: Values,
+}
+
+impl DeadLetter {
+    pub fn new(uplink: &str, reason: &Error, batch: &CompactBatch) -> Self {
+        DeadLetter {
+            uplink: uplink.to_string(),
+            reason: reason.to_string(),
+            timestamp_ms: batch.timestamp_ms,
+            measurements: spool::values(batch),
+        }
+    }
+}
+
+pub fn add(path: &Path, letter: DeadLetter) -> io::Result<()> {
+    let mut letters = read(path)?;
+    letters.push(letter);
+    let mut file = BufWriter::new(File::create(path)?);
+    for letter in &letters[letters.len().saturating_sub(MAX_LETTERS)..] {
+        serde_json::to_writer(&mut file, letter)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()
+}
+
+/// Oldest first, nothing without a file. A line that can't be read is skipped.
+pub fn read(path: &Path) -> io::Result<Vec<DeadLetter>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut letters = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(letter) = serde_json::from_str(&line?) {
+            letters.push(letter);
+        }
+    }
+    Ok(letters)
+}
+
+pub fn clear(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Phase;
+    use crate::sensors::Measurement;
+
+    #[test]
+    fn keeps_the_latest_letters() {
+        let path = std::env::temp_dir().join(format!("dead-letters-{}.jsonl", std::process::id()));
+        let reason = Error::rejected(Phase::Upload, "Datadog answered 400");
+        for timestamp_ms in 0..MAX_LETTERS as u64 + 2 {
+            let measurements = [Measurement {
+                name: "co2".into(),
+                value: 500.0,
+            }];
+            let batch = CompactBatch::encode(timestamp_ms, &measurements);
+            add(&path, DeadLetter::new("datadog", &reason, &batch)).unwrap();
+        }
+
+        let letters = read(&path).unwrap();
+        assert_eq!(letters.len(), MAX_LETTERS);
+        assert_eq!(letters[0].timestamp_ms, 2);
+        assert_eq!(letters[0].measurements, vec![("co2".to_string(), Some(500.0))]);
+        assert_eq!(letters[0].reason, "upload: rejected: Datadog answered 400");
+        clear(&path).unwrap();
+        assert!(read(&path).unwrap().is_empty());
+    }
+}
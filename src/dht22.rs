@@ -0,0 +1,59 @@
+//! DHT22/AM2302 frame decoding, kept apart from the RMT capture so it can be tested on the host.
+
+// The sensor holds the line high for 26-28 µs for a 0 and 70 µs for a 1
+const ONE_MIN_US: u16 = 48;
+const FRAME_BITS: usize = 40;
+
+/// The five frame bytes from the durations of the high pulses on the line, in µs. The bits are
+/// the last 40, anything before them is the start signal and the sensor's response.
+pub fn frame(high_us: &[u16]) -> Option<[u8; 5]> {
+    let bits = high_us.get(high_us.len().checked_sub(FRAME_BITS)?..)?;
+    let mut frame = [0u8; 5];
+    for (i, duration) in bits.iter().enumerate() {
+        if *duration >= ONE_MIN_US {
+            frame[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+    Some(frame)
+}
+
+/// Temperature in °C and relative humidity in %, `None` if the checksum doesn't match.
+pub fn decode(frame: [u8; 5]) -> Option<(f32, f32)> {
+    let sum = frame[..4].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if sum != frame[4] {
+        return None;
+    }
+    let humidity = u16::from_be_bytes([frame[0], frame[1]]) as f32 / 10.0;
+    // Sign and magnitude, not two's complement
+    let magnitude = u16::from_be_bytes([frame[2] & 0x7F, frame[3]]) as f32 / 10.0;
+    let temperature = if frame[2] & 0x80 != 0 { -magnitude } else { magnitude };
+    Some((temperature, humidity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulses(frame: [u8; 5]) -> Vec<u16> {
+        // The sensor's 80 µs response comes first
+        let mut high_us = vec![80];
+        for byte in frame {
+            high_us.extend((0..8).map(|bit| if byte & (0x80 >> bit) != 0 { 70 } else { 27 }));
+        }
+        high_us
+    }
+
+    #[test]
+    fn decodes_the_datasheet_example() {
+        let example = [0x02, 0x8C, 0x01, 0x5F, 0xEE];
+        assert_eq!(frame(&pulses(example)), Some(example));
+        assert_eq!(decode(example), Some((35.1, 65.2)));
+    }
+
+    #[test]
+    fn handles_negative_temperatures_and_bad_frames() {
+        assert_eq!(decode([0x02, 0x8C, 0x80, 0x65, 0x73]), Some((-10.1, 65.2)));
+        assert_eq!(decode([0x02, 0x8C, 0x01, 0x5F, 0xEF]), None);
+        assert_eq!(frame(&[70; 39]), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Stack and heap usage, to catch a task about to overflow its stack or a heap too fragmented for
+//! the next allocation before the node crashes. The task list needs
+//! `CONFIG_FREERTOS_USE_TRACE_FACILITY`.
+
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::ptr;
+
+use esp_idf_svc::sys::{
+    esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_DEEPSLEEP,
+    esp_reset_reason_t_ESP_RST_EXT, esp_reset_reason_t_ESP_RST_INT_WDT, esp_reset_reason_t_ESP_RST_PANIC,
+    esp_reset_reason_t_ESP_RST_POWERON, esp_reset_reason_t_ESP_RST_SDIO, esp_reset_reason_t_ESP_RST_SW,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT, heap_caps_get_info, multi_heap_info_t,
+    uxTaskGetNumberOfTasks, uxTaskGetSystemState, TaskStatus_t, MALLOC_CAP_DEFAULT,
+};
+
+use crate::pipeline::Filter;
+use crate::sensors::Measurement;
+
+pub struct HeapStats {
+    pub free: u32,
+    pub minimum_free: u32,
+    pub largest_block: u32,
+}
+
+impl HeapStats {
+    /// Share of the free heap outside the largest block in %, 0 while it's all in one piece.
+    pub fn fragmentation(&self) -> f32 {
+        if self.free == 0 {
+            return 0.0;
+        }
+        100.0 * (1.0 - self.largest_block as f32 / self.free as f32)
+    }
+}
+
+pub struct TaskStats {
+    pub name: String,
+    pub priority: u32,
+    /// Least free stack the task has had so far, in bytes
+    pub stack_free: u32,
+}
+
+pub fn heap() -> HeapStats {
+    let mut info = multi_heap_info_t::default();
+    unsafe { heap_caps_get_info(&mut info, MALLOC_CAP_DEFAULT) };
+    HeapStats {
+        free: info.total_free_bytes as u32,
+        minimum_free: info.minimum_free_bytes as u32,
+        largest_block: info.largest_free_block as u32,
+    }
+}
+
+pub fn tasks() -> Vec<TaskStats> {
+    // Room for a few tasks started in between, the rest would be left out
+    let capacity = unsafe { uxTaskGetNumberOfTasks() } as usize + 4;
+    let mut status: Vec<TaskStatus_t> = Vec::with_capacity(capacity);
+    unsafe {
+        let filled = uxTaskGetSystemState(status.as_mut_ptr(), capacity as _, ptr::null_mut());
+        status.set_len(filled as usize);
+    }
+    let mut tasks: Vec<TaskStats> = status
+        .iter()
+        .map(|task| TaskStats {
+            name: unsafe { CStr::from_ptr(task.pcTaskName) }.to_string_lossy().into_owned(),
+            priority: task.uxCurrentPriority as u32,
+            stack_free: task.usStackHighWaterMark as u32,
+        })
+        .collect();
+    tasks.sort_by(|a, b| a.name.cmp(&b.name));
+    tasks
+}
+
+/// Why the chip last reset, as ESP-IDF tells.
+#[allow(non_upper_case_globals)]
+pub fn reset_reason() -> &'static str {
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external_pin",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// `heap_free`, `heap_min_free`, `heap_largest_block`, `heap_fragmentation` and a
+/// `stack_free.<task>` per task.
+pub fn measurements() -> Vec<Measurement> {
+    let mut measurements = Vec::new();
+    let heap = heap();
+    let metric = |name: Cow<'static, str>, value: f32| Measurement { name, value };
+    measurements.push(metric("heap_free".into(), heap.free as f32));
+    measurements.push(metric("heap_min_free".into(), heap.minimum_free as f32));
+    measurements.push(metric("heap_largest_block".into(), heap.largest_block as f32));
+    measurements.push(metric("heap_fragmentation".into(), heap.fragmentation()));
+    for task in tasks() {
+        // Task names like `remote-console` or `IDLE0` as metric names
+        let name: String = task
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+            .collect();
+        measurements.push(metric(format!("stack_free.{}", name).into(), task.stack_free as f32));
+    }
+    measurements
+}
+
+/// Adds the system [`measurements`] to every round.
+pub struct SystemMetrics;
+
+impl Filter for SystemMetrics {
+    fn apply(&mut self, mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+        measurements.extend(self::measurements());
+        measurements
+    }
+}
@@ -0,0 +1,309 @@
+//! What the displays show: the latest readings, a day of history, the pages and how bright they
+//! may be, kept apart from the panel drivers so it can be tested on the host.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{DisplayConfig, Page};
+use crate::pipeline::{now, Filter};
+use crate::sensors::Measurement;
+
+#[cfg(all(feature = "display", target_os = "espidf"))]
+mod oled;
+
+#[cfg(all(feature = "epaper", target_os = "espidf"))]
+mod epaper;
+
+#[cfg(all(feature = "display", target_os = "espidf"))]
+pub use oled::spawn;
+#[cfg(all(feature = "epaper", target_os = "espidf"))]
+pub use epaper::{Epaper, EpaperSink};
+
+// Metrics on the e-paper summary: label, name, unit and precision
+const SUMMARY: &[(&str, &str, &str, usize)] = &[
+    ("Temp", "temperature", "C", 1),
+    ("Hum", "humidity", "%", 0),
+    ("CO2", "co2", "ppm", 0),
+    ("IAQ", "iaq", "", 0),
+    ("Light", "lux", "lx", 1),
+    ("Press", "pressure", "mmHg", 0),
+];
+
+// A day of history in 15 minute buckets, a few KB even with every metric
+const BUCKET_SEC: u64 = 15 * 60;
+const HISTORY_SEC: u64 = 24 * 60 * 60;
+
+pub type SharedReadings = Arc<Mutex<Readings>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    start: u64,
+    min: f32,
+    max: f32,
+    last: f32,
+}
+
+/// Latest value and a day of history of every metric.
+#[derive(Default)]
+pub struct Readings {
+    metrics: BTreeMap<String, VecDeque<Bucket>>,
+}
+
+impl Readings {
+    pub fn update(&mut self, timestamp: u64, measurements: &[Measurement]) {
+        let start = timestamp - timestamp % BUCKET_SEC;
+        for measurement in measurements {
+            let value = measurement.value;
+            let buckets = self.metrics.entry(measurement.name.to_string()).or_default();
+            match buckets.back_mut() {
+                Some(bucket) if bucket.start == start => {
+                    bucket.min = bucket.min.min(value);
+                    bucket.max = bucket.max.max(value);
+                    bucket.last = value;
+                }
+                _ => buckets.push_back(Bucket {
+                    start,
+                    min: value,
+                    max: value,
+                    last: value,
+                }),
+            }
+            while buckets.front().is_some_and(|bucket| bucket.start + HISTORY_SEC <= start) {
+                buckets.pop_front();
+            }
+        }
+    }
+
+    pub fn latest(&self, name: &str) -> Option<f32> {
+        self.metrics.get(name)?.back().map(|bucket| bucket.last)
+    }
+
+    /// Minimum and maximum over the last 24 h.
+    pub fn range(&self, name: &str) -> Option<(f32, f32)> {
+        self.metrics.get(name)?.iter().fold(None, |range, bucket| match range {
+            None => Some((bucket.min, bucket.max)),
+            Some((min, max)) => Some((bucket.min.min(min), bucket.max.max(max))),
+        })
+    }
+
+    /// Last value of every 15 minute bucket, oldest first.
+    pub fn history(&self, name: &str) -> Vec<f32> {
+        self.metrics
+            .get(name)
+            .map(|buckets| buckets.iter().map(|bucket| bucket.last).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Passes the measurements through unchanged, keeping a copy for the displays.
+pub struct DisplayFeed {
+    readings: SharedReadings,
+}
+
+impl DisplayFeed {
+    pub fn new(readings: SharedReadings) -> Self {
+        DisplayFeed { readings }
+    }
+}
+
+impl Filter for DisplayFeed {
+    fn apply(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement> {
+        self.readings
+            .lock()
+            .expect("Readings lock poisoned")
+            .update(now(), &measurements);
+        measurements
+    }
+}
+
+/// Diagnostics for the network page, gathered by the firmware.
+#[derive(Default)]
+pub struct Status {
+    pub device_id: String,
+    pub uptime_sec: u64,
+    pub free_heap: u32,
+    pub buffered: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Brightness {
+    Off,
+    Dim,
+    Normal,
+}
+
+/// Follows the room: dimmed when it's getting dark, off when it's dark. Full brightness without a
+/// light reading.
+pub fn brightness(config: &DisplayConfig, lux: Option<f32>) -> Brightness {
+    match lux {
+        Some(lux) if lux < config.blank_below_lux => Brightness::Off,
+        Some(lux) if lux < config.dim_below_lux => Brightness::Dim,
+        _ => Brightness::Normal,
+    }
+}
+
+/// Text of a page, the history page shows its metric's range, the graph goes below.
+pub fn page_lines(page: Page, config: &DisplayConfig, readings: &Readings, status: &Status) -> Vec<String> {
+    let value = |label: &str, name: &str, unit: &str, precision: usize| {
+        let value = match readings.latest(name) {
+            Some(value) => format!("{:.*}{}", precision, value, unit),
+            None => "-".to_string(),
+        };
+        format!("{:<6}{}", label, value)
+    };
+    match page {
+        Page::Climate => vec![
+            value("Temp", "temperature", " C", 1),
+            value("Hum", "humidity", " %", 0),
+            value("Press", "pressure", " mmHg", 0),
+            value("Light", "lux", " lx", 1),
+        ],
+        Page::AirQuality => vec![
+            value("CO2", "co2", " ppm", 0),
+            value("IAQ", "iaq", "", 0),
+            value("TVOC", "tvoc", " ppb", 0),
+            value("PM2.5", "pm2_5", " ug", 0),
+        ],
+        Page::Network => vec![
+            format!("ID    {}", status.device_id),
+            format!("Up    {}h{:02}m", status.uptime_sec / 3600, status.uptime_sec / 60 % 60),
+            format!("Heap  {} KB", status.free_heap / 1024),
+            format!("Queue {}", status.buffered),
+        ],
+        Page::History => {
+            let name = &config.history_metric;
+            match readings.range(name) {
+                Some((min, max)) => vec![format!("{} 24h {:.0}-{:.0}", name, min, max)],
+                None => vec![format!("{} 24h -", name)],
+            }
+        }
+    }
+}
+
+/// Current value and 24 h range of every summary metric that has been measured.
+pub fn summary_lines(readings: &Readings) -> Vec<String> {
+    SUMMARY
+        .iter()
+        .filter_map(|(label, name, unit, precision)| {
+            let value = readings.latest(name)?;
+            let (min, max) = readings.range(name)?;
+            Some(format!(
+                "{:<6}{:>7.*}{:<5}{:.*}-{:.*}",
+                label, precision, value, unit, precision, min, precision, max
+            ))
+        })
+        .collect()
+}
+
+/// Scales the values to points on a `width` x `height` area, top left being (0, 0).
+pub fn graph(values: &[f32], width: u32, height: u32) -> Vec<(i32, i32)> {
+    let (min, max) = values
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| (min.min(*value), max.max(*value)));
+    let span = if max > min { max - min } else { 1.0 };
+    let steps = values.len().saturating_sub(1).max(1) as f32;
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let x = (i as f32 / steps * (width - 1) as f32).round() as i32;
+            let y = ((max - value) / span * (height - 1) as f32).round() as i32;
+            (x, y)
+        })
+        .collect()
+}
+
+/// Which page is showing: the next one comes up every `page_sec`, or right away on a button
+/// press. A press also keeps the panel on for `wake_sec` even when it would be off.
+pub struct Pager {
+    pages: Vec<Page>,
+    current: usize,
+    shown_at_ms: u64,
+    awake_until_ms: u64,
+}
+
+impl Pager {
+    pub fn new(pages: Vec<Page>) -> Self {
+        Pager {
+            pages,
+            current: 0,
+            shown_at_ms: 0,
+            awake_until_ms: 0,
+        }
+    }
+
+    pub fn page(&self) -> Option<Page> {
+        self.pages.get(self.current).copied()
+    }
+
+    pub fn awake(&self, now_ms: u64) -> bool {
+        now_ms < self.awake_until_ms
+    }
+
+    pub fn press(&mut self, now_ms: u64, config: &DisplayConfig, visible: bool) {
+        // The first press on a dark panel only wakes it
+        if visible || self.awake(now_ms) {
+            self.advance(now_ms);
+        }
+        self.awake_until_ms = now_ms + config.wake_sec as u64 * 1000;
+    }
+
+    pub fn tick(&mut self, now_ms: u64, config: &DisplayConfig) {
+        if config.page_sec > 0 && now_ms - self.shown_at_ms >= config.page_sec as u64 * 1000 {
+            self.advance(now_ms);
+        }
+    }
+
+    fn advance(&mut self, now_ms: u64) {
+        if !self.pages.is_empty() {
+            self.current = (self.current + 1) % self.pages.len();
+        }
+        self.shown_at_ms = now_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(name: &str, value: f32) -> Measurement {
+        Measurement {
+            name: name.to_string().into(),
+            value,
+        }
+    }
+
+    #[test]
+    fn keeps_a_day_of_history() {
+        let mut readings = Readings::default();
+        for i in 0..(25 * 4) {
+            readings.update(i * BUCKET_SEC, &[measurement("co2", 400.0 + i as f32)]);
+        }
+        readings.update(99 * BUCKET_SEC + 60, &[measurement("co2", 300.0)]);
+        assert_eq!(readings.latest("co2"), Some(300.0));
+        assert_eq!(readings.range("co2"), Some((300.0, 499.0)));
+        assert_eq!(readings.history("co2").len(), 96);
+        assert_eq!(readings.latest("lux"), None);
+        assert_eq!(summary_lines(&readings), vec!["CO2       300ppm  300-499"]);
+    }
+
+    #[test]
+    fn pages_dim_and_wake() {
+        let config = DisplayConfig::default();
+        assert_eq!(brightness(&config, Some(0.5)), Brightness::Off);
+        assert_eq!(brightness(&config, Some(config.dim_below_lux - 1.0)), Brightness::Dim);
+        assert_eq!(brightness(&config, None), Brightness::Normal);
+
+        let mut pager = Pager::new(vec![Page::Climate, Page::AirQuality]);
+        pager.tick(config.page_sec as u64 * 1000, &config);
+        assert_eq!(pager.page(), Some(Page::AirQuality));
+        // Dark panel: the first press wakes it, the second flips the page
+        pager.press(20_000, &config, false);
+        assert_eq!(pager.page(), Some(Page::AirQuality));
+        assert!(pager.awake(20_000));
+        pager.press(21_000, &config, false);
+        assert_eq!(pager.page(), Some(Page::Climate));
+
+        assert_eq!(graph(&[1.0, 3.0, 2.0], 5, 3), vec![(0, 2), (2, 0), (4, 1)]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use embedded_graphics::mono_font::ascii::{FONT_7X13, FONT_7X13_BOLD};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::SpiDevice;
+
+use super::{summary_lines, Readings};
+use crate::clock::seconds_of_day;
+use crate::config::Config;
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+// 2.13" SSD1680 panel, drawn in landscape
+const SOURCES: u32 = 122;
+const GATES: u32 = 250;
+const ROW_BYTES: usize = SOURCES.div_ceil(8) as usize;
+const LINE_HEIGHT: i32 = 16;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DRIVER_OUTPUT_CONTROL: u8 = 0x01;
+const DEEP_SLEEP: u8 = 0x10;
+const DATA_ENTRY_MODE: u8 = 0x11;
+const SOFT_RESET: u8 = 0x12;
+const TEMPERATURE_SENSOR: u8 = 0x18;
+const MASTER_ACTIVATION: u8 = 0x20;
+const DISPLAY_UPDATE_CONTROL_1: u8 = 0x21;
+const DISPLAY_UPDATE_CONTROL_2: u8 = 0x22;
+const WRITE_RAM_BW: u8 = 0x24;
+const BORDER_WAVEFORM: u8 = 0x3C;
+const RAM_X_RANGE: u8 = 0x44;
+const RAM_Y_RANGE: u8 = 0x45;
+const RAM_X_COUNTER: u8 = 0x4E;
+const RAM_Y_COUNTER: u8 = 0x4F;
+
+/// SSD1680 e-paper panel with a frame buffer to draw into, sent on [`Epaper::show`]. The panel
+/// keeps the image without power, it's put into deep sleep after every refresh.
+pub struct Epaper<SPI, DC, RST, BUSY, DELAY> {
+    spi: SPI,
+    dc: DC,
+    rst: RST,
+    busy: BUSY,
+    delay: DELAY,
+    // 1 is white
+    frame: Vec<u8>,
+}
+
+impl<SPI, DC, RST, BUSY, DELAY> Epaper<SPI, DC, RST, BUSY, DELAY>
+where
+    SPI: SpiDevice,
+    DC: OutputPin,
+    RST: OutputPin,
+    BUSY: InputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: SPI, dc: DC, rst: RST, busy: BUSY, delay: DELAY) -> Self {
+        Epaper {
+            spi,
+            dc,
+            rst,
+            busy,
+            delay,
+            frame: vec![0xFF; ROW_BYTES * GATES as usize],
+        }
+    }
+
+    /// Wakes the panel, sends the frame buffer and does a full refresh, which takes a few seconds.
+    pub fn show(&mut self) -> Result<()> {
+        self.init()?;
+        self.command(RAM_X_COUNTER, &[0x00])?;
+        self.command(RAM_Y_COUNTER, &[0x00, 0x00])?;
+        let frame = std::mem::take(&mut self.frame);
+        let result = self.command(WRITE_RAM_BW, &frame);
+        self.frame = frame;
+        result?;
+        self.command(DISPLAY_UPDATE_CONTROL_2, &[0xF7])?;
+        self.command(MASTER_ACTIVATION, &[])?;
+        self.wait()?;
+        self.command(DEEP_SLEEP, &[0x01])
+    }
+
+    fn init(&mut self) -> Result<()> {
+        // Deep sleep is only left through a hardware reset
+        self.rst.set_low().map_err(failed("Failed to reset"))?;
+        self.delay.delay_ms(10);
+        self.rst.set_high().map_err(failed("Failed to reset"))?;
+        self.delay.delay_ms(10);
+        self.wait()?;
+        self.command(SOFT_RESET, &[])?;
+        self.wait()?;
+
+        let last_gate = (GATES - 1).to_le_bytes();
+        self.command(DRIVER_OUTPUT_CONTROL, &[last_gate[0], last_gate[1], 0x00])?;
+        // X and Y incrementing
+        self.command(DATA_ENTRY_MODE, &[0x03])?;
+        self.command(RAM_X_RANGE, &[0x00, ROW_BYTES as u8 - 1])?;
+        self.command(RAM_Y_RANGE, &[0x00, 0x00, last_gate[0], last_gate[1]])?;
+        self.command(BORDER_WAVEFORM, &[0x05])?;
+        self.command(DISPLAY_UPDATE_CONTROL_1, &[0x00, 0x80])?;
+        // Internal temperature sensor for the waveform
+        self.command(TEMPERATURE_SENSOR, &[0x80])?;
+        self.wait()
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<()> {
+        self.dc.set_low().map_err(failed("Failed to select command mode"))?;
+        self.spi.write(&[command]).map_err(failed("Failed to send a command"))?;
+        if !data.is_empty() {
+            self.dc.set_high().map_err(failed("Failed to select data mode"))?;
+            self.spi.write(data).map_err(failed("Failed to send data"))?;
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        let started = Instant::now();
+        while self.busy.is_high().map_err(failed("Failed to read the busy pin"))? {
+            if started.elapsed() > BUSY_TIMEOUT {
+                return Err(Error::failed(Phase::Output, "E-paper panel stuck busy"));
+            }
+            self.delay.delay_ms(10);
+        }
+        Ok(())
+    }
+}
+
+fn failed<E: fmt::Debug>(message: &'static str) -> impl FnOnce(E) -> Error {
+    move |err| Error::failed(Phase::Output, format!("E-paper: {}: {:?}", message, err))
+}
+
+impl<SPI, DC, RST, BUSY, DELAY> OriginDimensions for Epaper<SPI, DC, RST, BUSY, DELAY> {
+    fn size(&self) -> Size {
+        Size::new(GATES, SOURCES)
+    }
+}
+
+impl<SPI, DC, RST, BUSY, DELAY> DrawTarget for Epaper<SPI, DC, RST, BUSY, DELAY> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) else {
+                continue;
+            };
+            if x >= GATES || y >= SOURCES {
+                continue;
+            }
+            // Landscape: the gates run along x, the sources along y, flipped
+            let source = SOURCES - 1 - y;
+            let index = x as usize * ROW_BYTES + source as usize / 8;
+            let bit = 0x80 >> (source % 8);
+            match color {
+                BinaryColor::On => self.frame[index] &= !bit,
+                BinaryColor::Off => self.frame[index] |= bit,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shows the current values and their 24 h range, refreshed every `epaper.refresh_min`.
+pub struct EpaperSink<SPI, DC, RST, BUSY, DELAY> {
+    panel: Epaper<SPI, DC, RST, BUSY, DELAY>,
+    readings: Readings,
+    refreshed_at: Option<u64>,
+}
+
+impl<SPI, DC, RST, BUSY, DELAY> EpaperSink<SPI, DC, RST, BUSY, DELAY> {
+    pub fn new(panel: Epaper<SPI, DC, RST, BUSY, DELAY>) -> Self {
+        EpaperSink {
+            panel,
+            readings: Readings::default(),
+            refreshed_at: None,
+        }
+    }
+}
+
+impl<SPI, DC, RST, BUSY, DELAY> Sink for EpaperSink<SPI, DC, RST, BUSY, DELAY>
+where
+    SPI: SpiDevice,
+    DC: OutputPin,
+    RST: OutputPin,
+    BUSY: InputPin,
+    DELAY: DelayNs,
+{
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let timestamp = timestamp_ms / 1000;
+        self.readings.update(timestamp, measurements);
+        let refresh_sec = config.epaper.refresh_min as u64 * 60;
+        if self.refreshed_at.is_some_and(|refreshed| timestamp.saturating_sub(refreshed) < refresh_sec) {
+            return Ok(());
+        }
+
+        let time = seconds_of_day(timestamp, config.utc_offset_min);
+        let header = format!("{}  {:02}:{:02}", config.device_id(), time / 3600, time / 60 % 60);
+        let _ = self.panel.clear(BinaryColor::Off);
+        let bold = MonoTextStyle::new(&FONT_7X13_BOLD, BinaryColor::On);
+        let _ = Text::with_baseline(&header, Point::new(2, 2), bold, Baseline::Top).draw(&mut self.panel);
+        let regular = MonoTextStyle::new(&FONT_7X13, BinaryColor::On);
+        for (i, line) in summary_lines(&self.readings).iter().enumerate() {
+            let position = Point::new(2, 2 + (i as i32 + 1) * LINE_HEIGHT);
+            let _ = Text::with_baseline(line, position, regular, Baseline::Top).draw(&mut self.panel);
+        }
+        // Not retried before the next refresh is due, a stuck panel would hold up every round
+        self.refreshed_at = Some(timestamp);
+        self.panel.show()
+    }
+}
@@ -0,0 +1,170 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Polyline, PrimitiveStyle};
+use embedded_graphics::text::{Baseline, Text};
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, PinDriver, Pull};
+use log::error;
+use ssd1306::mode::{BufferedGraphicsMode, DisplayConfig as _};
+use ssd1306::prelude::{Brightness as PanelBrightness, DisplayRotation, DisplaySize128x64, I2CInterface};
+use ssd1306::{I2CDisplayInterface, Ssd1306};
+
+use super::{brightness, graph, page_lines, Brightness, Pager, SharedReadings, Status};
+use crate::config::{Output, Page, SharedConfig};
+use crate::error::{Context, Error, Phase, Result};
+use crate::buffers::PriorityBuffers;
+use crate::quiet_hours;
+use crate::sensors::I2cDevice;
+
+const STACK_SIZE: usize = 6 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+const WIDTH: u32 = 128;
+const HEIGHT: u32 = 64;
+const LINE_HEIGHT: i32 = 12;
+
+type Panel = Ssd1306<I2CInterface<I2cDevice<'static>>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>;
+
+/// What's on the panel, it's only redrawn when this changes.
+#[derive(PartialEq)]
+struct Frame {
+    brightness: Brightness,
+    lines: Vec<String>,
+    graph: Vec<(i32, i32)>,
+}
+
+/// Starts a thread driving an SSD1306 OLED, it redraws every second and polls the button in
+/// between.
+pub fn spawn(
+    i2c: I2cDevice<'static>,
+    config: SharedConfig,
+    readings: SharedReadings,
+    buffers: PriorityBuffers,
+) -> Result<()> {
+    let display = config.lock().expect("Config lock poisoned").display.clone();
+    let interface = I2CDisplayInterface::new_custom_address(i2c, display.address);
+    let mut panel = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0).into_buffered_graphics_mode();
+    panel
+        .init()
+        .map_err(|err| Error::failed(Phase::Output, format!("Failed to set up the display: {:?}", err)))?;
+
+    let button = match display.button {
+        Some(pin) => {
+            let context = "Failed to set up the display button";
+            let mut button = PinDriver::input(unsafe { AnyIOPin::new(pin) }).context(Phase::Output, context)?;
+            button.set_pull(Pull::Up).context(Phase::Output, context)?;
+            Some(button)
+        }
+        None => None,
+    };
+
+    thread::Builder::new()
+        .name("display".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(panel, button, config, readings, buffers))
+        .context(Phase::Output, "Failed to start the display thread")?;
+    Ok(())
+}
+
+fn run(
+    mut panel: Panel,
+    button: Option<PinDriver<'static, AnyIOPin, Input>>,
+    config: SharedConfig,
+    readings: SharedReadings,
+    buffers: PriorityBuffers,
+) {
+    let started = Instant::now();
+    let mut pager = Pager::new(config.lock().expect("Config lock poisoned").display.pages.clone());
+    let mut shown: Option<Frame> = None;
+    let mut was_pressed = false;
+    let mut last_redraw: Option<Instant> = None;
+    loop {
+        let now_ms = started.elapsed().as_millis() as u64;
+        let pressed = button.as_ref().is_some_and(|button| button.is_low());
+        let press = pressed && !was_pressed;
+        was_pressed = pressed;
+
+        if press || last_redraw.is_none_or(|redraw| redraw.elapsed() >= REDRAW_INTERVAL) {
+            let (display, quiet, status) = {
+                let config = config.lock().expect("Config lock poisoned");
+                let status = Status {
+                    device_id: config.device_id().to_string(),
+                    uptime_sec: started.elapsed().as_secs(),
+                    free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+                    buffered: buffers.len(),
+                };
+                (config.display.clone(), !quiet_hours::allowed(&config, Output::Display), status)
+            };
+            let readings = readings.lock().expect("Readings lock poisoned");
+            let visible = shown.as_ref().is_some_and(|frame| frame.brightness != Brightness::Off);
+            if press {
+                pager.press(now_ms, &display, visible);
+            }
+            pager.tick(now_ms, &display);
+
+            let brightness = if pager.awake(now_ms) {
+                Brightness::Normal
+            } else if quiet {
+                Brightness::Off
+            } else {
+                brightness(&display, readings.latest("lux"))
+            };
+            let frame = match pager.page() {
+                Some(page) if brightness != Brightness::Off => {
+                    let lines = page_lines(page, &display, &readings, &status);
+                    let graph = if page == Page::History {
+                        let top = lines.len() as i32 * LINE_HEIGHT + 2;
+                        let height = HEIGHT - top as u32;
+                        graph(&readings.history(&display.history_metric), WIDTH, height)
+                            .into_iter()
+                            .map(|(x, y)| (x, y + top))
+                            .collect()
+                    } else {
+                        Vec::new()
+                    };
+                    Frame { brightness, lines, graph }
+                }
+                _ => Frame {
+                    brightness: Brightness::Off,
+                    lines: Vec::new(),
+                    graph: Vec::new(),
+                },
+            };
+            drop(readings);
+
+            if shown.as_ref() != Some(&frame) {
+                match draw(&mut panel, &frame) {
+                    Ok(_) => shown = Some(frame),
+                    Err(err) => error!("Failed to update the display: {:?}", err),
+                }
+            }
+            last_redraw = Some(Instant::now());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn draw(panel: &mut Panel, frame: &Frame) -> Result<(), display_interface::DisplayError> {
+    match frame.brightness {
+        Brightness::Off => return panel.set_display_on(false),
+        Brightness::Dim => panel.set_brightness(PanelBrightness::DIMMEST)?,
+        Brightness::Normal => panel.set_brightness(PanelBrightness::NORMAL)?,
+    }
+    panel.clear_buffer();
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    for (i, line) in frame.lines.iter().enumerate() {
+        Text::with_baseline(line, Point::new(0, i as i32 * LINE_HEIGHT), style, Baseline::Top).draw(panel)?;
+    }
+    if !frame.graph.is_empty() {
+        let points: Vec<Point> = frame.graph.iter().map(|(x, y)| Point::new(*x, *y)).collect();
+        Polyline::new(&points)
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(panel)?;
+    }
+    panel.flush()?;
+    panel.set_display_on(true)
+}
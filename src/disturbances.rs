@@ -0,0 +1,244 @@
+//! Sleep disturbances: light switched on, noise, motion and CO2 alarms as discrete, timestamped
+//! events rather than metrics, for overlaying on sleep charts. They're kept in a log of their own
+//! and sent while the network is up for an upload, to Graphite's events API, a webhook and/or
+//! MQTT.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+use serde_json::{json, Value};
+
+use crate::config::{Config, DisturbancesConfig};
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{now_ms, Filter, HttpClient, Reporter};
+use crate::sensors::Measurement;
+use crate::sinks::{base_topic, Publisher};
+
+// Kept while the uplink is down, the oldest go first
+const MAX_DISTURBANCES: usize = 256;
+
+pub type DisturbanceLog = Arc<Mutex<AllocRingBuffer<Disturbance>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disturbance {
+    /// `light`, `noise`, `motion`, `co2` or `window`
+    pub kind: &'static str,
+    /// In ms since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Lux, noise events, the motion metric, ppm or 1 for a window opened and 0 for one closed
+    pub value: f32,
+}
+
+pub fn new_log() -> DisturbanceLog {
+    Arc::new(Mutex::new(AllocRingBuffer::new(MAX_DISTURBANCES)))
+}
+
+pub fn record(log: &DisturbanceLog, kind: &'static str, timestamp_ms: u64, value: f32) {
+    info!("Disturbance: {} ({})", kind, value);
+    log.lock().expect("Disturbance log lock poisoned").push(Disturbance {
+        kind,
+        timestamp_ms,
+        value,
+    });
+}
+
+/// Picks the disturbances out of the rounds: `lux`, `co2` and the motion metrics going above
+/// their threshold, and any `noise_events`. Only the rise is an event, a light left on is one.
+/// Passes the measurements on unchanged.
+pub struct DisturbanceDetector {
+    config: DisturbancesConfig,
+    // Light disturbances come from the TSL2591 interrupt instead
+    light_interrupt: bool,
+    log: DisturbanceLog,
+    above: HashMap<String, bool>,
+}
+
+impl DisturbanceDetector {
+    pub fn new(config: &Config, log: DisturbanceLog) -> Self {
+        DisturbanceDetector {
+            config: config.disturbances.clone(),
+            light_interrupt: config.tsl2591.interrupt_pin.is_some(),
+            log,
+            above: HashMap::new(),
+        }
+    }
+
+    fn detect(&mut self, measurements: &[Measurement], timestamp_ms: u64) {
+        for measurement in measurements.iter().filter(|measurement| measurement.value.is_finite()) {
+            let name = measurement.name.as_ref();
+            let (kind, threshold) = match name {
+                "lux" if !self.light_interrupt => ("light", self.config.light_lux),
+                "co2" => ("co2", self.config.co2_ppm),
+                "noise_events" => ("noise", 0.0),
+                _ if self.config.motion_metrics.iter().any(|metric| metric == name) => ("motion", 0.0),
+                _ => continue,
+            };
+            let above = measurement.value > threshold;
+            let was_above = self.above.insert(name.to_string(), above).unwrap_or(false);
+            // Noise events are counted per round, every round with some is a disturbance
+            if above && (!was_above || kind == "noise") {
+                record(&self.log, kind, timestamp_ms, measurement.value);
+            }
+        }
+    }
+}
+
+impl Filter for DisturbanceDetector {
+    fn apply(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement> {
+        self.detect(&measurements, now_ms());
+        measurements
+    }
+}
+
+/// Sends the logged disturbances to `disturbances.graphite_url`, `disturbances.webhook_url` and,
+/// with `disturbances.mqtt`, the broker. One that fails anywhere stays in the log with the ones
+/// after it, for the next upload.
+pub struct DisturbanceReporter<'a> {
+    client: Box<dyn HttpClient + Send + 'a>,
+    publisher: Option<Box<dyn Publisher + Send + 'a>>,
+    log: DisturbanceLog,
+}
+
+impl<'a> DisturbanceReporter<'a> {
+    pub fn new(
+        client: Box<dyn HttpClient + Send + 'a>,
+        publisher: Option<Box<dyn Publisher + Send + 'a>>,
+        log: DisturbanceLog,
+    ) -> Self {
+        DisturbanceReporter { client, publisher, log }
+    }
+
+    fn send(&mut self, config: &Config, disturbance: &Disturbance) -> Result<()> {
+        let disturbances = &config.disturbances;
+        if !disturbances.graphite_url.is_empty() {
+            let body = serde_json::to_vec(&graphite_event(config, disturbance))?;
+            self.post(&disturbances.graphite_url, &body)?;
+        }
+        if !disturbances.webhook_url.is_empty() {
+            let body = serde_json::to_vec(&webhook_event(config, disturbance))?;
+            self.post(&disturbances.webhook_url, &body)?;
+        }
+        if let Some(publisher) = self.publisher.as_mut().filter(|_| disturbances.mqtt) {
+            let topic = format!("{}/disturbance", base_topic(config));
+            let body = serde_json::to_vec(&webhook_event(config, disturbance))?;
+            publisher.publish(&topic, &body, config.mqtt.qos, false)?;
+        }
+        Ok(())
+    }
+
+    fn post(&mut self, url: &str, body: &[u8]) -> Result<()> {
+        match self.client.post(url, &[("Content-Type", "application/json")], body)? {
+            200..=299 => Ok(()),
+            status => Err(Error::failed(Phase::Upload, format!("{} answered {}", url, status))),
+        }
+    }
+}
+
+impl Reporter for DisturbanceReporter<'_> {
+    fn report(&mut self, config: &Config) -> Result<()> {
+        loop {
+            // Not holding the lock while sending, the interrupt threads keep recording
+            let next = self.log.lock().expect("Disturbance log lock poisoned").dequeue();
+            let Some(disturbance) = next else {
+                return Ok(());
+            };
+            if let Err(err) = self.send(config, &disturbance) {
+                let mut log = self.log.lock().expect("Disturbance log lock poisoned");
+                let newer: Vec<Disturbance> = log.drain().collect();
+                // The oldest would be overwritten first if the log is full
+                for disturbance in std::iter::once(disturbance).chain(newer) {
+                    log.push(disturbance);
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+fn graphite_event(config: &Config, disturbance: &Disturbance) -> Value {
+    json!({
+        "what": format!("{} disturbance", disturbance.kind),
+        "tags": ["sleep_thing", "disturbance", disturbance.kind, config.device_id()],
+        "when": disturbance.timestamp_ms / 1000,
+        "data": disturbance.value.to_string(),
+    })
+}
+
+fn webhook_event(config: &Config, disturbance: &Disturbance) -> Value {
+    json!({
+        "device": config.device_id(),
+        "kind": disturbance.kind,
+        "value": disturbance.value,
+        "timestamp_ms": disturbance.timestamp_ms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockClient {
+        requests: Arc<Mutex<Vec<(String, Value)>>>,
+        status: u16,
+    }
+
+    impl HttpClient for MockClient {
+        fn post(&mut self, url: &str, _headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+            let body = serde_json::from_slice(body).unwrap();
+            self.requests.lock().unwrap().push((url.to_string(), body));
+            Ok(self.status)
+        }
+    }
+
+    fn round(values: &[(&'static str, f32)]) -> Vec<Measurement> {
+        values
+            .iter()
+            .map(|(name, value)| Measurement {
+                name: (*name).into(),
+                value: *value,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_rises_and_keeps_them_until_sent() {
+        let mut config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        config.disturbances.graphite_url = "http://graphite.local/events/".to_string();
+        let log = new_log();
+        let mut detector = DisturbanceDetector::new(&config, log.clone());
+        detector.detect(&round(&[("lux", 0.5), ("co2", 900.0), ("presence_moving", 0.0)]), 1_000);
+        detector.detect(&round(&[("lux", 120.0), ("co2", 1500.0), ("presence_moving", 0.0)]), 2_000);
+        // Still on, not a new disturbance
+        detector.detect(&round(&[("lux", 110.0), ("co2", 1500.0), ("presence_moving", 1.0)]), 3_000);
+        let kinds: Vec<_> = log.lock().unwrap().iter().map(|disturbance| disturbance.kind).collect();
+        assert_eq!(kinds, ["light", "co2", "motion"]);
+
+        let client = MockClient {
+            status: 503,
+            ..MockClient::default()
+        };
+        let mut reporter = DisturbanceReporter::new(Box::new(client), None, log.clone());
+        assert!(reporter.report(&config).is_err());
+        assert_eq!(log.lock().unwrap().len(), 3);
+        assert_eq!(log.lock().unwrap().front().unwrap().kind, "light");
+
+        let client = MockClient {
+            status: 200,
+            ..MockClient::default()
+        };
+        let mut reporter = DisturbanceReporter::new(Box::new(client.clone()), None, log.clone());
+        reporter.report(&config).unwrap();
+        assert!(log.lock().unwrap().is_empty());
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].1["when"], 2);
+        assert_eq!(requests[0].1["tags"][2], "light");
+        assert_eq!(requests[0].1["tags"][3], "bedroom");
+    }
+}
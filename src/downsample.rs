@@ -0,0 +1,94 @@
+//! Averages for the uplinks set to get fewer rounds, e.g. a cloud sink on a metered link sent
+//! 15-minute averages while the local Graphite gets every round. The windows are aligned to the
+//! clock, an average goes out stamped with the start of its window once a round from a later
+//! window comes in. A window still open at a reboot is lost to that uplink.
+
+use std::borrow::Cow;
+
+use crate::compact::CompactBatch;
+use crate::sensors::Measurement;
+
+/// Averages the rounds of one buffer for one uplink.
+#[derive(Debug, Default)]
+pub struct Downsampler {
+    window_start_ms: u64,
+    /// Sum and count of the values that aren't NaN, in the order the metrics came in
+    sums: Vec<(Cow<'static, str>, f64, u32)>,
+    /// The average of the last window completed, until the uplink has it
+    pub ready: Option<CompactBatch>,
+}
+
+impl Downsampler {
+    /// Adds a round to the window it falls in, the window so far is completed if that's a later
+    /// one. `ready` has to be taken before.
+    pub fn push(&mut self, window_ms: u64, batch: &CompactBatch) {
+        let window_start_ms = batch.timestamp_ms - batch.timestamp_ms % window_ms.max(1);
+        if window_start_ms != self.window_start_ms {
+            self.finish();
+            self.window_start_ms = window_start_ms;
+        }
+        for measurement in batch.decode() {
+            let index = match self.sums.iter().position(|(name, _, _)| *name == measurement.name) {
+                Some(index) => index,
+                None => {
+                    self.sums.push((measurement.name, 0.0, 0));
+                    self.sums.len() - 1
+                }
+            };
+            if !measurement.value.is_nan() {
+                let (_, sum, count) = &mut self.sums[index];
+                *sum += measurement.value as f64;
+                *count += 1;
+            }
+        }
+    }
+
+    /// Completes the window so far, true if there was one.
+    pub fn finish(&mut self) -> bool {
+        if self.sums.is_empty() {
+            return false;
+        }
+        let averages: Vec<Measurement> = self
+            .sums
+            .drain(..)
+            .map(|(name, sum, count)| Measurement {
+                name,
+                value: if count == 0 { f32::NAN } else { (sum / count as f64) as f32 },
+            })
+            .collect();
+        self.ready = Some(CompactBatch::encode(self.window_start_ms, &averages));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(timestamp_ms: u64, co2: f32, lux: f32) -> CompactBatch {
+        let measurements = [("co2", co2), ("lux", lux)].map(|(name, value)| Measurement {
+            name: name.into(),
+            value,
+        });
+        CompactBatch::encode(timestamp_ms, &measurements)
+    }
+
+    #[test]
+    fn averages_the_rounds_of_each_window() {
+        let window_ms = 15 * 60_000;
+        let mut downsampler = Downsampler::default();
+        downsampler.push(window_ms, &round(1_000, 800.0, 1.0));
+        downsampler.push(window_ms, &round(301_000, 900.0, f32::NAN));
+        assert!(downsampler.ready.is_none());
+
+        downsampler.push(window_ms, &round(901_000, 1000.0, 3.0));
+        let average = downsampler.ready.take().unwrap();
+        assert_eq!(average.timestamp_ms, 0);
+        let values: Vec<f32> = average.decode().iter().map(|measurement| measurement.value).collect();
+        assert_eq!(values, [850.0, 1.0]);
+
+        assert!(downsampler.finish());
+        assert_eq!(downsampler.ready.take().unwrap().timestamp_ms, window_ms);
+        assert!(!downsampler.finish());
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt;
+use std::io;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::EspError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What the firmware was doing when something failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Boot,
+    Storage,
+    Config,
+    SensorInit,
+    Measure,
+    Connect,
+    TimeSync,
+    Upload,
+    Archive,
+    Console,
+    SelfTest,
+    Output,
+}
+
+impl Phase {
+    /// Short identifier, usable as a log tag or a metric name component.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Boot => "boot",
+            Phase::Storage => "storage",
+            Phase::Config => "config",
+            Phase::SensorInit => "sensor_init",
+            Phase::Measure => "measure",
+            Phase::Connect => "connect",
+            Phase::TimeSync => "time_sync",
+            Phase::Upload => "upload",
+            Phase::Archive => "archive",
+            Phase::Console => "console",
+            Phase::SelfTest => "selftest",
+            Phase::Output => "output",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Driver errors are generic over the bus, so they're kept as their debug representation
+    #[error("{sensor} ({phase}): {message}")]
+    Sensor {
+        sensor: &'static str,
+        phase: Phase,
+        message: String,
+    },
+    #[error("{phase}: {context}: {source}")]
+    Io {
+        phase: Phase,
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    #[cfg(target_os = "espidf")]
+    #[error("{phase}: {context}: {source}")]
+    Esp {
+        phase: Phase,
+        context: String,
+        #[source]
+        source: EspError,
+    },
+    #[error("Unknown configuration key '{0}'")]
+    UnknownKey(String),
+    #[error("Invalid value for '{key}': {source}")]
+    InvalidValue {
+        key: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Unknown profile '{0}'")]
+    UnknownProfile(String),
+    #[error("config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{phase}: {message}")]
+    Failed { phase: Phase, message: String },
+    /// The other end turned the data down for good, e.g. with an HTTP 400 or for a bad API key
+    #[error("{phase}: rejected: {message}")]
+    Rejected { phase: Phase, message: String },
+}
+
+impl Error {
+    pub fn sensor(sensor: &'static str, phase: Phase, message: impl Into<String>) -> Self {
+        Error::Sensor {
+            sensor,
+            phase,
+            message: message.into(),
+        }
+    }
+
+    pub fn failed(phase: Phase, message: impl Into<String>) -> Self {
+        Error::Failed {
+            phase,
+            message: message.into(),
+        }
+    }
+
+    pub fn rejected(phase: Phase, message: impl Into<String>) -> Self {
+        Error::Rejected {
+            phase,
+            message: message.into(),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        match self {
+            Error::Sensor { phase, .. }
+            | Error::Io { phase, .. }
+            | Error::Failed { phase, .. }
+            | Error::Rejected { phase, .. } => *phase,
+            #[cfg(target_os = "espidf")]
+            Error::Esp { phase, .. } => *phase,
+            Error::UnknownKey(_) | Error::InvalidValue { .. } | Error::UnknownProfile(_) | Error::Json(_) => {
+                Phase::Config
+            }
+        }
+    }
+
+    /// Whether trying again later has a chance of succeeding, e.g. a server that's down, as
+    /// opposed to a broken configuration or a sensor that isn't there.
+    pub fn is_transient(&self) -> bool {
+        !self.is_rejected()
+            && matches!(
+                self.phase(),
+                Phase::Measure | Phase::Connect | Phase::TimeSync | Phase::Upload | Phase::Archive
+            )
+    }
+
+    /// Whether the data itself was turned down, sending it again won't help.
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, Error::Rejected { .. })
+    }
+}
+
+/// `map_err` adapter for driver errors while setting up a sensor.
+pub fn sensor_init<E: fmt::Debug>(sensor: &'static str, message: &'static str) -> impl FnOnce(E) -> Error {
+    move |err| Error::sensor(sensor, Phase::SensorInit, format!("{}: {:?}", message, err))
+}
+
+/// Attaches the phase and a short description to I/O and ESP-IDF errors.
+pub trait Context<T> {
+    fn context(self, phase: Phase, context: &str) -> Result<T>;
+    fn with_context(self, phase: Phase, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, io::Error> {
+    fn context(self, phase: Phase, context: &str) -> Result<T> {
+        self.with_context(phase, || context.to_string())
+    }
+
+    fn with_context(self, phase: Phase, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            phase,
+            context: context(),
+            source,
+        })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl<T> Context<T> for std::result::Result<T, EspError> {
+    fn context(self, phase: Phase, context: &str) -> Result<T> {
+        self.with_context(phase, || context.to_string())
+    }
+
+    fn with_context(self, phase: Phase, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Esp {
+            phase,
+            context: context(),
+            source,
+        })
+    }
+}
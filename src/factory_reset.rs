@@ -0,0 +1,22 @@
+//! Factory reset, for handing a node on: the configuration file (with the Wi-Fi credentials and
+//! API keys), NVS (calibration, profile, certificate, the Wi-Fi driver's own copy) and everything
+//! else on the storage partition go. The SD card is left alone, it's easy enough to take out.
+
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::sys::{esp, nvs_flash_erase};
+use log::{error, warn};
+
+use crate::config;
+
+/// Wipes everything and reboots, the node comes back with the built-in defaults.
+pub fn run() -> ! {
+    warn!("Factory reset, erasing the storage partition and NVS");
+    if let Err(err) = config::format_storage() {
+        error!("{}", err);
+    }
+    // Closes the open handles first
+    if let Err(err) = esp!(unsafe { nvs_flash_erase() }) {
+        error!("Failed to erase NVS: {:?}", err);
+    }
+    restart();
+}
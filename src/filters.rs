@@ -0,0 +1,91 @@
+mod average;
+mod derived;
+mod occupancy;
+mod outlier;
+mod sleep_score;
+
+use std::collections::{HashMap, VecDeque};
+
+pub use average::Average;
+pub use derived::Derived;
+pub use occupancy::Occupancy;
+pub use outlier::Outlier;
+pub use sleep_score::SleepScore;
+
+use crate::config::{Config, FilterConfig};
+use crate::error::Result;
+use crate::pipeline::Filter;
+
+pub fn from_config(filter: &FilterConfig, config: &Config) -> Result<Box<dyn Filter>> {
+    Ok(match filter {
+        FilterConfig::Outlier {
+            metrics,
+            window,
+            threshold,
+        } => Box::new(Outlier::new(metrics.clone(), *window, *threshold)),
+        FilterConfig::Average { metrics, window } => Box::new(Average::new(metrics.clone(), *window)),
+        FilterConfig::Derived { metrics } => Box::new(Derived::new(metrics.clone())?),
+        FilterConfig::SleepScore { bedtime, morning } => {
+            Box::new(SleepScore::new(bedtime, morning, config.utc_offset_min)?)
+        }
+        FilterConfig::Occupancy {
+            sources,
+            threshold,
+            hold_sec,
+        } => Box::new(Occupancy::new(sources.clone(), *threshold, *hold_sec)),
+    })
+}
+
+/// Last values per metric for the filters working on a sliding window.
+struct History {
+    metrics: Vec<String>,
+    window: usize,
+    values: HashMap<String, VecDeque<f32>>,
+}
+
+impl History {
+    fn new(metrics: Vec<String>, window: usize) -> Self {
+        History {
+            metrics,
+            window: window.max(1),
+            values: HashMap::new(),
+        }
+    }
+
+    fn applies_to(&self, name: &str) -> bool {
+        self.metrics.is_empty() || self.metrics.iter().any(|metric| metric == name)
+    }
+
+    fn get(&self, name: &str) -> Option<&VecDeque<f32>> {
+        self.values.get(name)
+    }
+
+    /// Adds a value, returns the window including it.
+    fn push(&mut self, name: &str, value: f32) -> &VecDeque<f32> {
+        let values = self.values.entry(name.to_string()).or_default();
+        if values.len() == self.window {
+            values.pop_front();
+        }
+        values.push_back(value);
+        values
+    }
+}
+
+pub fn median(values: impl IntoIterator<Item = f32>) -> f32 {
+    let mut values: Vec<f32> = values.into_iter().collect();
+    values.sort_by(f32::total_cmp);
+    // Both indices point at the same element for odd lengths
+    (values[(values.len() - 1) / 2] + values[values.len() / 2]) / 2.0
+}
+
+/// Piecewise linear interpolation between `(x, y)` points sorted by x, flat outside of them.
+fn interpolate(points: &[(f32, f32)], x: f32) -> f32 {
+    match points.iter().position(|(anchor, _)| x < *anchor) {
+        Some(0) => points[0].1,
+        Some(i) => {
+            let ((x0, y0), (x1, y1)) = (points[i - 1], points[i]);
+            y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+        }
+        None => points[points.len() - 1].1,
+    }
+}
@@ -0,0 +1,29 @@
+use super::History;
+use crate::pipeline::Filter;
+use crate::sensors::Measurement;
+
+/// Moving average over the last readings, smooths out sensor noise at the cost of some lag.
+pub struct Average {
+    history: History,
+}
+
+impl Average {
+    pub fn new(metrics: Vec<String>, window: usize) -> Self {
+        Average {
+            history: History::new(metrics, window),
+        }
+    }
+}
+
+impl Filter for Average {
+    fn apply(&mut self, mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+        for measurement in &mut measurements {
+            if !self.history.applies_to(&measurement.name) || !measurement.value.is_finite() {
+                continue;
+            }
+            let values = self.history.push(&measurement.name, measurement.value);
+            measurement.value = values.iter().sum::<f32>() / values.len() as f32;
+        }
+        measurements
+    }
+}
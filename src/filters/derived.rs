@@ -0,0 +1,134 @@
+use super::interpolate;
+use crate::climate::{absolute_humidity, dew_point, heat_index, humidex};
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::Filter;
+use crate::sensors::Measurement;
+
+const KNOWN_METRICS: &[&str] = &[
+    "dew_point",
+    "absolute_humidity",
+    "heat_index",
+    "humidex",
+    "melanopic_edi",
+    "iaq",
+];
+
+// Melanopic daylight efficacy ratio of typical light sources, by the TSL2591 visible/IR ratio:
+// halogen and incandescent bulbs, daylight (1.0 by definition of D65) and warm to neutral LEDs
+const MELANOPIC_RATIOS: &[(f32, f32)] = &[(0.8, 0.54), (2.5, 1.0), (8.0, 0.6)];
+
+// IAQ sub-scores from 100 (good) to 0 (bad), interpolated between the points. CO2 after the usual
+// ventilation guidance (1000 ppm is the classic limit), humidity around the 40-60 % comfort band,
+// TVOC after the German UBA levels and PM2.5 after the WHO 24 h guideline and interim targets.
+const IAQ_SCORES: &[(&str, &[(f32, f32)])] = &[
+    ("co2", &[(600.0, 100.0), (1000.0, 75.0), (1500.0, 40.0), (2000.0, 0.0)]),
+    ("humidity", &[(10.0, 0.0), (30.0, 70.0), (40.0, 100.0), (60.0, 100.0), (70.0, 70.0), (90.0, 0.0)]),
+    ("tvoc", &[(300.0, 100.0), (1000.0, 60.0), (3000.0, 0.0)]),
+    ("pm2_5", &[(15.0, 100.0), (25.0, 70.0), (50.0, 30.0), (75.0, 0.0)]),
+];
+
+/// Adds metrics computed from the ones measured in the same round.
+pub struct Derived {
+    metrics: Vec<String>,
+}
+
+impl Derived {
+    pub fn new(metrics: Vec<String>) -> Result<Self> {
+        if let Some(unknown) = metrics.iter().find(|metric| !KNOWN_METRICS.contains(&metric.as_str())) {
+            return Err(Error::failed(
+                Phase::Config,
+                format!("Unknown derived metric '{}', known: {}", unknown, KNOWN_METRICS.join(", ")),
+            ));
+        }
+        Ok(Derived { metrics })
+    }
+}
+
+impl Filter for Derived {
+    fn apply(&mut self, mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+        let find = |name: &str| {
+            measurements
+                .iter()
+                .find(|measurement| measurement.name == name)
+                .map(|measurement| measurement.value)
+        };
+        let temperature = find("temperature");
+        let humidity = find("humidity");
+        let lux = find("lux");
+        let visible_ir_ratio = find("light_visible_ir_ratio");
+        let air_quality = air_quality_index(&measurements);
+        for metric in &self.metrics {
+            let value = match (metric.as_str(), temperature, humidity) {
+                ("dew_point", Some(temperature), Some(humidity)) => dew_point(temperature, humidity),
+                ("absolute_humidity", Some(temperature), Some(humidity)) => absolute_humidity(temperature, humidity),
+                ("heat_index", Some(temperature), Some(humidity)) => heat_index(temperature, humidity),
+                ("humidex", Some(temperature), Some(humidity)) => humidex(temperature, humidity),
+                ("melanopic_edi", _, _) => match (lux, visible_ir_ratio) {
+                    (Some(lux), Some(ratio)) => melanopic_edi(lux, ratio),
+                    _ => continue,
+                },
+                ("iaq", _, _) => match air_quality {
+                    Some(score) => score,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            measurements.push(Measurement {
+                name: metric.clone().into(),
+                value,
+            });
+        }
+        measurements
+    }
+}
+
+/// Rough melanopic equivalent daylight illuminance in lux. The TSL2591 only tells apart how much
+/// IR a source emits, which is enough to separate daylight from bulbs and LEDs but not warm from
+/// cool LEDs, so treat it as a trend rather than an absolute value.
+pub fn melanopic_edi(lux: f32, visible_ir_ratio: f32) -> f32 {
+    lux * interpolate(MELANOPIC_RATIOS, visible_ir_ratio)
+}
+
+/// Indoor air quality from 100 (good) to 0 (bad): the worst sub-score of CO2, humidity, TVOC and
+/// PM2.5, whichever are measured, so one bad factor can't be averaged away. `None` without any.
+pub fn air_quality_index(measurements: &[Measurement]) -> Option<f32> {
+    IAQ_SCORES
+        .iter()
+        .filter_map(|(name, points)| {
+            measurements
+                .iter()
+                .find(|measurement| measurement.name == *name)
+                .map(|measurement| interpolate(points, measurement.value))
+        })
+        .reduce(f32::min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighs_light_by_source() {
+        assert!((melanopic_edi(100.0, 0.5) - 54.0).abs() < 0.01);
+        assert!((melanopic_edi(100.0, 2.5) - 100.0).abs() < 0.01);
+        assert!((melanopic_edi(100.0, 20.0) - 60.0).abs() < 0.01);
+        assert!((melanopic_edi(100.0, 1.65) - 77.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn air_quality_follows_the_worst_factor() {
+        let measurement = |name: &str, value| Measurement {
+            name: name.to_string().into(),
+            value,
+        };
+        assert_eq!(air_quality_index(&[]), None);
+        assert_eq!(air_quality_index(&[measurement("co2", 500.0), measurement("humidity", 50.0)]), Some(100.0));
+        assert_eq!(air_quality_index(&[measurement("co2", 1000.0), measurement("humidity", 50.0)]), Some(75.0));
+        assert_eq!(air_quality_index(&[measurement("co2", 500.0), measurement("humidity", 20.0)]), Some(35.0));
+    }
+
+    #[test]
+    fn rejects_unknown_metrics() {
+        assert!(Derived::new(vec!["wind_chill".to_string()]).is_err());
+    }
+}
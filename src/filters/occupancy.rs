@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use crate::config::{OccupancyArea, OccupancySource};
+use crate::pipeline::{now, Filter};
+use crate::sensors::Measurement;
+
+/// Fuses presence readings (radar, PIR, load cell, accelerometer...) into debounced
+/// `bed_occupied` and `room_occupied` metrics, 1 or 0, added to every round. An area becomes
+/// occupied as soon as the weighted share of its sources reporting presence reaches `threshold`,
+/// and only counts as empty again after staying below it for `hold_sec`. An occupied bed implies
+/// an occupied room.
+pub struct Occupancy {
+    sources: Vec<OccupancySource>,
+    threshold: f32,
+    hold_sec: u64,
+    // Last state of every source, they don't necessarily report every round
+    present: HashMap<String, bool>,
+    bed: Debounce,
+    room: Debounce,
+}
+
+#[derive(Default)]
+struct Debounce {
+    occupied: bool,
+    last_seen: u64,
+}
+
+impl Debounce {
+    fn update(&mut self, detected: bool, timestamp: u64, hold_sec: u64) -> bool {
+        if detected {
+            self.occupied = true;
+            self.last_seen = timestamp;
+        } else if self.occupied && timestamp.saturating_sub(self.last_seen) >= hold_sec {
+            self.occupied = false;
+        }
+        self.occupied
+    }
+}
+
+impl Occupancy {
+    pub fn new(sources: Vec<OccupancySource>, threshold: f32, hold_sec: u64) -> Self {
+        Occupancy {
+            sources,
+            threshold,
+            hold_sec,
+            present: HashMap::new(),
+            bed: Debounce::default(),
+            room: Debounce::default(),
+        }
+    }
+
+    fn score(&self, area: OccupancyArea) -> Option<f32> {
+        let (mut total, mut present) = (0.0, 0.0);
+        for source in self.sources.iter().filter(|source| source.area == area) {
+            if let Some(&is_present) = self.present.get(&source.metric) {
+                total += source.weight;
+                if is_present {
+                    present += source.weight;
+                }
+            }
+        }
+        (total > 0.0).then(|| present / total)
+    }
+
+    fn apply_at(&mut self, mut measurements: Vec<Measurement>, timestamp: u64) -> Vec<Measurement> {
+        for source in &self.sources {
+            if let Some(measurement) = measurements.iter().find(|measurement| measurement.name == source.metric) {
+                self.present.insert(source.metric.clone(), measurement.value > source.above);
+            }
+        }
+        let detected = |score: Option<f32>| score.is_some_and(|score| score >= self.threshold);
+        let bed_detected = detected(self.score(OccupancyArea::Bed));
+        let room_detected = detected(self.score(OccupancyArea::Room));
+
+        let bed = self.bed.update(bed_detected, timestamp, self.hold_sec);
+        let room = self.room.update(room_detected || bed_detected, timestamp, self.hold_sec) || bed;
+        measurements.push(Measurement {
+            name: "bed_occupied".into(),
+            value: if bed { 1.0 } else { 0.0 },
+        });
+        measurements.push(Measurement {
+            name: "room_occupied".into(),
+            value: if room { 1.0 } else { 0.0 },
+        });
+        measurements
+    }
+}
+
+impl Filter for Occupancy {
+    fn apply(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement> {
+        self.apply_at(measurements, now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(metric: &str, weight: f32, area: OccupancyArea) -> OccupancySource {
+        OccupancySource {
+            metric: metric.to_string(),
+            weight,
+            above: 0.5,
+            area,
+        }
+    }
+
+    fn reading(values: &[(&str, f32)]) -> Vec<Measurement> {
+        values
+            .iter()
+            .map(|(name, value)| Measurement {
+                name: name.to_string().into(),
+                value: *value,
+            })
+            .collect()
+    }
+
+    fn occupied(measurements: &[Measurement]) -> (f32, f32) {
+        let value = |name: &str| measurements.iter().find(|m| m.name == name).unwrap().value;
+        (value("bed_occupied"), value("room_occupied"))
+    }
+
+    #[test]
+    fn fuses_sources_and_holds_the_state() {
+        let mut occupancy = Occupancy::new(
+            vec![
+                source("bed_load", 2.0, OccupancyArea::Bed),
+                source("bed_motion", 1.0, OccupancyArea::Bed),
+                source("pir", 1.0, OccupancyArea::Room),
+            ],
+            0.5,
+            300,
+        );
+        // The motion sensor alone isn't enough for the bed, the PIR is for the room
+        let result = occupancy.apply_at(reading(&[("bed_load", 0.0), ("bed_motion", 1.0), ("pir", 1.0)]), 0);
+        assert_eq!(occupied(&result), (0.0, 1.0));
+
+        let result = occupancy.apply_at(reading(&[("bed_load", 1.0), ("pir", 0.0)]), 60);
+        assert_eq!(occupied(&result), (1.0, 1.0));
+
+        // Getting up for a moment doesn't end the night
+        let result = occupancy.apply_at(reading(&[("bed_load", 0.0), ("bed_motion", 0.0)]), 120);
+        assert_eq!(occupied(&result), (1.0, 1.0));
+        let result = occupancy.apply_at(reading(&[]), 360);
+        assert_eq!(occupied(&result), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,77 @@
+use log::warn;
+
+use super::{median, History};
+use crate::pipeline::Filter;
+use crate::sensors::Measurement;
+
+/// Drops single readings far away from the recent ones, e.g. the occasional bogus CO2 spike.
+/// Rejected values still go into the history, so a real step change passes after a few rounds.
+pub struct Outlier {
+    history: History,
+    threshold: f32,
+}
+
+impl Outlier {
+    pub fn new(metrics: Vec<String>, window: usize, threshold: f32) -> Self {
+        Outlier {
+            history: History::new(metrics, window),
+            threshold,
+        }
+    }
+
+    fn accept(&mut self, measurement: &Measurement) -> bool {
+        if !self.history.applies_to(&measurement.name) {
+            return true;
+        }
+        let outlier = match self.history.get(&measurement.name) {
+            // Not enough history to tell yet
+            Some(values) if values.len() >= 3 => {
+                let center = median(values.iter().copied());
+                let deviation = median(values.iter().map(|value| (value - center).abs()));
+                // A perfectly flat history has no spread to compare against
+                deviation > 0.0 && (measurement.value - center).abs() > self.threshold * deviation
+            }
+            _ => false,
+        };
+        self.history.push(&measurement.name, measurement.value);
+        if outlier {
+            warn!("Dropping outlier {} = {}", measurement.name, measurement.value);
+        }
+        !outlier
+    }
+}
+
+impl Filter for Outlier {
+    fn apply(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement> {
+        measurements
+            .into_iter()
+            .filter(|measurement| self.accept(measurement))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn co2(value: f32) -> Vec<Measurement> {
+        vec![Measurement {
+            name: "co2".into(),
+            value,
+        }]
+    }
+
+    #[test]
+    fn drops_spikes_but_follows_step_changes() {
+        let mut filter = Outlier::new(vec![], 5, 5.0);
+        for value in [500.0, 505.0, 498.0, 502.0] {
+            assert_eq!(filter.apply(co2(value)).len(), 1);
+        }
+        assert!(filter.apply(co2(5000.0)).is_empty());
+        assert_eq!(filter.apply(co2(503.0)).len(), 1);
+
+        // The window of 5 is dominated by the new level after three readings
+        let passed: Vec<usize> = (0..4).map(|_| filter.apply(co2(900.0)).len()).collect();
+        assert_eq!(passed.last(), Some(&1));
+    }
+}
@@ -0,0 +1,171 @@
+use super::interpolate;
+use crate::clock::{in_window, parse_time_of_day, seconds_of_day};
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{now, Filter};
+use crate::sensors::Measurement;
+
+// Sub-scores from 100 (good) to 0 (bad): average CO2 overnight, average bedroom temperature
+// (16-20 °C is the usual recommendation), how much it swings, average light and noise events
+const CO2_SCORE: &[(f32, f32)] = &[(800.0, 100.0), (1200.0, 60.0), (2000.0, 0.0)];
+const TEMPERATURE_SCORE: &[(f32, f32)] = &[(12.0, 0.0), (16.0, 100.0), (20.0, 100.0), (26.0, 0.0)];
+const TEMPERATURE_STDDEV_SCORE: &[(f32, f32)] = &[(0.5, 100.0), (1.5, 50.0), (3.0, 0.0)];
+const LUX_SCORE: &[(f32, f32)] = &[(1.0, 100.0), (10.0, 60.0), (50.0, 0.0)];
+const NOISE_EVENTS_SCORE: &[(f32, f32)] = &[(0.0, 100.0), (10.0, 50.0), (30.0, 0.0)];
+
+/// Collects the night between `bedtime` and `morning` and adds a summary to the first round after
+/// `morning`, as `sleep_score.*` metrics. The `sleep_score.score` composite is the mean of the
+/// sub-scores of whatever was measured.
+pub struct SleepScore {
+    bedtime: u32,
+    morning: u32,
+    utc_offset_min: i32,
+    night: Night,
+}
+
+#[derive(Default)]
+struct Night {
+    co2: Stats,
+    temperature: Stats,
+    lux: Stats,
+    noise_events: Option<f32>,
+}
+
+#[derive(Default)]
+struct Stats {
+    count: usize,
+    sum: f32,
+    sum_of_squares: f32,
+    max: f32,
+}
+
+impl Stats {
+    fn push(&mut self, value: f32) {
+        self.max = if self.count == 0 { value } else { self.max.max(value) };
+        self.count += 1;
+        self.sum += value;
+        self.sum_of_squares += value * value;
+    }
+
+    fn mean(&self) -> Option<f32> {
+        (self.count > 0).then(|| self.sum / self.count as f32)
+    }
+
+    fn stddev(&self) -> Option<f32> {
+        let mean = self.mean()?;
+        Some((self.sum_of_squares / self.count as f32 - mean * mean).max(0.0).sqrt())
+    }
+}
+
+impl SleepScore {
+    pub fn new(bedtime: &str, morning: &str, utc_offset_min: i32) -> Result<Self> {
+        let parse = |value: &str| {
+            parse_time_of_day(value).ok_or_else(|| {
+                Error::failed(Phase::Config, format!("Invalid time of day '{}', expected HH:MM", value))
+            })
+        };
+        Ok(SleepScore {
+            bedtime: parse(bedtime)?,
+            morning: parse(morning)?,
+            utc_offset_min,
+            night: Night::default(),
+        })
+    }
+
+    fn apply_at(&mut self, mut measurements: Vec<Measurement>, timestamp: u64) -> Vec<Measurement> {
+        let time = seconds_of_day(timestamp, self.utc_offset_min);
+        if in_window(time, self.bedtime, self.morning) {
+            for measurement in &measurements {
+                match measurement.name.as_ref() {
+                    "co2" => self.night.co2.push(measurement.value),
+                    "temperature" => self.night.temperature.push(measurement.value),
+                    "lux" => self.night.lux.push(measurement.value),
+                    "noise_events" => *self.night.noise_events.get_or_insert(0.0) += measurement.value,
+                    _ => {}
+                }
+            }
+        } else if self.night.co2.count + self.night.temperature.count + self.night.lux.count > 0 {
+            measurements.extend(std::mem::take(&mut self.night).summary());
+        }
+        measurements
+    }
+}
+
+impl Night {
+    fn summary(&self) -> Vec<Measurement> {
+        let mut values = Vec::new();
+        let mut scores = Vec::new();
+        if let Some(mean) = self.co2.mean() {
+            values.extend([("co2_avg", mean), ("co2_max", self.co2.max)]);
+            scores.push(interpolate(CO2_SCORE, mean));
+        }
+        if let (Some(mean), Some(stddev)) = (self.temperature.mean(), self.temperature.stddev()) {
+            values.extend([("temperature_avg", mean), ("temperature_stddev", stddev)]);
+            scores.push(interpolate(TEMPERATURE_SCORE, mean));
+            scores.push(interpolate(TEMPERATURE_STDDEV_SCORE, stddev));
+        }
+        if let Some(mean) = self.lux.mean() {
+            values.extend([("light_avg", mean), ("light_max", self.lux.max)]);
+            scores.push(interpolate(LUX_SCORE, mean));
+        }
+        if let Some(events) = self.noise_events {
+            values.push(("noise_events", events));
+            scores.push(interpolate(NOISE_EVENTS_SCORE, events));
+        }
+        values.push(("score", scores.iter().sum::<f32>() / scores.len() as f32));
+        values
+            .into_iter()
+            .map(|(name, value)| Measurement {
+                name: format!("sleep_score.{}", name).into(),
+                value,
+            })
+            .collect()
+    }
+}
+
+impl Filter for SleepScore {
+    fn apply(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement> {
+        self.apply_at(measurements, now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 00:00 UTC
+    const MIDNIGHT: u64 = 1_704_067_200;
+
+    fn round(co2: f32, temperature: f32) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "co2".into(),
+                value: co2,
+            },
+            Measurement {
+                name: "temperature".into(),
+                value: temperature,
+            },
+        ]
+    }
+
+    #[test]
+    fn summarizes_the_night_once_in_the_morning() {
+        let mut score = SleepScore::new("22:00", "07:00", 0).unwrap();
+        assert_eq!(score.apply_at(round(700.0, 18.0), MIDNIGHT - 3600).len(), 2);
+        assert_eq!(score.apply_at(round(900.0, 18.0), MIDNIGHT + 3 * 3600).len(), 2);
+
+        let morning = score.apply_at(round(600.0, 19.0), MIDNIGHT + 7 * 3600 + 300);
+        let value = |name: &str| morning.iter().find(|m| m.name == name).map(|m| m.value);
+        assert_eq!(value("sleep_score.co2_avg"), Some(800.0));
+        assert_eq!(value("sleep_score.co2_max"), Some(900.0));
+        assert_eq!(value("sleep_score.temperature_stddev"), Some(0.0));
+        assert_eq!(value("sleep_score.score"), Some(100.0));
+
+        assert_eq!(score.apply_at(round(600.0, 19.0), MIDNIGHT + 8 * 3600).len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        assert!(SleepScore::new("22:00", "7am", 0).is_err());
+    }
+}
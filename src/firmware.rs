@@ -0,0 +1,842 @@
+#[cfg(feature = "lorawan")]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
+use esp_idf_svc::hal::prelude::Peripherals;
+#[cfg(any(feature = "epaper", feature = "lora"))]
+use esp_idf_svc::hal::delay::Delay;
+#[cfg(any(feature = "ir", feature = "epaper", feature = "cellular", feature = "lora"))]
+use esp_idf_svc::hal::gpio::AnyOutputPin;
+#[cfg(feature = "epaper")]
+use esp_idf_svc::hal::gpio::Input;
+#[cfg(any(feature = "epaper", feature = "cellular"))]
+use esp_idf_svc::hal::gpio::Output;
+#[cfg(any(feature = "epaper", feature = "cellular", feature = "lora"))]
+use esp_idf_svc::hal::gpio::PinDriver;
+#[cfg(any(feature = "sdcard", feature = "epaper", feature = "bme280", feature = "lora"))]
+use esp_idf_svc::hal::spi::{config::DriverConfig as SpiDriverConfig, Dma, SpiDriver};
+#[cfg(any(feature = "epaper", feature = "bme280", feature = "lora"))]
+use esp_idf_svc::hal::spi::{config::Config as SpiConfig, SpiDeviceDriver};
+#[cfg(any(feature = "ld2410", feature = "cellular"))]
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver};
+#[cfg(all(feature = "cellular", not(esp32s3)))]
+use esp_idf_svc::hal::uart::UART1 as CellularUart;
+// The S3 has a UART to spare, the LD2410 keeps UART1
+#[cfg(all(feature = "cellular", esp32s3))]
+use esp_idf_svc::hal::uart::UART2 as CellularUart;
+use esp_idf_svc::hal::units::FromValueType;
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::timer::EspTaskTimerService;
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, trace, warn, LevelFilter};
+
+use crate::board::{self, Board};
+use crate::calibration;
+#[cfg(feature = "cellular")]
+use crate::cellular::{SharedSignal, SignalMetrics};
+use crate::climate_control::SharedOverrides;
+#[cfg(feature = "ir")]
+use crate::climate_control::ClimateControl;
+use crate::clock::ClockSync;
+#[cfg(feature = "ble_provisioning")]
+use crate::coex::{RadioScheduler, Scheduled};
+#[cfg(feature = "lora")]
+use crate::config::LoraChip;
+use crate::config::{self, Config, SharedConfig, Uplink};
+use crate::consistency::ConsistencyCheck;
+use crate::console;
+use crate::crash_loop;
+use crate::daily::DailySummary;
+use crate::dead_letter::DEAD_LETTER_PATH;
+use crate::diagnostics::{self, SystemMetrics};
+#[cfg(feature = "display")]
+use crate::display::{self, DisplayFeed, SharedReadings};
+#[cfg(feature = "epaper")]
+use crate::display::{Epaper, EpaperSink};
+use crate::disturbances::{self, DisturbanceDetector, DisturbanceReporter};
+use crate::error::{Context, Error, Phase, Result};
+use crate::factory_reset;
+use crate::fleet::{AppliedVersion, FleetClient, VersionMetric};
+use crate::history::{self, HistoryFeed};
+use crate::http::EspHttpClient;
+use crate::identity::Identity;
+use crate::insights::Insights;
+#[cfg(feature = "lora")]
+use crate::lora::{LoraSink, Radio, Sx1262, Sx1276};
+use crate::lora::RadioOnly;
+#[cfg(feature = "lorawan")]
+use crate::lorawan::{LorawanSink, LORAWAN_PATH};
+use crate::modbus::{self, ModbusFeed};
+#[cfg(feature = "ir")]
+use crate::ir::RmtTransmitter;
+#[cfg(feature = "cellular")]
+use crate::modem::CellularNetwork;
+#[cfg(feature = "mqtt")]
+use crate::mqtt;
+#[cfg(feature = "thread")]
+use crate::openthread::ThreadNetwork;
+use crate::pipeline::{Alerts, Network, PipelineBuilder, Sink};
+use crate::profile;
+use crate::provisioning;
+use crate::registry::{SensorFactory, SensorRegistry};
+use crate::reporting::{self, CapturingLogger, ErrorReporter, SharedEvents};
+use crate::shutdown::{self, Shutdown};
+use crate::spool::SPOOL_PATH;
+#[cfg(feature = "sdcard")]
+use crate::sdcard;
+use crate::selftest::{self, Request};
+use crate::sensor_memory;
+#[cfg(feature = "as7341")]
+use crate::sensors::As7341Sensor;
+#[cfg(feature = "bme280")]
+use crate::sensors::Bme280Sensor;
+#[cfg(feature = "ccs811")]
+use crate::sensors::Ccs811Sensor;
+#[cfg(feature = "hdc1080")]
+use crate::sensors::Hdc1080Sensor;
+#[cfg(feature = "sgp30")]
+use crate::sensors::Sgp30Sensor;
+#[cfg(feature = "max44009")]
+use crate::sensors::Max44009Sensor;
+#[cfg(feature = "dht22")]
+use crate::sensors::{Dht22Line, Dht22Sensor};
+#[cfg(feature = "reed_switch")]
+use crate::sensors::ReedSwitchSensor;
+#[cfg(feature = "thermistor")]
+use crate::sensors::ThermistorSensor;
+#[cfg(feature = "analog")]
+use crate::sensors::AnalogSensor;
+#[cfg(feature = "ld2410")]
+use crate::sensors::Ld2410Sensor;
+#[cfg(feature = "snore")]
+use crate::sensors::SnoreSensor;
+use crate::sensors::{I2cBus, I2cSensor};
+#[cfg(feature = "bme280")]
+use crate::sensors::SpiSensor;
+#[cfg(feature = "scd4x")]
+use crate::sensors::Scd4xSensor;
+#[cfg(feature = "tsl2591")]
+use crate::sensors::{LightWatch, Tsl2591Sensor};
+use crate::sinks::{CoapSink, DatadogSink, GraphiteSink};
+use crate::snmp::{self, SnmpFeed};
+#[cfg(feature = "ds3231")]
+use crate::rtc::{self, Ds3231};
+#[cfg(any(feature = "ld2410", feature = "tsl2591", feature = "reed_switch", feature = "lis3dh", feature = "snore"))]
+use crate::tasks::{self, Task};
+use crate::timesync::{self, OnSync};
+use crate::tls;
+#[cfg(feature = "mqtt")]
+use crate::sinks::{MqttSink, Publisher};
+use crate::watchdog;
+use crate::wifi::WifiNetwork;
+
+// Both need UART1, the other one is the console
+#[cfg(all(feature = "ld2410", feature = "cellular", not(esp32s3)))]
+compile_error!("The `ld2410` and `cellular` features can't be enabled together");
+
+fn preamble() -> Result<SharedEvents> {
+    esp_idf_svc::sys::link_patches();
+    let logger = EspLogger::new();
+    logger.initialize();
+    logger.set_target_level("wifi", LevelFilter::Error).context(Phase::Boot, "Failed to set the log level")?;
+    // Errors are captured from the start, they're only sent once the network is up for an upload
+    let events = SharedEvents::default();
+    let logger = Box::leak(Box::new(CapturingLogger::new(logger, events.clone())));
+    log::set_logger(logger).map_err(|_| Error::failed(Phase::Boot, "Logger already set"))?;
+    reporting::install_panic_hook();
+    Ok(events)
+}
+
+/// Brings up the board and runs the measurement loop, never returns unless initialization fails.
+pub fn start() -> Result<()> {
+    let events = preamble()?;
+    // First thing, anything after can be what crashes
+    let boots = crash_loop::boot();
+
+    config::mount_storage()?;
+    let crash = reporting::take_last_panic();
+    if let Some(crash) = &crash {
+        reporting::capture(&events, "fatal", "panic", crash.clone());
+    }
+    let nvs = EspDefaultNvsPartition::take().context(Phase::Boot, "Failed to open NVS")?;
+    // Before loading the configuration, the board's pins are its defaults
+    if let Some(board) = board::load(&nvs) {
+        Board::select(board);
+    }
+    info!("Board: {}", Board::current().name());
+    let mut config = Config::load();
+    config.identity = Identity::read()?;
+    info!("Device ID: {} (MAC {})", config.device_id(), config.identity.mac_string());
+    config.set_active_profile(profile::select(&config, &nvs))?;
+    config.calibration = calibration::load(&nvs);
+    config.tls_certificate = tls::load(&nvs);
+    config.sensor_memory = sensor_memory::load(&nvs);
+    if crash_loop::looping(boots, config.crash_loop.boots) {
+        log::error!(
+            "{} boots in a row didn't stay up for {} s, starting in safe mode",
+            boots - 1,
+            config.crash_loop.stable_sec
+        );
+        crash_loop::restrict(&mut config);
+    }
+    // Clears the count once the node has been up for long enough
+    let timers = EspTaskTimerService::new().context(Phase::Boot, "Failed to start the timer service")?;
+    let stable = timers.timer(crash_loop::stable).context(Phase::Boot, "Failed to set up a timer")?;
+    stable
+        .after(Duration::from_secs(config.crash_loop.stable_sec as u64))
+        .context(Phase::Boot, "Failed to set up a timer")?;
+    match watchdog::reboots(&nvs) {
+        0 => {}
+        reboots => warn!("The delivery watchdog has rebooted this node {} times", reboots),
+    }
+    let run_selftest = match selftest::requested(&config.selftest) {
+        Request::FactoryReset => factory_reset::run(),
+        request => request == Request::SelfTest,
+    };
+
+    let mut peripherals = Peripherals::take().context(Phase::Boot, "Peripherals already taken")?;
+    let i2c_config = I2cConfig::new().baudrate(config.i2c.baudrate_khz.kHz().into());
+    // Pin numbers come from the configuration file, so the typed pins can't be used here
+    let i2c = I2cDriver::new(
+        peripherals.i2c0,
+        unsafe { AnyIOPin::new(config.i2c.sda) },
+        unsafe { AnyIOPin::new(config.i2c.scl) },
+        &i2c_config,
+    )
+    .context(Phase::Boot, "Failed to set up the I2C bus")?;
+
+    // The only general purpose SPI bus, shared by the SD card, the e-paper panel, the LoRa radio and SPI
+    // sensors
+    #[cfg(any(feature = "sdcard", feature = "epaper", feature = "bme280", feature = "lora"))]
+    let spi: &'static SpiDriver<'static> = Box::leak(Box::new(
+        SpiDriver::new(
+            peripherals.spi2,
+            unsafe { AnyIOPin::new(config.sdcard.sclk) },
+            unsafe { AnyIOPin::new(config.sdcard.mosi) },
+            Some(unsafe { AnyIOPin::new(config.sdcard.miso) }),
+            &SpiDriverConfig::default().dma(Dma::Auto(4096)),
+        )
+        .context(Phase::Boot, "Failed to set up the SPI bus")?,
+    ));
+
+    #[cfg(feature = "sdcard")]
+    let _sd_card = sdcard::mount(spi, unsafe { AnyIOPin::new(config.sdcard.cs) })?;
+
+    // Sensors sampled on threads of their own run next to the measurement loop
+    #[cfg(feature = "ld2410")]
+    let radar = tasks::spawning(&config.tasks, Task::Sensing, || {
+        Ld2410Sensor::spawn_reader(
+            UartDriver::new(
+                peripherals.uart1,
+                unsafe { AnyIOPin::new(config.ld2410.tx) },
+                unsafe { AnyIOPin::new(config.ld2410.rx) },
+                Option::<AnyIOPin>::None,
+                Option::<AnyIOPin>::None,
+                &UartConfig::new().baudrate(256_000.Hz()),
+            )
+            .context(Phase::SensorInit, "Failed to set up the LD2410 UART")?,
+            &config.ld2410,
+        )
+    })?;
+    // Without the microphone or its model the rest of the node carries on
+    #[cfg(feature = "snore")]
+    let snore = match (config.snore.bclk, config.snore.ws, config.snore.din) {
+        (Some(bclk), Some(ws), Some(din)) => tasks::spawning(&config.tasks, Task::Sensing, || {
+            SnoreSensor::spawn_capture(
+                peripherals.i2s0,
+                unsafe { AnyIOPin::new(bclk) },
+                unsafe { AnyIOPin::new(ws) },
+                unsafe { AnyIOPin::new(din) },
+                &config.snore,
+            )
+        })
+        .inspect_err(|err| log::error!("{}", err))
+        .ok(),
+        _ => None,
+    };
+    // Channels 2 and 3 are the receive ones on the C3 and the C6, 4 to 7 on the S3
+    #[cfg(all(feature = "dht22", not(esp32s3)))]
+    let dht22_channel = peripherals.rmt.channel2;
+    #[cfg(all(feature = "dht22", esp32s3))]
+    let dht22_channel = peripherals.rmt.channel4;
+    #[cfg(feature = "dht22")]
+    let dht22_line = Dht22Line::open(
+        dht22_channel,
+        unsafe { AnyIOPin::new(config.dht22.pin) },
+        config.dht22.pin,
+    )?;
+
+    // Shared with the console from here on, `config` stays as the snapshot used while booting
+    let shared_config: SharedConfig = Arc::new(Mutex::new(config.clone()));
+
+    // Every sensor compiled in gets registered, disabled ones can be switched on from the console
+    // Leaked, so sensors sampled on their own threads can share the bus
+    let disturbance_log = disturbances::new_log();
+    let alerts = Alerts::default();
+    let mut sensors = SensorRegistry::default();
+    let i2c: &'static I2cBus<'static> = Box::leak(Box::new(I2cBus::new(i2c, &config.i2c, sensors.states())));
+    #[cfg(feature = "bme280")]
+    let factory = match config.spi_sensors.get("bme280") {
+        Some(cs) => spi_factory::<Bme280Sensor>(spi, *cs, &shared_config),
+        None => i2c_factory::<Bme280Sensor>(i2c, "bme280", &shared_config),
+    };
+    #[cfg(feature = "bme280")]
+    sensors.register("bme280", config.sensors().bme280, factory);
+    #[cfg(feature = "scd4x")]
+    sensors.register("scd4x", config.sensors().scd4x, i2c_factory::<Scd4xSensor>(i2c, "scd4x", &shared_config));
+    #[cfg(feature = "tsl2591")]
+    let light_watch = match config.tsl2591.interrupt_pin {
+        Some(pin) => Some(tasks::spawning(&config.tasks, Task::Sensing, || {
+            crate::sensors::spawn_light_events(i2c.device("tsl2591"), &config.tsl2591, pin, disturbance_log.clone())
+        })?),
+        None => None,
+    };
+    #[cfg(feature = "tsl2591")]
+    sensors.register("tsl2591", config.sensors().tsl2591, tsl2591_factory(i2c, &shared_config, light_watch));
+    #[cfg(feature = "as7341")]
+    sensors.register("as7341", config.sensors().as7341, i2c_factory::<As7341Sensor>(i2c, "as7341", &shared_config));
+    #[cfg(feature = "max44009")]
+    sensors.register(
+        "max44009",
+        config.sensors().max44009,
+        i2c_factory::<Max44009Sensor>(i2c, "max44009", &shared_config),
+    );
+    #[cfg(feature = "hdc1080")]
+    sensors.register("hdc1080", config.sensors().hdc1080, i2c_factory::<Hdc1080Sensor>(i2c, "hdc1080", &shared_config));
+    #[cfg(feature = "reed_switch")]
+    let window = tasks::spawning(&config.tasks, Task::Sensing, || {
+        crate::sensors::spawn_reed_switch(&config.reed_switch, disturbance_log.clone(), alerts.clone())
+    })?;
+    #[cfg(feature = "reed_switch")]
+    sensors.register(
+        "reed_switch",
+        config.sensors().reed_switch,
+        Box::new(move || Ok(Box::new(ReedSwitchSensor::new(window.clone())))),
+    );
+    #[cfg(feature = "dht22")]
+    sensors.register("dht22", config.sensors().dht22, Box::new(move || Ok(Box::new(Dht22Sensor::new(dht22_line.clone())))));
+    #[cfg(feature = "thermistor")]
+    sensors.register("thermistor", config.sensors().thermistor, {
+        let config = shared_config.clone();
+        Box::new(move || Ok(Box::new(ThermistorSensor::new(&config.lock().expect("Config lock poisoned"))?)))
+    });
+    #[cfg(feature = "analog")]
+    sensors.register("analog", config.sensors().analog, {
+        let config = shared_config.clone();
+        Box::new(move || Ok(Box::new(AnalogSensor::new(&config.lock().expect("Config lock poisoned"))?)))
+    });
+    // After the temperature and humidity sensors, the gas sensors compensate with their readings
+    #[cfg(feature = "ccs811")]
+    sensors.register("ccs811", config.sensors().ccs811, i2c_factory::<Ccs811Sensor>(i2c, "ccs811", &shared_config));
+    #[cfg(feature = "sgp30")]
+    sensors.register("sgp30", config.sensors().sgp30, i2c_factory::<Sgp30Sensor>(i2c, "sgp30", &shared_config));
+    #[cfg(feature = "ld2410")]
+    sensors.register("ld2410", config.sensors().ld2410, Box::new(move || Ok(Box::new(Ld2410Sensor::new(radar.clone())))));
+    #[cfg(feature = "snore")]
+    if let Some(snore) = snore {
+        sensors.register(
+            "snore",
+            config.sensors().snore,
+            Box::new(move || Ok(Box::new(SnoreSensor::new(snore.clone())))),
+        );
+    }
+    sensors.check_consistency(ConsistencyCheck::new(config.consistency.clone()));
+    sensors.name_metrics(config.metric_names.clone());
+    let (memory_nvs, memory_config) = (nvs.clone(), shared_config.clone());
+    sensors.persist(
+        config.sensor_memory.clone(),
+        Box::new(move |memory| {
+            // Sensors set up again from the console pick it up as well
+            memory_config.lock().expect("Config lock poisoned").sensor_memory = memory.clone();
+            if let Err(err) = sensor_memory::store(&memory_nvs, memory) {
+                log::error!("{}", err);
+            }
+        }),
+    );
+
+    let sys_loop = EspSystemEventLoop::take().context(Phase::Boot, "Failed to take the system event loop")?;
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(&mut peripherals.modem, sys_loop.clone(), Some(nvs.clone()))
+            .context(Phase::Boot, "Failed to set up Wi-Fi")?,
+        sys_loop.clone(),
+    )
+    .context(Phase::Boot, "Failed to set up Wi-Fi")?;
+    wifi.wifi_mut()
+        .sta_netif_mut()
+        .set_hostname(&config.hostname())
+        .context(Phase::Boot, "Failed to set the hostname")?;
+    let cellular = cfg!(feature = "cellular") && config.cellular.enabled;
+    let thread = cfg!(feature = "thread") && config.thread.enabled;
+    // Nothing to bring up for a node that only has the radio, its clock has to come from the DS3231
+    let radio_only = cfg!(feature = "lora")
+        && !cellular
+        && !thread
+        && config.wifi.ssid.is_empty()
+        && config.uplinks().all(|uplink| matches!(uplink, Uplink::Lora | Uplink::Lorawan));
+    let wifi_only = !cellular && !thread && !radio_only;
+    if wifi_only && config.wifi.ssid.is_empty() && provisioning::run(&mut wifi, &mut config) {
+        shared_config.lock().expect("Config lock poisoned").wifi = config.wifi.clone();
+        if let Err(err) = config.save() {
+            log::error!("{}", err);
+        }
+    }
+
+    #[cfg(feature = "mdns")]
+    let _mdns = {
+        let context = "Failed to set up mDNS";
+        let mut mdns = esp_idf_svc::mdns::EspMdns::take().context(Phase::Boot, context)?;
+        mdns.set_hostname(config.hostname()).context(Phase::Boot, context)?;
+        mdns.set_instance_name(format!("Sleep Thing {}", config.device_id()))
+            .context(Phase::Boot, context)?;
+        mdns
+    };
+
+    if run_selftest {
+        selftest::run(&config, &mut sensors, &mut wifi, &mut GraphiteSink::default());
+    }
+
+    let clock = ClockSync::default();
+    #[cfg(feature = "ds3231")]
+    let on_sync = if config.ds3231.enabled { Some(rtc_clock(i2c, &config, &clock)?) } else { None };
+    #[cfg(not(feature = "ds3231"))]
+    let on_sync: Option<OnSync> = None;
+
+    let mut network: Box<dyn Network + Send + '_> =
+        if radio_only { Box::new(RadioOnly) } else { Box::new(WifiNetwork::new(wifi)) };
+    #[cfg(feature = "cellular")]
+    let cellular_signal = SharedSignal::default();
+    #[cfg(feature = "cellular")]
+    if cellular {
+        #[cfg(not(esp32s3))]
+        let uart = peripherals.uart1;
+        #[cfg(esp32s3)]
+        let uart = peripherals.uart2;
+        network = Box::new(cellular_network(uart, &config, cellular_signal.clone())?);
+    }
+    #[cfg(feature = "thread")]
+    if thread {
+        network = Box::new(ThreadNetwork::new(&config.thread)?);
+    }
+    // Whatever advertises over BLE registers with the scheduler, to be paused while Wi-Fi is up
+    #[cfg(feature = "ble_provisioning")]
+    let radio_scheduler = RadioScheduler::default();
+    #[cfg(feature = "ble_provisioning")]
+    if wifi_only {
+        network = Box::new(Scheduled::new(network, radio_scheduler.clone()));
+    }
+    network.connect(&config)?;
+    // Syncs in the background, the pipeline holds back what it measures until then
+    let _sntp = timesync::start(&config.sntp, clock.clone(), on_sync)?;
+    info!("SNTP initialized");
+
+    trace!("Calling run");
+    let sensor_states = sensors.states();
+    let watchdog_nvs = nvs.clone();
+    let https = || Box::new(EspHttpClient::new(shared_config.clone()));
+    let shutdown = Shutdown::default();
+    let builder = PipelineBuilder::new(shared_config.clone())
+        .sensors(sensors)
+        .shutdown(shutdown.clone())
+        .spool(SPOOL_PATH)
+        .dead_letters(DEAD_LETTER_PATH)
+        .clock(clock)
+        .alerts(alerts)
+        .network(network)
+        .reboot(Box::new(move || {
+            if let Err(err) = watchdog::record_reboot(&watchdog_nvs) {
+                log::error!("{}", err);
+            }
+            esp_idf_svc::hal::reset::restart();
+        }));
+    let error_reporting = &config.error_reporting;
+    let builder = if error_reporting.sentry_dsn.is_empty() && error_reporting.webhook_url.is_empty() {
+        builder
+    } else {
+        builder.reporter(Box::new(ErrorReporter::new(https(), events)))
+    };
+    let builder = if config.insights.url.is_empty() {
+        builder
+    } else {
+        let reset_reason = diagnostics::reset_reason();
+        info!("Reset reason: {}", reset_reason);
+        let metrics = Box::new(diagnostics::measurements);
+        builder.reporter(Box::new(Insights::new(https(), reset_reason, crash, metrics)))
+    };
+    #[cfg(feature = "lis3dh")]
+    let builder = if config.actigraphy.enabled {
+        let epochs = tasks::spawning(&config.tasks, Task::Sensing, || {
+            crate::sensors::spawn_actigraphy(i2c.device("lis3dh"), &config.actigraphy)
+        })?;
+        builder.side_buffer(epochs)
+    } else {
+        builder
+    };
+    let ir_overrides = SharedOverrides::default();
+    #[cfg(feature = "ir")]
+    let builder = match config.ir.pin {
+        Some(pin) => {
+            let transmitter = RmtTransmitter::new(
+                peripherals.rmt.channel0,
+                unsafe { AnyOutputPin::new(pin) },
+                config.ir.carrier_khz,
+            )?;
+            builder.filter(Box::new(ClimateControl::new(
+                config.ir.devices.clone(),
+                config.ir.rules.clone(),
+                ir_overrides.clone(),
+                Box::new(transmitter),
+            )))
+        }
+        None => builder,
+    };
+    #[cfg(feature = "display")]
+    let readings = SharedReadings::default();
+    #[cfg(feature = "display")]
+    let builder = if config.display.enabled {
+        builder.filter(Box::new(DisplayFeed::new(readings.clone())))
+    } else {
+        builder
+    };
+    let builder = if config.fleet.url.is_empty() {
+        builder
+    } else {
+        let version = AppliedVersion::default();
+        let (command_config, command_nvs, command_shutdown) = (shared_config.clone(), nvs.clone(), shutdown.clone());
+        let handler = Box::new(move |command: &str| {
+            fleet_command(command, &command_config, &command_nvs, &command_shutdown)
+        });
+        let reboot = Box::new(|| {
+            esp_idf_svc::hal::reset::restart();
+        });
+        let fleet = FleetClient::new(https(), shared_config.clone(), version.clone()).commands(handler, reboot);
+        builder.filter(Box::new(VersionMetric(version))).reporter(Box::new(fleet))
+    };
+    #[cfg(feature = "cellular")]
+    let builder = if cellular {
+        builder.filter(Box::new(SignalMetrics(cellular_signal)))
+    } else {
+        builder
+    };
+    let builder = if config.system_metrics {
+        builder.filter(Box::new(SystemMetrics))
+    } else {
+        builder
+    };
+    #[cfg(feature = "sdcard")]
+    let builder = builder.archive(Box::new(sdcard::CsvLog::default()));
+    let builder = if config.daily.enabled {
+        let summary = DailySummary::new();
+        // Picks up the rounds of the day so far, a reboot would start the day over otherwise
+        #[cfg(feature = "sdcard")]
+        let summary = {
+            let mut summary = summary;
+            let since_ms = DailySummary::start_of_day_ms(&config, crate::pipeline::now_ms());
+            let replayed = sdcard::replay(since_ms, |timestamp_ms, round| {
+                summary.record(&config, timestamp_ms, round);
+            });
+            if let Err(err) = replayed {
+                warn!("Failed to read the day's history from the SD card: {:?}", err);
+            }
+            summary
+        };
+        builder.side_buffer(summary.buffer()).archive(Box::new(summary))
+    } else {
+        builder
+    };
+    #[cfg(feature = "epaper")]
+    let builder = if config.epaper.enabled {
+        builder.archive(Box::new(EpaperSink::new(epaper(spi, &config)?)))
+    } else {
+        builder
+    };
+    let builder = if config.modbus.enabled {
+        let feed = ModbusFeed::new();
+        match modbus::spawn(config.modbus.port, feed.registers()) {
+            Ok(_) => builder.archive(Box::new(feed)),
+            Err(err) => {
+                log::error!("{}", err);
+                builder
+            }
+        }
+    } else {
+        builder
+    };
+    let builder = if config.snmp.enabled {
+        let feed = SnmpFeed::default();
+        match snmp::spawn(&config, feed.mib()) {
+            Ok(_) => builder.archive(Box::new(feed)),
+            Err(err) => {
+                log::error!("{}", err);
+                builder
+            }
+        }
+    } else {
+        builder
+    };
+    let builder = if config.history.enabled {
+        let feed = HistoryFeed::new(&config);
+        match history::serve(&config, feed.history()) {
+            Ok(()) => builder.archive(Box::new(feed)),
+            Err(err) => {
+                log::error!("{}", err);
+                builder
+            }
+        }
+    } else {
+        builder
+    };
+    #[cfg(feature = "mqtt")]
+    let publisher = if config.mqtt.url.is_empty() {
+        None
+    } else {
+        // Not worth failing the boot over, the uplink doesn't depend on it
+        let commands = mqtt::Commands {
+            shutdown: shutdown.clone(),
+            config: shared_config.clone(),
+            nvs: nvs.clone(),
+            ir_overrides: ir_overrides.clone(),
+        };
+        match mqtt::connect(&config, commands) {
+            Ok(publisher) => Some(publisher),
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        }
+    };
+    let mut builder = builder;
+    for uplink in config.uplinks() {
+        let sink: Box<dyn Sink + Send> = match uplink {
+            Uplink::Graphite => Box::new(GraphiteSink::default()),
+            Uplink::Datadog => Box::new(DatadogSink::new(https())),
+            Uplink::Coap => Box::new(CoapSink::default()),
+            #[cfg(feature = "mqtt")]
+            Uplink::Mqtt => match &publisher {
+                Some(publisher) => Box::new(MqttSink::new(Box::new(publisher.clone()))),
+                None => continue,
+            },
+            #[cfg(not(feature = "mqtt"))]
+            Uplink::Mqtt => {
+                log::error!("Built without MQTT, it can't be an uplink");
+                continue;
+            }
+            #[cfg(feature = "lora")]
+            Uplink::Lora => Box::new(LoraSink::new(lora_radio(spi, &config)?)),
+            #[cfg(not(feature = "lora"))]
+            Uplink::Lora => {
+                log::error!("Built without LoRa, it can't be an uplink");
+                continue;
+            }
+            #[cfg(feature = "lorawan")]
+            Uplink::Lorawan => Box::new(LorawanSink::new(lora_radio(spi, &config)?, Some(Path::new(LORAWAN_PATH)))),
+            #[cfg(not(feature = "lorawan"))]
+            Uplink::Lorawan => {
+                log::error!("Built without LoRaWAN, it can't be an uplink");
+                continue;
+            }
+        };
+        builder = builder.uplink(sink);
+    }
+    // Published as the rounds come in unless it's an uplink
+    #[cfg(feature = "mqtt")]
+    let builder = match &publisher {
+        Some(publisher) if !config.uplinks().any(|uplink| uplink == Uplink::Mqtt) => {
+            builder.archive(Box::new(MqttSink::new(Box::new(publisher.clone()))))
+        }
+        _ => builder,
+    };
+    let builder = if config.disturbances.enabled {
+        #[cfg(feature = "mqtt")]
+        let publisher = publisher.map(|publisher| Box::new(publisher) as Box<dyn Publisher + Send>);
+        #[cfg(not(feature = "mqtt"))]
+        let publisher = None;
+        builder
+            .filter(Box::new(DisturbanceDetector::new(&config, disturbance_log.clone())))
+            .reporter(Box::new(DisturbanceReporter::new(https(), publisher, disturbance_log)))
+    } else {
+        builder
+    };
+    let pipeline = builder.build()?;
+
+    #[cfg(feature = "display")]
+    if config.display.enabled {
+        // Not worth failing the boot over, the measurements don't depend on it
+        if let Err(err) = display::spawn(i2c.device("display"), shared_config.clone(), readings, pipeline.buffers()) {
+            log::error!("{}", err);
+        }
+    }
+
+    console::spawn(pipeline.buffers(), shared_config, sensor_states, ir_overrides, nvs, shutdown)
+        .context(Phase::Console, "Failed to start the console")?;
+    pipeline.run();
+    shutdown::halt()
+}
+
+/// The commands from the fleet manager besides `reboot`.
+fn fleet_command(
+    command: &str,
+    config: &SharedConfig,
+    nvs: &EspDefaultNvsPartition,
+    shutdown: &Shutdown,
+) -> Result<String> {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    match args.as_slice() {
+        ["measure-now"] => {
+            shutdown.wake();
+            Ok("Measuring".to_string())
+        }
+        ["log-level", level] => {
+            let level: LevelFilter = level
+                .parse()
+                .map_err(|_| Error::failed(Phase::Console, format!("Unknown log level '{}'", level)))?;
+            log::set_max_level(level);
+            Ok(format!("Logging up to {}", level))
+        }
+        ["calibrate", rest @ ..] => {
+            let usage = "Usage: calibrate <metric> <reading> <reference> <reading> <reference>|clear";
+            let (metric, correction) =
+                calibration::parse_correction(rest).ok_or_else(|| Error::failed(Phase::Console, usage))?;
+            calibration::update(nvs, config, |calibration| calibration.set_correction(metric, correction))?;
+            Ok(format!("Correction of {} set", metric))
+        }
+        _ => Err(Error::failed(Phase::Console, format!("Unknown command '{}'", command))),
+    }
+}
+
+/// Sets the sensor up with the current configuration, so a re-init picks up changed settings.
+fn i2c_factory<'a, S: I2cSensor<'a> + 'a>(
+    i2c: &'a I2cBus<'a>,
+    name: &'static str,
+    config: &SharedConfig,
+) -> SensorFactory<'a> {
+    let config = config.clone();
+    Box::new(move || {
+        let config = config.lock().expect("Config lock poisoned").clone();
+        Ok(Box::new(S::get_sensor(i2c.device(name), &config)?))
+    })
+}
+
+// A device per attempt, so a sensor that failed to come up can be set up again from the console
+#[cfg(feature = "bme280")]
+fn spi_factory<S: SpiSensor<'static> + 'static>(
+    spi: &'static SpiDriver<'static>,
+    cs: i32,
+    config: &SharedConfig,
+) -> SensorFactory<'static> {
+    let config = config.clone();
+    Box::new(move || {
+        let config = config.lock().expect("Config lock poisoned").clone();
+        let device = SpiDeviceDriver::new(
+            spi,
+            Some(unsafe { AnyIOPin::new(cs) }),
+            // Well below the sensors' limits, the wires to a sensor board can be long
+            &SpiConfig::new().baudrate(1.MHz().into()),
+        )
+        .context(Phase::SensorInit, "Failed to set up the SPI device")?;
+        Ok(Box::new(S::get_sensor(device, &config)?))
+    })
+}
+
+#[cfg(feature = "tsl2591")]
+fn tsl2591_factory(
+    i2c: &'static I2cBus<'static>,
+    config: &SharedConfig,
+    watch: Option<LightWatch>,
+) -> SensorFactory<'static> {
+    let config = config.clone();
+    Box::new(move || {
+        let config = config.lock().expect("Config lock poisoned").clone();
+        let sensor = Tsl2591Sensor::get_sensor(i2c.device("tsl2591"), &config)?;
+        Ok(Box::new(match watch.clone() {
+            Some(watch) => sensor.with_watch(watch),
+            None => sensor,
+        }))
+    })
+}
+
+/// Sets the system time from the DS3231, and the DS3231 from every SNTP sync.
+#[cfg(feature = "ds3231")]
+fn rtc_clock(i2c: &'static I2cBus<'static>, config: &Config, clock: &ClockSync) -> Result<OnSync> {
+    let mut ds3231 = Ds3231::new(i2c.device("ds3231"), config.ds3231.address);
+    match ds3231.read() {
+        Ok(Some(timestamp)) => {
+            rtc::set_system_time(timestamp)?;
+            clock.set();
+            info!("Time set from the DS3231");
+        }
+        Ok(None) => warn!("The DS3231 lost the time, it's set again with the next SNTP sync"),
+        // SNTP may still come through, the clock is tried again with every sync
+        Err(err) => warn!("Failed to read the DS3231: {:?}", err),
+    }
+    Ok(Box::new(move |time: Duration| {
+        if let Err(err) = ds3231.write(time.as_secs()) {
+            warn!("Failed to set the DS3231: {:?}", err);
+        }
+    }))
+}
+
+#[cfg(feature = "cellular")]
+fn cellular_network(uart: CellularUart, config: &Config, signal: SharedSignal) -> Result<CellularNetwork> {
+    let context = "Failed to set up the modem";
+    let uart = UartDriver::new(
+        uart,
+        unsafe { AnyIOPin::new(config.cellular.tx) },
+        unsafe { AnyIOPin::new(config.cellular.rx) },
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::new().baudrate(config.cellular.baudrate.Hz()),
+    )
+    .context(Phase::Boot, context)?;
+    let power_key = match config.cellular.power_key {
+        Some(pin) => Some(PinDriver::output(unsafe { AnyOutputPin::new(pin) }).context(Phase::Boot, context)?),
+        None => None,
+    };
+    CellularNetwork::new(uart, power_key, &config.cellular, signal)
+}
+
+#[cfg(feature = "epaper")]
+type EpaperPanel = Epaper<
+    SpiDeviceDriver<'static, &'static SpiDriver<'static>>,
+    PinDriver<'static, AnyOutputPin, Output>,
+    PinDriver<'static, AnyOutputPin, Output>,
+    PinDriver<'static, AnyIOPin, Input>,
+    Delay,
+>;
+
+#[cfg(feature = "epaper")]
+fn epaper(spi: &'static SpiDriver<'static>, config: &Config) -> Result<EpaperPanel> {
+    let context = "Failed to set up the e-paper panel";
+    let device = SpiDeviceDriver::new(
+        spi,
+        Some(unsafe { AnyIOPin::new(config.epaper.cs) }),
+        &SpiConfig::new().baudrate(4.MHz().into()),
+    )
+    .context(Phase::Output, context)?;
+    let dc = PinDriver::output(unsafe { AnyOutputPin::new(config.epaper.dc) }).context(Phase::Output, context)?;
+    let rst = PinDriver::output(unsafe { AnyOutputPin::new(config.epaper.rst) }).context(Phase::Output, context)?;
+    let busy = PinDriver::input(unsafe { AnyIOPin::new(config.epaper.busy) }).context(Phase::Output, context)?;
+    Ok(Epaper::new(device, dc, rst, busy, Delay::new_default()))
+}
+
+#[cfg(feature = "lora")]
+fn lora_radio(spi: &'static SpiDriver<'static>, config: &Config) -> Result<Box<dyn Radio + Send>> {
+    let context = "Failed to set up the LoRa radio";
+    let lora = &config.lora;
+    let device = SpiDeviceDriver::new(
+        spi,
+        Some(unsafe { AnyIOPin::new(lora.cs) }),
+        &SpiConfig::new().baudrate(8.MHz().into()),
+    )
+    .context(Phase::Upload, context)?;
+    let reset = PinDriver::output(unsafe { AnyOutputPin::new(lora.reset) }).context(Phase::Upload, context)?;
+    let busy = PinDriver::input(unsafe { AnyIOPin::new(lora.busy) }).context(Phase::Upload, context)?;
+    Ok(match lora.chip {
+        LoraChip::Sx1276 => Box::new(Sx1276::new(device, reset, busy, Delay::new_default())),
+        LoraChip::Sx1262 => Box::new(Sx1262::new(device, reset, busy, Delay::new_default())),
+    })
+}
@@ -0,0 +1,332 @@
+//! Pulls each node's configuration from a fleet manager, so the intervals, thresholds and sensor
+//! flags of a handful of nodes are kept in one place rather than set over each one's console. The
+//! document at `fleet.url` is fetched while the network is up for an upload, with the ETag of the
+//! last one applied so an unchanged document costs a 304. The version applied goes out with every
+//! round as `fleet_config_version`.
+//!
+//! The document can also carry commands queued for the node, for sites where the node can't be
+//! reached from outside and MQTT isn't an option: `reboot`, `measure-now`, `log-level <level>` and
+//! `calibrate` as it's sent over MQTT. Each has an ID and runs once, the results are POSTed to
+//! `fleet.url` on the next check-in. The server has to change the ETag when it queues a command,
+//! and drops the command once its result is in. A reboot waits for its result to be delivered,
+//! the node would otherwise boot into the same reboot again.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::config::{Config, SharedConfig};
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{Filter, HttpClient, Reporter};
+use crate::sensors::Measurement;
+
+/// The version of the last document applied, none until one is.
+pub type AppliedVersion = Arc<Mutex<Option<u64>>>;
+
+// IDs of the commands run, to tell the ones sent again before the server had their result
+const MAX_SEEN_COMMANDS: usize = 32;
+
+/// Runs a command other than `reboot`, returns what it has to say or why it failed.
+pub type CommandHandler<'a> = Box<dyn FnMut(&str) -> Result<String> + Send + 'a>;
+
+#[derive(Deserialize)]
+struct Document {
+    version: u64,
+    #[serde(default)]
+    config: Map<String, Value>,
+    #[serde(default)]
+    commands: Vec<Command>,
+}
+
+#[derive(Deserialize)]
+struct Command {
+    id: String,
+    command: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct CommandResult {
+    id: String,
+    ok: bool,
+    output: String,
+}
+
+pub struct FleetClient<'a> {
+    client: Box<dyn HttpClient + Send + 'a>,
+    config: SharedConfig,
+    version: AppliedVersion,
+    etag: Option<String>,
+    handler: Option<CommandHandler<'a>>,
+    reboot: Option<Box<dyn FnMut() + Send + 'a>>,
+    seen: VecDeque<String>,
+    results: Vec<CommandResult>,
+    reboot_requested: bool,
+}
+
+impl<'a> FleetClient<'a> {
+    pub fn new(client: Box<dyn HttpClient + Send + 'a>, config: SharedConfig, version: AppliedVersion) -> Self {
+        FleetClient {
+            client,
+            config,
+            version,
+            etag: None,
+            handler: None,
+            reboot: None,
+            seen: VecDeque::new(),
+            results: Vec::new(),
+            reboot_requested: false,
+        }
+    }
+
+    /// Runs the commands in the documents, without them they're ignored.
+    pub fn commands(mut self, handler: CommandHandler<'a>, reboot: Box<dyn FnMut() + Send + 'a>) -> Self {
+        self.handler = Some(handler);
+        self.reboot = Some(reboot);
+        self
+    }
+
+    fn run(&mut self, commands: Vec<Command>) {
+        let Some(handler) = self.handler.as_mut() else {
+            if !commands.is_empty() {
+                warn!("Ignoring {} commands from the fleet manager", commands.len());
+            }
+            return;
+        };
+        for Command { id, command } in commands {
+            if self.seen.contains(&id) {
+                continue;
+            }
+            info!("Running '{}' from the fleet manager", command);
+            let result = match command.trim() {
+                "reboot" => {
+                    self.reboot_requested = true;
+                    Ok("Rebooting".to_string())
+                }
+                command => handler(command),
+            };
+            let (ok, output) = match result {
+                Ok(output) => (true, output),
+                Err(err) => (false, err.to_string()),
+            };
+            if self.seen.len() == MAX_SEEN_COMMANDS {
+                self.seen.pop_front();
+            }
+            self.seen.push_back(id.clone());
+            self.results.push(CommandResult { id, ok, output });
+        }
+    }
+
+    /// Delivers the results so far, then reboots if that was asked for.
+    fn send_results(&mut self, config: &Config, url: &str, headers: &[(&str, &str)]) -> Result<()> {
+        if !self.results.is_empty() {
+            let body = serde_json::to_vec(&json!({ "device": config.device_id(), "results": self.results }))?;
+            let headers: Vec<(&str, &str)> =
+                headers.iter().copied().chain([("Content-Type", "application/json")]).collect();
+            match self.client.post(url, &headers, &body)? {
+                200..=299 => self.results.clear(),
+                status => return Err(Error::failed(Phase::Upload, format!("Fleet manager answered {}", status))),
+            }
+        }
+        if self.reboot_requested {
+            if let Some(reboot) = self.reboot.as_mut() {
+                warn!("Rebooting as the fleet manager asked");
+                reboot();
+            }
+            self.reboot_requested = false;
+        }
+        Ok(())
+    }
+
+    /// Merges the configuration of a document into the configuration, saved if that changed
+    /// anything. It's fetched again after a reboot, without an ETag to compare.
+    fn apply(&mut self, version: u64, patch: Map<String, Value>) -> Result<()> {
+        let mut config = self.config.lock().expect("Config lock poisoned");
+        let before = serde_json::to_value(&*config)?;
+        config.merge(&Value::Object(patch))?;
+        if serde_json::to_value(&*config)? != before {
+            info!("Applied version {} of the fleet configuration", version);
+            if let Err(err) = config.save() {
+                error!("{}", err);
+            }
+        }
+        *self.version.lock().expect("Fleet version lock poisoned") = Some(version);
+        Ok(())
+    }
+}
+
+impl Reporter for FleetClient<'_> {
+    fn report(&mut self, config: &Config) -> Result<()> {
+        let url = config.fleet.url.replace("{id}", config.device_id());
+        let authorization = format!("Bearer {}", config.fleet.token);
+        let mut headers = vec![("Accept", "application/json")];
+        if !config.fleet.token.is_empty() {
+            headers.push(("Authorization", &authorization));
+        }
+        self.send_results(config, &url, &headers)?;
+
+        let mut request = headers.clone();
+        if let Some(etag) = &self.etag {
+            request.push(("If-None-Match", etag));
+        }
+        let response = self.client.get(&url, &request)?;
+        match response.status {
+            304 => Ok(()),
+            200 => {
+                let document: Document = serde_json::from_slice(&response.body)?;
+                // A document that fails to apply is fetched and tried again with the next upload,
+                // its commands don't run twice
+                let applied = self.apply(document.version, document.config);
+                self.run(document.commands);
+                if self.reboot_requested {
+                    self.send_results(config, &url, &headers)?;
+                }
+                applied?;
+                self.etag = response.etag;
+                Ok(())
+            }
+            status => Err(Error::failed(Phase::Upload, format!("Fleet manager answered {}", status))),
+        }
+    }
+}
+
+/// Adds `fleet_config_version` to every round once a document has been applied.
+pub struct VersionMetric(pub AppliedVersion);
+
+impl Filter for VersionMetric {
+    fn apply(&mut self, mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+        if let Some(version) = *self.0.lock().expect("Fleet version lock poisoned") {
+            measurements.push(Measurement {
+                name: "fleet_config_version".into(),
+                value: version as f32,
+            });
+        }
+        measurements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::pipeline::Response;
+
+    #[derive(Clone, Default)]
+    struct MockClient {
+        responses: Arc<Mutex<Vec<Response>>>,
+        /// The `If-None-Match` of each request
+        etags: Arc<Mutex<Vec<Option<String>>>>,
+        posted: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl HttpClient for MockClient {
+        fn post(&mut self, _url: &str, _headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+            self.posted.lock().unwrap().push(serde_json::from_slice(body).unwrap());
+            Ok(200)
+        }
+
+        fn get(&mut self, _url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+            let etag = headers.iter().find(|(name, _)| *name == "If-None-Match");
+            self.etags.lock().unwrap().push(etag.map(|(_, value)| value.to_string()));
+            Ok(self.responses.lock().unwrap().remove(0))
+        }
+    }
+
+    fn document(value: Value, etag: &str) -> Response {
+        Response {
+            status: 200,
+            etag: Some(etag.to_string()),
+            body: serde_json::to_vec(&value).unwrap(),
+        }
+    }
+
+    #[test]
+    fn applies_the_document_and_reports_its_version() {
+        let mut config = Config::default();
+        config.fleet.url = "https://fleet.local/nodes/{id}.json".to_string();
+        let shared = Arc::new(Mutex::new(config.clone()));
+        let client = MockClient::default();
+        client.responses.lock().unwrap().extend([
+            document(json!({"version": 3, "config": {"interval_sec": 60, "thresholds": {"co2": {"max": 1200}}}}), "a"),
+            Response {
+                status: 304,
+                ..Response::default()
+            },
+            // Rejected as a whole, the interval stays
+            document(json!({"version": 4, "config": {"interval_sec": 120, "no_such_key": 1}}), "b"),
+        ]);
+        let version = AppliedVersion::default();
+        let mut fleet = FleetClient::new(Box::new(client.clone()), shared.clone(), version.clone());
+        let mut metric = VersionMetric(version);
+        assert!(metric.apply(Vec::new()).is_empty());
+
+        fleet.report(&config).unwrap();
+        assert_eq!(shared.lock().unwrap().interval_sec, 60);
+        assert_eq!(shared.lock().unwrap().thresholds["co2"].max, Some(1200.0));
+        let measurements = metric.apply(Vec::new());
+        assert_eq!(measurements[0].name, "fleet_config_version");
+        assert_eq!(measurements[0].value, 3.0);
+
+        fleet.report(&config).unwrap();
+        assert_eq!(*client.etags.lock().unwrap(), [None, Some("a".to_string())]);
+        assert!(fleet.report(&config).is_err());
+        assert_eq!(shared.lock().unwrap().interval_sec, 60);
+        assert_eq!(metric.apply(Vec::new())[0].value, 3.0);
+    }
+
+    #[test]
+    fn runs_commands_once_and_reports_their_results() {
+        let config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        let shared = Arc::new(Mutex::new(config.clone()));
+        let client = MockClient::default();
+        let commands = json!({"version": 1, "commands": [
+            {"id": "1", "command": "measure-now"},
+            {"id": "2", "command": "selfdestruct"},
+        ]});
+        client.responses.lock().unwrap().extend([
+            document(commands.clone(), "a"),
+            // Sent again before the server had the results
+            document(commands, "a"),
+            document(json!({"version": 1, "commands": [{"id": "3", "command": "reboot"}]}), "b"),
+        ]);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let rebooted = Arc::new(Mutex::new(false));
+        let handler = {
+            let ran = ran.clone();
+            Box::new(move |command: &str| {
+                ran.lock().unwrap().push(command.to_string());
+                match command {
+                    "measure-now" => Ok("Measuring".to_string()),
+                    _ => Err(Error::failed(Phase::Console, format!("Unknown command '{}'", command))),
+                }
+            })
+        };
+        let reboot = {
+            let rebooted = rebooted.clone();
+            Box::new(move || *rebooted.lock().unwrap() = true)
+        };
+        let mut fleet = FleetClient::new(Box::new(client.clone()), shared, AppliedVersion::default())
+            .commands(handler, reboot);
+
+        fleet.report(&config).unwrap();
+        assert!(client.posted.lock().unwrap().is_empty());
+        fleet.report(&config).unwrap();
+        assert_eq!(*ran.lock().unwrap(), ["measure-now", "selfdestruct"]);
+        let posted = client.posted.lock().unwrap().clone();
+        assert_eq!(posted.len(), 1);
+        assert_eq!(posted[0]["device"], "bedroom");
+        assert_eq!(posted[0]["results"][0], json!({"id": "1", "ok": true, "output": "Measuring"}));
+        assert_eq!(posted[0]["results"][1]["ok"], false);
+
+        // The reboot's result goes out before the node reboots
+        fleet.report(&config).unwrap();
+        assert!(*rebooted.lock().unwrap());
+        assert_eq!(client.posted.lock().unwrap()[1]["results"][0]["id"], "3");
+    }
+}
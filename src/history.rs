@@ -0,0 +1,161 @@
+//! The last `history.minutes` of rounds kept on the node and served over HTTP, so the dashboard
+//! and scripts on the LAN can plot recent trends without the upstream database:
+//!
+//! `GET /history?metric=co2&minutes=120` answers
+//! `{"metric": "co2", "minutes": 120, "points": [[<ms since the Unix epoch>, 812.0], ...]}`, oldest
+//! first. Without `minutes` it's all of the history.
+
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::Method;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::Write;
+#[cfg(target_os = "espidf")]
+use log::info;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+use serde_json::{json, Value};
+
+use crate::compact::CompactBatch;
+use crate::config::Config;
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase};
+use crate::error::Result;
+#[cfg(target_os = "espidf")]
+use crate::pipeline::now_ms;
+use crate::pipeline::Sink;
+use crate::schedule;
+use crate::sensors::Measurement;
+
+// 8 bytes a value, about 120 KB with 30 metrics a round
+const MAX_ROUNDS: usize = 512;
+
+pub type SharedHistory = Arc<Mutex<AllocRingBuffer<CompactBatch>>>;
+
+/// Keeps a copy of every round as it's archived.
+pub struct HistoryFeed {
+    history: SharedHistory,
+}
+
+impl HistoryFeed {
+    /// Sized for `history.minutes` at the shortest interval of the schedule.
+    pub fn new(config: &Config) -> Self {
+        let interval_sec = schedule::shortest_interval_sec(config).max(1) as usize;
+        let rounds = (config.history.minutes as usize * 60).div_ceil(interval_sec).clamp(1, MAX_ROUNDS);
+        HistoryFeed {
+            history: Arc::new(Mutex::new(AllocRingBuffer::new(rounds))),
+        }
+    }
+
+    pub fn history(&self) -> SharedHistory {
+        self.history.clone()
+    }
+}
+
+impl Sink for HistoryFeed {
+    fn send(&mut self, _config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let batch = CompactBatch::encode(timestamp_ms, measurements);
+        self.history.lock().expect("History lock poisoned").push(batch);
+        Ok(())
+    }
+}
+
+/// The status and JSON body answering a request for `uri`, `minutes` is capped at
+/// `max_minutes`.
+pub fn answer(history: &SharedHistory, uri: &str, max_minutes: u32, now_ms: u64) -> (u16, Value) {
+    let query = uri.split_once('?').map_or("", |(_, query)| query);
+    let (mut metric, mut minutes) = (None, max_minutes);
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "metric" if !value.is_empty() => metric = Some(value),
+            "minutes" => match value.parse::<u32>() {
+                Ok(value) if value > 0 => minutes = value.min(max_minutes),
+                _ => return (400, json!({ "error": "minutes must be a positive number" })),
+            },
+            _ => {}
+        }
+    }
+    let Some(metric) = metric else {
+        return (400, json!({ "error": "metric is missing" }));
+    };
+    let since_ms = now_ms.saturating_sub(minutes as u64 * 60_000);
+    let points: Vec<Value> = history
+        .lock()
+        .expect("History lock poisoned")
+        .iter()
+        .filter(|batch| batch.timestamp_ms >= since_ms)
+        .flat_map(|batch| {
+            batch
+                .decode()
+                .into_iter()
+                .filter(|measurement| measurement.name == metric && measurement.value.is_finite())
+                .map(|measurement| json!([batch.timestamp_ms, measurement.value]))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    (200, json!({ "metric": metric, "minutes": minutes, "points": points }))
+}
+
+/// Serves `/history` on `history.port` for as long as the node runs.
+#[cfg(target_os = "espidf")]
+pub fn serve(config: &Config, history: SharedHistory) -> Result<()> {
+    let port = config.history.port;
+    let max_minutes = config.history.minutes;
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: port,
+        ..Default::default()
+    })
+    .with_context(Phase::Output, || format!("Failed to start the HTTP server on port {}", port))?;
+    server
+        .fn_handler("/history", Method::Get, move |request| {
+            let (status, body) = answer(&history, request.uri(), max_minutes, now_ms());
+            // Also for dashboards served from elsewhere
+            let headers = [("Content-Type", "application/json"), ("Access-Control-Allow-Origin", "*")];
+            let mut response = request.into_response(status, None, &headers)?;
+            response.write_all(body.to_string().as_bytes())
+        })
+        .context(Phase::Output, "Failed to register the history handler")?;
+    // Stops the server when dropped
+    std::mem::forget(server);
+    info!("History served on port {}", port);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_with_the_recent_points_of_a_metric() {
+        let config = Config {
+            interval_sec: 300,
+            ..Config::default()
+        };
+        let mut feed = HistoryFeed::new(&config);
+        let history = feed.history();
+        let round = |co2: f32| {
+            [("co2", co2), ("lux", 3.0)].map(|(name, value)| Measurement {
+                name: name.into(),
+                value,
+            })
+        };
+        let now_ms = 1_700_000_000_000;
+        for (minutes_ago, co2) in [(180, 700.0), (100, 800.0), (5, f32::NAN), (0, 900.0)] {
+            feed.send(&config, now_ms - minutes_ago * 60_000, &round(co2)).unwrap();
+        }
+
+        let (status, body) = answer(&history, "/history?metric=co2&minutes=120", 720, now_ms);
+        assert_eq!(status, 200);
+        assert_eq!(body["minutes"], 120);
+        assert_eq!(body["points"], json!([[now_ms - 100 * 60_000, 800.0], [now_ms, 900.0]]));
+        let (_, body) = answer(&history, "/history?metric=co2", 720, now_ms);
+        assert_eq!(body["points"].as_array().unwrap().len(), 3);
+        let (_, body) = answer(&history, "/history?metric=co2&minutes=9999", 60, now_ms);
+        assert_eq!(body["minutes"], 60);
+
+        assert_eq!(answer(&history, "/history?minutes=10", 720, now_ms).0, 400);
+        assert_eq!(answer(&history, "/history?metric=co2&minutes=-1", 720, now_ms).0, 400);
+    }
+}
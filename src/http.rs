@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::tls::X509;
+
+use crate::config::SharedConfig;
+use crate::error::{Context, Error, Phase, Result};
+use crate::pipeline::{HttpClient, Response};
+use crate::tls;
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+// Of a fetched document, the heap is tight
+const MAX_RESPONSE: usize = 16 * 1024;
+
+/// HTTPS client checking servers against the ESP-IDF certificate bundle, or only against the
+/// certificate stored with `cert`. A connection per request, the uplink only sends every few
+/// minutes.
+pub struct EspHttpClient {
+    config: SharedConfig,
+    // The certificate picked up from the config, changed from the console at any time
+    certificate: Option<(String, X509<'static>)>,
+}
+
+impl EspHttpClient {
+    pub fn new(config: SharedConfig) -> Self {
+        EspHttpClient {
+            config,
+            certificate: None,
+        }
+    }
+
+    fn certificate(&mut self) -> Option<X509<'static>> {
+        let pem = self.config.lock().expect("Config lock poisoned").tls_certificate.clone();
+        match pem {
+            Some(pem) => {
+                if self.certificate.as_ref().is_none_or(|(current, _)| *current != pem) {
+                    let x509 = tls::x509(&pem);
+                    self.certificate = Some((pem, x509));
+                }
+                self.certificate.as_ref().map(|(_, x509)| *x509)
+            }
+            None => None,
+        }
+    }
+
+    fn connect(&mut self) -> Result<EspHttpConnection> {
+        let certificate = self.certificate();
+        let configuration = Configuration {
+            timeout: Some(TIMEOUT),
+            crt_bundle_attach: match certificate {
+                Some(_) => None,
+                None => Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            },
+            server_certificate: certificate,
+            ..Default::default()
+        };
+        EspHttpConnection::new(&configuration).context(Phase::Upload, "Failed to set up HTTPS")
+    }
+}
+
+impl HttpClient for EspHttpClient {
+    fn post(&mut self, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+        let mut connection = self.connect()?;
+        let length = body.len().to_string();
+        let headers: Vec<(&str, &str)> = headers.iter().copied().chain([("Content-Length", length.as_str())]).collect();
+        connection
+            .initiate_request(Method::Post, url, &headers)
+            .with_context(Phase::Upload, || format!("Failed to connect to {}", url))?;
+        let mut sent = 0;
+        while sent < body.len() {
+            match connection.write(&body[sent..]) {
+                Ok(0) => return Err(Error::failed(Phase::Upload, format!("Connection to {} closed", url))),
+                Ok(written) => sent += written,
+                Err(err) => return Err(err).with_context(Phase::Upload, || format!("Failed to write to {}", url)),
+            }
+        }
+        connection
+            .initiate_response()
+            .with_context(Phase::Upload, || format!("No response from {}", url))?;
+        Ok(connection.status())
+    }
+
+    fn get(&mut self, url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let mut connection = self.connect()?;
+        connection
+            .initiate_request(Method::Get, url, headers)
+            .with_context(Phase::Upload, || format!("Failed to connect to {}", url))?;
+        connection
+            .initiate_response()
+            .with_context(Phase::Upload, || format!("No response from {}", url))?;
+        let etag = connection.header("ETag").map(str::to_string);
+        let mut body = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            match connection.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) if body.len() + read > MAX_RESPONSE => {
+                    return Err(Error::failed(Phase::Upload, format!("Response from {} is too large", url)));
+                }
+                Ok(read) => body.extend_from_slice(&chunk[..read]),
+                Err(err) => return Err(err).with_context(Phase::Upload, || format!("Failed to read from {}", url)),
+            }
+        }
+        Ok(Response {
+            status: connection.status(),
+            etag,
+            body,
+        })
+    }
+}
@@ -0,0 +1,35 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::{esp, esp_efuse_mac_get_default};
+
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase, Result};
+
+/// Stable identity of this unit, derived from the factory MAC address burned into eFuse.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub mac: [u8; 6],
+    /// Short ID, the last three bytes of the MAC as lowercase hex
+    pub id: String,
+}
+
+impl Identity {
+    #[cfg(target_os = "espidf")]
+    pub fn read() -> Result<Self> {
+        let mut mac = [0u8; 6];
+        esp!(unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) }).context(Phase::Boot, "Failed to read the MAC address")?;
+        Ok(Identity::from_mac(mac))
+    }
+
+    pub fn from_mac(mac: [u8; 6]) -> Self {
+        let id = format!("{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+        Identity { mac, id }
+    }
+
+    pub fn mac_string(&self) -> String {
+        self.mac
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
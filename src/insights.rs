@@ -0,0 +1,110 @@
+//! Fleet diagnostics for a self-hosted collector, along the lines of ESP Insights: why the node
+//! last reset and the panic behind it once per boot, then the uptime and system metrics every
+//! `insights.interval_min`.
+
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+
+use crate::config::Config;
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{HttpClient, Reporter};
+use crate::reporting::VERSION;
+use crate::sensors::Measurement;
+
+/// Gathers the metrics sent with every report, e.g. heap and stack usage.
+pub type MetricSource<'a> = Box<dyn FnMut() -> Vec<Measurement> + Send + 'a>;
+
+pub struct Insights<'a> {
+    client: Box<dyn HttpClient + Send + 'a>,
+    metrics: MetricSource<'a>,
+    // Until it's been delivered
+    boot: Option<Value>,
+    started: Instant,
+    sent_at: Option<Instant>,
+}
+
+impl<'a> Insights<'a> {
+    pub fn new(
+        client: Box<dyn HttpClient + Send + 'a>,
+        reset_reason: &str,
+        crash: Option<String>,
+        metrics: MetricSource<'a>,
+    ) -> Self {
+        Insights {
+            client,
+            metrics,
+            boot: Some(json!({ "reset_reason": reset_reason, "crash": crash })),
+            started: Instant::now(),
+            sent_at: None,
+        }
+    }
+}
+
+impl Reporter for Insights<'_> {
+    fn report(&mut self, config: &Config) -> Result<()> {
+        let interval = Duration::from_secs(config.insights.interval_min as u64 * 60);
+        if self.boot.is_none() && self.sent_at.is_some_and(|sent_at| sent_at.elapsed() < interval) {
+            return Ok(());
+        }
+        let metrics: Map<String, Value> = (self.metrics)()
+            .into_iter()
+            .filter(|measurement| measurement.value.is_finite())
+            .map(|measurement| (measurement.name.into_owned(), measurement.value.into()))
+            .collect();
+        let body = serde_json::to_vec(&json!({
+            "device": config.device_id(),
+            "version": VERSION,
+            "uptime_sec": self.started.elapsed().as_secs(),
+            "boot": self.boot,
+            "metrics": metrics,
+        }))?;
+        match self.client.post(&config.insights.url, &[("Content-Type", "application/json")], &body)? {
+            200..=299 => {
+                self.boot = None;
+                self.sent_at = Some(Instant::now());
+                Ok(())
+            }
+            status => Err(Error::failed(Phase::Upload, format!("Insights collector answered {}", status))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockClient {
+        bodies: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl HttpClient for MockClient {
+        fn post(&mut self, _url: &str, _headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+            self.bodies.lock().unwrap().push(serde_json::from_slice(body).unwrap());
+            Ok(200)
+        }
+    }
+
+    #[test]
+    fn sends_the_boot_once_then_on_the_interval() {
+        let client = MockClient::default();
+        let metrics = Box::new(|| {
+            vec![Measurement {
+                name: "heap_free".into(),
+                value: 120_000.0,
+            }]
+        });
+        let mut insights = Insights::new(Box::new(client.clone()), "panic", Some("oops".to_string()), metrics);
+        let config = Config::default();
+        insights.report(&config).unwrap();
+        insights.report(&config).unwrap();
+
+        let bodies = client.bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0]["boot"], json!({ "reset_reason": "panic", "crash": "oops" }));
+        assert_eq!(bodies[0]["metrics"]["heap_free"], 120_000.0);
+    }
+}
@@ -0,0 +1,48 @@
+use esp_idf_svc::hal::gpio::OutputPin;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::rmt::config::{CarrierConfig, DutyPercent, TransmitConfig};
+use esp_idf_svc::hal::rmt::{PinState, Pulse, PulseTicks, RmtChannel, TxRmtDriver, VariableLengthSignal};
+use esp_idf_svc::hal::units::FromValueType;
+
+use crate::climate_control::Transmitter;
+use crate::error::{Context, Error, Phase, Result};
+
+// 80 MHz APB clock divided down to 1 µs ticks
+const CLOCK_DIVIDER: u8 = 80;
+const DUTY_PERCENT: u8 = 33;
+
+/// IR LED on an RMT channel, marks are sent as bursts of the carrier.
+pub struct RmtTransmitter<'d> {
+    tx: TxRmtDriver<'d>,
+}
+
+impl<'d> RmtTransmitter<'d> {
+    pub fn new<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'd,
+        pin: impl Peripheral<P = impl OutputPin> + 'd,
+        carrier_khz: u32,
+    ) -> Result<Self> {
+        let context = "Failed to set up the IR transmitter";
+        let carrier = CarrierConfig::new()
+            .frequency(carrier_khz.kHz().into())
+            .duty_percent(DutyPercent::new(DUTY_PERCENT).context(Phase::Output, context)?);
+        let config = TransmitConfig::new().clock_divider(CLOCK_DIVIDER).carrier(Some(carrier));
+        let tx = TxRmtDriver::new(channel, pin, &config).context(Phase::Output, context)?;
+        Ok(RmtTransmitter { tx })
+    }
+}
+
+impl Transmitter for RmtTransmitter<'_> {
+    fn transmit(&mut self, code: &[u32]) -> Result<()> {
+        let mut signal = VariableLengthSignal::new();
+        for (i, duration) in code.iter().enumerate() {
+            let level = if i % 2 == 0 { PinState::High } else { PinState::Low };
+            let ticks = u16::try_from(*duration)
+                .ok()
+                .and_then(|ticks| PulseTicks::new(ticks).ok())
+                .ok_or_else(|| Error::failed(Phase::Output, format!("IR pulse of {} µs is too long", duration)))?;
+            signal.push(&[Pulse::new(level, ticks)]).context(Phase::Output, "Failed to encode the IR code")?;
+        }
+        self.tx.start_blocking(&signal).context(Phase::Output, "Failed to send the IR code")
+    }
+}
@@ -0,0 +1,87 @@
+pub mod actigraphy;
+pub mod alerts;
+pub mod analog;
+pub mod baseline;
+pub mod bme280_compensation;
+pub mod board;
+pub mod buffers;
+pub mod calibration;
+pub mod cellular;
+pub mod climate;
+pub mod climate_control;
+pub mod clock;
+pub mod coex;
+pub mod compact;
+pub mod config;
+pub mod consistency;
+pub mod crash_loop;
+pub mod daily;
+pub mod dead_letter;
+pub mod dht22;
+#[cfg(target_os = "espidf")]
+pub mod console;
+#[cfg(target_os = "espidf")]
+pub mod diagnostics;
+pub mod display;
+pub mod disturbances;
+pub mod downsample;
+#[cfg(target_os = "espidf")]
+pub mod firmware;
+pub mod filters;
+pub mod fleet;
+pub mod error;
+#[cfg(target_os = "espidf")]
+pub mod factory_reset;
+pub mod history;
+#[cfg(target_os = "espidf")]
+pub mod http;
+pub mod identity;
+pub mod insights;
+#[cfg(all(feature = "ir", target_os = "espidf"))]
+pub mod ir;
+pub mod light;
+pub mod lora;
+pub mod lorawan;
+pub mod metrics;
+#[cfg(any(test, feature = "simulator"))]
+pub mod mock;
+pub mod modbus;
+#[cfg(all(feature = "cellular", target_os = "espidf"))]
+pub mod modem;
+#[cfg(all(feature = "mqtt", target_os = "espidf"))]
+pub mod mqtt;
+#[cfg(all(feature = "thread", target_os = "espidf"))]
+pub mod openthread;
+pub mod pipeline;
+#[cfg(target_os = "espidf")]
+pub mod profile;
+#[cfg(target_os = "espidf")]
+pub mod provisioning;
+pub mod quiet_hours;
+pub mod radar;
+pub mod registry;
+pub mod reporting;
+pub mod rtc;
+pub mod schedule;
+#[cfg(all(feature = "sdcard", target_os = "espidf"))]
+pub mod sdcard;
+#[cfg(target_os = "espidf")]
+pub mod selftest;
+pub mod sensor_memory;
+pub mod sensors;
+#[cfg(feature = "simulator")]
+pub mod sim;
+pub mod shutdown;
+pub mod sinks;
+pub mod snmp;
+pub mod snore;
+pub mod spool;
+pub mod tasks;
+pub mod tls;
+#[cfg(target_os = "espidf")]
+pub mod timesync;
+pub mod watchdog;
+#[cfg(target_os = "espidf")]
+pub mod wifi;
+
+pub use error::{Error, Result};
@@ -0,0 +1,106 @@
+//! Photometric helpers for the light sensors.
+
+// CIE 1931 colour matching functions (x̄, ȳ, z̄) at the centres of the AS7341 F1-F8 channels,
+// 415, 445, 480, 515, 555, 590, 630 and 680 nm
+const CHANNEL_CMF: [(f32, f32, f32); 8] = [
+    (0.0776, 0.0022, 0.3713),
+    (0.3481, 0.0298, 1.7826),
+    (0.0956, 0.1390, 0.8130),
+    (0.0291, 0.6082, 0.1117),
+    (0.5121, 1.0000, 0.0058),
+    (1.0263, 0.7570, 0.0011),
+    (0.6424, 0.2650, 0.0001),
+    (0.0468, 0.0170, 0.0000),
+];
+
+/// Correlated colour temperature in K from the eight visible channels, `None` in the dark.
+/// The channels are treated as point samples of the spectrum, which is good enough to tell warm
+/// from blue-heavy light but not for colorimetry.
+pub fn correlated_color_temperature(channels: &[f32; 8]) -> Option<f32> {
+    let (x, y, z) = channels
+        .iter()
+        .zip(CHANNEL_CMF)
+        .fold((0.0, 0.0, 0.0), |(x, y, z), (value, (x_bar, y_bar, z_bar))| {
+            (x + value * x_bar, y + value * y_bar, z + value * z_bar)
+        });
+    let total = x + y + z;
+    if total <= 0.0 {
+        return None;
+    }
+    // McCamy's approximation from the chromaticity coordinates
+    let n = (x / total - 0.3320) / (0.1858 - y / total);
+    let cct = 449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33;
+    Some(cct).filter(|cct| cct.is_finite() && *cct > 0.0)
+}
+
+// TSL2591 counts per lux at 1x gain and 1 ms of integration, the device factor from the datasheet
+const TSL2591_LUX_DF: f32 = 408.0;
+
+/// Lux from the TSL2591 full spectrum (`ch0`) and infrared (`ch1`) counts, with `gain` as the
+/// multiplier of the gain setting.
+pub fn tsl2591_lux(ch0: u16, ch1: u16, gain: f32, integration_ms: f32) -> f32 {
+    if ch0 == 0 {
+        return 0.0;
+    }
+    let (ch0, ch1) = (ch0 as f32, ch1 as f32);
+    let counts_per_lux = integration_ms * gain / TSL2591_LUX_DF;
+    ((ch0 - ch1) * (1.0 - ch1 / ch0) / counts_per_lux).max(0.0)
+}
+
+/// Low and high interrupt thresholds around a `ch0` reading, so that a change by more than
+/// `change_percent` either way raises the interrupt. The band is at least `min_counts` wide, in
+/// the dark a few counts of noise would be a big change otherwise.
+pub fn threshold_band(ch0: u16, change_percent: f32, min_counts: u16) -> (u16, u16) {
+    let factor = 1.0 + change_percent.max(0.0) / 100.0;
+    let low = (ch0 as f32 / factor) as u16;
+    let high = (ch0 as f32 * factor).min(u16::MAX as f32) as u16;
+    (low.min(ch0.saturating_sub(min_counts)), high.max(ch0.saturating_add(min_counts)))
+}
+
+/// Lux from the MAX44009's lux high and low registers, `None` when it's over range.
+pub fn max44009_lux(high: u8, low: u8) -> Option<f32> {
+    let exponent = high >> 4;
+    if exponent == 0x0F {
+        return None;
+    }
+    let mantissa = ((high & 0x0F) << 4 | (low & 0x0F)) as u32;
+    Some((mantissa << exponent) as f32 * 0.045)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_warm_from_cold_light() {
+        let flat = correlated_color_temperature(&[1.0; 8]).unwrap();
+        let warm = correlated_color_temperature(&[0.1, 0.2, 0.3, 0.5, 0.8, 1.0, 1.2, 1.2]).unwrap();
+        assert!((5500.0..6500.0).contains(&flat));
+        assert!((2500.0..3500.0).contains(&warm));
+        assert_eq!(correlated_color_temperature(&[0.0; 8]), None);
+    }
+
+    #[test]
+    fn computes_lux_from_tsl2591_counts() {
+        assert_eq!(tsl2591_lux(0, 0, 1.0, 100.0), 0.0);
+        let lux = tsl2591_lux(1000, 200, 1.0, 100.0);
+        assert!((lux - 2611.2).abs() < 0.1, "{}", lux);
+        // Same light at 25x gain
+        assert!((tsl2591_lux(25000, 5000, 25.0, 100.0) - lux).abs() < 0.1);
+    }
+
+    #[test]
+    fn decodes_max44009_readings() {
+        assert_eq!(max44009_lux(0x00, 0x01), Some(0.045));
+        // Full scale, 188 000 lx
+        assert!((max44009_lux(0xEF, 0x0F).unwrap() - 188_006.4).abs() < 0.5);
+        assert_eq!(max44009_lux(0xF0, 0x00), None);
+    }
+
+    #[test]
+    fn keeps_a_minimum_threshold_band() {
+        assert_eq!(threshold_band(1000, 100.0, 10), (500, 2000));
+        assert_eq!(threshold_band(4, 100.0, 10), (0, 14));
+        assert_eq!(threshold_band(60000, 100.0, 10), (30000, u16::MAX));
+    }
+}
@@ -0,0 +1,265 @@
+//! LoRa point-to-point uplink, for nodes far outside Wi-Fi range talking to a receiver node or
+//! gateway of our own. Every batch goes out as frames of at most `lora.max_payload` bytes:
+//!
+//! - version (1 byte), the FNV-1a hash of the device ID (4), a frame counter (2) and the time of
+//!   the round in s since the Unix epoch (4), little-endian
+//! - then per measurement the 16-bit FNV-1a hash of its name (2) and the value as an `f32` (4)
+//!
+//! The receiver hashes the metric names it knows to tell them apart. Every transmission counts
+//! against the duty cycle in `lora.duty_cycle_percent` over the last hour, a batch that would
+//! exceed it stays buffered for a later upload.
+//!
+//! The drivers for the SX1276 and the SX1262 are kept apart from the framing, so it can be tested
+//! on the host.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, LoraConfig};
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{Network, Sink};
+use crate::sensors::Measurement;
+
+#[cfg(all(feature = "lora", target_os = "espidf"))]
+mod sx1262;
+#[cfg(all(feature = "lora", target_os = "espidf"))]
+mod sx1276;
+
+#[cfg(all(feature = "lora", target_os = "espidf"))]
+pub use sx1262::Sx1262;
+#[cfg(all(feature = "lora", target_os = "espidf"))]
+pub use sx1276::Sx1276;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 11;
+pub const RECORD_LEN: usize = 6;
+const PREAMBLE_SYMBOLS: u16 = 8;
+const DUTY_CYCLE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Sends a frame with the settings in `lora`, returns once it's out.
+pub trait Radio {
+    fn transmit(&mut self, config: &LoraConfig, payload: &[u8]) -> Result<()>;
+
+    /// Listens with inverted IQ, as gateways send, for a frame that starts between `at` and
+    /// `at + window`. None if there was none.
+    fn receive(&mut self, _config: &LoraConfig, _at: Instant, _window: Duration) -> Result<Option<Vec<u8>>> {
+        Err(Error::failed(Phase::Upload, "The radio can't receive"))
+    }
+}
+
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0x811C_9DC5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Folded to 16 bits, for the metric names.
+pub fn name_hash(name: &str) -> u16 {
+    let hash = fnv1a(name.as_bytes());
+    ((hash >> 16) ^ (hash & 0xFFFF)) as u16
+}
+
+/// The frames of a round, values that are NaN are left out.
+pub fn encode(
+    device_id: &str,
+    counter: &mut u16,
+    timestamp_ms: u64,
+    measurements: &[Measurement],
+    max_payload: usize,
+) -> Vec<Vec<u8>> {
+    let per_frame = (max_payload.saturating_sub(HEADER_LEN) / RECORD_LEN).max(1);
+    let values: Vec<&Measurement> = measurements.iter().filter(|measurement| !measurement.value.is_nan()).collect();
+    values
+        .chunks(per_frame)
+        .map(|chunk| {
+            let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len() * RECORD_LEN);
+            frame.push(VERSION);
+            frame.extend_from_slice(&fnv1a(device_id.as_bytes()).to_le_bytes());
+            frame.extend_from_slice(&counter.to_le_bytes());
+            frame.extend_from_slice(&((timestamp_ms / 1000) as u32).to_le_bytes());
+            for measurement in chunk {
+                frame.extend_from_slice(&record(measurement));
+            }
+            *counter = counter.wrapping_add(1);
+            frame
+        })
+        .collect()
+}
+
+/// The hash of the name and the value.
+pub fn record(measurement: &Measurement) -> [u8; RECORD_LEN] {
+    let mut record = [0; RECORD_LEN];
+    record[..2].copy_from_slice(&name_hash(&measurement.name).to_le_bytes());
+    record[2..].copy_from_slice(&measurement.value.to_le_bytes());
+    record
+}
+
+pub fn symbol_time(config: &LoraConfig) -> Duration {
+    Duration::from_secs_f64((1u32 << config.spreading_factor) as f64 / (config.bandwidth_khz as f64 * 1000.0))
+}
+
+/// Time on air of a frame with an explicit header and CRC, after Semtech's AN1200.13.
+pub fn airtime(config: &LoraConfig, payload_len: usize) -> Duration {
+    let sf = config.spreading_factor as f64;
+    let symbol_sec = symbol_time(config).as_secs_f64();
+    let low_data_rate = if low_data_rate_optimize(config) { 1.0 } else { 0.0 };
+    let coding_rate = (config.coding_rate - 4) as f64;
+    let bits = 8.0 * payload_len as f64 - 4.0 * sf + 28.0 + 16.0;
+    let payload_symbols = 8.0 + ((bits / (4.0 * (sf - 2.0 * low_data_rate))).ceil() * (coding_rate + 4.0)).max(0.0);
+    let preamble_sec = (PREAMBLE_SYMBOLS as f64 + 4.25) * symbol_sec;
+    Duration::from_secs_f64(preamble_sec + payload_symbols * symbol_sec)
+}
+
+/// Needed once a symbol takes longer than 16 ms.
+pub fn low_data_rate_optimize(config: &LoraConfig) -> bool {
+    (1u32 << config.spreading_factor) as f32 / config.bandwidth_khz as f32 > 16.0
+}
+
+/// Time on air over the last hour.
+#[derive(Default)]
+pub struct DutyCycle {
+    sent: VecDeque<(Instant, Duration)>,
+}
+
+impl DutyCycle {
+    /// Whether `airtime` more keeps to `percent` of the last hour, the ones before are forgotten.
+    pub fn allows(&mut self, now: Instant, percent: f32, airtime: Duration) -> bool {
+        while self.sent.front().is_some_and(|(at, _)| now.duration_since(*at) >= DUTY_CYCLE_WINDOW) {
+            self.sent.pop_front();
+        }
+        let used: Duration = self.sent.iter().map(|(_, airtime)| *airtime).sum();
+        used + airtime <= DUTY_CYCLE_WINDOW.mul_f32(percent / 100.0)
+    }
+
+    pub fn record(&mut self, now: Instant, airtime: Duration) {
+        self.sent.push_back((now, airtime));
+    }
+}
+
+pub struct LoraSink<'a> {
+    radio: Box<dyn Radio + Send + 'a>,
+    counter: u16,
+    duty_cycle: DutyCycle,
+    bytes_written: u64,
+}
+
+impl<'a> LoraSink<'a> {
+    pub fn new(radio: Box<dyn Radio + Send + 'a>) -> Self {
+        LoraSink {
+            radio,
+            counter: 0,
+            duty_cycle: DutyCycle::default(),
+            bytes_written: 0,
+        }
+    }
+}
+
+impl Sink for LoraSink<'_> {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let lora = &config.lora;
+        let mut counter = self.counter;
+        let frames = encode(config.device_id(), &mut counter, timestamp_ms, measurements, lora.max_payload);
+        let total = frames.iter().map(|frame| airtime(lora, frame.len())).sum();
+        if !self.duty_cycle.allows(Instant::now(), lora.duty_cycle_percent, total) {
+            return Err(Error::failed(
+                Phase::Upload,
+                format!("{:.1} s on air would exceed the {}% duty cycle", total.as_secs_f32(), lora.duty_cycle_percent),
+            ));
+        }
+        for frame in &frames {
+            self.radio.transmit(lora, frame)?;
+            self.duty_cycle.record(Instant::now(), airtime(lora, frame.len()));
+            self.bytes_written += frame.len() as u64;
+        }
+        self.counter = counter;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    fn name(&self) -> &'static str {
+        "lora"
+    }
+}
+
+/// The network of a node whose only uplink is the radio, there's no link to bring up.
+pub struct RadioOnly;
+
+impl Network for RadioOnly {
+    fn connect(&mut self, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockRadio {
+        frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Radio for MockRadio {
+        fn transmit(&mut self, _config: &LoraConfig, payload: &[u8]) -> Result<()> {
+            self.frames.lock().unwrap().push(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    fn round(count: usize) -> Vec<Measurement> {
+        (0..count)
+            .map(|index| Measurement {
+                name: format!("metric_{}", index).into(),
+                value: index as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_batches_into_frames() {
+        let mut counter = u16::MAX;
+        let mut measurements = round(5);
+        measurements[1].value = f32::NAN;
+        let frames = encode("garden", &mut counter, 1_700_000_000_500, &measurements, HEADER_LEN + 3 * RECORD_LEN);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].len(), HEADER_LEN + 3 * RECORD_LEN);
+        assert_eq!(frames[1].len(), HEADER_LEN + RECORD_LEN);
+        assert_eq!(&frames[0][1..5], &fnv1a(b"garden").to_le_bytes());
+        assert_eq!(&frames[1][5..7], &[0, 0]);
+        assert_eq!(&frames[0][7..11], &1_700_000_000u32.to_le_bytes());
+        assert_eq!(&frames[0][11..13], &name_hash("metric_0").to_le_bytes());
+        assert_eq!(&frames[1][13..17], &4.0f32.to_le_bytes());
+        assert_eq!(counter, 1);
+    }
+
+    #[test]
+    fn keeps_to_the_duty_cycle() {
+        let mut config = Config::default();
+        config.lora.spreading_factor = 12;
+        config.lora.duty_cycle_percent = 1.0;
+        // 2.466 s on air at SF12 and 125 kHz
+        assert_eq!(airtime(&config.lora, 51).as_millis(), 2465);
+        let airtime = airtime(&config.lora, 47);
+        assert!(low_data_rate_optimize(&config.lora));
+
+        let radio = MockRadio::default();
+        let mut sink = LoraSink::new(Box::new(radio.clone()));
+        let mut sent = 0;
+        while sink.send(&config, 0, &round(6)).is_ok() {
+            sent += 1;
+        }
+        // 36 s an hour
+        assert_eq!(sent, (36.0 / airtime.as_secs_f32()) as usize);
+        assert_eq!(radio.frames.lock().unwrap().len(), sent);
+        assert_eq!(sink.bytes_written(), sent as u64 * 47);
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::{Operation, SpiDevice};
+
+use super::{airtime, low_data_rate_optimize, Radio, PREAMBLE_SYMBOLS};
+use crate::config::LoraConfig;
+use crate::error::{Error, Phase, Result};
+
+const CRYSTAL_HZ: u64 = 32_000_000;
+const BUSY_TIMEOUT: Duration = Duration::from_millis(100);
+
+const CLEAR_IRQ_STATUS: u8 = 0x02;
+const SET_DIO_IRQ_PARAMS: u8 = 0x08;
+const WRITE_REGISTER: u8 = 0x0D;
+const WRITE_BUFFER: u8 = 0x0E;
+const GET_IRQ_STATUS: u8 = 0x12;
+const GET_RX_BUFFER_STATUS: u8 = 0x13;
+const READ_REGISTER: u8 = 0x1D;
+const READ_BUFFER: u8 = 0x1E;
+const SET_STANDBY: u8 = 0x80;
+const SET_RX: u8 = 0x82;
+const SET_TX: u8 = 0x83;
+const SET_SLEEP: u8 = 0x84;
+const SET_RF_FREQUENCY: u8 = 0x86;
+const SET_PACKET_TYPE: u8 = 0x8A;
+const SET_MODULATION_PARAMS: u8 = 0x8B;
+const SET_PACKET_PARAMS: u8 = 0x8C;
+const SET_TX_PARAMS: u8 = 0x8E;
+const SET_BUFFER_BASE_ADDRESS: u8 = 0x8F;
+const SET_PA_CONFIG: u8 = 0x95;
+const SET_DIO2_AS_RF_SWITCH: u8 = 0x9D;
+
+const REG_IQ_POLARITY: u16 = 0x0736;
+const REG_SYNC_WORD: u16 = 0x0740;
+const IRQ_TX_DONE: u16 = 0x0001;
+const IRQ_RX_DONE: u16 = 0x0002;
+const IRQ_HEADER_VALID: u16 = 0x0010;
+const IRQ_CRC_ERROR: u16 = 0x0040;
+const IRQ_TIMEOUT: u16 = 0x0200;
+// Taken by a reset and the setup before a receive window
+const RX_SETUP: Duration = Duration::from_millis(20);
+
+/// SX1262 with DIO2 switching the antenna, as on most modules. Reset before every frame and put
+/// into cold sleep after it.
+pub struct Sx1262<SPI, RST, BUSY, DELAY> {
+    spi: SPI,
+    rst: RST,
+    busy: BUSY,
+    delay: DELAY,
+}
+
+impl<SPI, RST, BUSY, DELAY> Sx1262<SPI, RST, BUSY, DELAY>
+where
+    SPI: SpiDevice,
+    RST: OutputPin,
+    BUSY: InputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: SPI, rst: RST, busy: BUSY, delay: DELAY) -> Self {
+        Sx1262 { spi, rst, busy, delay }
+    }
+
+    fn init(&mut self, config: &LoraConfig, payload_len: usize, invert_iq: bool) -> Result<()> {
+        self.rst.set_low().map_err(failed("Failed to reset"))?;
+        self.delay.delay_ms(1);
+        self.rst.set_high().map_err(failed("Failed to reset"))?;
+        self.delay.delay_ms(5);
+
+        // Standby on the RC oscillator
+        self.command(SET_STANDBY, &[0x00])?;
+        self.command(SET_DIO2_AS_RF_SWITCH, &[0x01])?;
+        // LoRa
+        self.command(SET_PACKET_TYPE, &[0x01])?;
+        let frf = ((config.frequency_hz as u64) << 25) / CRYSTAL_HZ;
+        self.command(SET_RF_FREQUENCY, &(frf as u32).to_be_bytes())?;
+        // Up to 22 dBm, the SX1262's high power PA
+        self.command(SET_PA_CONFIG, &[0x04, 0x07, 0x00, 0x01])?;
+        // 200 µs ramp
+        self.command(SET_TX_PARAMS, &[config.tx_power_dbm.clamp(-9, 22) as u8, 0x04])?;
+        self.command(SET_BUFFER_BASE_ADDRESS, &[0x00, 0x00])?;
+        let bandwidth = match config.bandwidth_khz {
+            250 => 0x05,
+            500 => 0x06,
+            _ => 0x04,
+        };
+        let low_data_rate = low_data_rate_optimize(config) as u8;
+        self.command(
+            SET_MODULATION_PARAMS,
+            &[config.spreading_factor, bandwidth, config.coding_rate - 4, low_data_rate],
+        )?;
+        let [preamble_msb, preamble_lsb] = PREAMBLE_SYMBOLS.to_be_bytes();
+        // Explicit header, CRC on
+        let iq = invert_iq as u8;
+        self.command(SET_PACKET_PARAMS, &[preamble_msb, preamble_lsb, 0x00, payload_len as u8, 0x01, iq])?;
+        // Inverted IQ only works with bit 2 of this register cleared, see the datasheet's errata
+        let polarity = self.read_register(REG_IQ_POLARITY)?;
+        self.write_register(REG_IQ_POLARITY, if invert_iq { polarity & !0x04 } else { polarity | 0x04 })?;
+        // The one byte sync word of the SX127x, spread over two
+        let sync_word = [(config.sync_word & 0xF0) | 0x04, (config.sync_word << 4) | 0x04];
+        self.write_register(REG_SYNC_WORD, sync_word[0])?;
+        self.write_register(REG_SYNC_WORD + 1, sync_word[1])?;
+        let irqs = IRQ_TX_DONE | IRQ_RX_DONE | IRQ_HEADER_VALID | IRQ_CRC_ERROR | IRQ_TIMEOUT;
+        let [mask_msb, mask_lsb] = irqs.to_be_bytes();
+        self.command(SET_DIO_IRQ_PARAMS, &[mask_msb, mask_lsb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+    }
+
+    fn read_register(&mut self, address: u16) -> Result<u8> {
+        self.wait_ready()?;
+        let [address_msb, address_lsb] = address.to_be_bytes();
+        let mut buffer = [READ_REGISTER, address_msb, address_lsb, 0x00, 0x00];
+        self.spi.transfer_in_place(&mut buffer).map_err(failed("Failed to read a register"))?;
+        Ok(buffer[4])
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) -> Result<()> {
+        let [address_msb, address_lsb] = address.to_be_bytes();
+        self.command(WRITE_REGISTER, &[address_msb, address_lsb, value])
+    }
+
+    fn command(&mut self, opcode: u8, data: &[u8]) -> Result<()> {
+        self.wait_ready()?;
+        self.spi
+            .transaction(&mut [Operation::Write(&[opcode]), Operation::Write(data)])
+            .map_err(failed("Failed to send a command"))
+    }
+
+    fn irq_status(&mut self) -> Result<u16> {
+        self.wait_ready()?;
+        let mut buffer = [GET_IRQ_STATUS, 0x00, 0x00, 0x00];
+        self.spi.transfer_in_place(&mut buffer).map_err(failed("Failed to read the IRQ status"))?;
+        Ok(u16::from_be_bytes([buffer[2], buffer[3]]))
+    }
+
+    fn wait_ready(&mut self) -> Result<()> {
+        let started = Instant::now();
+        while self.busy.is_high().map_err(failed("Failed to read BUSY"))? {
+            if started.elapsed() > BUSY_TIMEOUT {
+                return Err(Error::failed(Phase::Upload, "SX1262 stuck busy"));
+            }
+            self.delay.delay_us(100);
+        }
+        Ok(())
+    }
+}
+
+impl<SPI, RST, BUSY, DELAY> Radio for Sx1262<SPI, RST, BUSY, DELAY>
+where
+    SPI: SpiDevice,
+    RST: OutputPin,
+    BUSY: InputPin,
+    DELAY: DelayNs,
+{
+    fn transmit(&mut self, config: &LoraConfig, payload: &[u8]) -> Result<()> {
+        self.init(config, payload.len(), false)?;
+        let mut buffer = Vec::with_capacity(payload.len() + 1);
+        buffer.push(0x00);
+        buffer.extend_from_slice(payload);
+        self.command(WRITE_BUFFER, &buffer)?;
+        // The chip's own timeout, as a backstop to ours
+        let timeout = airtime(config, payload.len()) * 2 + Duration::from_millis(100);
+        self.command(SET_TX, &steps(timeout))?;
+
+        let started = Instant::now();
+        let status = loop {
+            let status = self.irq_status()?;
+            if status & (IRQ_TX_DONE | IRQ_TIMEOUT) != 0 || started.elapsed() > timeout {
+                break status;
+            }
+            self.delay.delay_ms(5);
+        };
+        self.command(CLEAR_IRQ_STATUS, &[0xFF, 0xFF])?;
+        self.command(SET_SLEEP, &[0x00])?;
+        if status & IRQ_TX_DONE == 0 {
+            return Err(Error::failed(Phase::Upload, format!("SX1262 didn't finish sending, IRQ {:#06x}", status)));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, config: &LoraConfig, at: Instant, window: Duration) -> Result<Option<Vec<u8>>> {
+        if let Some(wait) = (at - RX_SETUP).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        self.init(config, u8::MAX as usize, true)?;
+        // The chip's timer stops once a header comes in, the frame is then received to the end
+        let window = window + at.saturating_duration_since(Instant::now());
+        self.command(SET_RX, &steps(window))?;
+
+        let longest = Instant::now() + window + airtime(config, u8::MAX as usize);
+        let status = loop {
+            let status = self.irq_status()?;
+            if status & (IRQ_RX_DONE | IRQ_TIMEOUT) != 0 || Instant::now() > longest {
+                break status;
+            }
+            self.delay.delay_ms(2);
+        };
+        let mut frame = None;
+        if status & IRQ_RX_DONE != 0 && status & IRQ_CRC_ERROR == 0 {
+            self.wait_ready()?;
+            let mut buffer = [GET_RX_BUFFER_STATUS, 0x00, 0x00, 0x00];
+            self.spi.transfer_in_place(&mut buffer).map_err(failed("Failed to read the buffer status"))?;
+            let (len, start) = (buffer[2], buffer[3]);
+            let mut data = vec![0; len as usize];
+            self.wait_ready()?;
+            self.spi
+                .transaction(&mut [Operation::Write(&[READ_BUFFER, start, 0x00]), Operation::Read(&mut data)])
+                .map_err(failed("Failed to read the buffer"))?;
+            frame = Some(data);
+        }
+        self.command(CLEAR_IRQ_STATUS, &[0xFF, 0xFF])?;
+        self.command(SET_SLEEP, &[0x00])?;
+        Ok(frame)
+    }
+}
+
+/// A timeout in the chip's steps of 15.625 µs.
+fn steps(timeout: Duration) -> [u8; 3] {
+    let steps = (timeout.as_micros() * 64 / 1000).min(0xFF_FFFF) as u32;
+    let [_, high, middle, low] = steps.to_be_bytes();
+    [high, middle, low]
+}
+
+fn failed<E: fmt::Debug>(message: &'static str) -> impl FnOnce(E) -> Error {
+    move |err| Error::failed(Phase::Upload, format!("SX1262: {}: {:?}", message, err))
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::{Operation, SpiDevice};
+
+use super::{airtime, low_data_rate_optimize, Radio, PREAMBLE_SYMBOLS};
+use crate::config::LoraConfig;
+use crate::error::{Error, Phase, Result};
+
+const CRYSTAL_HZ: u64 = 32_000_000;
+const SILICON_VERSION: u8 = 0x12;
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0E;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_INVERT_IQ: u8 = 0x33;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_INVERT_IQ_2: u8 = 0x3B;
+const REG_DIO_MAPPING_1: u8 = 0x40;
+const REG_VERSION: u8 = 0x42;
+
+// LoRa mode, with the low frequency registers off
+const MODE_SLEEP: u8 = 0x80;
+const MODE_STANDBY: u8 = 0x81;
+const MODE_TX: u8 = 0x83;
+const MODE_RX_CONTINUOUS: u8 = 0x85;
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_CRC_ERROR: u8 = 0x20;
+const IRQ_VALID_HEADER: u8 = 0x10;
+const IRQ_TX_DONE: u8 = 0x08;
+// Taken by a reset and the setup before a receive window
+const RX_SETUP: Duration = Duration::from_millis(20);
+
+/// SX1276 (RFM95W) on PA_BOOST, reset before every frame and put to sleep after it. TxDone and
+/// RxDone are mapped to DIO0.
+pub struct Sx1276<SPI, RST, DIO0, DELAY> {
+    spi: SPI,
+    rst: RST,
+    dio0: DIO0,
+    delay: DELAY,
+}
+
+impl<SPI, RST, DIO0, DELAY> Sx1276<SPI, RST, DIO0, DELAY>
+where
+    SPI: SpiDevice,
+    RST: OutputPin,
+    DIO0: InputPin,
+    DELAY: DelayNs,
+{
+    pub fn new(spi: SPI, rst: RST, dio0: DIO0, delay: DELAY) -> Self {
+        Sx1276 { spi, rst, dio0, delay }
+    }
+
+    fn init(&mut self, config: &LoraConfig, invert_iq: bool) -> Result<()> {
+        self.rst.set_low().map_err(failed("Failed to reset"))?;
+        self.delay.delay_ms(1);
+        self.rst.set_high().map_err(failed("Failed to reset"))?;
+        self.delay.delay_ms(10);
+        let version = self.read(REG_VERSION)?;
+        if version != SILICON_VERSION {
+            return Err(Error::failed(Phase::Upload, format!("No SX1276 found, version {:#04x}", version)));
+        }
+
+        // The mode can only be switched to LoRa while asleep
+        self.write(REG_OP_MODE, &[MODE_SLEEP])?;
+        self.write(REG_OP_MODE, &[MODE_STANDBY])?;
+        let frf = ((config.frequency_hz as u64) << 19) / CRYSTAL_HZ;
+        self.write(REG_FRF_MSB, &frf.to_be_bytes()[5..])?;
+        // PA_BOOST, 2 to 17 dBm
+        let power = config.tx_power_dbm.clamp(2, 17) as u8;
+        self.write(REG_PA_CONFIG, &[0x80 | 0x70 | (power - 2)])?;
+        let bandwidth = match config.bandwidth_khz {
+            250 => 0x08,
+            500 => 0x09,
+            _ => 0x07,
+        };
+        // Explicit header
+        self.write(REG_MODEM_CONFIG_1, &[bandwidth << 4 | (config.coding_rate - 4) << 1])?;
+        // CRC on
+        self.write(REG_MODEM_CONFIG_2, &[config.spreading_factor << 4 | 0x04])?;
+        // AGC on
+        let low_data_rate = if low_data_rate_optimize(config) { 0x08 } else { 0x00 };
+        self.write(REG_MODEM_CONFIG_3, &[low_data_rate | 0x04])?;
+        self.write(REG_PREAMBLE_MSB, &PREAMBLE_SYMBOLS.to_be_bytes())?;
+        self.write(REG_SYNC_WORD, &[config.sync_word])?;
+        let (invert_iq, invert_iq_2) = if invert_iq { (0x67, 0x19) } else { (0x27, 0x1D) };
+        self.write(REG_INVERT_IQ, &[invert_iq])?;
+        self.write(REG_INVERT_IQ_2, &[invert_iq_2])
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8> {
+        let mut buffer = [register & 0x7F, 0x00];
+        self.spi.transfer_in_place(&mut buffer).map_err(failed("Failed to read a register"))?;
+        Ok(buffer[1])
+    }
+
+    fn read_fifo(&mut self, data: &mut [u8]) -> Result<()> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[REG_FIFO]), Operation::Read(data)])
+            .map_err(failed("Failed to read the FIFO"))
+    }
+
+    fn write(&mut self, register: u8, data: &[u8]) -> Result<()> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[register | 0x80]), Operation::Write(data)])
+            .map_err(failed("Failed to write a register"))
+    }
+
+    fn wait_sent(&mut self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        while !self.dio0.is_high().map_err(failed("Failed to read DIO0"))? {
+            if started.elapsed() > timeout {
+                return Err(Error::failed(Phase::Upload, "SX1276 didn't finish sending"));
+            }
+            self.delay.delay_ms(5);
+        }
+        Ok(())
+    }
+}
+
+impl<SPI, RST, DIO0, DELAY> Radio for Sx1276<SPI, RST, DIO0, DELAY>
+where
+    SPI: SpiDevice,
+    RST: OutputPin,
+    DIO0: InputPin,
+    DELAY: DelayNs,
+{
+    fn transmit(&mut self, config: &LoraConfig, payload: &[u8]) -> Result<()> {
+        self.init(config, false)?;
+        self.write(REG_DIO_MAPPING_1, &[0x40])?;
+        self.write(REG_FIFO_TX_BASE_ADDR, &[0x00])?;
+        self.write(REG_FIFO_ADDR_PTR, &[0x00])?;
+        self.write(REG_FIFO, payload)?;
+        self.write(REG_PAYLOAD_LENGTH, &[payload.len() as u8])?;
+        self.write(REG_OP_MODE, &[MODE_TX])?;
+        let sent = self.wait_sent(airtime(config, payload.len()) * 2 + Duration::from_millis(100));
+        let flags = self.read(REG_IRQ_FLAGS)?;
+        self.write(REG_IRQ_FLAGS, &[0xFF])?;
+        self.write(REG_OP_MODE, &[MODE_SLEEP])?;
+        sent?;
+        if flags & IRQ_TX_DONE == 0 {
+            return Err(Error::failed(Phase::Upload, format!("SX1276 reported {:#04x} rather than TxDone", flags)));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, config: &LoraConfig, at: Instant, window: Duration) -> Result<Option<Vec<u8>>> {
+        if let Some(wait) = (at - RX_SETUP).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        self.init(config, true)?;
+        self.write(REG_DIO_MAPPING_1, &[0x00])?;
+        self.write(REG_FIFO_RX_BASE_ADDR, &[0x00])?;
+        self.write(REG_FIFO_ADDR_PTR, &[0x00])?;
+        self.write(REG_OP_MODE, &[MODE_RX_CONTINUOUS])?;
+
+        // A frame whose header came in within the window is waited for to the end
+        let closes = at + window;
+        let longest = closes + airtime(config, u8::MAX as usize);
+        let flags = loop {
+            let flags = self.read(REG_IRQ_FLAGS)?;
+            let now = Instant::now();
+            if flags & IRQ_RX_DONE != 0 || now > longest || (now > closes && flags & IRQ_VALID_HEADER == 0) {
+                break flags;
+            }
+            self.delay.delay_ms(2);
+        };
+        let mut frame = None;
+        if flags & IRQ_RX_DONE != 0 && flags & IRQ_CRC_ERROR == 0 {
+            let len = self.read(REG_RX_NB_BYTES)?;
+            let start = self.read(REG_FIFO_RX_CURRENT_ADDR)?;
+            self.write(REG_FIFO_ADDR_PTR, &[start])?;
+            let mut data = vec![0; len as usize];
+            self.read_fifo(&mut data)?;
+            frame = Some(data);
+        }
+        self.write(REG_IRQ_FLAGS, &[0xFF])?;
+        self.write(REG_OP_MODE, &[MODE_SLEEP])?;
+        Ok(frame)
+    }
+}
+
+fn failed<E: fmt::Debug>(message: &'static str) -> impl FnOnce(E) -> Error {
+    move |err| Error::failed(Phase::Upload, format!("SX1276: {}: {:?}", message, err))
+}
@@ -0,0 +1,683 @@
+//! LoRaWAN 1.0.3 class A over the radio of the `lora` uplink, for sites without a gateway of our
+//! own but in reach of The Things Network. The node joins over the air with the keys in `lorawan`
+//! on its first upload and keeps the session on flash, every batch then goes out on the channels
+//! the network handed out, as unconfirmed or confirmed uplinks:
+//!
+//! - `cayenne_lpp`: one entry per measurement, on the channel of its position in the round, which
+//!   stays the same while the sensors do. Temperature, humidity, pressure and light have types of
+//!   their own, the rest go out as analog inputs or, beyond ±327.67, as whole numbers in the
+//!   illuminance type.
+//! - `compact`: the time of the round in s since the Unix epoch, then per measurement the hash of
+//!   its name and the value as in the point-to-point frames, for a decoder of our own.
+//!
+//! Confirmed uplinks that aren't acknowledged leave the batch buffered. ADR stays off and MAC
+//! commands from the network are ignored, the data rate is `lora.spreading_factor`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, LoraConfig, LorawanCodec, LorawanConfig};
+use crate::error::{Error, Phase, Result};
+use crate::lora::{airtime, record, symbol_time, DutyCycle, Radio, RECORD_LEN};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+/// On the storage partition, next to the configuration.
+pub const LORAWAN_PATH: &str = "/storage/lorawan.json";
+
+const PUBLIC_SYNC_WORD: u8 = 0x34;
+const JOIN_ACCEPT_DELAY: Duration = Duration::from_secs(5);
+// MHDR, DevAddr, FCtrl, FCnt, FPort and MIC
+const OVERHEAD: usize = 13;
+// The frame counter is saved this often, a session read back skips as many
+const FCNT_SAVE_EVERY: u32 = 16;
+// Confirmed uplinks in a row that weren't acknowledged before the node joins again
+const REJOIN_AFTER: u32 = 8;
+
+const MTYPE_JOIN_REQUEST: u8 = 0x00;
+const MTYPE_JOIN_ACCEPT: u8 = 0x20;
+const MTYPE_UNCONFIRMED_UP: u8 = 0x40;
+const MTYPE_UNCONFIRMED_DOWN: u8 = 0x60;
+const MTYPE_CONFIRMED_UP: u8 = 0x80;
+const MTYPE_CONFIRMED_DOWN: u8 = 0xA0;
+const FCTRL_ACK: u8 = 0x20;
+
+const LPP_ANALOG_INPUT: u8 = 0x02;
+const LPP_ILLUMINANCE: u8 = 0x65;
+const LPP_TEMPERATURE: u8 = 0x67;
+const LPP_HUMIDITY: u8 = 0x68;
+const LPP_BAROMETER: u8 = 0x73;
+const HPA_PER_MMHG: f32 = 1.333_224;
+
+/// What has to survive a reboot, a network won't accept a DevNonce it has seen.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct State {
+    pub dev_nonce: u16,
+    pub session: Option<Session>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub dev_addr: u32,
+    pub nwk_s_key: [u8; 16],
+    pub app_s_key: [u8; 16],
+    pub fcnt_up: u32,
+    /// The next one expected
+    pub fcnt_down: u32,
+    pub rx1_delay_sec: u8,
+    pub rx2_spreading_factor: u8,
+    /// Handed out in the join accept, the join channels otherwise
+    pub channels_hz: Vec<u32>,
+}
+
+/// What a downlink said, once it checked out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Downlink {
+    pub ack: bool,
+    pub port: Option<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Parses `N` bytes written in hex, none if there are more or fewer or they aren't hex.
+pub fn hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    let value = value.trim();
+    if value.len() != N * 2 || !value.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn encrypt_block(key: &[u8; 16], block: &mut [u8; 16]) {
+    let cipher = Aes128::new(key.into());
+    cipher.encrypt_block(block.into());
+}
+
+fn mic(key: &[u8; 16], parts: &[&[u8]]) -> [u8; 4] {
+    let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(key).expect("AES-128 keys are 16 bytes");
+    for part in parts {
+        mac.update(part);
+    }
+    let tag = mac.finalize().into_bytes();
+    [tag[0], tag[1], tag[2], tag[3]]
+}
+
+/// A JoinRequest for the EUIs as they're written, most significant byte first.
+pub fn join_request(join_eui: &[u8; 8], dev_eui: &[u8; 8], dev_nonce: u16, app_key: &[u8; 16]) -> Vec<u8> {
+    let mut frame = vec![MTYPE_JOIN_REQUEST];
+    frame.extend(join_eui.iter().rev());
+    frame.extend(dev_eui.iter().rev());
+    frame.extend_from_slice(&dev_nonce.to_le_bytes());
+    let mic = mic(app_key, &[&frame]);
+    frame.extend_from_slice(&mic);
+    frame
+}
+
+/// The session of a JoinAccept, with the session keys derived for `dev_nonce`.
+pub fn join_accept(frame: &[u8], app_key: &[u8; 16], dev_nonce: u16, join: &LorawanConfig) -> Result<Session> {
+    if frame.first() != Some(&MTYPE_JOIN_ACCEPT) || !matches!(frame.len(), 17 | 33) {
+        return Err(Error::failed(Phase::Upload, "Not a JoinAccept"));
+    }
+    // The network encrypts with AES decryption, so that devices only need encryption
+    let mut plain = frame.to_vec();
+    for chunk in plain[1..].chunks_exact_mut(16) {
+        let block: &mut [u8; 16] = chunk.try_into().expect("Chunks of 16 bytes");
+        encrypt_block(app_key, block);
+    }
+    let (message, received_mic) = plain.split_at(plain.len() - 4);
+    if mic(app_key, &[message]) != received_mic {
+        return Err(Error::failed(Phase::Upload, "JoinAccept failed its MIC check"));
+    }
+
+    let (app_nonce, net_id) = (&message[1..4], &message[4..7]);
+    let dev_addr = u32::from_le_bytes(message[7..11].try_into().expect("Four bytes"));
+    let (dl_settings, rx_delay) = (message[11], message[12]);
+    let key = |kind: u8| {
+        let mut block = [0; 16];
+        block[0] = kind;
+        block[1..4].copy_from_slice(app_nonce);
+        block[4..7].copy_from_slice(net_id);
+        block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+        encrypt_block(app_key, &mut block);
+        block
+    };
+    let mut channels_hz = join.channels_hz.clone();
+    // A CFList of five more frequencies in steps of 100 Hz, zero for none
+    if message.len() == 29 && message[28] == 0 {
+        channels_hz.extend(
+            message[13..28]
+                .chunks_exact(3)
+                .map(|frequency| u32::from_le_bytes([frequency[0], frequency[1], frequency[2], 0]) * 100)
+                .filter(|frequency| *frequency != 0),
+        );
+    }
+    Ok(Session {
+        dev_addr,
+        nwk_s_key: key(0x01),
+        app_s_key: key(0x02),
+        fcnt_up: 0,
+        fcnt_down: 0,
+        rx1_delay_sec: (rx_delay & 0x0F).max(1),
+        // DR0 is SF12 and DR5 SF7 in EU868
+        rx2_spreading_factor: 12u8.saturating_sub(dl_settings & 0x0F).max(7),
+        channels_hz,
+    })
+}
+
+/// Encrypts or decrypts the FRMPayload of a frame in place.
+fn crypt(key: &[u8; 16], dev_addr: u32, fcnt: u32, downlink: bool, payload: &mut [u8]) {
+    for (index, chunk) in payload.chunks_mut(16).enumerate() {
+        let mut block = [0; 16];
+        block[0] = 0x01;
+        block[5] = downlink as u8;
+        block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+        block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        block[15] = index as u8 + 1;
+        encrypt_block(key, &mut block);
+        for (byte, key) in chunk.iter_mut().zip(block) {
+            *byte ^= key;
+        }
+    }
+}
+
+fn frame_mic(session: &Session, fcnt: u32, downlink: bool, message: &[u8]) -> [u8; 4] {
+    let mut b0 = [0; 16];
+    b0[0] = 0x49;
+    b0[5] = downlink as u8;
+    b0[6..10].copy_from_slice(&session.dev_addr.to_le_bytes());
+    b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b0[15] = message.len() as u8;
+    mic(&session.nwk_s_key, &[&b0, message])
+}
+
+/// An uplink with the next frame counter, `ack` acknowledges a confirmed downlink.
+pub fn uplink(session: &Session, port: u8, payload: &[u8], confirmed: bool, ack: bool) -> Vec<u8> {
+    let fcnt = session.fcnt_up;
+    let mut frame = vec![if confirmed { MTYPE_CONFIRMED_UP } else { MTYPE_UNCONFIRMED_UP }];
+    frame.extend_from_slice(&session.dev_addr.to_le_bytes());
+    frame.push(if ack { FCTRL_ACK } else { 0x00 });
+    frame.extend_from_slice(&(fcnt as u16).to_le_bytes());
+    frame.push(port);
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    crypt(&session.app_s_key, session.dev_addr, fcnt, false, &mut frame[start..]);
+    let mic = frame_mic(session, fcnt, false, &frame);
+    frame.extend_from_slice(&mic);
+    frame
+}
+
+/// Checks and decrypts a downlink to this session, none if it's for another device. Advances the
+/// downlink frame counter.
+pub fn downlink(session: &mut Session, frame: &[u8]) -> Result<Option<Downlink>> {
+    if frame.len() < 12 || !matches!(frame[0] & 0xE0, MTYPE_UNCONFIRMED_DOWN | MTYPE_CONFIRMED_DOWN) {
+        return Ok(None);
+    }
+    let dev_addr = u32::from_le_bytes(frame[1..5].try_into().expect("Four bytes"));
+    if dev_addr != session.dev_addr {
+        return Ok(None);
+    }
+    let fctrl = frame[5];
+    // The 16 bits sent, completed from the counter so far
+    let low = u16::from_le_bytes([frame[6], frame[7]]) as u32;
+    let mut fcnt = session.fcnt_down & !0xFFFF | low;
+    if fcnt < session.fcnt_down {
+        fcnt += 0x1_0000;
+    }
+    let (message, received_mic) = frame.split_at(frame.len() - 4);
+    if frame_mic(session, fcnt, true, message) != received_mic {
+        return Err(Error::failed(Phase::Upload, "Downlink failed its MIC check"));
+    }
+    session.fcnt_down = fcnt + 1;
+
+    let fopts_end = 8 + (fctrl & 0x0F) as usize;
+    let (port, mut payload) = match message.get(fopts_end..) {
+        Some([port, payload @ ..]) => (Some(*port), payload.to_vec()),
+        _ => (None, Vec::new()),
+    };
+    let key = if port == Some(0) { session.nwk_s_key } else { session.app_s_key };
+    crypt(&key, session.dev_addr, fcnt, true, &mut payload);
+    Ok(Some(Downlink {
+        ack: fctrl & FCTRL_ACK != 0,
+        port,
+        payload,
+    }))
+}
+
+/// The payloads of a round in `codec`, each at most `max_len` bytes.
+pub fn encode(codec: LorawanCodec, timestamp_ms: u64, measurements: &[Measurement], max_len: usize) -> Vec<Vec<u8>> {
+    let (header, entries): (Vec<u8>, Vec<Vec<u8>>) = match codec {
+        LorawanCodec::CayenneLpp => (
+            Vec::new(),
+            measurements
+                .iter()
+                .enumerate()
+                .filter_map(|(index, measurement)| lpp(index as u8 + 1, measurement))
+                .collect(),
+        ),
+        LorawanCodec::Compact => (
+            ((timestamp_ms / 1000) as u32).to_le_bytes().to_vec(),
+            measurements
+                .iter()
+                .filter(|measurement| !measurement.value.is_nan())
+                .map(|measurement| record(measurement).to_vec())
+                .collect(),
+        ),
+    };
+    let mut payloads = Vec::new();
+    let mut payload = header.clone();
+    for entry in entries {
+        if payload.len() > header.len() && payload.len() + entry.len() > max_len {
+            payloads.push(std::mem::replace(&mut payload, header.clone()));
+        }
+        payload.extend(entry);
+    }
+    if payload.len() > header.len() {
+        payloads.push(payload);
+    }
+    payloads
+}
+
+/// A Cayenne LPP entry, none for a value that's NaN or doesn't fit any type.
+fn lpp(channel: u8, measurement: &Measurement) -> Option<Vec<u8>> {
+    let value = measurement.value;
+    let (kind, data) = match &*measurement.name {
+        _ if value.is_nan() => return None,
+        "temperature" => (LPP_TEMPERATURE, ((value * 10.0).round() as i16).to_be_bytes().to_vec()),
+        "humidity" => (LPP_HUMIDITY, vec![(value * 2.0).round().clamp(0.0, 255.0) as u8]),
+        "pressure" => (LPP_BAROMETER, ((value * HPA_PER_MMHG * 10.0).round() as u16).to_be_bytes().to_vec()),
+        "lux" => (LPP_ILLUMINANCE, (value.round() as u16).to_be_bytes().to_vec()),
+        _ if value.abs() <= i16::MAX as f32 / 100.0 => {
+            (LPP_ANALOG_INPUT, ((value * 100.0).round() as i16).to_be_bytes().to_vec())
+        }
+        _ if (0.0..=u16::MAX as f32).contains(&value) => {
+            (LPP_ILLUMINANCE, (value.round() as u16).to_be_bytes().to_vec())
+        }
+        _ => return None,
+    };
+    Some([vec![channel, kind], data].concat())
+}
+
+pub struct LorawanSink<'a> {
+    radio: Box<dyn Radio + Send + 'a>,
+    path: Option<PathBuf>,
+    state: State,
+    duty_cycle: DutyCycle,
+    channel: usize,
+    /// A confirmed downlink waiting for the acknowledgement in the next uplink
+    ack: bool,
+    unacknowledged: u32,
+    /// The round last cut short by a failed payload and how many of its payloads went out, they
+    /// aren't sent again with the retry
+    partial: Option<(u64, usize)>,
+    bytes_written: u64,
+}
+
+impl<'a> LorawanSink<'a> {
+    /// Picks up the session kept at `path`, if there is one.
+    pub fn new(radio: Box<dyn Radio + Send + 'a>, path: Option<&Path>) -> Self {
+        let mut state: State = path
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        if let Some(session) = &mut state.session {
+            session.fcnt_up += FCNT_SAVE_EVERY;
+        }
+        LorawanSink {
+            radio,
+            path: path.map(Path::to_path_buf),
+            state,
+            duty_cycle: DutyCycle::default(),
+            channel: 0,
+            ack: false,
+            unacknowledged: 0,
+            partial: None,
+            bytes_written: 0,
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(err) = serde_json::to_vec(&self.state).map(|json| fs::write(path, json)) {
+            error!("Failed to save the LoRaWAN session: {}", err);
+        }
+    }
+
+    /// The settings of the next uplink, hopping over the channels.
+    fn next_channel(&mut self, lora: &LoraConfig, channels_hz: &[u32]) -> LoraConfig {
+        self.channel = (self.channel + 1) % channels_hz.len().max(1);
+        LoraConfig {
+            frequency_hz: channels_hz.get(self.channel).copied().unwrap_or(lora.frequency_hz),
+            sync_word: PUBLIC_SYNC_WORD,
+            ..lora.clone()
+        }
+    }
+
+    /// Sends `frame` and listens in RX1, on the same channel, then in RX2.
+    fn exchange(
+        &mut self,
+        config: &Config,
+        channel: &LoraConfig,
+        frame: &[u8],
+        rx1_delay: Duration,
+        rx2_spreading_factor: u8,
+    ) -> Result<Option<Vec<u8>>> {
+        let airtime = airtime(channel, frame.len());
+        if !self.duty_cycle.allows(Instant::now(), config.lora.duty_cycle_percent, airtime) {
+            return Err(Error::failed(
+                Phase::Upload,
+                format!("LoRaWAN frame would exceed the {}% duty cycle", config.lora.duty_cycle_percent),
+            ));
+        }
+        self.radio.transmit(channel, frame)?;
+        let sent_at = Instant::now();
+        self.duty_cycle.record(sent_at, airtime);
+        self.bytes_written += frame.len() as u64;
+
+        if let Some(frame) = self.radio.receive(channel, sent_at + rx1_delay, window(channel))? {
+            return Ok(Some(frame));
+        }
+        let rx2 = LoraConfig {
+            frequency_hz: config.lorawan.rx2_frequency_hz,
+            spreading_factor: rx2_spreading_factor,
+            ..channel.clone()
+        };
+        self.radio.receive(&rx2, sent_at + rx1_delay + Duration::from_secs(1), window(&rx2))
+    }
+
+    fn join(&mut self, config: &Config) -> Result<()> {
+        let lorawan = &config.lorawan;
+        let keys = (hex::<8>(&lorawan.join_eui), hex::<8>(&lorawan.dev_eui), hex::<16>(&lorawan.app_key));
+        let (Some(join_eui), Some(dev_eui), Some(app_key)) = keys else {
+            return Err(Error::failed(Phase::Config, "lorawan needs join_eui, dev_eui and app_key"));
+        };
+        // Counted up before the request goes out, a nonce is never sent twice
+        self.state.dev_nonce = self.state.dev_nonce.wrapping_add(1);
+        self.save();
+        let dev_nonce = self.state.dev_nonce;
+        let request = join_request(&join_eui, &dev_eui, dev_nonce, &app_key);
+        let channel = self.next_channel(&config.lora, &lorawan.channels_hz);
+        info!("Joining the LoRaWAN network on {} Hz", channel.frequency_hz);
+        let accept = self.exchange(config, &channel, &request, JOIN_ACCEPT_DELAY, lorawan.rx2_spreading_factor)?;
+        let accept = accept.ok_or_else(|| Error::failed(Phase::Upload, "No JoinAccept from the LoRaWAN network"))?;
+        let session = join_accept(&accept, &app_key, dev_nonce, lorawan)?;
+        info!("Joined the LoRaWAN network as {:08X}", session.dev_addr);
+        self.state.session = Some(session);
+        self.unacknowledged = 0;
+        self.save();
+        Ok(())
+    }
+
+    fn send_payload(&mut self, config: &Config, payload: &[u8]) -> Result<()> {
+        let lorawan = &config.lorawan;
+        let mut session = self.state.session.clone().expect("Joined before sending");
+        let frame = uplink(&session, lorawan.port, payload, lorawan.confirmed, self.ack);
+        let channel = self.next_channel(&config.lora, &session.channels_hz);
+        let rx1_delay = Duration::from_secs(session.rx1_delay_sec as u64);
+        let received = self.exchange(config, &channel, &frame, rx1_delay, session.rx2_spreading_factor)?;
+        session.fcnt_up += 1;
+        self.ack = false;
+
+        let mut acknowledged = false;
+        if let Some(frame) = received {
+            match downlink(&mut session, &frame) {
+                Ok(Some(downlink)) => {
+                    acknowledged = downlink.ack;
+                    self.ack = frame[0] & 0xE0 == MTYPE_CONFIRMED_DOWN;
+                    if downlink.port.is_some_and(|port| port != 0) && !downlink.payload.is_empty() {
+                        debug!("Ignoring a downlink of {} bytes on port {:?}", downlink.payload.len(), downlink.port);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => warn!("{}", err),
+            }
+        }
+        let save = session.fcnt_up % FCNT_SAVE_EVERY == 0;
+        self.state.session = Some(session);
+        if save {
+            self.save();
+        }
+        if lorawan.confirmed && !acknowledged {
+            self.unacknowledged += 1;
+            if self.unacknowledged >= REJOIN_AFTER {
+                warn!("No acknowledgement for {} uplinks, joining again", self.unacknowledged);
+                self.state.session = None;
+            }
+            return Err(Error::failed(Phase::Upload, "LoRaWAN uplink wasn't acknowledged"));
+        }
+        self.unacknowledged = 0;
+        Ok(())
+    }
+}
+
+/// Long enough to catch the preamble of a downlink starting a little off.
+fn window(config: &LoraConfig) -> Duration {
+    Duration::from_millis(50) + symbol_time(config) * 8
+}
+
+impl Sink for LorawanSink<'_> {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        if self.state.session.is_none() {
+            self.join(config)?;
+        }
+        let max_len = config.lora.max_payload.saturating_sub(OVERHEAD).max(RECORD_LEN);
+        let sent = match self.partial {
+            Some((timestamp, sent)) if timestamp == timestamp_ms => sent,
+            _ => 0,
+        };
+        let payloads = encode(config.lorawan.codec, timestamp_ms, measurements, max_len);
+        for (index, payload) in payloads.iter().enumerate().skip(sent) {
+            if let Err(err) = self.send_payload(config, payload) {
+                self.partial = Some((timestamp_ms, index));
+                return Err(err);
+            }
+        }
+        self.partial = None;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    fn name(&self) -> &'static str {
+        "lorawan"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use aes::cipher::BlockDecrypt;
+
+    use super::*;
+
+    const APP_KEY: [u8; 16] = [0x2B; 16];
+
+    // Frequency and payload
+    type Frame = (u32, Vec<u8>);
+    // None for a frame that goes unanswered
+    type Answer = Option<Vec<u8>>;
+
+    /// Answers every frame sent with the next one queued, in RX1.
+    #[derive(Clone, Default)]
+    struct MockRadio {
+        sent: Arc<Mutex<Vec<Frame>>>,
+        answers: Arc<Mutex<VecDeque<Answer>>>,
+        answer: Option<Vec<u8>>,
+    }
+
+    impl Radio for MockRadio {
+        fn transmit(&mut self, config: &LoraConfig, payload: &[u8]) -> Result<()> {
+            assert_eq!(config.sync_word, PUBLIC_SYNC_WORD);
+            self.sent.lock().unwrap().push((config.frequency_hz, payload.to_vec()));
+            self.answer = self.answers.lock().unwrap().pop_front().flatten();
+            Ok(())
+        }
+
+        fn receive(&mut self, _config: &LoraConfig, _at: Instant, _window: Duration) -> Result<Option<Vec<u8>>> {
+            Ok(self.answer.take())
+        }
+    }
+
+    /// A JoinAccept as the network sends it, with a CFList of 867.1 and 867.3 MHz.
+    fn accept(dev_addr: u32) -> Vec<u8> {
+        let mut message = vec![MTYPE_JOIN_ACCEPT, 0x01, 0x02, 0x03, 0x13, 0x00, 0x00];
+        message.extend_from_slice(&dev_addr.to_le_bytes());
+        // RX2 on DR3, RX1 after 1 s
+        message.extend_from_slice(&[0x03, 0x01]);
+        for frequency in [867_100_000u32, 867_300_000, 0, 0, 0] {
+            message.extend_from_slice(&(frequency / 100).to_le_bytes()[..3]);
+        }
+        message.push(0x00);
+        let mic = mic(&APP_KEY, &[&message]);
+        message.extend_from_slice(&mic);
+        // Encrypted with AES decryption
+        let cipher = Aes128::new(&APP_KEY.into());
+        for chunk in message[1..].chunks_exact_mut(16) {
+            let block: &mut [u8; 16] = chunk.try_into().unwrap();
+            cipher.decrypt_block(block.into());
+        }
+        message
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.lorawan.join_eui = "70B3D57ED0000000".to_string();
+        config.lorawan.dev_eui = "0004A30B001C0530".to_string();
+        config.lorawan.app_key = "2B".repeat(16);
+        config.lorawan.confirmed = true;
+        config
+    }
+
+    #[test]
+    fn builds_the_join_request() {
+        let join_eui = hex::<8>("70B3D57ED0000000").unwrap();
+        let dev_eui = hex::<8>("0004A30B001C0530").unwrap();
+        let request = join_request(&join_eui, &dev_eui, 0x0102, &APP_KEY);
+        assert_eq!(request.len(), 23);
+        assert_eq!(&request[1..9], &[0x00, 0x00, 0x00, 0xD0, 0x7E, 0xD5, 0xB3, 0x70]);
+        assert_eq!(&request[17..19], &[0x02, 0x01]);
+        assert_eq!(&request[19..], &mic(&APP_KEY, &[&request[..19]]));
+        assert_eq!(hex::<8>("0004A30B001C053"), None);
+    }
+
+    #[test]
+    fn joins_and_sends_confirmed_uplinks() {
+        let path = std::env::temp_dir().join(format!("lorawan-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = config();
+        let radio = MockRadio::default();
+        radio.answers.lock().unwrap().push_back(Some(accept(0x2601_1234)));
+        let mut sink = LorawanSink::new(Box::new(radio.clone()), Some(&path));
+
+        // Joined, then the uplink goes unacknowledged
+        radio.answers.lock().unwrap().push_back(None);
+        let measurements = [("temperature", 21.5), ("humidity", 40.0), ("co2", 812.0)].map(|(name, value)| {
+            Measurement {
+                name: name.into(),
+                value,
+            }
+        });
+        assert!(sink.send(&config, 0, &measurements).is_err());
+        let mut session = sink.state.session.clone().unwrap();
+        assert_eq!(session.dev_addr, 0x2601_1234);
+        assert_eq!(session.rx2_spreading_factor, 9);
+        assert_eq!(session.channels_hz, [868_100_000, 868_300_000, 868_500_000, 867_100_000, 867_300_000]);
+        assert_eq!(session.fcnt_up, 1);
+
+        // The network decrypts what it got
+        let sent = radio.sent.lock().unwrap().clone();
+        let frame = &sent[1].1;
+        assert_eq!(frame[0], MTYPE_CONFIRMED_UP);
+        let mut payload = frame[9..frame.len() - 4].to_vec();
+        crypt(&session.app_s_key, session.dev_addr, 0, false, &mut payload);
+        assert_eq!(payload, [1, LPP_TEMPERATURE, 0, 215, 2, LPP_HUMIDITY, 80, 3, LPP_ILLUMINANCE, 3, 44]);
+
+        // Acknowledged by a downlink with counter 0
+        let mut ack = vec![MTYPE_UNCONFIRMED_DOWN];
+        ack.extend_from_slice(&session.dev_addr.to_le_bytes());
+        ack.extend_from_slice(&[FCTRL_ACK, 0x00, 0x00]);
+        let mic = frame_mic(&session, 0, true, &ack);
+        ack.extend_from_slice(&mic);
+        radio.answers.lock().unwrap().push_back(Some(ack.clone()));
+        sink.send(&config, 0, &measurements).unwrap();
+        // Joined on the second channel, the uplinks hop on
+        assert_eq!(sent[0].0, 868_300_000);
+        assert_eq!(sent[1].0, 868_500_000);
+        assert_eq!(radio.sent.lock().unwrap()[2].0, 867_100_000);
+        // Replayed, the counter has moved on
+        assert!(downlink(&mut sink.state.session.clone().unwrap(), &ack).is_err());
+
+        // After a reboot the session is picked up, the counter skipped ahead
+        let saved: State = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.dev_nonce, 1);
+        session = LorawanSink::new(Box::new(radio), Some(&path)).state.session.unwrap();
+        assert_eq!(session.fcnt_up, FCNT_SAVE_EVERY);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn resends_only_what_didnt_go_out_of_a_split_round() {
+        let mut config = config();
+        config.lorawan.codec = LorawanCodec::Compact;
+        config.lora.max_payload = OVERHEAD + 4 + 2 * RECORD_LEN;
+        let radio = MockRadio::default();
+        radio.answers.lock().unwrap().push_back(Some(accept(0x2601_1234)));
+        let mut sink = LorawanSink::new(Box::new(radio.clone()), None);
+        let ack = |session: &Session, fcnt: u16| {
+            let mut ack = vec![MTYPE_UNCONFIRMED_DOWN];
+            ack.extend_from_slice(&session.dev_addr.to_le_bytes());
+            ack.push(FCTRL_ACK);
+            ack.extend_from_slice(&fcnt.to_le_bytes());
+            let mic = frame_mic(session, fcnt as u32, true, &ack);
+            ack.extend_from_slice(&mic);
+            ack
+        };
+        let measurements: Vec<Measurement> = (0..5)
+            .map(|index| Measurement {
+                name: format!("metric_{}", index).into(),
+                value: index as f32,
+            })
+            .collect();
+
+        // Joined, the first of the three payloads is acknowledged and the second isn't
+        sink.join(&config).unwrap();
+        let session = sink.state.session.clone().unwrap();
+        radio.answers.lock().unwrap().extend([Some(ack(&session, 0)), None]);
+        assert!(sink.send(&config, 0, &measurements).is_err());
+        assert_eq!(radio.sent.lock().unwrap().len(), 3);
+
+        radio.answers.lock().unwrap().extend([Some(ack(&session, 1)), Some(ack(&session, 2))]);
+        sink.send(&config, 0, &measurements).unwrap();
+        assert_eq!(radio.sent.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn splits_rounds_for_the_compact_codec() {
+        let measurements: Vec<Measurement> = (0..5)
+            .map(|index| Measurement {
+                name: format!("metric_{}", index).into(),
+                value: index as f32,
+            })
+            .collect();
+        let payloads = encode(LorawanCodec::Compact, 1_700_000_000_000, &measurements, 4 + 2 * RECORD_LEN);
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(&payloads[2][..4], &1_700_000_000u32.to_le_bytes());
+        assert_eq!(&payloads[2][4..], &record(&measurements[4]));
+    }
+}
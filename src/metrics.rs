@@ -0,0 +1,122 @@
+//! The metrics the sensors report, each with its unit and the range a sound reading falls in.
+//! Drivers declare theirs, the sensor registry collects them as the sensors are set up and the
+//! sampler drops a value outside its range before it's buffered. A driver bug, e.g. pressure in
+//! Pa instead of hPa, then shows up in the log rather than in the database. Metrics nobody
+//! declared, like the derived ones and the diagnostics, pass as they are.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use log::{error, warn};
+
+use crate::sensors::Measurement;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSpec {
+    pub name: Cow<'static, str>,
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl MetricSpec {
+    pub const fn new(name: &'static str, unit: &'static str, min: f32, max: f32) -> Self {
+        MetricSpec {
+            name: Cow::Borrowed(name),
+            unit,
+            min,
+            max,
+        }
+    }
+
+    pub fn contains(&self, value: f32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MetricRegistry {
+    specs: BTreeMap<Cow<'static, str>, MetricSpec>,
+}
+
+impl MetricRegistry {
+    /// Declares a metric of `sensor`. Declared again in the same unit, e.g. the temperature of two
+    /// sensors, the range covers both. In another unit it's a driver bug, the first one stays.
+    pub fn declare(&mut self, sensor: &str, spec: MetricSpec) {
+        match self.specs.get_mut(&spec.name) {
+            Some(known) if known.unit != spec.unit => {
+                error!("{} reports {} in {}, already declared in {}", sensor, spec.name, spec.unit, known.unit)
+            }
+            Some(known) => {
+                known.min = known.min.min(spec.min);
+                known.max = known.max.max(spec.max);
+            }
+            None => {
+                self.specs.insert(spec.name.clone(), spec);
+            }
+        }
+    }
+
+    /// A metric named `<sensor>.<metric>` to tell it from another sensor's falls back to the
+    /// declaration of `<metric>`.
+    pub fn get(&self, name: &str) -> Option<&MetricSpec> {
+        self.specs
+            .get(name)
+            .or_else(|| name.split_once('.').and_then(|(_, metric)| self.specs.get(metric)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MetricSpec> {
+        self.specs.values()
+    }
+
+    /// Drops the values outside their declared range and returns how many. NaN stands for a
+    /// reading that's missing, it's kept.
+    pub fn validate(&self, measurements: &mut Vec<Measurement>) -> usize {
+        let before = measurements.len();
+        measurements.retain(|measurement| match self.get(&measurement.name) {
+            Some(spec) if !measurement.value.is_nan() && !spec.contains(measurement.value) => {
+                warn!(
+                    "{} of {} {} is outside {} to {}, dropped",
+                    measurement.name, measurement.value, spec.unit, spec.min, spec.max
+                );
+                false
+            }
+            _ => true,
+        });
+        before - measurements.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(name: &'static str, value: f32) -> Measurement {
+        Measurement {
+            name: name.into(),
+            value,
+        }
+    }
+
+    #[test]
+    fn drops_values_outside_the_declared_range() {
+        let mut metrics = MetricRegistry::default();
+        metrics.declare("bme280", MetricSpec::new("temperature", "°C", -40.0, 85.0));
+        metrics.declare("scd4x", MetricSpec::new("temperature", "°C", -10.0, 60.0));
+        metrics.declare("bme280", MetricSpec::new("pressure", "hPa", 300.0, 1100.0));
+        // In another unit the first declaration stays
+        metrics.declare("other", MetricSpec::new("pressure", "Pa", 30_000.0, 110_000.0));
+        assert_eq!(metrics.get("temperature").unwrap().min, -40.0);
+        assert_eq!(metrics.get("pressure").unwrap().unit, "hPa");
+
+        let mut measurements = vec![
+            measurement("temperature", 21.5),
+            measurement("pressure", 101_325.0),
+            measurement("humidity", 150.0),
+            measurement("pressure", f32::NAN),
+        ];
+        assert_eq!(metrics.validate(&mut measurements), 1);
+        let names: Vec<&str> = measurements.iter().map(|measurement| measurement.name.as_ref()).collect();
+        assert_eq!(names, ["temperature", "humidity", "pressure"]);
+    }
+}
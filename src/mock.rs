@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::error::{Error, Phase, Result};
+use crate::metrics::MetricSpec;
+use crate::pipeline::{Batch, Network, Sink};
+use crate::sensors::{Measurement, Sensor};
+
+pub enum MockReading {
+    Values(Vec<(&'static str, f32)>),
+    /// Drivers log failures and return nothing, so does the mock
+    Error,
+}
+
+/// Sensor replaying a scripted sequence of readings, returns nothing once the script runs out.
+pub struct MockSensor {
+    script: VecDeque<MockReading>,
+    metrics: Vec<MetricSpec>,
+}
+
+impl MockSensor {
+    pub fn new(script: impl IntoIterator<Item = MockReading>) -> Self {
+        MockSensor {
+            script: script.into_iter().collect(),
+            metrics: Vec::new(),
+        }
+    }
+
+    /// Declares the metrics like a driver does.
+    pub fn with_metrics(mut self, metrics: Vec<MetricSpec>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
+impl Sensor for MockSensor {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        match self.script.pop_front() {
+            Some(MockReading::Values(values)) => values
+                .into_iter()
+                .map(|(name, value)| Measurement {
+                    name: name.into(),
+                    value,
+                })
+                .collect(),
+            Some(MockReading::Error) | None => vec![],
+        }
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        self.metrics.clone()
+    }
+}
+
+#[derive(Default)]
+pub struct MockSinkState {
+    pub sent: Vec<Batch>,
+    /// Number of upcoming sends that fail
+    pub failures: usize,
+    /// Number of upcoming sends turned down for good
+    pub rejections: usize,
+    pub attempts: usize,
+}
+
+/// Sink recording everything it's given, cloned handles share the same state so tests can
+/// inspect it after handing the sink over to a pipeline.
+#[derive(Clone, Default)]
+pub struct MockSink {
+    pub state: Arc<Mutex<MockSinkState>>,
+}
+
+impl MockSink {
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    pub fn reject_next(&self, count: usize) {
+        self.state.lock().unwrap().rejections = count;
+    }
+
+    pub fn sent_values(&self, name: &str) -> Vec<f32> {
+        self.state
+            .lock()
+            .unwrap()
+            .sent
+            .iter()
+            .flat_map(|(_, measurements)| measurements.iter())
+            .filter(|measurement| measurement.name == name)
+            .map(|measurement| measurement.value)
+            .collect()
+    }
+}
+
+impl Sink for MockSink {
+    fn send(&mut self, _config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.attempts += 1;
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err(Error::failed(Phase::Upload, "Injected sink failure"));
+        }
+        if state.rejections > 0 {
+            state.rejections -= 1;
+            return Err(Error::rejected(Phase::Upload, "Injected rejection"));
+        }
+        state.sent.push((timestamp_ms, measurements.to_vec()));
+        Ok(())
+    }
+}
+
+/// Network whose next `failures` connection attempts fail.
+#[derive(Clone, Default)]
+pub struct MockNetwork {
+    pub failures: Arc<Mutex<usize>>,
+    /// Links brought up and taken down so far
+    pub connects: Arc<Mutex<usize>>,
+    pub disconnects: Arc<Mutex<usize>>,
+}
+
+impl MockNetwork {
+    pub fn fail_next(&self, count: usize) {
+        *self.failures.lock().unwrap() = count;
+    }
+}
+
+impl Network for MockNetwork {
+    fn connect(&mut self, _config: &Config) -> Result<()> {
+        let mut failures = self.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Err(Error::failed(Phase::Connect, "Injected network failure"));
+        }
+        *self.connects.lock().unwrap() += 1;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        *self.disconnects.lock().unwrap() += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Modbus TCP server for building automation: the latest round in registers, read with function
+//! 3 (holding) or 4 (input), both see the same map.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv6Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::config::Config;
+use crate::error::{Context, Phase, Result};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+const STACK_SIZE: usize = 4 * 1024;
+// A client that went away without closing would keep everyone else out
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Signed 16 bit values, scaled by the factor, e.g. 21.7 °C is 217. Registers 14 and 15 hold the
+// round's Unix time in s, high word first.
+const MAP: &[(&str, f32)] = &[
+    ("temperature", 10.0),
+    ("humidity", 10.0),
+    ("pressure", 10.0),
+    ("co2", 1.0),
+    ("lux", 1.0),
+    ("iaq", 1.0),
+    ("tvoc", 1.0),
+    ("pm2_5", 10.0),
+    ("bed_occupied", 1.0),
+    ("room_occupied", 1.0),
+    // In KB, needs `system_metrics`
+    ("heap_free", 1.0 / 1024.0),
+    ("buffer_depth", 1.0),
+    ("uplink_consecutive_failures", 1.0),
+    ("uplink_success", 1.0),
+];
+const REGISTERS: usize = MAP.len() + 2;
+/// Metrics missing from the last round
+pub const NO_VALUE: u16 = 0x8000;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const MAX_READ: usize = 125;
+const MAX_PDU: usize = 253;
+
+pub type SharedRegisters = Arc<Mutex<[u16; REGISTERS]>>;
+
+/// Archive sink filling the registers, every round replaces all of them.
+pub struct ModbusFeed {
+    registers: SharedRegisters,
+}
+
+impl ModbusFeed {
+    pub fn new() -> Self {
+        ModbusFeed {
+            registers: Arc::new(Mutex::new([NO_VALUE; REGISTERS])),
+        }
+    }
+
+    pub fn registers(&self) -> SharedRegisters {
+        self.registers.clone()
+    }
+}
+
+impl Default for ModbusFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for ModbusFeed {
+    fn send(&mut self, _config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let mut registers = self.registers.lock().expect("Modbus registers lock poisoned");
+        for (register, (name, scale)) in registers.iter_mut().zip(MAP) {
+            *register = measurements
+                .iter()
+                .find(|measurement| measurement.name == *name && measurement.value.is_finite())
+                .map(|measurement| (measurement.value * scale).round().clamp(-32767.0, 32767.0) as i16 as u16)
+                .unwrap_or(NO_VALUE);
+        }
+        let timestamp = (timestamp_ms / 1000) as u32;
+        registers[MAP.len()] = (timestamp >> 16) as u16;
+        registers[MAP.len() + 1] = timestamp as u16;
+        Ok(())
+    }
+}
+
+/// Starts the server thread, it takes one connection at a time.
+pub fn spawn(port: u16, registers: SharedRegisters) -> Result<()> {
+    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port))
+        .with_context(Phase::Output, || format!("Failed to listen for Modbus on port {}", port))?;
+    thread::Builder::new()
+        .name("modbus".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || serve(listener, registers))
+        .context(Phase::Output, "Failed to start the Modbus thread")?;
+    info!("Modbus TCP listening on port {}", port);
+    Ok(())
+}
+
+fn serve(listener: TcpListener, registers: SharedRegisters) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = session(stream, &registers) {
+                    warn!("Modbus connection ended: {:?}", err);
+                }
+            }
+            Err(err) => error!("Failed to accept a Modbus connection: {:?}", err),
+        }
+    }
+}
+
+fn session(mut stream: TcpStream, registers: &SharedRegisters) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    // Transaction ID, protocol ID, length and unit ID
+    let mut header = [0u8; 7];
+    loop {
+        match stream.read_exact(&mut header) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if !(2..=MAX_PDU + 1).contains(&length) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid Modbus frame length"));
+        }
+        let mut request = vec![0u8; length - 1];
+        stream.read_exact(&mut request)?;
+
+        let registers = *registers.lock().expect("Modbus registers lock poisoned");
+        let response = respond(&registers, &request);
+        let mut frame = Vec::with_capacity(header.len() + response.len());
+        frame.extend_from_slice(&header[..4]);
+        frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend_from_slice(&response);
+        stream.write_all(&frame)?;
+    }
+}
+
+/// Answers a request PDU, the function code and its data, with the response PDU.
+fn respond(registers: &[u16], request: &[u8]) -> Vec<u8> {
+    let function = request[0];
+    let exception = |code: u8| vec![function | 0x80, code];
+    if function != READ_HOLDING_REGISTERS && function != READ_INPUT_REGISTERS {
+        return exception(ILLEGAL_FUNCTION);
+    }
+    let &[_, start_hi, start_lo, count_hi, count_lo] = request else {
+        return exception(ILLEGAL_DATA_VALUE);
+    };
+    let start = u16::from_be_bytes([start_hi, start_lo]) as usize;
+    let count = u16::from_be_bytes([count_hi, count_lo]) as usize;
+    if !(1..=MAX_READ).contains(&count) {
+        return exception(ILLEGAL_DATA_VALUE);
+    }
+    let Some(values) = registers.get(start..start + count) else {
+        return exception(ILLEGAL_DATA_ADDRESS);
+    };
+    let mut response = vec![function, (count * 2) as u8];
+    for value in values {
+        response.extend_from_slice(&value.to_be_bytes());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_the_round_to_registers() {
+        let mut feed = ModbusFeed::new();
+        let measurements = [
+            Measurement {
+                name: "temperature".into(),
+                value: -1.26,
+            },
+            Measurement {
+                name: "co2".into(),
+                value: 612.0,
+            },
+        ];
+        feed.send(&Config::default(), 1_700_000_000_999, &measurements).unwrap();
+        let registers = *feed.registers().lock().unwrap();
+
+        // Temperature and humidity
+        assert_eq!(respond(&registers, &[0x03, 0, 0, 0, 2]), vec![0x03, 4, 0xFF, 0xF3, 0x80, 0x00]);
+        assert_eq!(respond(&registers, &[0x04, 0, 3, 0, 1]), vec![0x04, 2, 0x02, 0x64]);
+        assert_eq!(
+            respond(&registers, &[0x03, 0, MAP.len() as u8, 0, 2]),
+            vec![0x03, 4, 0x65, 0x53, 0xF1, 0x00]
+        );
+        assert_eq!(respond(&registers, &[0x03, 0, 15, 0, 2]), vec![0x83, ILLEGAL_DATA_ADDRESS]);
+        assert_eq!(respond(&registers, &[0x06, 0, 0, 0, 1]), vec![0x86, ILLEGAL_FUNCTION]);
+    }
+}
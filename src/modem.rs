@@ -0,0 +1,301 @@
+//! SIM7000/SIM7600 modem as the network: AT commands over the UART to register and dial the
+//! data connection, then PPP over the same UART through an lwIP netif. The modem is only up for
+//! the uploads, switched off in between if its PWRKEY is wired and in flight mode otherwise.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, PinDriver};
+use esp_idf_svc::hal::uart::UartDriver;
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_svc::sys::{
+    esp, esp_err_t, esp_netif_action_start, esp_netif_action_stop, esp_netif_attach, esp_netif_driver_base_t,
+    esp_netif_driver_ifconfig_t, esp_netif_receive, esp_netif_set_driver_config, esp_netif_t, uart_port_t,
+    uart_read_bytes, uart_write_bytes, ESP_FAIL, ESP_OK,
+};
+use log::{info, warn};
+
+use crate::cellular::{self, SharedSignal, Signal};
+use crate::config::{CellularConfig, Config, ModemModel};
+use crate::error::{Context, Error, Phase, Result};
+use crate::pipeline::Network;
+
+const STACK_SIZE: usize = 4 * 1024;
+const READ_TIMEOUT_MS: u64 = 100;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// Dialing can take a while on a busy cell
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+const BOOT_TIMEOUT: Duration = Duration::from_secs(15);
+const ADDRESS_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Silence around `+++` for the modem to take it as the escape from data mode
+const ESCAPE_GUARD: Duration = Duration::from_millis(1100);
+
+/// Handed to lwIP as the netif's driver, it has to start with the base.
+#[repr(C)]
+struct PppDriver {
+    base: esp_netif_driver_base_t,
+    port: uart_port_t,
+}
+
+pub struct CellularNetwork {
+    uart: UartDriver<'static>,
+    power_key: Option<PinDriver<'static, AnyOutputPin, Output>>,
+    model: ModemModel,
+    netif: EspNetif,
+    // Boxed, lwIP keeps a pointer to it
+    driver: Box<PppDriver>,
+    attached: bool,
+    receiving: Arc<AtomicBool>,
+    receiver: Option<JoinHandle<()>>,
+    signal: SharedSignal,
+}
+
+// The raw pointers are only used by lwIP and the receive thread, which stops before the network
+// is used from another thread
+unsafe impl Send for CellularNetwork {}
+
+impl CellularNetwork {
+    pub fn new(
+        uart: UartDriver<'static>,
+        power_key: Option<PinDriver<'static, AnyOutputPin, Output>>,
+        config: &CellularConfig,
+        signal: SharedSignal,
+    ) -> Result<Self> {
+        let netif = EspNetif::new_with_conf(&NetifConfiguration::ppp_default_client())
+            .context(Phase::Boot, "Failed to set up the PPP interface")?;
+        let driver = Box::new(PppDriver {
+            base: esp_netif_driver_base_t {
+                post_attach: Some(post_attach),
+                netif: ptr::null_mut(),
+            },
+            port: uart.port(),
+        });
+        Ok(CellularNetwork {
+            uart,
+            power_key,
+            model: config.model,
+            netif,
+            driver,
+            attached: false,
+            receiving: Arc::new(AtomicBool::new(false)),
+            receiver: None,
+            signal,
+        })
+    }
+
+    /// Sends a command and waits for it to succeed, returns the response.
+    fn command(&mut self, command: &str, timeout: Duration) -> Result<String> {
+        // Without the arguments, they can be a PIN
+        let name = command.split('=').next().unwrap_or(command);
+        let context = || format!("Failed to send {} to the modem", name);
+        self.uart.clear_rx().with_context(Phase::Connect, context)?;
+        self.uart.write(format!("{}\r", command).as_bytes()).with_context(Phase::Connect, context)?;
+        let started = Instant::now();
+        let mut response = String::new();
+        let mut buf = [0; 128];
+        while started.elapsed() < timeout {
+            let read = self
+                .uart
+                .read(&mut buf, TickType::new_millis(READ_TIMEOUT_MS).ticks())
+                .with_context(Phase::Connect, || format!("Failed to read the modem's answer to {}", name))?;
+            response.push_str(&String::from_utf8_lossy(&buf[..read]));
+            match cellular::finished(&response) {
+                Some(true) => return Ok(response),
+                Some(false) => {
+                    return Err(Error::failed(Phase::Connect, format!("{} failed: {}", name, response.trim())));
+                }
+                None => {}
+            }
+        }
+        Err(Error::failed(Phase::Connect, format!("No answer from the modem to {}", name)))
+    }
+
+    fn power_on(&mut self) -> Result<()> {
+        if let Some(power_key) = self.power_key.as_mut() {
+            let pulse = match self.model {
+                ModemModel::Sim7000 => Duration::from_secs(1),
+                ModemModel::Sim7600 => Duration::from_millis(500),
+            };
+            // The boards drive PWRKEY through a transistor, high pulls it low
+            power_key.set_high().context(Phase::Connect, "Failed to switch the modem on")?;
+            thread::sleep(pulse);
+            power_key.set_low().context(Phase::Connect, "Failed to switch the modem on")?;
+        }
+        let started = Instant::now();
+        while self.command("AT", Duration::from_millis(500)).is_err() {
+            if started.elapsed() > BOOT_TIMEOUT {
+                return Err(Error::failed(Phase::Connect, "The modem doesn't answer"));
+            }
+        }
+        self.command("ATE0", COMMAND_TIMEOUT)?;
+        if self.power_key.is_none() {
+            self.command("AT+CFUN=1", COMMAND_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        let command = match (&self.power_key, self.model) {
+            (None, _) => "AT+CFUN=0",
+            (Some(_), ModemModel::Sim7000) => "AT+CPOWD=1",
+            (Some(_), ModemModel::Sim7600) => "AT+CPOF",
+        };
+        self.command(command, COMMAND_TIMEOUT).map(|_| ())
+    }
+
+    /// Unlocks the SIM, waits for the network and notes the signal on the way.
+    fn register(&mut self, config: &CellularConfig) -> Result<()> {
+        let sim = self.command("AT+CPIN?", COMMAND_TIMEOUT)?;
+        match cellular::field(&sim, "+CPIN") {
+            Some("READY") => {}
+            Some("SIM PIN") if !config.pin.is_empty() => {
+                self.command(&format!("AT+CPIN={}", config.pin), COMMAND_TIMEOUT)?;
+            }
+            status => {
+                return Err(Error::failed(Phase::Connect, format!("SIM not ready: {}", status.unwrap_or("unknown"))));
+            }
+        }
+        if !config.apn.is_empty() {
+            self.command(&format!("AT+CGDCONT=1,\"IP\",\"{}\"", config.apn), COMMAND_TIMEOUT)?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(config.register_timeout_sec as u64);
+        let registered = loop {
+            let registration = ["AT+CEREG?", "AT+CREG?"]
+                .iter()
+                .filter_map(|command| self.command(command, COMMAND_TIMEOUT).ok())
+                .collect::<String>();
+            if cellular::registered(&registration) {
+                break true;
+            }
+            if Instant::now() >= deadline {
+                break false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        let rssi_dbm = self.command("AT+CSQ", COMMAND_TIMEOUT).ok().and_then(|csq| cellular::rssi_dbm(&csq));
+        *self.signal.lock().expect("Cellular signal lock poisoned") = Some(Signal { rssi_dbm, registered });
+        if !registered {
+            return Err(Error::failed(
+                Phase::Connect,
+                format!("Not registered with a network after {} s", config.register_timeout_sec),
+            ));
+        }
+        info!("Registered, signal {:?} dBm", rssi_dbm);
+        Ok(())
+    }
+
+    /// Dials the data connection and hands the UART to PPP until an address comes in.
+    fn start_ppp(&mut self) -> Result<()> {
+        self.command("ATD*99#", DIAL_TIMEOUT)?;
+        if !self.attached {
+            let driver = &mut *self.driver as *mut PppDriver as *mut c_void;
+            esp!(unsafe { esp_netif_attach(self.netif.handle(), driver) })
+                .context(Phase::Connect, "Failed to attach PPP to the modem")?;
+            self.attached = true;
+        }
+        self.receiving.store(true, Ordering::Relaxed);
+        let (receiving, port, netif) = (self.receiving.clone(), self.driver.port, self.netif.handle() as usize);
+        let receiver = thread::Builder::new()
+            .name("ppp".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || receive(&receiving, port, netif as *mut esp_netif_t))
+            .context(Phase::Connect, "Failed to start the PPP thread")?;
+        self.receiver = Some(receiver);
+        unsafe { esp_netif_action_start(self.netif.handle() as *mut c_void, ptr::null(), 0, ptr::null_mut()) };
+
+        let started = Instant::now();
+        loop {
+            let ip = self.netif.get_ip_info().map(|info| info.ip).ok().filter(|ip| !ip.is_unspecified());
+            if let Some(ip) = ip {
+                info!("PPP address: {}", ip);
+                return Ok(());
+            }
+            if started.elapsed() > ADDRESS_TIMEOUT {
+                return Err(Error::failed(Phase::Connect, "No IP address over PPP"));
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Takes PPP down and the modem back to command mode.
+    fn stop_ppp(&mut self) {
+        let Some(receiver) = self.receiver.take() else {
+            return;
+        };
+        unsafe { esp_netif_action_stop(self.netif.handle() as *mut c_void, ptr::null(), 0, ptr::null_mut()) };
+        self.receiving.store(false, Ordering::Relaxed);
+        let _ = receiver.join();
+        thread::sleep(ESCAPE_GUARD);
+        let _ = self.uart.write(b"+++");
+        thread::sleep(ESCAPE_GUARD);
+        if let Err(err) = self.command("ATH", COMMAND_TIMEOUT) {
+            warn!("{}", err);
+        }
+    }
+}
+
+impl Network for CellularNetwork {
+    fn connect(&mut self, config: &Config) -> Result<()> {
+        let started = self.power_on().and_then(|_| self.register(&config.cellular)).and_then(|_| self.start_ppp());
+        if started.is_err() {
+            self.stop_ppp();
+            if let Err(err) = self.power_off() {
+                warn!("{}", err);
+            }
+        }
+        started
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        // Gives the TCP stack time to get the last batch out before the link goes away
+        thread::sleep(Duration::from_secs(2));
+        self.stop_ppp();
+        self.power_off()
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.stop_ppp();
+        // A full reset of the modem, it's powered on again with the next connect
+        let _ = self.command("AT+CFUN=1,1", COMMAND_TIMEOUT);
+        thread::sleep(Duration::from_secs(10));
+        self.power_off()
+    }
+}
+
+fn receive(receiving: &AtomicBool, port: uart_port_t, netif: *mut esp_netif_t) {
+    let mut buf = [0u8; 512];
+    while receiving.load(Ordering::Relaxed) {
+        let ticks = TickType::new_millis(READ_TIMEOUT_MS).ticks();
+        let read = unsafe { uart_read_bytes(port, buf.as_mut_ptr() as *mut c_void, buf.len() as u32, ticks) };
+        if read > 0 {
+            unsafe { esp_netif_receive(netif, buf.as_mut_ptr() as *mut c_void, read as usize, ptr::null_mut()) };
+        }
+    }
+}
+
+unsafe extern "C" fn post_attach(netif: *mut esp_netif_t, handle: *mut c_void) -> esp_err_t {
+    let driver = &mut *(handle as *mut PppDriver);
+    driver.base.netif = netif;
+    let config = esp_netif_driver_ifconfig_t {
+        handle,
+        transmit: Some(transmit),
+        transmit_wrap: None,
+        driver_free_rx_buffer: None,
+    };
+    esp_netif_set_driver_config(netif, &config)
+}
+
+unsafe extern "C" fn transmit(handle: *mut c_void, data: *mut c_void, len: usize) -> esp_err_t {
+    let driver = &*(handle as *const PppDriver);
+    if uart_write_bytes(driver.port, data as *const c_void, len) == len as i32 {
+        ESP_OK as esp_err_t
+    } else {
+        ESP_FAIL
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EspMqttConnection, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{error, info, warn};
+
+use crate::calibration;
+use crate::climate_control::{self, SharedOverrides};
+use crate::config::{Config, SharedConfig};
+use crate::error::{Context, Phase, Result};
+use crate::shutdown::Shutdown;
+use crate::sinks::{command_topic, heartbeat_topic, status_topic, Publisher, OFFLINE, ONLINE};
+use crate::tls;
+
+const STACK_SIZE: usize = 4 * 1024;
+// The broker gives up on the node, and publishes the will, after 1.5 times this without a word
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+type SharedClient = Arc<Mutex<EspMqttClient<'static>>>;
+
+/// Client of the broker in `mqtt.url`, it connects and reconnects in the background. Clones
+/// share the client.
+#[derive(Clone)]
+pub struct EspPublisher {
+    client: SharedClient,
+}
+
+/// What the commands on the command topic act on: `shutdown` requests a shutdown, `calibrate
+/// <metric> <reading> <reference> <reading> <reference>|clear` sets the correction of a metric,
+/// `ir <device> on|off|auto` overrides the climate rules for an IR device.
+pub struct Commands {
+    pub shutdown: Shutdown,
+    pub config: SharedConfig,
+    pub nvs: EspDefaultNvsPartition,
+    pub ir_overrides: SharedOverrides,
+}
+
+impl Commands {
+    fn handle(&self, data: &[u8]) {
+        let command = String::from_utf8_lossy(data);
+        let args: Vec<&str> = command.split_whitespace().collect();
+        match args.as_slice() {
+            ["shutdown"] => self.shutdown.request("MQTT"),
+            ["calibrate", rest @ ..] => {
+                let Some((metric, correction)) = calibration::parse_correction(rest) else {
+                    warn!("Malformed MQTT command '{}'", command);
+                    return;
+                };
+                let change = |calibration: &mut calibration::Calibration| {
+                    calibration.set_correction(metric, correction)
+                };
+                match calibration::update(&self.nvs, &self.config, change) {
+                    Ok(()) => info!("Correction of {} set over MQTT", metric),
+                    Err(err) => error!("{}", err),
+                }
+            }
+            ["ir", device, state] => {
+                if !self.config.lock().expect("Config lock poisoned").ir.devices.contains_key(*device) {
+                    warn!("Unknown IR device '{}' in an MQTT command", device);
+                } else if climate_control::set_override(&self.ir_overrides, device, state) {
+                    info!("{} set to {} over MQTT", device, state);
+                } else {
+                    warn!("Malformed MQTT command '{}'", command);
+                }
+            }
+            _ => warn!("Unknown MQTT command '{}'", command),
+        }
+    }
+}
+
+/// Sets up the client with an `offline` Last Will on the status topic. `online` is published on
+/// every connect and the uptime every `mqtt.heartbeat_sec`. An `mqtts://` broker is checked
+/// against the stored certificate, or the CA bundle without one. What arrives on the command
+/// topic goes to `commands`.
+pub fn connect(config: &Config, commands: Commands) -> Result<EspPublisher> {
+    let context = "Failed to set up MQTT";
+    let status = status_topic(config);
+    let client_id = config.hostname();
+    let configuration = MqttClientConfiguration {
+        client_id: Some(&client_id),
+        username: Some(config.mqtt.username.as_str()).filter(|username| !username.is_empty()),
+        password: Some(config.mqtt.password.as_str()).filter(|password| !password.is_empty()),
+        keep_alive_interval: Some(KEEP_ALIVE),
+        lwt: Some(LwtConfiguration {
+            topic: &status,
+            payload: OFFLINE.as_bytes(),
+            qos: to_qos(config.mqtt.qos),
+            retain: true,
+        }),
+        crt_bundle_attach: match config.tls_certificate {
+            Some(_) => None,
+            None => Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        },
+        server_certificate: config.tls_certificate.as_deref().map(tls::x509),
+        ..Default::default()
+    };
+    let (client, connection) = EspMqttClient::new(&config.mqtt.url, &configuration).context(Phase::Connect, context)?;
+    let client: SharedClient = Arc::new(Mutex::new(client));
+
+    // The client can't be used while an event is being handled, so publishing is left to the
+    // heartbeat thread
+    let (connected, on_connect) = mpsc::channel();
+    thread::Builder::new()
+        .name("mqtt".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || events(connection, connected, commands))
+        .context(Phase::Connect, context)?;
+    let heartbeat = Heartbeat {
+        client: client.clone(),
+        qos: to_qos(config.mqtt.qos),
+        status,
+        topic: heartbeat_topic(config),
+        commands: command_topic(config),
+        interval: Duration::from_secs(config.mqtt.heartbeat_sec.max(1) as u64),
+    };
+    thread::Builder::new()
+        .name("mqtt-heartbeat".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || heartbeat.run(on_connect))
+        .context(Phase::Connect, context)?;
+    info!("MQTT client started for {}", config.mqtt.url);
+    Ok(EspPublisher { client })
+}
+
+fn events(mut connection: EspMqttConnection, connected: Sender<()>, commands: Commands) {
+    while let Ok(event) = connection.next() {
+        match event.payload() {
+            EventPayload::Connected(_) => {
+                info!("MQTT connected");
+                let _ = connected.send(());
+            }
+            EventPayload::Received { data, .. } => commands.handle(data),
+            EventPayload::Disconnected => warn!("MQTT disconnected"),
+            EventPayload::Error(err) => error!("MQTT: {:?}", err),
+            _ => {}
+        }
+    }
+}
+
+struct Heartbeat {
+    client: SharedClient,
+    qos: QoS,
+    status: String,
+    topic: String,
+    commands: String,
+    interval: Duration,
+}
+
+impl Heartbeat {
+    fn run(self, on_connect: Receiver<()>) {
+        let started = Instant::now();
+        loop {
+            let (topic, payload, retain) = match on_connect.recv_timeout(self.interval) {
+                // Replaces the will left by the last time the node dropped off
+                Ok(()) => {
+                    // Subscriptions don't survive a reconnect with a clean session
+                    self.subscribe();
+                    (&self.status, ONLINE.to_string(), true)
+                }
+                Err(RecvTimeoutError::Timeout) => (&self.topic, started.elapsed().as_secs().to_string(), false),
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let mut client = self.client.lock().expect("MQTT client lock poisoned");
+            if let Err(err) = client.enqueue(topic, self.qos, retain, payload.as_bytes()) {
+                error!("Failed to publish to {}: {}", topic, err);
+            }
+        }
+    }
+
+    fn subscribe(&self) {
+        let mut client = self.client.lock().expect("MQTT client lock poisoned");
+        if let Err(err) = client.subscribe(&self.commands, QoS::AtLeastOnce) {
+            error!("Failed to subscribe to {}: {}", self.commands, err);
+        }
+    }
+}
+
+impl Publisher for EspPublisher {
+    fn publish(&mut self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()> {
+        self.client
+            .lock()
+            .expect("MQTT client lock poisoned")
+            .enqueue(topic, to_qos(qos), retain, payload)
+            .with_context(Phase::Archive, || format!("Failed to publish to {}", topic))?;
+        Ok(())
+    }
+}
+
+fn to_qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
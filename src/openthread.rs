@@ -0,0 +1,149 @@
+//! Thread as the network, over the 802.15.4 radio of the ESP32-C6/H2: OpenThread with the native
+//! radio, its netif attached to lwIP so the sinks reach the border router and beyond over IPv6.
+//! The node attaches for the uploads and detaches in between, which turns the radio off.
+
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::{
+    esp, esp_netif_attach, esp_netif_config_t, esp_netif_inherent_config_t, esp_netif_new, esp_netif_t,
+    esp_openthread_get_instance, esp_openthread_host_connection_config_t,
+    esp_openthread_host_connection_mode_t_HOST_CONNECTION_MODE_NONE, esp_openthread_init,
+    esp_openthread_launch_mainloop, esp_openthread_lock_acquire, esp_openthread_lock_release,
+    esp_openthread_netif_glue_init, esp_openthread_platform_config_t, esp_openthread_port_config_t,
+    esp_openthread_radio_config_t, esp_openthread_radio_mode_t_RADIO_MODE_NATIVE,
+    g_esp_netif_netstack_default_openthread, otDatasetSetActiveTlvs, otDeviceRole, otDeviceRole_OT_DEVICE_ROLE_CHILD,
+    otError, otError_OT_ERROR_NONE, otInstance, otIp6SetEnabled, otOperationalDatasetTlvs, otThreadGetDeviceRole,
+    otThreadSetEnabled, portMAX_DELAY,
+};
+use log::info;
+
+use crate::config::{Config, ThreadConfig};
+use crate::error::{Context, Error, Phase, Result};
+use crate::pipeline::Network;
+
+#[cfg(not(esp32c6))]
+compile_error!("The `thread` feature needs the 802.15.4 radio of the ESP32-C6");
+
+const STACK_SIZE: usize = 8 * 1024;
+const QUEUE_SIZE: u8 = 10;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Route priority of the netif, below Wi-Fi's
+const ROUTE_PRIO: i32 = 15;
+
+pub struct ThreadNetwork {
+    instance: *mut otInstance,
+}
+
+// OpenThread is only called into with its lock held
+unsafe impl Send for ThreadNetwork {}
+
+impl ThreadNetwork {
+    /// Starts OpenThread with the dataset, the node only attaches with `connect`.
+    pub fn new(config: &ThreadConfig) -> Result<Self> {
+        let context = "Failed to set up OpenThread";
+        let tlvs = config
+            .dataset_tlvs()
+            .ok_or_else(|| Error::failed(Phase::Boot, "thread.dataset isn't a dataset"))?;
+        // Kept for as long as OpenThread runs
+        let platform: &'static _ = Box::leak(Box::new(esp_openthread_platform_config_t {
+            radio_config: esp_openthread_radio_config_t {
+                radio_mode: esp_openthread_radio_mode_t_RADIO_MODE_NATIVE,
+                ..Default::default()
+            },
+            host_config: esp_openthread_host_connection_config_t {
+                host_connection_mode: esp_openthread_host_connection_mode_t_HOST_CONNECTION_MODE_NONE,
+                ..Default::default()
+            },
+            port_config: esp_openthread_port_config_t {
+                storage_partition_name: c"nvs".as_ptr(),
+                netif_queue_size: QUEUE_SIZE,
+                task_queue_size: QUEUE_SIZE,
+            },
+        }));
+        let inherent: &'static _ = Box::leak(Box::new(esp_netif_inherent_config_t {
+            if_key: c"OT_DEF".as_ptr(),
+            if_desc: c"openthread".as_ptr(),
+            route_prio: ROUTE_PRIO,
+            ..Default::default()
+        }));
+        let netif_config = esp_netif_config_t {
+            base: inherent,
+            driver: ptr::null(),
+            stack: unsafe { g_esp_netif_netstack_default_openthread },
+        };
+        let netif: *mut esp_netif_t = unsafe { esp_netif_new(&netif_config) };
+        if netif.is_null() {
+            return Err(Error::failed(Phase::Boot, "Failed to create the Thread netif"));
+        }
+        esp!(unsafe { esp_openthread_init(platform) }).context(Phase::Boot, context)?;
+        esp!(unsafe { esp_netif_attach(netif, esp_openthread_netif_glue_init(platform)) })
+            .context(Phase::Boot, context)?;
+        thread::Builder::new()
+            .name("openthread".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(|| esp!(unsafe { esp_openthread_launch_mainloop() }))
+            .context(Phase::Boot, context)?;
+
+        let network = ThreadNetwork {
+            instance: unsafe { esp_openthread_get_instance() },
+        };
+        let mut dataset = otOperationalDatasetTlvs {
+            mLength: tlvs.len() as u8,
+            ..Default::default()
+        };
+        dataset.mTlvs[..tlvs.len()].copy_from_slice(&tlvs);
+        network.locked(|instance| {
+            ot(unsafe { otDatasetSetActiveTlvs(instance, &dataset) }, "set the dataset")?;
+            ot(unsafe { otIp6SetEnabled(instance, true) }, "bring up IPv6")
+        })?;
+        Ok(network)
+    }
+
+    fn locked<T>(&self, f: impl FnOnce(*mut otInstance) -> T) -> T {
+        unsafe { esp_openthread_lock_acquire(portMAX_DELAY) };
+        let result = f(self.instance);
+        unsafe { esp_openthread_lock_release() };
+        result
+    }
+
+    fn role(&self) -> otDeviceRole {
+        self.locked(|instance| unsafe { otThreadGetDeviceRole(instance) })
+    }
+}
+
+impl Network for ThreadNetwork {
+    fn connect(&mut self, config: &Config) -> Result<()> {
+        self.locked(|instance| ot(unsafe { otThreadSetEnabled(instance, true) }, "start Thread"))?;
+        let started = Instant::now();
+        let timeout = Duration::from_secs(config.thread.attach_timeout_sec as u64);
+        loop {
+            // Child, router or leader
+            if self.role() >= otDeviceRole_OT_DEVICE_ROLE_CHILD {
+                info!("Attached to the Thread network");
+                return Ok(());
+            }
+            if started.elapsed() > timeout {
+                let _ = self.disconnect();
+                return Err(Error::failed(
+                    Phase::Connect,
+                    format!("Not attached to the Thread network after {} s", timeout.as_secs()),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        self.locked(|instance| ot(unsafe { otThreadSetEnabled(instance, false) }, "stop Thread"))
+    }
+}
+
+fn ot(err: otError, action: &str) -> Result<()> {
+    if err == otError_OT_ERROR_NONE {
+        Ok(())
+    } else {
+        Err(Error::failed(Phase::Connect, format!("OpenThread failed to {}: error {}", action, err)))
+    }
+}
@@ -0,0 +1,1512 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, error, info, warn};
+use rand::prelude::*;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+
+use crate::alerts::AlertWatch;
+use crate::buffers::PriorityBuffers;
+use crate::clock::ClockSync;
+use crate::compact::CompactBatch;
+use crate::config::{Config, JitterMode, SharedConfig, MAX_INTERVAL_SEC, MAX_JITTER_PERCENT, MIN_INTERVAL_SEC};
+use crate::dead_letter::{self, DeadLetter};
+use crate::downsample::Downsampler;
+use crate::error::{Error, Phase, Result};
+use crate::filters;
+use crate::registry::SensorRegistry;
+use crate::schedule;
+use crate::sensors::Measurement;
+use crate::shutdown::{BatteryCheck, Shutdown};
+use crate::spool;
+use crate::tasks::{self, Task};
+use crate::watchdog::{DeliveryWatchdog, Recovery};
+
+/// A round of measurements and when it was taken, in ms since the Unix epoch.
+pub type Batch = (u64, Vec<Measurement>);
+pub type SharedBuffer = Arc<Mutex<AllocRingBuffer<CompactBatch>>>;
+
+/// What the sampler hands the uploader.
+enum Upload {
+    /// A round is being measured, the link can come up in the meantime
+    Warm,
+    /// A round and whether it was measured aligned to the clock, its upload is spread then
+    Batch(Batch, bool),
+    /// The round came out empty, a link brought up for it goes down again
+    Cancel,
+    /// A round with an alert in it, or the measurements of an alert raised between rounds
+    Alert(Batch),
+}
+
+// Batches in flight between the sampler and the uploader, anything beyond goes straight to the buffer
+const CHANNEL_CAPACITY: usize = 8;
+#[cfg(target_os = "espidf")]
+const UPLINK_STACK_SIZE: usize = 16 * 1024;
+// Debug builds on the host need a lot more stack than the firmware
+#[cfg(not(target_os = "espidf"))]
+const UPLINK_STACK_SIZE: usize = 256 * 1024;
+
+/// Raised by the threads watching for events between rounds, e.g. the reed switch, to get the
+/// measurements of the event out right away, see `alerts.rs`. Alerts raised before the pipeline
+/// is running, while the clock isn't set or while the uploader is swamped are dropped, the next
+/// round has the state anyway. Clones share the uploader.
+#[derive(Clone, Default)]
+pub struct Alerts(Arc<Mutex<Option<AlertTarget>>>);
+
+struct AlertTarget {
+    sender: SyncSender<Upload>,
+    clock: ClockSync,
+}
+
+impl Alerts {
+    pub fn raise(&self, measurements: Vec<Measurement>) {
+        let target = self.0.lock().expect("Alerts lock poisoned");
+        let Some(target) = target.as_ref().filter(|target| target.clock.is_synced()) else {
+            return;
+        };
+        if target.sender.try_send(Upload::Alert((now_ms(), measurements))).is_err() {
+            warn!("Uploader is busy, dropping an alert");
+        }
+    }
+
+    fn connect(&self, sender: SyncSender<Upload>, clock: ClockSync) {
+        *self.0.lock().expect("Alerts lock poisoned") = Some(AlertTarget { sender, clock });
+    }
+
+    /// Lets go of the channel, the uploader stops once the sampler did.
+    fn disconnect(&self) {
+        *self.0.lock().expect("Alerts lock poisoned") = None;
+    }
+}
+
+/// Destination for measurements, e.g. a Graphite server or the SD card.
+pub trait Sink {
+    /// `timestamp_ms` is in ms since the Unix epoch, sinks that only take seconds truncate it.
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()>;
+
+    /// Total sent over the network so far, for the `uplink_bytes` metric.
+    fn bytes_written(&self) -> u64 {
+        0
+    }
+
+    /// For the logs, and the metrics of each uplink when there are several.
+    fn name(&self) -> &'static str {
+        "uplink"
+    }
+}
+
+/// Link that has to be brought up before buffered measurements can be sent to the uplink sink.
+pub trait Network {
+    fn connect(&mut self, config: &Config) -> Result<()>;
+    fn disconnect(&mut self) -> Result<()>;
+
+    /// Resets the link harder than a reconnect does, for the delivery watchdog.
+    fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a GET request got back.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub etag: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// HTTPS requests for the sinks talking to web APIs.
+pub trait HttpClient {
+    /// Sends `body` with the headers, returns the status code.
+    fn post(&mut self, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<u16>;
+
+    /// Fetches `url` with the headers, only needed for pulling from a server.
+    fn get(&mut self, url: &str, _headers: &[(&str, &str)]) -> Result<Response> {
+        Err(Error::failed(Phase::Upload, format!("Can't fetch {}, GET isn't supported", url)))
+    }
+}
+
+/// Runs while the network is up for an upload, e.g. to send error reports.
+pub trait Reporter {
+    fn report(&mut self, config: &Config) -> Result<()>;
+}
+
+/// Processing step between the sensors and the sinks, e.g. smoothing or derived metrics.
+pub trait Filter {
+    fn apply(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement>;
+}
+
+/// Measures all sensors, runs the results through the filters, writes them to the archive sinks
+/// right away and hands them to the uploader, which buffers them and sends everything buffered so
+/// far whenever the network comes up. With several uplinks each keeps its own place in the
+/// buffers, a batch is only dropped from them once every uplink has it.
+pub struct Pipeline<'a> {
+    sampler: Sampler<'a>,
+    uploader: Uploader<'a>,
+    spool: Option<PathBuf>,
+    alerts: Option<Alerts>,
+}
+
+/// Puts a pipeline together, the filters listed in the configuration come first.
+pub struct PipelineBuilder<'a> {
+    config: SharedConfig,
+    sensors: SensorRegistry<'a>,
+    filters: Vec<Box<dyn Filter + 'a>>,
+    buffers: Option<PriorityBuffers>,
+    network: Option<Box<dyn Network + Send + 'a>>,
+    uplinks: Vec<Box<dyn Sink + Send + 'a>>,
+    archives: Vec<Box<dyn Sink + 'a>>,
+    side_buffers: Vec<SharedBuffer>,
+    reporters: Vec<Box<dyn Reporter + Send + 'a>>,
+    reboot: Option<Box<dyn FnMut() + Send + 'a>>,
+    shutdown: Shutdown,
+    spool: Option<PathBuf>,
+    dead_letters: Option<PathBuf>,
+    clock: ClockSync,
+    alerts: Option<Alerts>,
+}
+
+struct Sampler<'a> {
+    sensors: SensorRegistry<'a>,
+    filters: Vec<Box<dyn Filter + 'a>>,
+    buffers: PriorityBuffers,
+    config: SharedConfig,
+    archives: Vec<Box<dyn Sink + 'a>>,
+    sender: SyncSender<Upload>,
+    dropped: Arc<AtomicU64>,
+    /// Values dropped for being outside the range declared for their metric
+    out_of_range: u64,
+    uplink_health: SharedUplinkHealth,
+    last_delay: Option<Duration>,
+    shutdown: Shutdown,
+    battery: BatteryCheck,
+    clock: ClockSync,
+    /// Rounds measured before the clock was set
+    held: Vec<HeldRound>,
+    alert_watch: AlertWatch,
+    /// Set once the clock took longer than `sntp.timeout_sec`, rounds go out as they are from then on
+    gave_up_on_clock: bool,
+}
+
+/// A round on its way to the uploader, held back while the clock isn't set.
+struct HeldRound {
+    measured: Instant,
+    measurements: Vec<Measurement>,
+    alert: bool,
+    aligned: bool,
+}
+
+/// Runs on its own thread, so Wi-Fi bring-up, DNS stalls and slow servers never delay sampling.
+struct Uploader<'a> {
+    buffers: PriorityBuffers,
+    config: SharedConfig,
+    network: Box<dyn Network + Send + 'a>,
+    uplinks: Vec<UplinkQueue<'a>>,
+    side_buffers: Vec<SharedBuffer>,
+    reporters: Vec<Box<dyn Reporter + Send + 'a>>,
+    receiver: Receiver<Upload>,
+    dropped: Arc<AtomicU64>,
+    uplink_health: SharedUplinkHealth,
+    watchdog: DeliveryWatchdog,
+    last_delivery: Instant,
+    dead_letters: Option<PathBuf>,
+    /// Batches turned down by an uplink since the boot
+    rejected: u64,
+    /// The outcome of bringing the link up ahead of the batch, and how long that took
+    warm: Option<Result<u64>>,
+    /// The batches of the alert rounds that came in since the last upload, by `seq`
+    alert_batches: Vec<u64>,
+    reboot: Box<dyn FnMut() + Send + 'a>,
+}
+
+/// An uplink sink and how far it got in each of the uploader's buffers, as the `seq` of the last
+/// batch it sent from there.
+struct UplinkQueue<'a> {
+    sink: Box<dyn Sink + Send + 'a>,
+    sent: Vec<u64>,
+    /// Per buffer, for an uplink in `downsample_min`
+    downsamplers: Vec<Downsampler>,
+}
+
+/// Outcome of the last upload, reported by the sampler with the next round.
+#[derive(Default, Clone)]
+struct UplinkHealth {
+    connect_ms: Option<u64>,
+    bytes: u64,
+    success: bool,
+    consecutive_failures: u32,
+    rejected: u64,
+    /// Batches each uplink still has to send, only with several uplinks
+    pending: Vec<(&'static str, usize)>,
+}
+
+type SharedUplinkHealth = Arc<Mutex<Option<UplinkHealth>>>;
+
+impl UplinkHealth {
+    fn measurements(&self) -> Vec<Measurement> {
+        let measurement = |name: &'static str, value: f32| Measurement {
+            name: name.into(),
+            value,
+        };
+        let mut measurements = vec![
+            measurement("uplink_success", if self.success { 1.0 } else { 0.0 }),
+            measurement("uplink_consecutive_failures", self.consecutive_failures as f32),
+            measurement("uplink_bytes", self.bytes as f32),
+            measurement("uplink_rejected_total", self.rejected as f32),
+        ];
+        if let Some(connect_ms) = self.connect_ms {
+            measurements.push(measurement("uplink_connect_ms", connect_ms as f32));
+        }
+        measurements.extend(self.pending.iter().map(|(name, pending)| Measurement {
+            name: format!("uplink_pending.{}", name).into(),
+            value: *pending as f32,
+        }));
+        measurements
+    }
+}
+
+impl<'a> PipelineBuilder<'a> {
+    pub fn new(config: SharedConfig) -> Self {
+        PipelineBuilder {
+            config,
+            sensors: SensorRegistry::default(),
+            filters: Vec::new(),
+            buffers: None,
+            network: None,
+            uplinks: Vec::new(),
+            archives: Vec::new(),
+            side_buffers: Vec::new(),
+            reporters: Vec::new(),
+            reboot: None,
+            shutdown: Shutdown::default(),
+            spool: None,
+            dead_letters: None,
+            clock: ClockSync::synced(),
+            alerts: None,
+        }
+    }
+
+    pub fn sensors(mut self, sensors: SensorRegistry<'a>) -> Self {
+        self.sensors = sensors;
+        self
+    }
+
+    pub fn filter(mut self, filter: Box<dyn Filter + 'a>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Defaults to buffers sized by `config.buffers`.
+    pub fn buffers(mut self, buffers: PriorityBuffers) -> Self {
+        self.buffers = Some(buffers);
+        self
+    }
+
+    pub fn network(mut self, network: Box<dyn Network + Send + 'a>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Can be called more than once, every uplink gets every batch. One that's failing doesn't
+    /// hold up the others, it catches up once it's back as long as the buffers keep its batches.
+    pub fn uplink(mut self, uplink: Box<dyn Sink + Send + 'a>) -> Self {
+        self.uplinks.push(uplink);
+        self
+    }
+
+    pub fn archive(mut self, sink: Box<dyn Sink + 'a>) -> Self {
+        self.archives.push(sink);
+        self
+    }
+
+    /// Extra buffer filled outside of the sampling loop, e.g. actigraphy epochs, it's sent along
+    /// with every upload.
+    pub fn side_buffer(mut self, buffer: SharedBuffer) -> Self {
+        self.side_buffers.push(buffer);
+        self
+    }
+
+    /// Runs after every upload that got the network up, whether the uplink succeeded or not.
+    pub fn reporter(mut self, reporter: Box<dyn Reporter + Send + 'a>) -> Self {
+        self.reporters.push(reporter);
+        self
+    }
+
+    /// Last resort of the delivery watchdog, without it the watchdog stops at restarting the
+    /// network.
+    pub fn reboot(mut self, reboot: Box<dyn FnMut() + Send + 'a>) -> Self {
+        self.reboot = Some(reboot);
+        self
+    }
+
+    /// Ends [`Pipeline::run`] when requested, without it only a low battery does.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// File on flash that takes what couldn't be sent before a shutdown, it's read back into the
+    /// buffer when the pipeline is built.
+    pub fn spool(mut self, path: impl Into<PathBuf>) -> Self {
+        self.spool = Some(path.into());
+        self
+    }
+
+    /// File on flash that takes the batches an uplink turned down for good, they're dropped
+    /// without one.
+    pub fn dead_letters(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dead_letters = Some(path.into());
+        self
+    }
+
+    /// Rounds measured before the clock is set are held back, and stamped with the right time once
+    /// it is. Defaults to a clock that's set.
+    pub fn clock(mut self, clock: ClockSync) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Connected to the uploader once the pipeline is built.
+    pub fn alerts(mut self, alerts: Alerts) -> Self {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    pub fn build(self) -> Result<Pipeline<'a>> {
+        let config = self.config.lock().expect("Config lock poisoned").clone();
+        let mut filters = Vec::new();
+        for filter in &config.filters {
+            filters.push(filters::from_config(filter, &config)?);
+        }
+        filters.extend(self.filters);
+
+        let network = self.network.ok_or_else(|| Error::failed(Phase::Boot, "Pipeline has no network"))?;
+        if self.uplinks.is_empty() {
+            return Err(Error::failed(Phase::Boot, "Pipeline has no uplink sink"));
+        }
+        let buffer_count = 3 + self.side_buffers.len();
+        let uplinks = self
+            .uplinks
+            .into_iter()
+            .map(|sink| UplinkQueue {
+                sink,
+                sent: vec![0; buffer_count],
+                downsamplers: (0..buffer_count).map(|_| Downsampler::default()).collect(),
+            })
+            .collect();
+        let buffers = self.buffers.unwrap_or_else(|| PriorityBuffers::from_config(&config));
+        let dropped = Arc::new(AtomicU64::new(0));
+        if let Some(path) = &self.spool {
+            match spool::take(path) {
+                Ok(batches) if batches.is_empty() => {}
+                Ok(batches) => {
+                    info!("Restored {} batches kept over the last shutdown", batches.len());
+                    for batch in batches {
+                        buffers.push((batch.timestamp_ms, batch.decode()), &dropped);
+                    }
+                }
+                Err(err) => error!("Failed to restore the batches kept over the last shutdown: {}", err),
+            }
+        }
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        if let Some(alerts) = &self.alerts {
+            alerts.connect(sender.clone(), self.clock.clone());
+        }
+        let uplink_health = SharedUplinkHealth::default();
+        Ok(Pipeline {
+            sampler: Sampler {
+                sensors: self.sensors,
+                filters,
+                buffers: buffers.clone(),
+                config: self.config.clone(),
+                archives: self.archives,
+                sender,
+                dropped: dropped.clone(),
+                out_of_range: 0,
+                uplink_health: uplink_health.clone(),
+                last_delay: None,
+                shutdown: self.shutdown,
+                battery: BatteryCheck::default(),
+                clock: self.clock,
+                held: Vec::new(),
+                alert_watch: AlertWatch::default(),
+                gave_up_on_clock: false,
+            },
+            uploader: Uploader {
+                buffers,
+                config: self.config,
+                network,
+                uplinks,
+                side_buffers: self.side_buffers,
+                reporters: self.reporters,
+                receiver,
+                dropped,
+                uplink_health,
+                watchdog: DeliveryWatchdog::default(),
+                last_delivery: Instant::now(),
+                dead_letters: self.dead_letters,
+                rejected: 0,
+                warm: None,
+                alert_batches: Vec::new(),
+                reboot: self
+                    .reboot
+                    .unwrap_or_else(|| Box::new(|| error!("Delivery watchdog can't reboot, no reboot set up"))),
+            },
+            spool: self.spool,
+            alerts: self.alerts,
+        })
+    }
+}
+
+impl<'a> Pipeline<'a> {
+    /// Measurements waiting to be uploaded, shared with the console.
+    pub fn buffers(&self) -> PriorityBuffers {
+        self.sampler.buffers.clone()
+    }
+
+    /// Samples until a shutdown is requested. The sensors are then powered down, the uploader
+    /// makes a last attempt and whatever is still buffered goes to the spool.
+    pub fn run(self) {
+        let Pipeline {
+            mut sampler,
+            mut uploader,
+            spool,
+            alerts,
+        } = self;
+        let buffers = uploader.buffers();
+        let tasks = sampler.config.lock().expect("Config lock poisoned").tasks.clone();
+        thread::scope(|scope| {
+            tasks::spawning(&tasks, Task::Network, || {
+                thread::Builder::new()
+                    .name("uplink".to_string())
+                    .stack_size(UPLINK_STACK_SIZE)
+                    .spawn_scoped(scope, move || uploader.run())
+                    .expect("Failed to start the uplink thread")
+            });
+            tasks::prioritize(&tasks, Task::Sensing);
+
+            debug!("Starting main loop");
+            loop {
+                let delay = sampler.sample();
+                if sampler.wait(delay) {
+                    break;
+                }
+            }
+            sampler.sensors.power_down();
+            sampler.release_held(true);
+            // Closes the channel, the uploader finishes once it's done with what's in it
+            if let Some(alerts) = &alerts {
+                alerts.disconnect();
+            }
+            drop(sampler);
+        });
+
+        let left: Vec<CompactBatch> = buffers
+            .iter()
+            .flat_map(|buffer| lock_buffer(buffer).drain().collect::<Vec<_>>())
+            .collect();
+        match spool {
+            _ if left.is_empty() => info!("Everything was sent before the shutdown"),
+            Some(path) => match spool::save(&path, &left) {
+                Ok(()) => info!("Kept {} batches on flash for the next boot", left.len()),
+                Err(err) => error!("Failed to keep {} batches on flash: {}", left.len(), err),
+            },
+            None => warn!("{} batches lost in the shutdown, there's no spool", left.len()),
+        }
+    }
+
+    /// Runs a single measure and upload cycle on the calling thread.
+    #[cfg(test)]
+    fn cycle(&mut self) {
+        self.sampler.sample();
+        self.uploader.upload();
+    }
+}
+
+impl Sampler<'_> {
+    /// Measures all sensors and passes the results on, returns how long to wait until the next round.
+    fn sample(&mut self) -> Duration {
+        // Picks up changes made from the console on every cycle
+        let config = self.config.lock().expect("Config lock poisoned").clone();
+        let started = Instant::now();
+
+        let clock_set = self.clock_set(&config);
+        let aligned = config.align_to_clock && clock_set;
+        // Wi-Fi takes about as long to come up as the slower sensors take to measure, an always-on
+        // link is up already. Not while waiting for the clock, the link brought up at boot for
+        // SNTP stays up until the first upload. Nor with aligned rounds, whose uploads are spread.
+        let warm = clock_set
+            && !aligned
+            && config.wifi.prewarm
+            && !config.wifi.always_on
+            && self.sender.try_send(Upload::Warm).is_ok();
+        let mut new_measurements = self.sensors.measure();
+        self.out_of_range += self.sensors.metrics().validate(&mut new_measurements) as u64;
+        config.calibration.correct(&mut new_measurements);
+        for filter in &mut self.filters {
+            new_measurements = filter.apply(new_measurements);
+        }
+
+        config.check_thresholds(&new_measurements);
+        let alert = config.alerts.enabled && self.alert_watch.check(&config, &new_measurements);
+        if self.battery.check(&config.shutdown, &new_measurements) {
+            self.shutdown.request("low battery");
+        }
+
+        if !new_measurements.is_empty() {
+            // Makes data lost during long outages visible next to the data that made it, in batches
+            // over all priorities
+            new_measurements.push(Measurement {
+                name: "buffer_depth".into(),
+                value: self.buffers.len() as f32,
+            });
+            new_measurements.push(Measurement {
+                name: "buffer_dropped_total".into(),
+                value: self.dropped.load(Ordering::Relaxed) as f32,
+            });
+            if self.out_of_range > 0 {
+                new_measurements.push(Measurement {
+                    name: "out_of_range_total".into(),
+                    value: self.out_of_range as f32,
+                });
+            }
+            if let Some(health) = &*self.uplink_health.lock().expect("Uplink health lock poisoned") {
+                new_measurements.extend(health.measurements());
+            }
+            // Aligned rounds are stamped with the time they were due
+            self.held.push(HeldRound {
+                measured: if aligned { started } else { Instant::now() },
+                measurements: new_measurements,
+                alert,
+                aligned,
+            });
+        } else if warm {
+            let _ = self.sender.try_send(Upload::Cancel);
+        }
+        self.release_held(clock_set);
+
+        if aligned {
+            return aligned_delay(&config, now_ms());
+        }
+        let delay = next_delay(&config, self.last_delay);
+        self.last_delay = Some(delay);
+        delay
+    }
+
+    /// Whether rounds can be stamped with the system clock. Once the clock took longer than
+    /// `sntp.timeout_sec` since the first round it's used as it is, rather than holding the rounds
+    /// back for good.
+    fn clock_set(&mut self, config: &Config) -> bool {
+        if self.clock.is_synced() || self.gave_up_on_clock {
+            return true;
+        }
+        let waited = self.held.first().map_or(Duration::ZERO, |round| round.measured.elapsed());
+        if waited >= Duration::from_secs(config.sntp.timeout_sec as u64) {
+            warn!("The clock still isn't set, going on with it as it is");
+            self.gave_up_on_clock = true;
+        }
+        self.gave_up_on_clock
+    }
+
+    /// Archives the held rounds and hands them to the uploader, stamped with the time they were
+    /// measured by the clock as it is now. Nothing while the clock isn't set.
+    fn release_held(&mut self, clock_set: bool) {
+        if !clock_set || self.held.is_empty() {
+            return;
+        }
+        let config = self.config.lock().expect("Config lock poisoned").clone();
+        let now = now_ms();
+        for round in std::mem::take(&mut self.held) {
+            let timestamp_ms = now.saturating_sub(round.measured.elapsed().as_millis() as u64);
+            for archive in &mut self.archives {
+                if let Err(err) = archive.send(&config, timestamp_ms, &round.measurements) {
+                    error!("Error while archiving measurements: {}", err);
+                }
+            }
+            let batch = (timestamp_ms, round.measurements);
+            let upload = if round.alert {
+                Upload::Alert(batch)
+            } else {
+                Upload::Batch(batch, round.aligned)
+            };
+            match self.sender.try_send(upload) {
+                Ok(_) => {}
+                // The uploader is stuck on the network, the buffer still takes the batch
+                Err(TrySendError::Full(upload) | TrySendError::Disconnected(upload)) => {
+                    warn!("Uploader is falling behind, buffering directly");
+                    if let Upload::Batch(batch, _) | Upload::Alert(batch) = upload {
+                        self.buffers.push(batch, &self.dropped);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sleeps until the next round, waking the sensors that want a head start on the way. True if
+    /// a shutdown was requested in the meantime.
+    fn wait(&mut self, delay: Duration) -> bool {
+        let lead = self.sensors.wake_lead().min(delay);
+        if self.shutdown.sleep(delay - lead) {
+            return true;
+        }
+        if lead.is_zero() {
+            return false;
+        }
+        let woken = Instant::now();
+        self.sensors.wake();
+        self.shutdown.sleep(lead.saturating_sub(woken.elapsed()))
+    }
+}
+
+impl Uploader<'_> {
+    fn run(&mut self) {
+        debug!("Starting uplink loop");
+        // Wakes up whenever a new batch arrives, stops once the sampler is gone
+        while let Ok(upload) = self.receiver.recv() {
+            match upload {
+                Upload::Warm => self.warm(),
+                Upload::Batch(batch, aligned) => {
+                    self.buffers.push(batch, &self.dropped);
+                    if aligned {
+                        let config = self.config.lock().expect("Config lock poisoned").clone();
+                        self.hold_back(upload_jitter(&config));
+                    }
+                    self.upload();
+                }
+                Upload::Cancel => self.cool(),
+                // Not held back by the upload jitter
+                Upload::Alert(batch) => {
+                    self.push_alert(batch);
+                    self.upload();
+                }
+            }
+        }
+        self.cool();
+        // The sampler stopped for a shutdown, one more try for what the last upload left behind
+        if self.buffers().iter().any(|buffer| !lock_buffer(buffer).is_empty()) {
+            let config = self.config.lock().expect("Config lock poisoned").clone();
+            self.send_buffered(&config);
+        }
+    }
+
+    /// Waits out the upload jitter, buffering the rounds that come in meanwhile. An alert ends
+    /// the wait right away.
+    fn hold_back(&mut self, jitter: Duration) {
+        let deadline = Instant::now() + jitter;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.receiver.recv_timeout(left) {
+                Ok(Upload::Batch(batch, _)) => self.buffers.push(batch, &self.dropped),
+                Ok(Upload::Alert(batch)) => {
+                    self.push_alert(batch);
+                    return;
+                }
+                Ok(Upload::Warm | Upload::Cancel) => {}
+                // Out of time, or the sampler is gone
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn push_alert(&mut self, batch: Batch) {
+        let seqs = self.buffers.push_seqs(batch, &self.dropped);
+        self.alert_batches.extend(seqs);
+    }
+
+    fn upload(&mut self) {
+        // Everything that came in while the last upload was running, the upload brings the link
+        // up anyway
+        while let Ok(upload) = self.receiver.try_recv() {
+            match upload {
+                Upload::Batch(batch, _) => self.buffers.push(batch, &self.dropped),
+                Upload::Alert(batch) => self.push_alert(batch),
+                Upload::Warm | Upload::Cancel => {}
+            }
+        }
+        debug!("Measurements available for sending: {}", self.buffers.len());
+
+        let config = self.config.lock().expect("Config lock poisoned").clone();
+        let (connect_ms, delivered, bytes) = self.send_buffered(&config);
+        self.alert_batches.clear();
+        let success = delivered == self.uplinks.len();
+
+        {
+            let mut health = self.uplink_health.lock().expect("Uplink health lock poisoned");
+            let failures = health.as_ref().map_or(0, |health| health.consecutive_failures);
+            *health = Some(UplinkHealth {
+                connect_ms,
+                bytes,
+                success,
+                consecutive_failures: if success { 0 } else { failures + 1 },
+                rejected: self.rejected,
+                pending: self.pending(),
+            });
+        }
+
+        // The network is fine as long as one of the uplinks got through
+        if delivered > 0 {
+            self.last_delivery = Instant::now();
+            self.watchdog.delivered();
+            return;
+        }
+        let since_delivery = self.last_delivery.elapsed();
+        match self.watchdog.check(config.delivery_watchdog_hours, since_delivery) {
+            Some(Recovery::RestartNetwork) => {
+                warn!("Nothing delivered for {} min, restarting the network", since_delivery.as_secs() / 60);
+                if let Err(err) = self.network.restart() {
+                    error!("Failed to restart the network: {}", err);
+                }
+            }
+            Some(Recovery::Reboot) => {
+                error!("Nothing delivered for {} min, rebooting", since_delivery.as_secs() / 60);
+                (self.reboot)();
+            }
+            None => {}
+        }
+    }
+
+    /// Brings the network up while the sampler measures. A failure is left for the upload to
+    /// report, it doesn't try again in the same round.
+    fn warm(&mut self) {
+        if self.warm.is_some() {
+            return;
+        }
+        let config = self.config.lock().expect("Config lock poisoned").clone();
+        self.warm = Some(self.connect(&config));
+    }
+
+    /// Takes down a link brought up for a round that didn't come.
+    fn cool(&mut self) {
+        if let Some(Ok(_)) = self.warm.take() {
+            if let Err(error) = self.network.disconnect() {
+                error!("Error while trying to disconnect from wifi: {}", error);
+            }
+        }
+    }
+
+    /// Brings the network up, returns how long that took in ms.
+    fn connect(&mut self, config: &Config) -> Result<u64> {
+        let started = Instant::now();
+        self.network.connect(config)?;
+        Ok(started.elapsed().as_millis() as u64)
+    }
+
+    /// Brings the network up unless it's warm already, sends the buffers and runs the reporters.
+    /// Returns how long the network took to come up, how many uplinks sent everything and how
+    /// many bytes they sent.
+    fn send_buffered(&mut self, config: &Config) -> (Option<u64>, usize, u64) {
+        let connected = match self.warm.take() {
+            Some(connected) => connected,
+            None => self.connect(config),
+        };
+        match connected {
+            Ok(connect_ms) => {
+                let written = self.bytes_written();
+                let delivered = self.flush(config);
+                let bytes = self.bytes_written() - written;
+                for reporter in &mut self.reporters {
+                    // Not an error, a failing reporter would otherwise report itself
+                    if let Err(err) = reporter.report(config) {
+                        warn!("Failed to send a report: {}", err);
+                    }
+                }
+                if let Err(error) = self.network.disconnect() {
+                    error!("Error while trying to disconnect from wifi: {}", error);
+                }
+                (Some(connect_ms), delivered, bytes)
+            }
+            Err(error) => {
+                error!("Error while trying to connect to wifi: {}", error);
+                (None, 0, 0)
+            }
+        }
+    }
+
+    fn buffers(&self) -> Vec<SharedBuffer> {
+        self.buffers.all().into_iter().chain(self.side_buffers.iter().cloned()).collect()
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.uplinks.iter().map(|uplink| uplink.sink.bytes_written()).sum()
+    }
+
+    /// Sends all buffers to every uplink and drops what all of them have, returns how many sent
+    /// everything.
+    fn flush(&mut self, config: &Config) -> usize {
+        let buffers = self.buffers();
+        let (dead_letters, rejected) = (self.dead_letters.as_deref(), &mut self.rejected);
+        let alert_batches: &[u64] = if config.alerts.enabled { &self.alert_batches } else { &[] };
+        let delivered = self
+            .uplinks
+            .iter_mut()
+            .map(|uplink| uplink.flush(config, &buffers, alert_batches, dead_letters, rejected))
+            .filter(|sent| *sent)
+            .count();
+        for (index, buffer) in buffers.iter().enumerate() {
+            let sent_by_all = self.uplinks.iter().map(|uplink| uplink.sent[index]).min().unwrap_or(0);
+            let mut buffer = lock_buffer(buffer);
+            while buffer.front().is_some_and(|batch| batch.seq() <= sent_by_all) {
+                buffer.dequeue();
+            }
+        }
+        delivered
+    }
+
+    /// Batches each uplink still has to send, only with several uplinks.
+    fn pending(&self) -> Vec<(&'static str, usize)> {
+        if self.uplinks.len() < 2 {
+            return Vec::new();
+        }
+        let buffers = self.buffers();
+        self.uplinks
+            .iter()
+            .map(|uplink| {
+                let pending = buffers
+                    .iter()
+                    .zip(&uplink.sent)
+                    .map(|(buffer, sent)| lock_buffer(buffer).iter().filter(|batch| batch.seq() > *sent).count())
+                    .sum();
+                (uplink.sink.name(), pending)
+            })
+            .collect()
+    }
+}
+
+impl UplinkQueue<'_> {
+    /// Sends what's new to this uplink in every buffer, returns false if it failed. A batch it
+    /// turns down goes to the dead letters, counted in `rejected`. `alert_batches` go to a
+    /// downsampled uplink as they are as well, and still count towards the average.
+    fn flush(
+        &mut self,
+        config: &Config,
+        buffers: &[SharedBuffer],
+        alert_batches: &[u64],
+        dead_letters: Option<&Path>,
+        rejected: &mut u64,
+    ) -> bool {
+        let window_ms = config.downsample_min.get(self.sink.name()).map_or(0, |minutes| *minutes as u64 * 60_000);
+        for ((buffer, sent), downsampler) in buffers.iter().zip(&mut self.sent).zip(&mut self.downsamplers) {
+            loop {
+                if let Some(average) = &downsampler.ready {
+                    if !send(&mut *self.sink, config, average, dead_letters, rejected) {
+                        return false;
+                    }
+                    downsampler.ready = None;
+                    continue;
+                }
+                // Not holding the lock while sending, so the console stays responsive
+                let next = lock_buffer(buffer).iter().find(|batch| batch.seq() > *sent).cloned();
+                let Some(batch) = next else {
+                    break;
+                };
+                if window_ms > 0 {
+                    let alert = alert_batches.contains(&batch.seq());
+                    if alert && !send(&mut *self.sink, config, &batch, dead_letters, rejected) {
+                        return false;
+                    }
+                    downsampler.push(window_ms, &batch);
+                } else if downsampler.finish() {
+                    // Downsampling was turned off, what was averaged so far goes first
+                    continue;
+                } else if !send(&mut *self.sink, config, &batch, dead_letters, rejected) {
+                    return false;
+                }
+                *sent = batch.seq();
+            }
+        }
+        true
+    }
+}
+
+/// Sends a batch to the uplink, false if it failed. One it turns down counts as sent.
+fn send(
+    sink: &mut (dyn Sink + Send + '_),
+    config: &Config,
+    batch: &CompactBatch,
+    dead_letters: Option<&Path>,
+    rejected: &mut u64,
+) -> bool {
+    match sink.send(config, batch.timestamp_ms, &batch.decode()) {
+        Ok(()) => true,
+        Err(err) if err.is_rejected() => {
+            error!("{} turned a batch down, it goes to the dead letters: {}", sink.name(), err);
+            *rejected += 1;
+            let letter = DeadLetter::new(sink.name(), &err, batch);
+            if let Some(Err(err)) = dead_letters.map(|path| dead_letter::add(path, letter)) {
+                error!("Failed to keep a dead letter: {}", err);
+            }
+            true
+        }
+        Err(err) => {
+            error!("Error while sending data to {}: {}", sink.name(), err);
+            false
+        }
+    }
+}
+
+fn lock_buffer(buffer: &SharedBuffer) -> MutexGuard<'_, AllocRingBuffer<CompactBatch>> {
+    buffer.lock().expect("Measurement buffer lock poisoned")
+}
+
+pub fn now() -> u64 {
+    now_ms() / 1000
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time should be after Unix epoch")
+        .as_millis() as u64
+}
+
+/// Wait until the next round, `previous` being the last one for decorrelated jitter. Clamped
+/// like `Config::validate` would, a bad value set in code shouldn't stop the sampling.
+fn next_delay(config: &Config, previous: Option<Duration>) -> Duration {
+    let interval = schedule::interval_sec(config).clamp(MIN_INTERVAL_SEC, MAX_INTERVAL_SEC) as f64;
+    let percent = config.jitter.percent.clamp(0.0, MAX_JITTER_PERCENT) as f64;
+    let spread = interval * percent / 100.0;
+    let mut rng = rand::rng();
+    let delay = match config.jitter.mode {
+        _ if spread <= 0.0 => interval,
+        JitterMode::None => interval,
+        JitterMode::Uniform => rng.random_range((interval - spread)..=(interval + spread)),
+        JitterMode::Decorrelated => {
+            let previous = previous.map_or(interval, |previous| previous.as_secs_f64());
+            (previous + rng.random_range(-spread..=spread)).clamp(interval - spread, interval + spread)
+        }
+    };
+    Duration::from_secs_f64(delay)
+}
+
+/// Wait until the next multiple of the interval by the local time. One that's closer than a tenth
+/// of the interval is skipped, it's the one just measured with the clock running a little ahead.
+fn aligned_delay(config: &Config, now_ms: u64) -> Duration {
+    let interval_ms = schedule::interval_sec(config).clamp(MIN_INTERVAL_SEC, MAX_INTERVAL_SEC) as i64 * 1000;
+    let local_ms = now_ms as i64 + config.utc_offset_min as i64 * 60 * 1000;
+    let mut delay_ms = interval_ms - local_ms.rem_euclid(interval_ms);
+    if delay_ms < interval_ms / 10 {
+        delay_ms += interval_ms;
+    }
+    Duration::from_millis(delay_ms as u64)
+}
+
+/// How long the upload of an aligned round is held back, so the nodes measuring at the same time
+/// don't all upload at once. Only ever later, never before the round.
+fn upload_jitter(config: &Config) -> Duration {
+    let interval = schedule::interval_sec(config).clamp(MIN_INTERVAL_SEC, MAX_INTERVAL_SEC) as f64;
+    let spread = interval * config.jitter.percent.clamp(0.0, MAX_JITTER_PERCENT) as f64 / 100.0;
+    if config.jitter.mode == JitterMode::None || spread <= 0.0 {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64(rand::rng().random_range(0.0..=spread))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BufferConfig;
+    use crate::metrics::MetricSpec;
+    use crate::mock::{MockNetwork, MockReading, MockSensor, MockSink};
+
+    fn co2(value: f32) -> MockReading {
+        MockReading::Values(vec![("co2", value)])
+    }
+
+    fn pipeline<'a>(
+        script: Vec<MockReading>,
+        capacity: usize,
+        network: &MockNetwork,
+        sink: &MockSink,
+    ) -> (Pipeline<'a>, PriorityBuffers) {
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(script)));
+        let pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .buffers(PriorityBuffers::new(&BufferConfig::default(), [capacity; 3]))
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .build()
+            .unwrap();
+        let buffers = pipeline.buffers();
+        (pipeline, buffers)
+    }
+
+    #[test]
+    fn sends_measurements_when_network_is_up() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (mut pipeline, buffers) = pipeline(vec![co2(500.0), co2(600.0)], 8, &network, &sink);
+
+        pipeline.cycle();
+        pipeline.cycle();
+
+        assert_eq!(sink.sent_values("co2"), vec![500.0, 600.0]);
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn keeps_measurements_while_network_is_down() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (mut pipeline, buffers) = pipeline(vec![co2(500.0), co2(600.0), co2(700.0)], 8, &network, &sink);
+
+        network.fail_next(2);
+        pipeline.cycle();
+        pipeline.cycle();
+        // CO2 and the buffer diagnostics go into different classes
+        assert_eq!(buffers.len(), 4);
+        assert_eq!(sink.state.lock().unwrap().attempts, 0);
+
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("co2"), vec![500.0, 600.0, 700.0]);
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn requeues_batch_when_sink_fails() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (mut pipeline, buffers) = pipeline(vec![co2(500.0), co2(600.0), co2(700.0)], 8, &network, &sink);
+
+        network.fail_next(2);
+        pipeline.cycle();
+        pipeline.cycle();
+
+        // First send fails, the flush stops and the batch goes back into the buffer
+        sink.fail_next(1);
+        pipeline.cycle();
+        assert!(sink.sent_values("co2").is_empty());
+        assert_eq!(buffers.len(), 6);
+
+        pipeline.cycle();
+        let mut sent = sink.sent_values("co2");
+        sent.sort_by(f32::total_cmp);
+        assert_eq!(sent, vec![500.0, 600.0, 700.0]);
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn skips_empty_cycles() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (mut pipeline, buffers) = pipeline(vec![MockReading::Error, co2(500.0)], 8, &network, &sink);
+
+        network.fail_next(1);
+        pipeline.cycle();
+        assert!(buffers.is_empty());
+
+        pipeline.cycle();
+        // The round's CO2 and its diagnostics, nothing for the empty one
+        assert_eq!(sink.state.lock().unwrap().sent.len(), 2);
+    }
+
+    #[test]
+    fn uplinks_drain_the_buffers_independently() {
+        let network = MockNetwork::default();
+        let (graphite, mqtt) = (MockSink::default(), MockSink::default());
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0), co2(600.0), co2(700.0)])));
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(graphite.clone()))
+            .uplink(Box::new(mqtt.clone()))
+            .build()
+            .unwrap();
+        let buffers = pipeline.buffers();
+
+        mqtt.fail_next(2);
+        pipeline.cycle();
+        pipeline.cycle();
+        assert_eq!(graphite.sent_values("co2"), vec![500.0, 600.0]);
+        assert!(mqtt.sent_values("co2").is_empty());
+        // Kept for the uplink that's behind
+        assert_eq!(buffers.len(), 4);
+
+        pipeline.cycle();
+        assert_eq!(graphite.sent_values("co2"), vec![500.0, 600.0, 700.0]);
+        assert_eq!(mqtt.sent_values("co2"), vec![500.0, 600.0, 700.0]);
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn moves_rejected_batches_to_the_dead_letters() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let dead_letters = std::env::temp_dir().join(format!("pipeline-dead-letters-{}.jsonl", std::process::id()));
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0), co2(600.0)])));
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .dead_letters(&dead_letters)
+            .build()
+            .unwrap();
+        let buffers = pipeline.buffers();
+
+        sink.reject_next(1);
+        pipeline.cycle();
+        // The rest of the round still goes out
+        assert!(sink.sent_values("co2").is_empty());
+        assert_eq!(sink.sent_values("buffer_depth"), vec![0.0]);
+        assert!(buffers.is_empty());
+
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("co2"), vec![600.0]);
+        assert_eq!(sink.sent_values("uplink_rejected_total"), vec![1.0]);
+        let letters = dead_letter::read(&dead_letters).unwrap();
+        assert_eq!(letters[0].measurements, vec![("co2".to_string(), Some(500.0))]);
+        dead_letter::clear(&dead_letters).unwrap();
+    }
+
+    #[test]
+    fn drops_oldest_batches_when_buffer_is_full() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let script = (1..=5).map(|value| co2(value as f32)).collect();
+        let (mut pipeline, _) = pipeline(script, 2, &network, &sink);
+
+        network.fail_next(4);
+        for _ in 0..5 {
+            pipeline.cycle();
+        }
+        assert_eq!(sink.sent_values("co2"), vec![4.0, 5.0]);
+        // Each batch reports the drops that happened before it was buffered, in both classes
+        assert_eq!(sink.sent_values("buffer_dropped_total"), vec![2.0, 4.0]);
+        assert_eq!(sink.sent_values("buffer_depth"), vec![4.0, 4.0]);
+    }
+
+    #[test]
+    fn archives_even_when_network_is_down() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let archive = MockSink::default();
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0)])));
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .archive(Box::new(archive.clone()))
+            .build()
+            .unwrap();
+
+        network.fail_next(1);
+        pipeline.cycle();
+        assert_eq!(archive.sent_values("co2"), vec![500.0]);
+        assert!(sink.sent_values("co2").is_empty());
+    }
+
+    #[test]
+    fn keeps_what_wasnt_sent_over_a_shutdown() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let spool = std::env::temp_dir().join(format!("pipeline-spool-{}.jsonl", std::process::id()));
+        let shutdown = Shutdown::default();
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0)])));
+        let pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .shutdown(shutdown.clone())
+            .spool(&spool)
+            .build()
+            .unwrap();
+
+        // The upload of the round and the last attempt
+        network.fail_next(2);
+        shutdown.request("test");
+        pipeline.run();
+        assert!(sink.sent_values("co2").is_empty());
+
+        let restored = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .spool(&spool)
+            .build()
+            .unwrap();
+        // The round's CO2 and diagnostics
+        assert_eq!(restored.buffers().len(), 2);
+        assert!(!spool.exists());
+    }
+
+    #[test]
+    fn brings_the_link_up_while_sensors_measure() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (pipeline, _) = pipeline(vec![MockReading::Error, co2(500.0)], 8, &network, &sink);
+        let Pipeline {
+            mut sampler,
+            mut uploader,
+            ..
+        } = pipeline;
+
+        sampler.sample();
+        sampler.sample();
+        drop(sampler);
+        uploader.run();
+        // One link a round, the empty round's taken down again without an upload
+        assert_eq!(*network.connects.lock().unwrap(), 2);
+        assert_eq!(*network.disconnects.lock().unwrap(), 2);
+        assert_eq!(sink.sent_values("co2"), vec![500.0]);
+    }
+
+    #[test]
+    fn holds_rounds_back_until_the_clock_is_set() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let clock = ClockSync::default();
+        let build = |config: Config, clock: &ClockSync| {
+            let mut sensors = SensorRegistry::default();
+            sensors.add(Box::new(MockSensor::new(vec![co2(500.0), co2(600.0)])));
+            PipelineBuilder::new(Arc::new(Mutex::new(config)))
+                .sensors(sensors)
+                .network(Box::new(network.clone()))
+                .uplink(Box::new(sink.clone()))
+                .clock(clock.clone())
+                .build()
+                .unwrap()
+        };
+        let mut pipeline = build(Config::default(), &clock);
+
+        pipeline.cycle();
+        assert!(sink.sent_values("co2").is_empty());
+        clock.set();
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("co2"), vec![500.0, 600.0]);
+        let sent = sink.state.lock().unwrap().sent.clone();
+        assert!(sent[0].0 <= sent[sent.len() - 1].0);
+
+        // Without a clock for longer than the timeout, the rounds go out as they are
+        let mut config = Config::default();
+        config.sntp.timeout_sec = 0;
+        let mut pipeline = build(config, &ClockSync::default());
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("co2"), vec![500.0, 600.0, 500.0]);
+    }
+
+    #[test]
+    fn alerts_get_past_the_downsampling() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let mut config = Config::default();
+        config.downsample_min.insert("uplink".to_string(), 15);
+        config.thresholds.insert(
+            "co2".to_string(),
+            crate::config::Threshold {
+                min: None,
+                max: Some(1000.0),
+            },
+        );
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0), co2(1500.0), co2(1600.0)])));
+        let alerts = Alerts::default();
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(config)))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .alerts(alerts.clone())
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            pipeline.cycle();
+        }
+        // The others wait for the end of the window
+        assert_eq!(sink.sent_values("co2"), vec![1500.0]);
+
+        alerts.raise(vec![Measurement {
+            name: "window_open".into(),
+            value: 1.0,
+        }]);
+        pipeline.uploader.upload();
+        assert_eq!(sink.sent_values("window_open"), vec![1.0]);
+    }
+
+    #[test]
+    fn only_the_alert_round_gets_past_the_downsampling() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let mut config = Config::default();
+        config.downsample_min.insert("uplink".to_string(), 15);
+        config.thresholds.insert(
+            "co2".to_string(),
+            crate::config::Threshold {
+                min: None,
+                max: Some(1000.0),
+            },
+        );
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0), co2(600.0), co2(1500.0), co2(700.0)])));
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(config)))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .build()
+            .unwrap();
+
+        // The regular rounds wait in the buffer, then go out with the alert round
+        network.fail_next(2);
+        for _ in 0..4 {
+            pipeline.cycle();
+        }
+        assert_eq!(sink.sent_values("co2"), vec![1500.0]);
+    }
+
+    #[test]
+    fn alerts_cut_the_upload_jitter_short() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (mut pipeline, buffers) = pipeline(vec![], 32, &network, &sink);
+        let batch = |value| {
+            let measurement = Measurement {
+                name: "co2".into(),
+                value,
+            };
+            (now_ms(), vec![measurement])
+        };
+        pipeline.sampler.sender.send(Upload::Batch(batch(900.0), true)).unwrap();
+        pipeline.sampler.sender.send(Upload::Alert(batch(1500.0))).unwrap();
+
+        let started = Instant::now();
+        pipeline.uploader.hold_back(Duration::from_secs(60));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(pipeline.uploader.alert_batches.len(), 1);
+    }
+
+    #[test]
+    fn spills_into_buffer_while_uploader_is_busy() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let script = (1..=CHANNEL_CAPACITY + 2).map(|value| co2(value as f32)).collect();
+        let (mut pipeline, buffers) = pipeline(script, 32, &network, &sink);
+        // Warm-ups would take up slots of their own
+        pipeline.sampler.config.lock().unwrap().wifi.prewarm = false;
+
+        for _ in 0..CHANNEL_CAPACITY + 2 {
+            pipeline.sampler.sample();
+        }
+        assert_eq!(buffers.len(), 4);
+
+        pipeline.uploader.upload();
+        assert_eq!(sink.sent_values("co2").len(), CHANNEL_CAPACITY + 2);
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn flushes_side_buffers_with_every_upload() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let epochs: SharedBuffer = Arc::new(Mutex::new(AllocRingBuffer::new(8)));
+        let mut sensors = SensorRegistry::default();
+        sensors.add(Box::new(MockSensor::new(vec![co2(500.0)])));
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .side_buffer(epochs.clone())
+            .build()
+            .unwrap();
+        let epoch = Measurement {
+            name: "activity_count".into(),
+            value: 12.0,
+        };
+        epochs.lock().unwrap().push((1, vec![epoch.clone()]).into());
+        epochs.lock().unwrap().push((31, vec![epoch]).into());
+
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("co2"), vec![500.0]);
+        assert_eq!(sink.sent_values("activity_count"), vec![12.0, 12.0]);
+        assert!(epochs.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_uplink_health_with_the_next_round() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let (mut pipeline, _) = pipeline(vec![co2(500.0), co2(600.0), co2(700.0)], 8, &network, &sink);
+
+        network.fail_next(1);
+        pipeline.cycle();
+        pipeline.cycle();
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("uplink_success"), vec![0.0, 1.0]);
+        assert_eq!(sink.sent_values("uplink_consecutive_failures"), vec![1.0, 0.0]);
+        assert_eq!(sink.sent_values("uplink_connect_ms").len(), 1);
+    }
+
+    #[test]
+    fn drops_values_outside_the_declared_range() {
+        let network = MockNetwork::default();
+        let sink = MockSink::default();
+        let mut sensors = SensorRegistry::default();
+        let sensor = MockSensor::new(vec![co2(500.0), co2(-3.0), co2(700.0)]);
+        sensors.add(Box::new(sensor.with_metrics(vec![MetricSpec::new("co2", "ppm", 0.0, 40_000.0)])));
+        let mut pipeline = PipelineBuilder::new(Arc::new(Mutex::new(Config::default())))
+            .sensors(sensors)
+            .network(Box::new(network.clone()))
+            .uplink(Box::new(sink.clone()))
+            .build()
+            .unwrap();
+
+        pipeline.cycle();
+        pipeline.cycle();
+        pipeline.cycle();
+        assert_eq!(sink.sent_values("co2"), vec![500.0, 700.0]);
+        assert_eq!(sink.sent_values("out_of_range_total"), vec![1.0]);
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let mut config = Config {
+            interval_sec: 300,
+            ..Config::default()
+        };
+        let mut previous = None;
+        for mode in [JitterMode::Uniform, JitterMode::Decorrelated] {
+            config.jitter.mode = mode;
+            for _ in 0..100 {
+                let delay = next_delay(&config, previous);
+                assert!((270.0..=330.0).contains(&delay.as_secs_f64()), "{:?} out of range", delay);
+                previous = Some(delay);
+            }
+        }
+
+        // Out of range values are clamped rather than panicking or spinning
+        config.interval_sec = 0;
+        config.jitter.percent = 500.0;
+        assert!(next_delay(&config, previous) <= Duration::from_secs_f64(1.5));
+        assert!(config.validate().is_err());
+        assert!(Config::default().set("jitter.percent", "80").is_err());
+    }
+
+    #[test]
+    fn aligns_rounds_to_the_clock() {
+        let mut config = Config {
+            interval_sec: 300,
+            align_to_clock: true,
+            ..Config::default()
+        };
+        // 2023-11-14 22:13:20 UTC
+        let now_ms = 1_700_000_000_000;
+        assert_eq!(aligned_delay(&config, now_ms), Duration::from_secs(100));
+        // Just past the boundary, or woken a little early for it
+        assert_eq!(aligned_delay(&config, now_ms + 100_500), Duration::from_millis(299_500));
+        assert_eq!(aligned_delay(&config, now_ms + 99_900), Duration::from_millis(300_100));
+        // Local quarter hours with a 5:45 offset
+        config.interval_sec = 900;
+        config.utc_offset_min = 345;
+        assert_eq!(aligned_delay(&config, now_ms), Duration::from_secs(100));
+
+        for _ in 0..100 {
+            assert!(upload_jitter(&config) <= Duration::from_secs(90));
+        }
+        config.jitter.mode = JitterMode::None;
+        assert_eq!(upload_jitter(&config), Duration::ZERO);
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, PinDriver, Pull};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{error, info, warn};
+
+use crate::config::{Config, ProfileStrap};
+use crate::error::{Context, Phase, Result};
+
+const NVS_NAMESPACE: &str = "sleep_thing";
+const NVS_PROFILE_KEY: &str = "profile";
+
+/// Picks the active profile: the one stored in NVS (set from the console) wins over the
+/// strapping pin, which wins over the default from the configuration file.
+pub fn select(config: &Config, nvs: &EspDefaultNvsPartition) -> Option<String> {
+    let candidates = [
+        ("NVS", stored(nvs)),
+        ("strapping pin", config.profile_strap.as_ref().and_then(read_strap)),
+        ("configuration file", Some(config.profile.clone()).filter(|name| !name.is_empty())),
+    ];
+    for (source, name) in candidates {
+        let Some(name) = name else {
+            continue;
+        };
+        if config.profiles.contains_key(&name) {
+            info!("Using profile '{}' selected by {}", name, source);
+            return Some(name);
+        }
+        warn!("Profile '{}' selected by {} doesn't exist, ignoring it", name, source);
+    }
+    None
+}
+
+/// Persists the profile selection in NVS, `None` clears it.
+pub fn store(nvs: &EspDefaultNvsPartition, name: Option<&str>) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true)
+        .context(Phase::Config, "Failed to open the NVS namespace")?;
+    match name {
+        Some(name) => nvs
+            .set_str(NVS_PROFILE_KEY, name)
+            .context(Phase::Config, "Failed to store the profile")?,
+        None => {
+            nvs.remove(NVS_PROFILE_KEY)
+                .context(Phase::Config, "Failed to clear the profile")?;
+        }
+    }
+    Ok(())
+}
+
+fn stored(nvs: &EspDefaultNvsPartition) -> Option<String> {
+    let nvs = match EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, err);
+            return None;
+        }
+    };
+    let mut buf = [0u8; 64];
+    match nvs.get_str(NVS_PROFILE_KEY, &mut buf) {
+        Ok(name) => name.map(|name| name.to_string()),
+        Err(err) => {
+            error!("Failed to read the profile from NVS: {:?}", err);
+            None
+        }
+    }
+}
+
+fn read_strap(strap: &ProfileStrap) -> Option<String> {
+    let pin = unsafe { AnyIOPin::new(strap.pin) };
+    let mut driver = match PinDriver::input(pin) {
+        Ok(driver) => driver,
+        Err(err) => {
+            error!("Failed to configure profile strapping pin {}: {:?}", strap.pin, err);
+            return None;
+        }
+    };
+    if let Err(err) = driver.set_pull(Pull::Up) {
+        error!("Failed to enable pull-up on profile strapping pin {}: {:?}", strap.pin, err);
+    }
+    std::thread::sleep(Duration::from_millis(10)); // Let the pull-up settle
+    let name = if driver.is_high() { &strap.high } else { &strap.low };
+    Some(name.clone())
+}
@@ -0,0 +1,244 @@
+//! Getting Wi-Fi credentials onto a node that boots without any. The methods enabled in
+//! `wifi.provisioning` are tried in turn, each for up to `timeout_sec`:
+//!
+//! - BLE, with the protocol Espressif's "ESP BLE Provisioning" apps speak. The provisioning
+//!   manager keeps what it gets in the Wi-Fi driver's NVS and stops on its own once the station is
+//!   connected with it.
+//! - ESP-Touch v2 (SmartConfig), the last resort for enclosures without a button or a BLE app
+//!   at hand. The phone broadcasts the credentials in the length of Wi-Fi frames the node sniffs.
+//!
+//! The credentials then go into the configuration, where the rest of the firmware takes them from.
+
+use std::ffi::{c_void, CString};
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::{
+    esp, esp_event_base_t, esp_event_handler_register, esp_event_handler_unregister, esp_smartconfig_set_type,
+    esp_smartconfig_start, esp_smartconfig_stop, smartconfig_event_got_ssid_pswd_t,
+    smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD, smartconfig_event_t_SC_EVENT_SEND_ACK_DONE,
+    smartconfig_start_config_t, smartconfig_type_t_SC_TYPE_ESPTOUCH_V2, ESP_EVENT_ANY_ID, SC_EVENT,
+};
+use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use log::{info, warn};
+
+use crate::config::Config;
+use crate::error::{Context, Error, Phase, Result};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// The phone app waits for the node to confirm it's connected
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tries the enabled methods in turn, true once the station is connected and the credentials are
+/// in `config.wifi`.
+pub fn run(wifi: &mut BlockingWifi<EspWifi>, config: &mut Config) -> bool {
+    #[cfg(feature = "ble_provisioning")]
+    if config.wifi.provisioning.ble {
+        match ble(wifi, config) {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(err) => log::error!("{}", err),
+        }
+    }
+    if config.wifi.provisioning.smartconfig {
+        match smartconfig(wifi, config) {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(err) => log::error!("{}", err),
+        }
+    }
+    false
+}
+
+/// Advertises as the hostname and waits for credentials.
+#[cfg(feature = "ble_provisioning")]
+fn ble(wifi: &mut BlockingWifi<EspWifi>, config: &mut Config) -> Result<bool> {
+    use esp_idf_svc::sys::{
+        esp_wifi_get_config, wifi_config_t, wifi_interface_t_WIFI_IF_STA, wifi_prov_event_handler_t,
+        wifi_prov_mgr_config_t, wifi_prov_mgr_deinit, wifi_prov_mgr_init, wifi_prov_mgr_start_provisioning,
+        wifi_prov_mgr_stop_provisioning, wifi_prov_mgr_wait, wifi_prov_scheme_ble,
+        wifi_prov_scheme_ble_event_cb_free_btdm, wifi_prov_security_WIFI_PROV_SECURITY_1,
+    };
+
+    let context = "Failed to start BLE provisioning";
+    let settings = &config.wifi.provisioning;
+    let service_name =
+        CString::new(config.hostname()).map_err(|_| Error::failed(Phase::Connect, "Invalid hostname"))?;
+    let pop = CString::new(settings.pop.as_str())
+        .map_err(|_| Error::failed(Phase::Config, "Invalid wifi.provisioning.pop"))?;
+    let manager = wifi_prov_mgr_config_t {
+        scheme: unsafe { wifi_prov_scheme_ble },
+        // Frees the Bluetooth memory once provisioning is over, the firmware doesn't use it otherwise
+        scheme_event_handler: wifi_prov_event_handler_t {
+            event_cb: Some(wifi_prov_scheme_ble_event_cb_free_btdm),
+            user_data: ptr::null_mut(),
+        },
+        app_event_handler: wifi_prov_event_handler_t {
+            event_cb: None,
+            user_data: ptr::null_mut(),
+        },
+    };
+    esp!(unsafe { wifi_prov_mgr_init(manager) }).context(Phase::Connect, context)?;
+    let pop = if settings.pop.is_empty() { ptr::null() } else { pop.as_ptr() as *const c_void };
+    let started = esp!(unsafe {
+        wifi_prov_mgr_start_provisioning(
+            wifi_prov_security_WIFI_PROV_SECURITY_1,
+            pop,
+            service_name.as_ptr(),
+            ptr::null(),
+        )
+    });
+    if let Err(err) = started {
+        unsafe { wifi_prov_mgr_deinit() };
+        return Err(err).context(Phase::Connect, context);
+    }
+    info!("Waiting for Wi-Fi credentials over BLE as {}", config.hostname());
+
+    let deadline = Instant::now() + Duration::from_secs(settings.timeout_sec as u64);
+    let connected = loop {
+        if wifi.is_connected().unwrap_or(false) {
+            break true;
+        }
+        if Instant::now() >= deadline {
+            break false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    if !connected {
+        unsafe { wifi_prov_mgr_stop_provisioning() };
+    }
+    unsafe {
+        wifi_prov_mgr_wait();
+        wifi_prov_mgr_deinit();
+    }
+    if !connected {
+        warn!("No Wi-Fi credentials received over BLE within {} s", settings.timeout_sec);
+        return Ok(false);
+    }
+
+    let mut station = wifi_config_t::default();
+    esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut station) })
+        .context(Phase::Connect, "Failed to read the provisioned credentials")?;
+    let station = unsafe { station.sta };
+    config.wifi.ssid = c_string(&station.ssid);
+    config.wifi.password = c_string(&station.password);
+    info!("Provisioned over BLE for {}", config.wifi.ssid);
+    Ok(true)
+}
+
+#[derive(Default)]
+struct SmartConfigState {
+    credentials: Option<(String, String)>,
+    acknowledged: bool,
+}
+
+/// Listens for ESP-Touch v2, decrypting with `wifi.provisioning.esptouch_key` if there is one.
+fn smartconfig(wifi: &mut BlockingWifi<EspWifi>, config: &mut Config) -> Result<bool> {
+    let context = "Failed to start SmartConfig";
+    let settings = config.wifi.provisioning.clone();
+    let key = CString::new(settings.esptouch_key.as_str())
+        .map_err(|_| Error::failed(Phase::Config, "Invalid wifi.provisioning.esptouch_key"))?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .context(Phase::Connect, context)?;
+    wifi.start().context(Phase::Connect, context)?;
+
+    // Outlives the handler, it's unregistered before returning
+    let state = Mutex::new(SmartConfigState::default());
+    esp!(unsafe {
+        esp_event_handler_register(
+            SC_EVENT,
+            ESP_EVENT_ANY_ID,
+            Some(on_smartconfig),
+            &state as *const Mutex<SmartConfigState> as *mut c_void,
+        )
+    })
+    .context(Phase::Connect, context)?;
+    let start = smartconfig_start_config_t {
+        enable_log: false,
+        esp_touch_v2_enable_crypt: !settings.esptouch_key.is_empty(),
+        esp_touch_v2_key: key.as_ptr() as *mut _,
+    };
+    let started = esp!(unsafe { esp_smartconfig_set_type(smartconfig_type_t_SC_TYPE_ESPTOUCH_V2) })
+        .and_then(|_| esp!(unsafe { esp_smartconfig_start(&start) }));
+    let connected = match started {
+        Ok(()) => {
+            info!("Waiting for Wi-Fi credentials over ESP-Touch v2");
+            wait_for_smartconfig(wifi, &state, Duration::from_secs(settings.timeout_sec as u64))
+        }
+        Err(err) => Err(err).context(Phase::Connect, context),
+    };
+    unsafe {
+        esp_smartconfig_stop();
+        esp_event_handler_unregister(SC_EVENT, ESP_EVENT_ANY_ID, Some(on_smartconfig));
+    }
+
+    let Some((ssid, password)) = connected? else {
+        warn!("No Wi-Fi credentials received over ESP-Touch within {} s", settings.timeout_sec);
+        return Ok(false);
+    };
+    info!("Provisioned over ESP-Touch for {}", ssid);
+    config.wifi.ssid = ssid;
+    config.wifi.password = password;
+    Ok(true)
+}
+
+/// Connects with the credentials as they come in, the ones it's connected with.
+fn wait_for_smartconfig(
+    wifi: &mut BlockingWifi<EspWifi>,
+    state: &Mutex<SmartConfigState>,
+    timeout: Duration,
+) -> Result<Option<(String, String)>> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+        let Some((ssid, password)) = state.lock().expect("SmartConfig state lock poisoned").credentials.take() else {
+            continue;
+        };
+        let client = ClientConfiguration {
+            ssid: ssid.as_str().try_into().map_err(|_| Error::failed(Phase::Connect, "SSID is too long"))?,
+            password: password
+                .as_str()
+                .try_into()
+                .map_err(|_| Error::failed(Phase::Connect, "Wi-Fi password is too long"))?,
+            auth_method: if password.is_empty() { AuthMethod::None } else { AuthMethod::WPA2Personal },
+            ..Default::default()
+        };
+        // Not through `wifi::connect_wifi`, stopping the station would stop SmartConfig before it
+        // could confirm to the phone
+        let connected = wifi
+            .set_configuration(&Configuration::Client(client))
+            .and_then(|_| wifi.connect())
+            .and_then(|_| wifi.wait_netif_up());
+        if let Err(err) = connected {
+            warn!("Failed to connect to {} with the credentials from ESP-Touch: {}", ssid, err);
+            continue;
+        }
+        let acknowledged = Instant::now() + ACK_TIMEOUT;
+        while Instant::now() < acknowledged && !state.lock().expect("SmartConfig state lock poisoned").acknowledged {
+            thread::sleep(POLL_INTERVAL);
+        }
+        return Ok(Some((ssid, password)));
+    }
+    Ok(None)
+}
+
+unsafe extern "C" fn on_smartconfig(arg: *mut c_void, _base: esp_event_base_t, id: i32, data: *mut c_void) {
+    let state = &*(arg as *const Mutex<SmartConfigState>);
+    let mut state = state.lock().expect("SmartConfig state lock poisoned");
+    match id as u32 {
+        smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD => {
+            let event = &*(data as *const smartconfig_event_got_ssid_pswd_t);
+            state.credentials = Some((c_string(&event.ssid), c_string(&event.password)));
+        }
+        smartconfig_event_t_SC_EVENT_SEND_ACK_DONE => state.acknowledged = true,
+        _ => {}
+    }
+}
+
+/// Up to the first NUL, the driver pads the fields with them.
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
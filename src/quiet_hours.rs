@@ -0,0 +1,69 @@
+//! Local-time windows in which outputs that light up or make noise stay off, consulted by every
+//! output before it does anything.
+
+use log::error;
+
+use crate::clock::{in_window, parse_time_of_day, seconds_of_day};
+use crate::config::{Config, Output};
+use crate::pipeline;
+
+// Anything before 2020 means the clock hasn't been synced yet
+const MIN_VALID_TIMESTAMP: u64 = 1_577_836_800;
+
+/// Whether `output` may be used at `timestamp`. Without a synced clock the local time is unknown,
+/// so nothing counts as quiet.
+pub fn allowed_at(config: &Config, output: Output, timestamp: u64) -> bool {
+    if timestamp < MIN_VALID_TIMESTAMP {
+        return true;
+    }
+    let time = seconds_of_day(timestamp, config.utc_offset_min);
+    !config
+        .quiet_hours
+        .iter()
+        .filter(|window| window.outputs.contains(&output))
+        .any(|window| match (parse_time_of_day(&window.start), parse_time_of_day(&window.end)) {
+            (Some(start), Some(end)) => in_window(time, start, end),
+            _ => {
+                error!("Invalid quiet hours {}-{}, expected HH:MM", window.start, window.end);
+                false
+            }
+        })
+}
+
+pub fn allowed(config: &Config, output: Output) -> bool {
+    allowed_at(config, output, pipeline::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuietHours;
+
+    #[test]
+    fn keeps_outputs_off_during_their_windows() {
+        let config = Config {
+            utc_offset_min: 60,
+            quiet_hours: vec![
+                QuietHours {
+                    outputs: vec![Output::Led, Output::Display],
+                    start: "22:00".to_string(),
+                    end: "07:00".to_string(),
+                },
+                QuietHours {
+                    outputs: vec![Output::Buzzer],
+                    start: "23:00".to_string(),
+                    end: "06:00".to_string(),
+                },
+            ],
+            ..Config::default()
+        };
+        // 2024-01-01 21:30 UTC, 22:30 local
+        let evening = 1_704_144_600;
+        assert!(!allowed_at(&config, Output::Led, evening));
+        assert!(allowed_at(&config, Output::Buzzer, evening));
+        assert!(!allowed_at(&config, Output::Buzzer, evening + 3600));
+        assert!(allowed_at(&config, Output::Led, evening + 9 * 3600));
+        assert!(allowed_at(&config, Output::WakeLight, evening));
+        assert!(allowed_at(&config, Output::Led, 3600));
+    }
+}
@@ -0,0 +1,228 @@
+//! LD2410 report parsing and respiration estimation, kept apart from the UART driver so it can be
+//! tested on the host.
+
+use std::collections::VecDeque;
+
+const DATA_HEADER: [u8; 4] = [0xF4, 0xF3, 0xF2, 0xF1];
+const DATA_TAIL: [u8; 4] = [0xF8, 0xF7, 0xF6, 0xF5];
+const COMMAND_HEADER: [u8; 4] = [0xFD, 0xFC, 0xFB, 0xFA];
+const COMMAND_TAIL: [u8; 4] = [0x04, 0x03, 0x02, 0x01];
+const MAX_FRAME: usize = 64;
+
+const REPORT_ENGINEERING: u8 = 0x01;
+const REPORT_HEAD: u8 = 0xAA;
+const GATE_CM: u16 = 75;
+
+pub const ENABLE_CONFIGURATION: (u16, &[u8]) = (0x00FF, &[0x01, 0x00]);
+pub const END_CONFIGURATION: (u16, &[u8]) = (0x00FE, &[]);
+pub const ENABLE_ENGINEERING_MODE: (u16, &[u8]) = (0x0062, &[]);
+
+// Breathing between 6 and 30 breaths per minute
+const MIN_PERIOD_SEC: f32 = 2.0;
+const MAX_PERIOD_SEC: f32 = 10.0;
+const MIN_CORRELATION: f32 = 0.3;
+
+/// One target report, the per-gate energies are only sent in engineering mode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub moving: bool,
+    pub stationary: bool,
+    pub moving_distance_cm: u16,
+    pub moving_energy: u8,
+    pub stationary_distance_cm: u16,
+    pub stationary_energy: u8,
+    pub moving_gates: Vec<u8>,
+    pub stationary_gates: Vec<u8>,
+}
+
+/// Builds a command frame, e.g. from [`ENABLE_ENGINEERING_MODE`].
+pub fn command((word, value): (u16, &[u8])) -> Vec<u8> {
+    let mut frame = COMMAND_HEADER.to_vec();
+    frame.extend_from_slice(&(2 + value.len() as u16).to_le_bytes());
+    frame.extend_from_slice(&word.to_le_bytes());
+    frame.extend_from_slice(value);
+    frame.extend_from_slice(&COMMAND_TAIL);
+    frame
+}
+
+/// Picks report frames out of the UART byte stream, command acknowledgements are skipped.
+#[derive(Default)]
+pub struct FrameParser {
+    frame: Vec<u8>,
+}
+
+impl FrameParser {
+    pub fn push(&mut self, byte: u8) -> Option<Report> {
+        self.frame.push(byte);
+        // Resynchronize on the header
+        let header_len = self.frame.len().min(DATA_HEADER.len());
+        if self.frame[..header_len] != DATA_HEADER[..header_len] {
+            self.frame.clear();
+            if byte == DATA_HEADER[0] {
+                self.frame.push(byte);
+            }
+            return None;
+        }
+        if self.frame.len() < 6 {
+            return None;
+        }
+        let length = u16::from_le_bytes([self.frame[4], self.frame[5]]) as usize;
+        let total = 6 + length + DATA_TAIL.len();
+        if total > MAX_FRAME {
+            self.frame.clear();
+            return None;
+        }
+        if self.frame.len() < total {
+            return None;
+        }
+        let frame = std::mem::take(&mut self.frame);
+        if frame[total - DATA_TAIL.len()..] != DATA_TAIL {
+            return None;
+        }
+        parse_report(&frame[6..6 + length])
+    }
+}
+
+fn parse_report(data: &[u8]) -> Option<Report> {
+    let [kind, REPORT_HEAD, state, rest @ ..] = data else {
+        return None;
+    };
+    if rest.len() < 8 {
+        return None;
+    }
+    let mut report = Report {
+        moving: state & 0x01 != 0,
+        stationary: state & 0x02 != 0,
+        moving_distance_cm: u16::from_le_bytes([rest[0], rest[1]]),
+        moving_energy: rest[2],
+        stationary_distance_cm: u16::from_le_bytes([rest[3], rest[4]]),
+        stationary_energy: rest[5],
+        ..Report::default()
+    };
+    if *kind == REPORT_ENGINEERING {
+        let gates = &rest[8..];
+        let [moving_max, stationary_max, energies @ ..] = gates else {
+            return None;
+        };
+        let (moving, stationary) = (*moving_max as usize + 1, *stationary_max as usize + 1);
+        if energies.len() < moving + stationary {
+            return None;
+        }
+        report.moving_gates = energies[..moving].to_vec();
+        report.stationary_gates = energies[moving..moving + stationary].to_vec();
+    }
+    Some(report)
+}
+
+/// Estimates the breathing rate from the micro-motion energy of the gate the stationary target is
+/// in. Only works while nobody moves, any movement starts the window over.
+pub struct BreathingEstimator {
+    window_ms: u64,
+    samples: VecDeque<(u64, f32)>,
+}
+
+impl BreathingEstimator {
+    pub fn new(window_sec: u32) -> Self {
+        BreathingEstimator {
+            window_ms: window_sec as u64 * 1000,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, time_ms: u64, report: &Report) {
+        let gate = (report.stationary_distance_cm / GATE_CM) as usize;
+        let energy = report.stationary_gates.get(gate.min(report.stationary_gates.len().saturating_sub(1)));
+        match energy {
+            Some(energy) if report.stationary && !report.moving => {
+                self.samples.push_back((time_ms, *energy as f32));
+                while let Some((oldest, _)) = self.samples.front() {
+                    if time_ms - oldest <= self.window_ms {
+                        break;
+                    }
+                    self.samples.pop_front();
+                }
+            }
+            _ => self.samples.clear(),
+        }
+    }
+
+    /// Breaths per minute, `None` until a full window of stillness with a clear rhythm.
+    pub fn rate(&self) -> Option<f32> {
+        let (first, last) = (self.samples.front()?.0, self.samples.back()?.0);
+        if self.samples.len() < 20 || (last - first) * 10 < self.window_ms * 9 {
+            return None;
+        }
+        let sample_rate = (self.samples.len() - 1) as f32 * 1000.0 / (last - first) as f32;
+        let mean = self.samples.iter().map(|(_, value)| value).sum::<f32>() / self.samples.len() as f32;
+        let values: Vec<f32> = self.samples.iter().map(|(_, value)| value - mean).collect();
+        let variance: f32 = values.iter().map(|value| value * value).sum();
+        if variance == 0.0 {
+            return None;
+        }
+
+        let min_lag = (MIN_PERIOD_SEC * sample_rate).round().max(1.0) as usize;
+        let max_lag = ((MAX_PERIOD_SEC * sample_rate).round() as usize).min(values.len() / 2);
+        let (lag, correlation) = (min_lag..=max_lag)
+            .map(|lag| {
+                let sum: f32 = values.iter().zip(&values[lag..]).map(|(a, b)| a * b).sum();
+                (lag, sum / variance)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        (correlation >= MIN_CORRELATION).then(|| 60.0 * sample_rate / lag as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engineering_frame(state: u8, stationary_cm: u16, stationary_gates: &[u8]) -> Vec<u8> {
+        let mut data = vec![REPORT_ENGINEERING, REPORT_HEAD, state, 0, 0, 0];
+        data.extend_from_slice(&stationary_cm.to_le_bytes());
+        data.extend_from_slice(&[40, 0, 0, 8, 8]);
+        data.extend_from_slice(&[0; 9]);
+        data.extend_from_slice(stationary_gates);
+        data.extend_from_slice(&[0x55, 0x00]);
+        let mut frame = DATA_HEADER.to_vec();
+        frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&data);
+        frame.extend_from_slice(&DATA_TAIL);
+        frame
+    }
+
+    #[test]
+    fn parses_engineering_reports_from_a_noisy_stream() {
+        let mut parser = FrameParser::default();
+        let mut stream = vec![0x00, 0xF4, 0x12];
+        stream.extend(engineering_frame(0x02, 100, &[1, 2, 3, 4, 5, 6, 7, 8, 9]));
+        let reports: Vec<Report> = stream.into_iter().filter_map(|byte| parser.push(byte)).collect();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].stationary && !reports[0].moving);
+        assert_eq!(reports[0].stationary_distance_cm, 100);
+        assert_eq!(reports[0].stationary_energy, 40);
+        assert_eq!(reports[0].stationary_gates, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn estimates_the_breathing_rate() {
+        let mut estimator = BreathingEstimator::new(60);
+        let mut report = Report {
+            stationary: true,
+            stationary_distance_cm: 100,
+            stationary_gates: vec![0; 9],
+            ..Report::default()
+        };
+        // 15 breaths per minute sampled at 10 Hz
+        for i in 0..=600u64 {
+            let phase = i as f32 / 10.0 * 15.0 / 60.0 * std::f32::consts::TAU;
+            report.stationary_gates[1] = (50.0 + 20.0 * phase.sin()) as u8;
+            estimator.push(i * 100, &report);
+        }
+        let rate = estimator.rate().unwrap();
+        assert!((rate - 15.0).abs() < 1.0, "{}", rate);
+
+        report.moving = true;
+        estimator.push(60_100, &report);
+        assert_eq!(estimator.rate(), None);
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use crate::config::MetricNamesConfig;
+use crate::consistency::ConsistencyCheck;
+use crate::error::{Error, Phase, Result};
+use crate::metrics::MetricRegistry;
+use crate::sensor_memory::SensorMemory;
+use crate::sensors::{Measurement, Sensor};
+
+/// Sets up a sensor, called again whenever it's (re-)enabled or re-initialized.
+pub type SensorFactory<'a> = Box<dyn FnMut() -> Result<Box<dyn Sensor + 'a>> + 'a>;
+
+/// Runtime state of a registered sensor, shared with the console.
+#[derive(Debug, Clone, Default)]
+pub struct SensorState {
+    pub enabled: bool,
+    /// Set from the console, the sampler runs the setup again before its next measurement
+    pub reinit_requested: bool,
+    /// Error from the last setup attempt
+    pub error: Option<String>,
+    /// Bus transactions that timed out since boot
+    pub errors: u32,
+    /// Metrics it's off the other sensors in, see `consistency.rs`
+    pub suspect: Option<String>,
+}
+
+pub type SharedSensorStates = Arc<Mutex<BTreeMap<&'static str, SensorState>>>;
+
+/// Gets the sensor memory whenever a sensor's changes.
+pub type MemoryStore<'a> = Box<dyn FnMut(&SensorMemory) + 'a>;
+
+struct Entry<'a> {
+    name: &'static str,
+    factory: SensorFactory<'a>,
+    sensor: Option<Box<dyn Sensor + 'a>>,
+    enabled: bool,
+    // End of the sensor's warm-up, counted from the setup
+    warm_until: Instant,
+}
+
+/// All sensors compiled into the firmware, keyed by name. Sensors can be enabled, disabled and
+/// re-initialized at runtime, the changes are picked up at the start of the next measurement.
+pub struct SensorRegistry<'a> {
+    entries: Vec<Entry<'a>>,
+    states: SharedSensorStates,
+    memory: SensorMemory,
+    store: Option<MemoryStore<'a>>,
+    metrics: MetricRegistry,
+    consistency: Option<ConsistencyCheck>,
+    names: MetricNamesConfig,
+}
+
+impl Default for SensorRegistry<'_> {
+    fn default() -> Self {
+        SensorRegistry {
+            entries: Vec::new(),
+            states: Arc::new(Mutex::new(BTreeMap::new())),
+            memory: SensorMemory::default(),
+            store: None,
+            metrics: MetricRegistry::default(),
+            consistency: None,
+            names: MetricNamesConfig::default(),
+        }
+    }
+}
+
+impl<'a> SensorRegistry<'a> {
+    /// Registers a sensor, it's set up right away if enabled.
+    pub fn register(&mut self, name: &'static str, enabled: bool, factory: SensorFactory<'a>) {
+        let mut entry = Entry {
+            name,
+            factory,
+            sensor: None,
+            enabled,
+            warm_until: Instant::now(),
+        };
+        // In place before the setup, so what goes wrong during it is counted
+        self.lock_states().insert(
+            name,
+            SensorState {
+                enabled,
+                ..Default::default()
+            },
+        );
+        if enabled {
+            let error = setup(&mut entry, &mut self.metrics).err();
+            self.lock_states().get_mut(name).expect("Sensor state just inserted").error = error;
+        }
+        self.entries.push(entry);
+    }
+
+    /// Registers an already set up sensor that can't be re-initialized.
+    pub fn add(&mut self, sensor: Box<dyn Sensor + 'a>) {
+        let name = sensor.name();
+        let mut sensor = Some(sensor);
+        self.register(
+            name,
+            true,
+            Box::new(move || {
+                sensor
+                    .take()
+                    .ok_or_else(|| Error::sensor(name, Phase::SensorInit, "can't be re-initialized"))
+            }),
+        );
+    }
+
+    pub fn states(&self) -> SharedSensorStates {
+        self.states.clone()
+    }
+
+    /// Compares the metrics several sensors report after every round, adding their spread to it.
+    pub fn check_consistency(&mut self, check: ConsistencyCheck) {
+        self.consistency = Some(check);
+    }
+
+    /// How metrics several sensors report are told apart, see `MetricNamesConfig`.
+    pub fn name_metrics(&mut self, names: MetricNamesConfig) {
+        self.names = names;
+    }
+
+    /// What the sensors set up so far declared about their metrics.
+    pub fn metrics(&self) -> &MetricRegistry {
+        &self.metrics
+    }
+
+    /// Picks up from the memory the sensors were set up with, `store` gets it after every round
+    /// that changed it.
+    pub fn persist(&mut self, memory: SensorMemory, store: MemoryStore<'a>) {
+        self.memory = memory;
+        self.store = Some(store);
+    }
+
+    /// Applies changes made from the console, then measures every enabled sensor.
+    pub fn measure(&mut self) -> Vec<Measurement> {
+        self.reconcile();
+        let started = Instant::now();
+        let ready: Vec<Duration> = self
+            .entries
+            .iter_mut()
+            .map(|entry| match entry.sensor.as_mut().filter(|_| entry.enabled) {
+                Some(sensor) => sensor.start(),
+                None => Duration::ZERO,
+            })
+            .collect();
+        let mut measurements = Vec::new();
+        // Where each sensor's measurements are in `measurements`
+        let mut readings = Vec::new();
+        let mut changed = false;
+        for (entry, ready) in self.entries.iter_mut().zip(ready) {
+            if !entry.enabled {
+                continue;
+            }
+            if let Some(sensor) = entry.sensor.as_mut() {
+                thread::sleep(ready.saturating_sub(started.elapsed()));
+                sensor.ambient(&measurements);
+                let measurement = sensor.measure();
+                println!("Measurement {:?}", measurement);
+                let warming_up = entry.warm_until.saturating_duration_since(Instant::now());
+                if warming_up.is_zero() {
+                    readings.push((entry.name, measurements.len()..measurements.len() + measurement.len()));
+                    measurements.extend(measurement);
+                } else if !measurement.is_empty() {
+                    info!("{} warming up for another {} s, readings discarded", entry.name, warming_up.as_secs());
+                }
+                for (field, value) in sensor.memory() {
+                    changed |= self.memory.set(entry.name, field, value);
+                }
+            }
+        }
+        if let (true, Some(store)) = (changed, self.store.as_mut()) {
+            store(&self.memory);
+        }
+        let mut spreads = Vec::new();
+        if let Some(check) = self.consistency.as_mut() {
+            let readings: Vec<(&'static str, &[Measurement])> =
+                readings.iter().map(|(name, range)| (*name, &measurements[range.clone()])).collect();
+            spreads = check.check(&readings);
+            let suspects = check.suspects();
+            for (name, state) in self.states.lock().expect("Sensor state lock poisoned").iter_mut() {
+                state.suspect = suspects.get(name).map(|metrics| metrics.join(", "));
+            }
+        }
+        rename(&self.names, &readings, &mut measurements);
+        measurements.extend(spreads);
+        measurements
+    }
+
+    /// The longest head start an enabled sensor wants before the next round.
+    pub fn wake_lead(&self) -> Duration {
+        self.entries
+            .iter()
+            .filter(|entry| entry.enabled)
+            .filter_map(|entry| entry.sensor.as_ref())
+            .map(|sensor| sensor.wake_lead())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Wakes the enabled sensors that want a head start, ahead of the next round.
+    pub fn wake(&mut self) {
+        for entry in self.entries.iter_mut().filter(|entry| entry.enabled) {
+            if let Some(sensor) = entry.sensor.as_mut().filter(|sensor| !sensor.wake_lead().is_zero()) {
+                sensor.wake();
+            }
+        }
+    }
+
+    /// Powers down every sensor that's set up, ahead of a shutdown.
+    pub fn power_down(&mut self) {
+        for sensor in self.entries.iter_mut().filter_map(|entry| entry.sensor.as_mut()) {
+            info!("Powering down {}", sensor.name());
+            sensor.power_down();
+        }
+    }
+
+    /// Measures every enabled sensor once and reports per sensor, for the self-test. Sensors
+    /// still warming up are measured like the others, what matters here is that they answer.
+    pub fn probe(&mut self) -> Vec<(&'static str, Result<Vec<Measurement>>)> {
+        self.reconcile();
+        let states = self.lock_states().clone();
+        let mut results = Vec::new();
+        for entry in &mut self.entries {
+            if !entry.enabled {
+                continue;
+            }
+            let result = match entry.sensor.as_mut() {
+                Some(sensor) => match sensor.measure() {
+                    measurements if measurements.is_empty() => {
+                        Err(Error::sensor(entry.name, Phase::Measure, "no measurements"))
+                    }
+                    measurements => Ok(measurements),
+                },
+                None => {
+                    let message = states.get(entry.name).and_then(|state| state.error.clone());
+                    Err(Error::sensor(
+                        entry.name,
+                        Phase::SensorInit,
+                        message.unwrap_or_else(|| "not set up".to_string()),
+                    ))
+                }
+            };
+            results.push((entry.name, result));
+        }
+        results
+    }
+
+    fn reconcile(&mut self) {
+        let states = self.states.clone();
+        for entry in &mut self.entries {
+            let Some(state) = states.lock().expect("Sensor state lock poisoned").get(entry.name).cloned() else {
+                continue;
+            };
+            let newly_enabled = state.enabled && !entry.enabled;
+            if newly_enabled || state.reinit_requested {
+                // Not under the lock, the bus counts timeouts during the setup in the states
+                let error = setup(entry, &mut self.metrics).err();
+                if let Some(state) = states.lock().expect("Sensor state lock poisoned").get_mut(entry.name) {
+                    state.reinit_requested = false;
+                    state.error = error;
+                }
+            }
+            if entry.enabled != state.enabled {
+                info!("Sensor {} {}", entry.name, if state.enabled { "enabled" } else { "disabled" });
+            }
+            entry.enabled = state.enabled;
+        }
+    }
+
+    fn lock_states(&self) -> MutexGuard<'_, BTreeMap<&'static str, SensorState>> {
+        self.states.lock().expect("Sensor state lock poisoned")
+    }
+}
+
+/// Names the metrics of each sensor `<sensor>.<metric>`, all of them or those another sensor
+/// keeps the plain name of. Names with a `.` already are left as they are.
+fn rename(names: &MetricNamesConfig, readings: &[(&'static str, Range<usize>)], measurements: &mut [Measurement]) {
+    let mut reporters: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (sensor, range) in readings {
+        for measurement in &measurements[range.clone()] {
+            reporters.entry(&measurement.name).or_default().push(sensor);
+        }
+    }
+    let owners: HashMap<String, &str> = reporters
+        .into_iter()
+        .map(|(metric, sensors)| {
+            let preferred = names
+                .preferred
+                .get(metric)
+                .and_then(|preferred| preferred.iter().find(|sensor| sensors.contains(&sensor.as_str())));
+            (metric.to_string(), preferred.map_or(sensors[0], |sensor| sensor.as_str()))
+        })
+        .collect();
+    for (sensor, range) in readings {
+        for measurement in &mut measurements[range.clone()] {
+            let owned = owners.get(measurement.name.as_ref()) == Some(sensor);
+            if measurement.name.contains('.') || (owned && !names.namespaced) {
+                continue;
+            }
+            measurement.name = format!("{}.{}", sensor, measurement.name).into();
+        }
+    }
+}
+
+fn setup(entry: &mut Entry, metrics: &mut MetricRegistry) -> std::result::Result<(), String> {
+    // Release the bus and driver state before setting it up again
+    entry.sensor = None;
+    match (entry.factory)() {
+        Ok(sensor) => {
+            for spec in sensor.metrics() {
+                metrics.declare(entry.name, spec);
+            }
+            entry.warm_until = Instant::now() + sensor.warm_up();
+            entry.sensor = Some(sensor);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Failed to set up {}: {}", entry.name, err);
+            Err(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockReading, MockSensor};
+
+    fn co2_sensor<'a>() -> Result<Box<dyn Sensor + 'a>> {
+        Ok(Box::new(MockSensor::new((0..10).map(|_| MockReading::Values(vec![("co2", 500.0)])))))
+    }
+
+    #[test]
+    fn disabled_sensors_are_set_up_when_enabled() {
+        let mut registry = SensorRegistry::default();
+        registry.register("co2", false, Box::new(co2_sensor));
+        assert!(registry.measure().is_empty());
+
+        registry.states().lock().unwrap().get_mut("co2").unwrap().enabled = true;
+        assert_eq!(registry.measure().len(), 1);
+
+        registry.states().lock().unwrap().get_mut("co2").unwrap().enabled = false;
+        assert!(registry.measure().is_empty());
+    }
+
+    #[test]
+    fn reinit_retries_a_failed_setup() {
+        let mut attempts = 0;
+        let mut registry = SensorRegistry::default();
+        registry.register(
+            "co2",
+            true,
+            Box::new(move || {
+                attempts += 1;
+                if attempts == 1 {
+                    Err(Error::sensor("co2", Phase::SensorInit, "not connected"))
+                } else {
+                    co2_sensor()
+                }
+            }),
+        );
+        let states = registry.states();
+        assert!(states.lock().unwrap()["co2"].error.is_some());
+        assert!(registry.measure().is_empty());
+
+        states.lock().unwrap().get_mut("co2").unwrap().reinit_requested = true;
+        assert_eq!(registry.measure().len(), 1);
+        assert!(states.lock().unwrap()["co2"].error.is_none());
+    }
+
+    #[test]
+    fn counts_errors_raised_during_the_setup() {
+        let mut registry = SensorRegistry::default();
+        let states = registry.states();
+        // Like a bus timeout while the sensor is probed
+        registry.register(
+            "co2",
+            true,
+            Box::new(move || {
+                states.lock().unwrap().get_mut("co2").unwrap().errors += 1;
+                co2_sensor()
+            }),
+        );
+        let states = registry.states();
+        assert_eq!(states.lock().unwrap()["co2"].errors, 1);
+
+        states.lock().unwrap().get_mut("co2").unwrap().reinit_requested = true;
+        registry.measure();
+        assert_eq!(states.lock().unwrap()["co2"].errors, 2);
+    }
+    // Raises its gain up to 2 with every round
+    struct LightSensor {
+        gain: u32,
+    }
+
+    impl Sensor for LightSensor {
+        fn name(&self) -> &'static str {
+            "light"
+        }
+
+        fn measure(&mut self) -> Vec<Measurement> {
+            self.gain = (self.gain + 1).min(2);
+            Vec::new()
+        }
+
+        fn memory(&self) -> Vec<(&'static str, u32)> {
+            vec![("gain", self.gain)]
+        }
+    }
+
+    #[test]
+    fn stores_the_memory_when_it_changes() {
+        let mut memory = SensorMemory::default();
+        memory.set("light", "gain", 1);
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = SensorRegistry::default();
+        let gain = memory.get("light", "gain").unwrap();
+        registry.add(Box::new(LightSensor { gain }));
+        let sink = stored.clone();
+        registry.persist(memory, Box::new(move |memory| sink.lock().unwrap().push(memory.clone())));
+
+        for _ in 0..3 {
+            registry.measure();
+        }
+        let stored = stored.lock().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].get("light", "gain"), Some(2));
+    }
+
+    // Remembers the humidity it was handed
+    struct GasSensor {
+        humidity: Arc<Mutex<Option<f32>>>,
+    }
+
+    impl Sensor for GasSensor {
+        fn name(&self) -> &'static str {
+            "gas"
+        }
+
+        fn measure(&mut self) -> Vec<Measurement> {
+            Vec::new()
+        }
+
+        fn ambient(&mut self, measurements: &[Measurement]) {
+            let humidity = measurements.iter().find(|measurement| measurement.name == "humidity");
+            *self.humidity.lock().unwrap() = humidity.map(|measurement| measurement.value);
+        }
+    }
+
+    // Reads from the start but is only right after its warm-up
+    struct HeatedSensor {
+        warm_up: Duration,
+        woken: Arc<Mutex<u32>>,
+    }
+
+    impl Sensor for HeatedSensor {
+        fn name(&self) -> &'static str {
+            "heated"
+        }
+
+        fn measure(&mut self) -> Vec<Measurement> {
+            vec![Measurement {
+                name: "tvoc".into(),
+                value: 100.0,
+            }]
+        }
+
+        fn warm_up(&self) -> Duration {
+            self.warm_up
+        }
+
+        fn wake_lead(&self) -> Duration {
+            Duration::from_secs(5)
+        }
+
+        fn wake(&mut self) {
+            *self.woken.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn discards_readings_during_the_warm_up() {
+        let woken = Arc::new(Mutex::new(0));
+        let mut registry = SensorRegistry::default();
+        registry.add(Box::new(HeatedSensor {
+            warm_up: Duration::from_secs(20 * 60),
+            woken: woken.clone(),
+        }));
+        assert!(registry.measure().is_empty());
+        assert_eq!(registry.probe()[0].1.as_ref().unwrap().len(), 1);
+
+        let mut registry = SensorRegistry::default();
+        registry.add(Box::new(HeatedSensor {
+            warm_up: Duration::ZERO,
+            woken: woken.clone(),
+        }));
+        assert_eq!(registry.measure().len(), 1);
+    }
+
+    #[test]
+    fn wakes_sensors_ahead_of_the_round() {
+        let woken = Arc::new(Mutex::new(0));
+        let mut registry = SensorRegistry::default();
+        registry.add(co2_sensor().unwrap());
+        assert_eq!(registry.wake_lead(), Duration::ZERO);
+        registry.add(Box::new(HeatedSensor {
+            warm_up: Duration::ZERO,
+            woken: woken.clone(),
+        }));
+        assert_eq!(registry.wake_lead(), Duration::from_secs(5));
+        registry.wake();
+        assert_eq!(*woken.lock().unwrap(), 1);
+
+        registry.states().lock().unwrap().get_mut("heated").unwrap().enabled = false;
+        registry.measure();
+        assert_eq!(registry.wake_lead(), Duration::ZERO);
+    }
+
+    // Logs its calls to see the order they come in
+    struct SlowSensor {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Sensor for SlowSensor {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn start(&mut self) -> Duration {
+            self.calls.lock().unwrap().push(format!("{} start", self.name));
+            Duration::from_millis(20)
+        }
+
+        fn measure(&mut self) -> Vec<Measurement> {
+            self.calls.lock().unwrap().push(format!("{} measure", self.name));
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn starts_every_conversion_before_collecting() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = SensorRegistry::default();
+        for name in ["light", "co2"] {
+            registry.add(Box::new(SlowSensor {
+                name,
+                calls: calls.clone(),
+            }));
+        }
+        let started = Instant::now();
+        registry.measure();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(*calls.lock().unwrap(), ["light start", "co2 start", "light measure", "co2 measure"]);
+    }
+
+    #[test]
+    fn hands_earlier_readings_to_later_sensors() {
+        let humidity = Arc::new(Mutex::new(None));
+        let mut registry = SensorRegistry::default();
+        registry.add(Box::new(MockSensor::new([MockReading::Values(vec![("humidity", 45.0)])])));
+        registry.add(Box::new(GasSensor {
+            humidity: humidity.clone(),
+        }));
+        registry.measure();
+        assert_eq!(*humidity.lock().unwrap(), Some(45.0));
+    }
+
+    #[test]
+    fn tells_metrics_of_different_sensors_apart() {
+        let mut registry = SensorRegistry::default();
+        for (name, temperature) in [("scd4x", 22.5), ("bme280", 21.0)] {
+            let reading = || MockReading::Values(vec![("temperature", temperature), ("co2", 500.0)]);
+            let mut sensor = Some(MockSensor::new([reading(), reading()]));
+            registry.register(name, true, Box::new(move || Ok(Box::new(sensor.take().unwrap()))));
+        }
+        let names = |measurements: Vec<Measurement>| -> Vec<String> {
+            measurements.into_iter().map(|measurement| measurement.name.into_owned()).collect()
+        };
+        // The BME280 is preferred for the temperature, the first registered keeps the plain CO2
+        assert_eq!(names(registry.measure()), ["scd4x.temperature", "co2", "temperature", "bme280.co2"]);
+
+        registry.name_metrics(MetricNamesConfig {
+            namespaced: true,
+            ..MetricNamesConfig::default()
+        });
+        assert_eq!(
+            names(registry.measure()),
+            ["scd4x.temperature", "scd4x.co2", "bme280.temperature", "bme280.co2"]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Error events for a Sentry-compatible DSN or a generic webhook: `error!` records and the last
+//! panic, with the device ID and firmware version. Repeats of the same error are one event with a
+//! count, so a sensor failing every round doesn't flood the project.
+
+use std::fs;
+use std::panic;
+use std::sync::{Arc, Mutex};
+
+use log::{Level, Log, Metadata, Record};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{now, HttpClient, Reporter};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Distinct events kept between uploads, anything beyond is dropped
+const MAX_EVENTS: usize = 16;
+const PANIC_PATH: &str = "/storage/panic.txt";
+
+pub type SharedEvents = Arc<Mutex<Vec<Event>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// `error`, or `fatal` for a panic
+    pub level: &'static str,
+    pub target: String,
+    pub message: String,
+    pub count: u32,
+    /// Of the first occurrence, in s since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Adds an event, or counts it if the same one is already waiting.
+pub fn capture(events: &SharedEvents, level: &'static str, target: &str, message: String) {
+    let mut events = events.lock().expect("Event lock poisoned");
+    if let Some(event) = events.iter_mut().find(|event| event.target == target && event.message == message) {
+        event.count += 1;
+    } else if events.len() < MAX_EVENTS {
+        events.push(Event {
+            level,
+            target: target.to_string(),
+            message,
+            count: 1,
+            timestamp: now(),
+        });
+    }
+}
+
+/// Logs through `inner` and captures what's logged at the error level.
+pub struct CapturingLogger<L> {
+    inner: L,
+    events: SharedEvents,
+}
+
+impl<L> CapturingLogger<L> {
+    pub fn new(inner: L, events: SharedEvents) -> Self {
+        CapturingLogger { inner, events }
+    }
+}
+
+impl<L: Log> Log for CapturingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+        if record.level() == Level::Error {
+            capture(&self.events, "error", record.target(), record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Writes the panic message to the storage partition before the default hook aborts, it's
+/// reported after the reboot.
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = fs::write(PANIC_PATH, info.to_string());
+        previous(info);
+    }));
+}
+
+/// Message of the panic that caused the last reboot, if there was one. Only returned once.
+pub fn take_last_panic() -> Option<String> {
+    let message = fs::read_to_string(PANIC_PATH).ok()?;
+    let _ = fs::remove_file(PANIC_PATH);
+    Some(message)
+}
+
+/// Sends the waiting events while the network is up for an upload, to `error_reporting.sentry_dsn`
+/// and/or `error_reporting.webhook_url`.
+pub struct ErrorReporter<'a> {
+    client: Box<dyn HttpClient + Send + 'a>,
+    events: SharedEvents,
+}
+
+impl<'a> ErrorReporter<'a> {
+    pub fn new(client: Box<dyn HttpClient + Send + 'a>, events: SharedEvents) -> Self {
+        ErrorReporter { client, events }
+    }
+
+    fn send(&mut self, config: &Config, event: &Event) -> Result<()> {
+        let reporting = &config.error_reporting;
+        if !reporting.sentry_dsn.is_empty() {
+            let (url, key) = parse_dsn(&reporting.sentry_dsn)
+                .ok_or_else(|| Error::failed(Phase::Upload, "Invalid error_reporting.sentry_dsn"))?;
+            let auth = format!("Sentry sentry_version=7, sentry_key={}, sentry_client=sleep_thing/{}", key, VERSION);
+            let body = serde_json::to_vec(&sentry_event(config, event))?;
+            self.post(&url, &[("Content-Type", "application/json"), ("X-Sentry-Auth", &auth)], &body)?;
+        }
+        if !reporting.webhook_url.is_empty() {
+            let body = serde_json::to_vec(&webhook_event(config, event))?;
+            self.post(&reporting.webhook_url, &[("Content-Type", "application/json")], &body)?;
+        }
+        Ok(())
+    }
+
+    fn post(&mut self, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<()> {
+        match self.client.post(url, headers, body)? {
+            200..=299 => Ok(()),
+            status => Err(Error::failed(Phase::Upload, format!("{} answered {}", url, status))),
+        }
+    }
+}
+
+impl Reporter for ErrorReporter<'_> {
+    fn report(&mut self, config: &Config) -> Result<()> {
+        let events = std::mem::take(&mut *self.events.lock().expect("Event lock poisoned"));
+        let mut events = events.into_iter();
+        while let Some(event) = events.next() {
+            if let Err(err) = self.send(config, &event) {
+                // Kept for the next upload, ahead of anything that came in since
+                let mut waiting = self.events.lock().expect("Event lock poisoned");
+                let newer = std::mem::take(&mut *waiting);
+                waiting.extend(std::iter::once(event).chain(events).chain(newer).take(MAX_EVENTS));
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Store endpoint and public key of a DSN, `https://<key>@<host>/<project>`.
+fn parse_dsn(dsn: &str) -> Option<(String, String)> {
+    let (scheme, rest) = dsn.split_once("://")?;
+    let (key, rest) = rest.split_once('@')?;
+    let (host, project) = rest.rsplit_once('/')?;
+    let key = key.split(':').next()?;
+    if key.is_empty() || host.is_empty() || project.is_empty() {
+        return None;
+    }
+    Some((format!("{}://{}/api/{}/store/", scheme, host, project), key.to_string()))
+}
+
+fn sentry_event(config: &Config, event: &Event) -> Value {
+    json!({
+        "event_id": format!("{:032x}", rand::random::<u128>()),
+        "timestamp": event.timestamp,
+        "level": event.level,
+        "logger": event.target,
+        "platform": "other",
+        "release": format!("sleep_thing@{}", VERSION),
+        "server_name": config.device_id(),
+        "message": { "formatted": event.message },
+        "tags": { "device": config.device_id() },
+        "extra": { "count": event.count },
+    })
+}
+
+fn webhook_event(config: &Config, event: &Event) -> Value {
+    json!({
+        "device": config.device_id(),
+        "version": VERSION,
+        "level": event.level,
+        "target": event.target,
+        "message": event.message,
+        "count": event.count,
+        "timestamp": event.timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockClient {
+        requests: Arc<Mutex<Vec<(String, Value)>>>,
+        status: u16,
+    }
+
+    impl HttpClient for MockClient {
+        fn post(&mut self, url: &str, _headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+            let body = serde_json::from_slice(body).unwrap();
+            self.requests.lock().unwrap().push((url.to_string(), body));
+            Ok(self.status)
+        }
+    }
+
+    #[test]
+    fn groups_repeats_and_keeps_events_until_sent() {
+        let events = SharedEvents::default();
+        for _ in 0..3 {
+            capture(&events, "error", "scd4x", "Failed to read".to_string());
+        }
+        capture(&events, "fatal", "panic", "index out of bounds".to_string());
+        assert_eq!(events.lock().unwrap().len(), 2);
+        assert_eq!(events.lock().unwrap()[0].count, 3);
+
+        let mut config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        config.error_reporting.webhook_url = "http://alerts.local/hook".to_string();
+        let client = MockClient {
+            status: 500,
+            ..MockClient::default()
+        };
+        let mut reporter = ErrorReporter::new(Box::new(client.clone()), events.clone());
+        assert!(reporter.report(&config).is_err());
+        assert_eq!(events.lock().unwrap().len(), 2);
+
+        let client = MockClient {
+            status: 200,
+            ..MockClient::default()
+        };
+        let mut reporter = ErrorReporter::new(Box::new(client.clone()), events.clone());
+        reporter.report(&config).unwrap();
+        assert!(events.lock().unwrap().is_empty());
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].1["count"], 3);
+        assert_eq!(requests[1].1["device"], "bedroom");
+
+        assert_eq!(
+            parse_dsn("https://abc123@o1.ingest.sentry.io/42"),
+            Some(("https://o1.ingest.sentry.io/api/42/store/".to_string(), "abc123".to_string()))
+        );
+        assert_eq!(parse_dsn("not a dsn"), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! DS3231 on the I2C bus as a battery-backed clock. It sets the system time at boot, so the
+//! timestamps are right before SNTP syncs or when it can't, and every SNTP sync sets it in turn.
+//! It runs on UTC, from 2000 to 2099.
+
+use embedded_hal::i2c::I2c;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_STATUS: u8 = 0x0F;
+// Set when the oscillator stopped, e.g. without a backup battery, the time can't be trusted
+const STATUS_OSF: u8 = 0x80;
+const HOUR_12H: u8 = 0x40;
+const HOUR_PM: u8 = 0x20;
+const DAY: u64 = 24 * 60 * 60;
+// Days from 1970-01-01 to 2000-01-01
+const EPOCH_2000_DAYS: u64 = 10_957;
+
+pub struct Ds3231<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Ds3231<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Ds3231 { i2c, address }
+    }
+
+    /// Unix time in seconds, `None` if the clock was never set or has stopped since.
+    pub fn read(&mut self) -> Result<Option<u64>, I2C::Error> {
+        let mut status = [0u8];
+        self.i2c.write_read(self.address, &[REG_STATUS], &mut status)?;
+        if status[0] & STATUS_OSF != 0 {
+            return Ok(None);
+        }
+        let mut registers = [0u8; 7];
+        self.i2c.write_read(self.address, &[REG_SECONDS], &mut registers)?;
+        Ok(from_registers(&registers))
+    }
+
+    /// Sets the clock, which clears the oscillator stop flag.
+    pub fn write(&mut self, timestamp: u64) -> Result<(), I2C::Error> {
+        let mut command = [REG_SECONDS; 8];
+        command[1..].copy_from_slice(&to_registers(timestamp));
+        self.i2c.write(self.address, &command)?;
+        let mut status = [0u8];
+        self.i2c.write_read(self.address, &[REG_STATUS], &mut status)?;
+        self.i2c.write(self.address, &[REG_STATUS, status[0] & !STATUS_OSF])
+    }
+}
+
+/// Sets the system clock, to the second.
+#[cfg(target_os = "espidf")]
+pub fn set_system_time(timestamp: u64) -> crate::Result<()> {
+    use crate::error::{Error, Phase};
+    use esp_idf_svc::sys::{settimeofday, timeval};
+
+    let time = timeval {
+        tv_sec: timestamp as _,
+        tv_usec: 0,
+    };
+    if unsafe { settimeofday(&time, std::ptr::null()) } != 0 {
+        return Err(Error::failed(Phase::TimeSync, "Failed to set the system time"));
+    }
+    Ok(())
+}
+
+/// Seconds, minutes, hours, weekday, date, month and year, in BCD.
+fn to_registers(timestamp: u64) -> [u8; 7] {
+    let days = timestamp / DAY;
+    let seconds = timestamp % DAY;
+    let (year, month, date) = civil_from_days(days);
+    // 1970-01-01 was a Thursday, the weekday counts from 1 on Monday
+    let weekday = ((days + 3) % 7 + 1) as u8;
+    [
+        bcd(seconds % 60),
+        bcd(seconds / 60 % 60),
+        bcd(seconds / 3600),
+        weekday,
+        bcd(date),
+        bcd(month),
+        bcd(year % 100),
+    ]
+}
+
+fn from_registers(registers: &[u8; 7]) -> Option<u64> {
+    let [seconds, minutes, hours, _, date, month, year] = *registers;
+    let hours = if hours & HOUR_12H != 0 {
+        from_bcd(hours & 0x1F)? % 12 + if hours & HOUR_PM != 0 { 12 } else { 0 }
+    } else {
+        from_bcd(hours & 0x3F)?
+    };
+    // The century bit is left out, it flips on the way from 2099 to 2100
+    let (year, month, date) = (2000 + from_bcd(year)?, from_bcd(month & 0x1F)?, from_bcd(date)?);
+    let (seconds, minutes) = (from_bcd(seconds & 0x7F)?, from_bcd(minutes)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&date) || hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    Some(days_from_civil(year, month, date) * DAY + hours * 3600 + minutes * 60 + seconds)
+}
+
+fn bcd(value: u64) -> u8 {
+    (value / 10 * 16 + value % 10) as u8
+}
+
+fn from_bcd(value: u8) -> Option<u64> {
+    let (tens, ones) = (value >> 4, value & 0x0F);
+    (tens < 10 && ones < 10).then_some((tens * 10 + ones) as u64)
+}
+
+/// Days since 1970 for a date from 2000 on, from Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: u64, month: u64, date: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + date - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and date, the inverse of `days_from_civil`. Dates before 2000 come out as 2000.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days.max(EPOCH_2000_DAYS) + 719_468;
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let date = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_and_from_the_registers() {
+        // 2024-02-29 23:59:58 UTC, a Thursday
+        let registers = to_registers(1_709_251_198);
+        assert_eq!(registers, [0x58, 0x59, 0x23, 4, 0x29, 0x02, 0x24]);
+        assert_eq!(from_registers(&registers), Some(1_709_251_198));
+        assert_eq!(from_registers(&to_registers(EPOCH_2000_DAYS * DAY)), Some(EPOCH_2000_DAYS * DAY));
+
+        // 11:30 pm in 12 hour mode
+        let registers = [0x00, 0x30, HOUR_12H | HOUR_PM | 0x11, 4, 0x29, 0x02, 0x24];
+        assert_eq!(from_registers(&registers), Some(1_709_249_400));
+        assert_eq!(from_registers(&[0x00, 0x00, 0x00, 1, 0x00, 0x01, 0x24]), None);
+    }
+}
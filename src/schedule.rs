@@ -0,0 +1,85 @@
+//! Sampling intervals by local time, e.g. every minute through the night for sleep tracking and
+//! every 10 minutes during the day.
+
+use log::error;
+
+use crate::clock::{in_window, parse_time_of_day, seconds_of_day};
+use crate::config::{Config, IntervalWindow};
+use crate::pipeline;
+
+// Anything before 2020 means the clock hasn't been synced yet
+const MIN_VALID_TIMESTAMP: u64 = 1_577_836_800;
+
+/// Interval of the first window `timestamp` falls into, `interval_sec` outside of them or
+/// without a synced clock.
+pub fn interval_sec_at(config: &Config, timestamp: u64) -> u32 {
+    if timestamp < MIN_VALID_TIMESTAMP {
+        return config.interval_sec;
+    }
+    let time = seconds_of_day(timestamp, config.utc_offset_min);
+    config
+        .schedule()
+        .iter()
+        .find(|window| match window_bounds(window) {
+            Some((start, end)) => in_window(time, start, end),
+            None => {
+                error!("Invalid schedule window {}-{}, expected HH:MM", window.start, window.end);
+                false
+            }
+        })
+        .map_or(config.interval_sec, |window| window.interval_sec)
+}
+
+pub fn interval_sec(config: &Config) -> u32 {
+    interval_sec_at(config, pipeline::now())
+}
+
+/// Shortest interval of the day, for sizing buffers.
+pub fn shortest_interval_sec(config: &Config) -> u32 {
+    config
+        .schedule()
+        .iter()
+        .map(|window| window.interval_sec)
+        .fold(config.interval_sec, u32::min)
+}
+
+pub fn window_bounds(window: &IntervalWindow) -> Option<(u32, u32)> {
+    Some((parse_time_of_day(&window.start)?, parse_time_of_day(&window.end)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::Profile;
+
+    #[test]
+    fn picks_the_interval_of_the_profile_window() {
+        let night = IntervalWindow {
+            start: "22:00".to_string(),
+            end: "08:00".to_string(),
+            interval_sec: 60,
+        };
+        let mut config = Config {
+            interval_sec: 600,
+            utc_offset_min: 60,
+            profiles: BTreeMap::from([(
+                "bedroom".to_string(),
+                Profile {
+                    schedule: Some(vec![night]),
+                    ..Profile::default()
+                },
+            )]),
+            ..Config::default()
+        };
+        // 2024-01-01 21:30 UTC, 22:30 local
+        assert_eq!(interval_sec_at(&config, 1_704_144_600), 600);
+        config.set_active_profile(Some("bedroom".to_string())).unwrap();
+        assert_eq!(interval_sec_at(&config, 1_704_144_600), 60);
+        // 12:30 local
+        assert_eq!(interval_sec_at(&config, 1_704_108_600), 600);
+        assert_eq!(interval_sec_at(&config, 0), 600);
+        assert_eq!(shortest_interval_sec(&config), 60);
+    }
+}
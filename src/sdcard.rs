@@ -0,0 +1,219 @@
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::{AnyIOPin, OutputPin};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::sd::spi::SdSpiHostDriver;
+use esp_idf_svc::hal::sd::{SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::SpiDriver;
+use esp_idf_svc::io::vfs::MountedFatfs;
+use esp_idf_svc::sys::{esp, esp_vfs_fat_info};
+use log::{error, info, warn};
+
+use crate::config::{Config, TimestampResolution};
+use crate::error::{Context, Phase, Result};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+const MOUNT_POINT: &str = "/sdcard";
+const MOUNT_POINT_C: &CStr = c"/sdcard";
+const MAX_OPEN_FILES: usize = 4;
+
+const MIN_FREE_BYTES: u64 = 4 * 1024 * 1024; // Oldest days get deleted until at least this much is free
+const MAX_DAYS: usize = 366;
+
+const CSV_HEADER: &str = "timestamp,name,value";
+
+/// Mounts a FAT formatted SD card attached over SPI at `/sdcard`.
+/// The returned handle has to be kept alive, the card is unmounted when it's dropped.
+pub fn mount<'d>(spi: &'d SpiDriver<'d>, cs: impl Peripheral<P = impl OutputPin> + 'd) -> Result<impl Sized + 'd> {
+    println!("Mounting SD card");
+    let sd_card_driver = SdCardDriver::new_spi(
+        SdSpiHostDriver::new(
+            spi,
+            Some(cs),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            None,
+        )
+        .context(Phase::Storage, "Failed to set up the SD card SPI host")?,
+        &SdCardConfiguration::new(),
+    )
+    .context(Phase::Storage, "No SD card found")?;
+
+    let fatfs = Fatfs::new_sdcard(0, sd_card_driver).context(Phase::Storage, "Failed to open the SD card")?;
+    let mounted = MountedFatfs::mount(fatfs, MOUNT_POINT, MAX_OPEN_FILES)
+        .context(Phase::Storage, "Failed to mount the SD card")?;
+    info!("SD card mounted at {}", MOUNT_POINT);
+    Ok(mounted)
+}
+
+/// Appends every measurement to a per-day CSV file (`YYYYMMDD.CSV`, UTC) on the SD card.
+#[derive(Default)]
+pub struct CsvLog {
+    day: u64,
+    file: Option<File>,
+    // Kept between rounds, so its capacity is reused
+    lines: Vec<u8>,
+}
+
+impl CsvLog {
+    pub fn log(
+        &mut self,
+        now_ms: u64,
+        resolution: TimestampResolution,
+        measurements: &[Measurement],
+    ) -> io::Result<()> {
+        let day = now_ms / (24 * 60 * 60 * 1000);
+        if self.file.is_none() || self.day != day {
+            self.rotate(day)?;
+        }
+
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "No open CSV file")),
+        };
+        self.lines.clear();
+        let now = resolution.convert(now_ms);
+        for measurement in measurements {
+            writeln!(self.lines, "{},{},{}", now, measurement.name, measurement.value)?;
+        }
+        let result = file.write_all(&self.lines).and_then(|_| file.sync_all());
+        if result.is_err() {
+            // Card might have been pulled out or got corrupted, reopen on the next write
+            self.file = None;
+        }
+        result
+    }
+
+    fn rotate(&mut self, day: u64) -> io::Result<()> {
+        self.file = None;
+        self.day = day;
+
+        let name = file_name(day);
+        if let Err(err) = free_space(&name) {
+            warn!("Failed to free up space on the SD card: {:?}", err);
+        }
+
+        let path = format!("{}/{}", MOUNT_POINT, name);
+        let is_new = !Path::new(&path).exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if is_new {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        info!("Logging measurements to {}", path);
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+impl Sink for CsvLog {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        self.log(timestamp_ms, config.timestamp_resolution, measurements)
+            .context(Phase::Archive, "Failed to write to the SD card")
+    }
+}
+
+/// Lists the daily CSV files on the card, oldest first.
+pub fn list_files() -> io::Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(MOUNT_POINT)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.len() == 12 && name.to_ascii_uppercase().ends_with(".CSV"))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Reads the rounds logged since `since_ms` back, oldest first, e.g. to pick up the day's
+/// summary after a reboot.
+pub fn replay(since_ms: u64, mut round: impl FnMut(u64, &[Measurement])) -> io::Result<()> {
+    let first = file_name(since_ms / (24 * 60 * 60 * 1000));
+    let mut timestamp_ms = None;
+    let mut measurements = Vec::new();
+    for name in list_files()?.into_iter().filter(|name| name.to_ascii_uppercase() >= first) {
+        for line in BufReader::new(File::open(path(&name))?).lines().map_while(Result::ok) {
+            let fields: Vec<&str> = line.split(',').collect();
+            let [timestamp, metric, value] = fields.as_slice() else {
+                continue;
+            };
+            let (Ok(timestamp), Ok(value)) = (timestamp.parse::<u64>(), value.parse::<f32>()) else {
+                continue; // Header line or a record cut short by a power loss
+            };
+            // Logged in s or ms, depending on `timestamp_resolution` at the time
+            let line_ms = if timestamp < 100_000_000_000 { timestamp * 1000 } else { timestamp };
+            if line_ms < since_ms {
+                continue;
+            }
+            if timestamp_ms != Some(line_ms) {
+                if let Some(timestamp_ms) = timestamp_ms {
+                    round(timestamp_ms, &measurements);
+                }
+                timestamp_ms = Some(line_ms);
+                measurements.clear();
+            }
+            measurements.push(Measurement {
+                name: metric.to_string().into(),
+                value,
+            });
+        }
+    }
+    if let Some(timestamp_ms) = timestamp_ms {
+        round(timestamp_ms, &measurements);
+    }
+    Ok(())
+}
+
+pub fn path(name: &str) -> String {
+    format!("{}/{}", MOUNT_POINT, name)
+}
+
+fn free_space(keep: &str) -> io::Result<()> {
+    let mut files = list_files()?;
+    files.retain(|name| !name.eq_ignore_ascii_case(keep));
+
+    let mut deleted = 0;
+    for name in &files {
+        let free = free_bytes()?;
+        if free >= MIN_FREE_BYTES && files.len() - deleted < MAX_DAYS {
+            break;
+        }
+        info!("Deleting {} from the SD card, {} bytes free", name, free);
+        if let Err(err) = fs::remove_file(path(name)) {
+            error!("Failed to delete {}: {:?}", name, err);
+            break;
+        }
+        deleted += 1;
+    }
+    Ok(())
+}
+
+fn free_bytes() -> io::Result<u64> {
+    let mut total: u64 = 0;
+    let mut free: u64 = 0;
+    esp!(unsafe { esp_vfs_fat_info(MOUNT_POINT_C.as_ptr(), &mut total, &mut free) }).map_err(io::Error::other)?;
+    Ok(free)
+}
+
+fn file_name(day: u64) -> String {
+    let (year, month, day) = civil_from_days(day as i64);
+    format!("{:04}{:02}{:02}.CSV", year, month, day)
+}
+
+// Howard Hinnant's days-to-civil algorithm, avoids pulling in a date crate just for file names
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
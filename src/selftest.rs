@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, PinDriver, Pull};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{error, info, warn};
+
+use crate::clock::ClockSync;
+use crate::config::{Config, Output, SelfTestConfig};
+use crate::error::{Context, Error, Phase, Result};
+use crate::pipeline::{self, Sink};
+use crate::quiet_hours;
+use crate::registry::SensorRegistry;
+use crate::sensors::Measurement;
+use crate::timesync;
+use crate::wifi;
+
+const BUTTON_WINDOW: Duration = Duration::from_secs(3);
+const BUTTON_HOLD: Duration = Duration::from_secs(1);
+const FACTORY_RESET_HOLD: Duration = Duration::from_secs(10);
+
+struct Check {
+    name: String,
+    result: Result<String, String>,
+}
+
+/// What the button held during boot asks for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+    None,
+    SelfTest,
+    FactoryReset,
+}
+
+/// Whether the self-test should run, either because the config says so or because the button
+/// was held during boot, or whether it was held long enough for a factory reset.
+pub fn requested(config: &SelfTestConfig) -> Request {
+    match config.button.map_or(Request::None, button_held) {
+        Request::None if config.on_boot => Request::SelfTest,
+        request => request,
+    }
+}
+
+/// Probes the sensors, Wi-Fi, SNTP and the uplink sink, then reports the result on the serial
+/// console, the LED and as a `selftest` metric. Returns whether all checks passed.
+pub fn run(
+    config: &Config,
+    sensors: &mut SensorRegistry,
+    wifi: &mut BlockingWifi<EspWifi>,
+    uplink: &mut dyn Sink,
+) -> bool {
+    println!("Running self-test");
+    let mut checks = Vec::new();
+
+    for (name, result) in sensors.probe() {
+        let result = result.map(|measurements| {
+            measurements
+                .iter()
+                .map(|measurement| format!("{}={}", measurement.name, measurement.value))
+                .collect::<Vec<_>>()
+                .join(" ")
+        });
+        checks.push(check(name, result));
+    }
+
+    let online = match wifi::connect_wifi(wifi, &config.wifi) {
+        Ok(_) => {
+            checks.push(check("wifi", Ok(format!("connected to {}", config.wifi.ssid))));
+            checks.push(check("sntp", sync_time(config)));
+            true
+        }
+        Err(err) => {
+            checks.push(check("wifi", Err(err)));
+            false
+        }
+    };
+
+    // The round trip uploads the result of the other checks, so the server ends up with a record
+    // of every unit that passed
+    let passed = checks.iter().all(|check| check.result.is_ok());
+    let result = if online {
+        let measurements = [Measurement {
+            name: "selftest".into(),
+            value: if passed { 1.0 } else { 0.0 },
+        }];
+        uplink
+            .send(config, pipeline::now_ms(), &measurements)
+            .map(|_| format!("sent to {}", config.graphite.address()))
+    } else {
+        Err(Error::failed(Phase::SelfTest, "skipped, no network"))
+    };
+    checks.push(check("uplink", result));
+    if let Err(err) = wifi::disconnect_wifi(wifi) {
+        warn!("Failed to disconnect after the self-test: {}", err);
+    }
+
+    let passed = checks.iter().all(|check| check.result.is_ok());
+    report(&checks, passed);
+    if let Some(pin) = config.selftest.led.filter(|_| quiet_hours::allowed(config, Output::Led)) {
+        if let Err(err) = signal(pin, passed) {
+            error!("Self-test LED on pin {}: {}", pin, err);
+        }
+    }
+    passed
+}
+
+fn check(name: &str, result: Result<String>) -> Check {
+    Check {
+        name: name.to_string(),
+        result: result.map_err(|err| err.to_string()),
+    }
+}
+
+fn sync_time(config: &Config) -> Result<String> {
+    let sntp = timesync::start(&config.sntp, ClockSync::default(), None)?;
+    let took = timesync::wait(&sntp, &config.sntp)?;
+    Ok(format!("synced in {} ms", took.as_millis()))
+}
+
+fn report(checks: &[Check], passed: bool) {
+    println!("--- SELF-TEST ---");
+    for check in checks {
+        match &check.result {
+            Ok(detail) => println!("PASS  {:<10} {}", check.name, detail),
+            Err(detail) => println!("FAIL  {:<10} {}", check.name, detail),
+        }
+    }
+    let failures = checks.iter().filter(|check| check.result.is_err()).count();
+    println!("Self-test {}, {} of {} checks failed", if passed { "PASSED" } else { "FAILED" }, failures, checks.len());
+    println!("--- END SELF-TEST ---");
+}
+
+fn button_held(pin: i32) -> Request {
+    let mut driver = match PinDriver::input(unsafe { AnyIOPin::new(pin) }) {
+        Ok(driver) => driver,
+        Err(err) => {
+            error!("Failed to configure self-test button pin {}: {:?}", pin, err);
+            return Request::None;
+        }
+    };
+    if let Err(err) = driver.set_pull(Pull::Up) {
+        error!("Failed to enable pull-up on self-test button pin {}: {:?}", pin, err);
+    }
+    println!("Hold the button on GPIO{} to start the self-test", pin);
+
+    let started = Instant::now();
+    let mut pressed_since = None;
+    while started.elapsed() < BUTTON_WINDOW {
+        if driver.is_low() {
+            let since = *pressed_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= BUTTON_HOLD {
+                break;
+            }
+        } else {
+            pressed_since = None;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let Some(since) = pressed_since.filter(|since| since.elapsed() >= BUTTON_HOLD) else {
+        return Request::None;
+    };
+    println!("Release the button to start the self-test, keep holding it for a factory reset");
+    while driver.is_low() {
+        if since.elapsed() >= FACTORY_RESET_HOLD {
+            info!("Factory reset requested by button");
+            return Request::FactoryReset;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    info!("Self-test requested by button");
+    Request::SelfTest
+}
+
+fn signal(pin: i32, passed: bool) -> Result<()> {
+    let context = "Failed to drive the LED";
+    let mut led = PinDriver::output(unsafe { AnyOutputPin::new(pin) }).context(Phase::SelfTest, context)?;
+    if passed {
+        led.set_high().context(Phase::SelfTest, context)?;
+        std::thread::sleep(Duration::from_secs(3));
+    } else {
+        for _ in 0..16 {
+            led.toggle().context(Phase::SelfTest, context)?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    led.set_low().context(Phase::SelfTest, context)?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(target_os = "espidf")]
+use log::error;
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase, Result};
+
+#[cfg(target_os = "espidf")]
+const NVS_NAMESPACE: &str = "sensors";
+#[cfg(target_os = "espidf")]
+const NVS_MEMORY_KEY: &str = "memory";
+
+/// What the sensors settled on at runtime, e.g. the gain of a light sensor, kept in NVS so a
+/// reboot doesn't send them back to their cold-boot defaults. Only written when it changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SensorMemory {
+    /// Keyed by `<sensor>.<field>`, e.g. `tsl2591.gain`
+    pub values: BTreeMap<String, u32>,
+}
+
+impl SensorMemory {
+    pub fn get(&self, sensor: &str, field: &str) -> Option<u32> {
+        self.values.get(&format!("{}.{}", sensor, field)).copied()
+    }
+
+    /// Records a value, returns whether it changed.
+    pub fn set(&mut self, sensor: &str, field: &str, value: u32) -> bool {
+        self.values.insert(format!("{}.{}", sensor, field), value) != Some(value)
+    }
+}
+
+/// Reads the memory from NVS, a missing or broken entry means cold-boot defaults.
+#[cfg(target_os = "espidf")]
+pub fn load(nvs: &EspDefaultNvsPartition) -> SensorMemory {
+    let nvs = match EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, err);
+            return SensorMemory::default();
+        }
+    };
+    let mut buf = [0u8; 256];
+    match nvs.get_str(NVS_MEMORY_KEY, &mut buf) {
+        Ok(Some(json)) => serde_json::from_str(json).unwrap_or_else(|err| {
+            error!("Failed to parse the sensor memory stored in NVS: {}", err);
+            SensorMemory::default()
+        }),
+        Ok(None) => SensorMemory::default(),
+        Err(err) => {
+            error!("Failed to read the sensor memory from NVS: {:?}", err);
+            SensorMemory::default()
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn store(nvs: &EspDefaultNvsPartition, memory: &SensorMemory) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true)
+        .context(Phase::Config, "Failed to open the NVS namespace")?;
+    nvs.set_str(NVS_MEMORY_KEY, &serde_json::to_string(memory)?)
+        .context(Phase::Config, "Failed to store the sensor memory")?;
+    Ok(())
+}
@@ -1,12 +1,88 @@
 mod trait_def;
 
-#[cfg(feature = "scd4x")]
+#[cfg(target_os = "espidf")]
+mod i2c;
+
+#[cfg(all(feature = "scd4x", target_os = "espidf"))]
 mod scd4x;
 
-#[cfg(feature = "bme280")]
+#[cfg(all(feature = "bme280", target_os = "espidf"))]
 mod bme280;
 
-#[cfg(feature = "tsl2591")]
+#[cfg(all(feature = "tsl2591", target_os = "espidf"))]
 mod tsl2591;
 
-pub(crate) use trait_def::{Measurement, Sensor};
\ No newline at end of file
+#[cfg(all(feature = "as7341", target_os = "espidf"))]
+mod as7341;
+
+#[cfg(all(feature = "ld2410", target_os = "espidf"))]
+mod ld2410;
+
+#[cfg(all(feature = "lis3dh", target_os = "espidf"))]
+mod lis3dh;
+
+#[cfg(all(feature = "hdc1080", target_os = "espidf"))]
+mod hdc1080;
+
+#[cfg(all(feature = "ccs811", target_os = "espidf"))]
+mod ccs811;
+
+#[cfg(all(feature = "sgp30", target_os = "espidf"))]
+mod sgp30;
+
+#[cfg(all(feature = "max44009", target_os = "espidf"))]
+mod max44009;
+
+#[cfg(all(feature = "dht22", target_os = "espidf"))]
+mod dht22;
+
+#[cfg(all(feature = "reed_switch", target_os = "espidf"))]
+mod reed_switch;
+
+#[cfg(all(any(feature = "thermistor", feature = "analog"), target_os = "espidf"))]
+mod adc;
+
+#[cfg(all(feature = "thermistor", target_os = "espidf"))]
+mod thermistor;
+
+#[cfg(all(feature = "analog", target_os = "espidf"))]
+mod analog;
+
+#[cfg(all(feature = "snore", target_os = "espidf"))]
+mod microphone;
+
+pub use trait_def::{Measurement, Sensor};
+#[cfg(target_os = "espidf")]
+pub use i2c::I2cBus;
+#[cfg(target_os = "espidf")]
+pub use trait_def::{I2cDevice, I2cSensor, SpiDevice, SpiSensor};
+#[cfg(all(feature = "scd4x", target_os = "espidf"))]
+pub use scd4x::Scd4xSensor;
+#[cfg(all(feature = "bme280", target_os = "espidf"))]
+pub use bme280::Bme280Sensor;
+#[cfg(all(feature = "tsl2591", target_os = "espidf"))]
+pub use tsl2591::{spawn_light_events, LightWatch, Tsl2591Sensor};
+#[cfg(all(feature = "as7341", target_os = "espidf"))]
+pub use as7341::As7341Sensor;
+#[cfg(all(feature = "ld2410", target_os = "espidf"))]
+pub use ld2410::{Ld2410Sensor, SharedRadar};
+#[cfg(all(feature = "lis3dh", target_os = "espidf"))]
+pub use lis3dh::spawn_actigraphy;
+#[cfg(all(feature = "hdc1080", target_os = "espidf"))]
+pub use hdc1080::Hdc1080Sensor;
+#[cfg(all(feature = "ccs811", target_os = "espidf"))]
+pub use ccs811::Ccs811Sensor;
+#[cfg(all(feature = "sgp30", target_os = "espidf"))]
+pub use sgp30::Sgp30Sensor;
+#[cfg(all(feature = "max44009", target_os = "espidf"))]
+pub use max44009::Max44009Sensor;
+#[cfg(all(feature = "dht22", target_os = "espidf"))]
+pub use dht22::{Dht22Line, Dht22Sensor};
+#[cfg(all(feature = "reed_switch", target_os = "espidf"))]
+pub use reed_switch::{spawn_reed_switch, ReedSwitchSensor};
+#[cfg(all(feature = "thermistor", target_os = "espidf"))]
+pub use thermistor::ThermistorSensor;
+#[cfg(all(feature = "analog", target_os = "espidf"))]
+pub use analog::AnalogSensor;
+#[cfg(all(feature = "snore", target_os = "espidf"))]
+pub use microphone::{SharedSnore, SnoreSensor};
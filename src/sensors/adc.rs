@@ -0,0 +1,119 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{
+    adc_atten_t_ADC_ATTEN_DB_12, adc_bitwidth_t_ADC_BITWIDTH_DEFAULT, adc_cali_create_scheme_curve_fitting,
+    adc_cali_curve_fitting_config_t, adc_cali_delete_scheme_curve_fitting, adc_cali_handle_t, adc_cali_raw_to_voltage,
+    adc_channel_t, adc_oneshot_chan_cfg_t, adc_oneshot_config_channel, adc_oneshot_io_to_channel, adc_oneshot_new_unit,
+    adc_oneshot_read, adc_oneshot_unit_handle_t, adc_oneshot_unit_init_cfg_t, adc_unit_t, adc_unit_t_ADC_UNIT_1, esp,
+};
+use log::warn;
+
+use crate::error::{Context, Error, Phase, Result};
+
+// Averaged per reading, a single conversion is noisy
+const SAMPLES: u32 = 16;
+// Full scale with 12 dB attenuation, only used when the chip has no calibration
+const FULL_SCALE_MV: f32 = 3300.0;
+const MAX_RAW: f32 = 4095.0;
+
+struct Unit(adc_oneshot_unit_handle_t);
+
+// The oneshot driver is thread safe, the handle is only a pointer to it
+unsafe impl Send for Unit {}
+
+// A unit can only be set up once, every input shares it
+static UNIT: Mutex<Option<Unit>> = Mutex::new(None);
+
+fn unit() -> Result<adc_oneshot_unit_handle_t> {
+    let mut unit = UNIT.lock().expect("ADC unit lock poisoned");
+    if let Some(Unit(handle)) = *unit {
+        return Ok(handle);
+    }
+    let config = adc_oneshot_unit_init_cfg_t {
+        unit_id: adc_unit_t_ADC_UNIT_1,
+        ..Default::default()
+    };
+    let mut handle: adc_oneshot_unit_handle_t = std::ptr::null_mut();
+    esp!(unsafe { adc_oneshot_new_unit(&config, &mut handle) })
+        .context(Phase::SensorInit, "Failed to set up the ADC")?;
+    *unit = Some(Unit(handle));
+    Ok(handle)
+}
+
+/// A GPIO read by ADC1 in oneshot mode, in mV over the full 0-3.3 V range.
+pub struct AdcInput {
+    unit: adc_oneshot_unit_handle_t,
+    channel: adc_channel_t,
+    calibration: Option<adc_cali_handle_t>,
+}
+
+unsafe impl Send for AdcInput {}
+
+impl AdcInput {
+    pub fn new(pin: i32) -> Result<Self> {
+        let mut unit_id: adc_unit_t = 0;
+        let mut channel: adc_channel_t = 0;
+        esp!(unsafe { adc_oneshot_io_to_channel(pin, &mut unit_id, &mut channel) })
+            .with_context(Phase::SensorInit, || format!("GPIO{} isn't an ADC pin", pin))?;
+        // ADC2 is shared with the radio
+        if unit_id != adc_unit_t_ADC_UNIT_1 {
+            return Err(Error::failed(Phase::SensorInit, format!("GPIO{} isn't on ADC1", pin)));
+        }
+        let unit = unit()?;
+        let config = adc_oneshot_chan_cfg_t {
+            atten: adc_atten_t_ADC_ATTEN_DB_12,
+            bitwidth: adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+        };
+        esp!(unsafe { adc_oneshot_config_channel(unit, channel, &config) })
+            .with_context(Phase::SensorInit, || format!("Failed to set up the ADC channel of GPIO{}", pin))?;
+
+        let calibration_config = adc_cali_curve_fitting_config_t {
+            unit_id,
+            chan: channel,
+            atten: adc_atten_t_ADC_ATTEN_DB_12,
+            bitwidth: adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+        };
+        let mut handle: adc_cali_handle_t = std::ptr::null_mut();
+        let created = esp!(unsafe { adc_cali_create_scheme_curve_fitting(&calibration_config, &mut handle) });
+        let calibration = match created {
+            Ok(()) => Some(handle),
+            Err(err) => {
+                // Without the eFuse calibration values the readings can be off by 100 mV or so
+                warn!("ADC: no calibration for GPIO{}: {:?}", pin, err);
+                None
+            }
+        };
+        Ok(AdcInput {
+            unit,
+            channel,
+            calibration,
+        })
+    }
+
+    /// The average of a burst of conversions.
+    pub fn read_mv(&mut self) -> Result<f32> {
+        let mut sum = 0;
+        for _ in 0..SAMPLES {
+            let mut raw = 0;
+            esp!(unsafe { adc_oneshot_read(self.unit, self.channel, &mut raw) })
+                .context(Phase::Measure, "Failed to read the ADC")?;
+            sum += raw;
+        }
+        let raw = sum / SAMPLES as i32;
+        let Some(calibration) = self.calibration else {
+            return Ok(raw as f32 / MAX_RAW * FULL_SCALE_MV);
+        };
+        let mut mv = 0;
+        esp!(unsafe { adc_cali_raw_to_voltage(calibration, raw, &mut mv) })
+            .context(Phase::Measure, "Failed to convert the ADC reading")?;
+        Ok(mv as f32)
+    }
+}
+
+impl Drop for AdcInput {
+    fn drop(&mut self) {
+        if let Some(calibration) = self.calibration {
+            unsafe { adc_cali_delete_scheme_curve_fitting(calibration) };
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use log::error;
+
+use crate::analog::percentage;
+use crate::calibration::AnalogRange;
+use crate::config::Config;
+use crate::error::Result;
+use crate::metrics::MetricSpec;
+
+use super::adc::AdcInput;
+use super::trait_def::{Measurement, Sensor};
+
+// A little over the 3.3 V full scale, a calibrated reading can overshoot it
+const MAX_MV: f32 = 3_500.0;
+
+struct Input {
+    name: String,
+    adc: AdcInput,
+    range: Option<AnalogRange>,
+}
+
+/// Generic analog transducers on ADC pins, e.g. a capacitive moisture pad under the sheet. The
+/// voltage is reported as `<name>_mv`, and as `<name>` in % of the range stored in NVS for the
+/// board, which differs from one pad and one mattress to the next.
+pub struct AnalogSensor {
+    inputs: Vec<Input>,
+}
+
+impl AnalogSensor {
+    pub fn new(config: &Config) -> Result<Self> {
+        println!("Initializing {} analog input(s)", config.analog_inputs.len());
+        let inputs = config
+            .analog_inputs
+            .iter()
+            .map(|input| {
+                Ok(Input {
+                    name: input.name.clone(),
+                    adc: AdcInput::new(input.pin)?,
+                    range: config.calibration.range(&input.name),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(AnalogSensor { inputs })
+    }
+}
+
+impl Sensor for AnalogSensor {
+    fn name(&self) -> &'static str {
+        "analog"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        let mut metrics = Vec::new();
+        for input in &self.inputs {
+            metrics.push(MetricSpec {
+                name: format!("{}_mv", input.name).into(),
+                unit: "mV",
+                min: 0.0,
+                max: MAX_MV,
+            });
+            metrics.push(MetricSpec {
+                name: input.name.clone().into(),
+                unit: "%",
+                min: 0.0,
+                max: 100.0,
+            });
+        }
+        metrics
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let mut measurements = Vec::new();
+        for input in &mut self.inputs {
+            let mv = match input.adc.read_mv() {
+                Ok(mv) => mv,
+                Err(err) => {
+                    error!("Analog input {}: {}", input.name, err);
+                    continue;
+                }
+            };
+            measurements.push(Measurement {
+                name: format!("{}_mv", input.name).into(),
+                value: mv,
+            });
+            if let Some(value) = input.range.and_then(|range| percentage(mv, range.zero_mv, range.full_mv)) {
+                measurements.push(Measurement {
+                    name: input.name.clone().into(),
+                    value,
+                });
+            }
+        }
+        measurements
+    }
+}
@@ -0,0 +1,246 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+use log::{debug, error, warn};
+
+use crate::config::Config;
+use crate::error::{sensor_init, Error, Phase, Result};
+use crate::light::correlated_color_temperature;
+use crate::metrics::MetricSpec;
+
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+const ADDRESS: u8 = 0x39;
+
+const REG_ENABLE: u8 = 0x80;
+const REG_ATIME: u8 = 0x81;
+const REG_ID: u8 = 0x92;
+const REG_ASTATUS: u8 = 0x94;
+const REG_STATUS2: u8 = 0xA3;
+const REG_CFG1: u8 = 0xAA;
+const REG_CFG6: u8 = 0xAF;
+const REG_ASTEP: u8 = 0xCA;
+
+const ENABLE_PON: u8 = 0x01;
+const ENABLE_SP_EN: u8 = 0x02;
+const ENABLE_SMUXEN: u8 = 0x10;
+const STATUS2_AVALID: u8 = 0x40;
+const CFG6_SMUX_WRITE: u8 = 0x10;
+const ID: u8 = 0x24;
+
+// (ATIME + 1) * (ASTEP + 1) * 2.78 µs = 281 ms, which also makes 65535 the full-scale count
+const ATIME: u8 = 100;
+const ASTEP: u16 = 999;
+const INTEGRATION_MS: f32 = 101.0 * 1000.0 * 2.78 / 1000.0;
+const SATURATION: u16 = 60000;
+
+// AGAIN register values are 0.5x, 1x, 2x ... 512x
+const MAX_GAIN: u8 = 10;
+const DEFAULT_GAIN: u8 = 9;
+const MAX_ATTEMPTS: usize = 4;
+
+// The six ADCs see F1-F4 and F5-F8 in turn, clear and NIR in both, from the AMS application note
+const SMUX_F1_F4: [u8; 20] = [
+    0x30, 0x01, 0x00, 0x00, 0x00, 0x42, 0x00, 0x00, 0x50, 0x00, 0x00, 0x00, 0x20, 0x04, 0x00, 0x30, 0x01, 0x50,
+    0x00, 0x06,
+];
+const SMUX_F5_F8: [u8; 20] = [
+    0x00, 0x00, 0x00, 0x40, 0x02, 0x00, 0x10, 0x03, 0x50, 0x10, 0x03, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x50,
+    0x00, 0x06,
+];
+
+const CHANNEL_NAMES: [&str; 10] = [
+    "spectral_415nm",
+    "spectral_445nm",
+    "spectral_480nm",
+    "spectral_515nm",
+    "spectral_555nm",
+    "spectral_590nm",
+    "spectral_630nm",
+    "spectral_680nm",
+    "spectral_clear",
+    "spectral_nir",
+];
+
+/// Register-level access to the AS7341, there's no maintained embedded-hal 1.0 driver.
+struct As7341<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> As7341<I2C> {
+    fn write(&mut self, register: u8, value: u8) -> Result<(), I2C::Error> {
+        self.i2c.write(ADDRESS, &[register, value])
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, I2C::Error> {
+        let mut value = [0u8];
+        self.i2c.write_read(ADDRESS, &[register], &mut value)?;
+        Ok(value[0])
+    }
+
+    fn id(&mut self) -> Result<u8, I2C::Error> {
+        Ok(self.read(REG_ID)? & 0xFC)
+    }
+
+    fn configure(&mut self, gain: u8) -> Result<(), I2C::Error> {
+        self.write(REG_ENABLE, ENABLE_PON)?;
+        self.write(REG_ATIME, ATIME)?;
+        let [low, high] = ASTEP.to_le_bytes();
+        self.i2c.write(ADDRESS, &[REG_ASTEP, low, high])?;
+        self.set_gain(gain)
+    }
+
+    fn set_gain(&mut self, gain: u8) -> Result<(), I2C::Error> {
+        self.write(REG_CFG1, gain)
+    }
+
+    /// Routes the photodiodes to the ADCs and runs one integration, returns the six ADC counts.
+    fn read_channels(&mut self, smux: &[u8; 20]) -> Result<Option<[u16; 6]>, I2C::Error> {
+        self.write(REG_ENABLE, ENABLE_PON)?;
+        self.write(REG_CFG6, CFG6_SMUX_WRITE)?;
+        for (register, value) in smux.iter().enumerate() {
+            self.write(register as u8, *value)?;
+        }
+        self.write(REG_ENABLE, ENABLE_PON | ENABLE_SMUXEN)?;
+        if !self.wait(REG_ENABLE, |enable| enable & ENABLE_SMUXEN == 0, Duration::from_millis(100))? {
+            warn!("AS7341: SMUX configuration timed out");
+            return Ok(None);
+        }
+
+        self.write(REG_ENABLE, ENABLE_PON | ENABLE_SP_EN)?;
+        let timeout = Duration::from_millis(2 * INTEGRATION_MS as u64);
+        let ready = self.wait(REG_STATUS2, |status| status & STATUS2_AVALID != 0, timeout)?;
+        let mut data = [0u8; 13];
+        if ready {
+            // Reading ASTATUS first latches the channel data
+            self.i2c.write_read(ADDRESS, &[REG_ASTATUS], &mut data)?;
+        }
+        self.write(REG_ENABLE, ENABLE_PON)?;
+        if !ready {
+            warn!("AS7341: measurement timed out");
+            return Ok(None);
+        }
+        let mut counts = [0u16; 6];
+        for (i, count) in counts.iter_mut().enumerate() {
+            *count = u16::from_le_bytes([data[1 + 2 * i], data[2 + 2 * i]]);
+        }
+        Ok(Some(counts))
+    }
+
+    /// All ten channels in order F1-F8, clear and NIR.
+    fn read_all(&mut self) -> Result<Option<[u16; 10]>, I2C::Error> {
+        let Some(low) = self.read_channels(&SMUX_F1_F4)? else {
+            return Ok(None);
+        };
+        let Some(high) = self.read_channels(&SMUX_F5_F8)? else {
+            return Ok(None);
+        };
+        // ADC 4 is the clear channel and ADC 5 the NIR one in both halves
+        Ok(Some([low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3], low[4], low[5]]))
+    }
+
+    fn wait(&mut self, register: u8, done: impl Fn(u8) -> bool, timeout: Duration) -> Result<bool, I2C::Error> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if done(self.read(register)?) {
+                return Ok(true);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(false)
+    }
+}
+
+/// AS7341 10-channel spectral sensor. Reports every channel as basic counts (counts per gain and
+/// millisecond), which are proportional to the irradiance in that band, plus the correlated
+/// colour temperature. The gain follows the light level and is kept between rounds and reboots.
+pub struct As7341Sensor<'a> {
+    device: As7341<I2cDevice<'a>>,
+    gain: u8,
+}
+
+const METRICS: [MetricSpec; 1] = [
+    MetricSpec::new("cct", "K", 1000.0, 40_000.0),
+];
+
+impl Sensor for As7341Sensor<'_> {
+    fn name(&self) -> &'static str {
+        "as7341"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        for _ in 0..MAX_ATTEMPTS {
+            if let Err(err) = self.device.set_gain(self.gain) {
+                error!("AS7341: Failed to set gain: {:?}", err);
+                return vec![];
+            }
+            let counts = match self.device.read_all() {
+                Ok(Some(counts)) => counts,
+                Ok(None) => return vec![],
+                Err(err) => {
+                    error!("AS7341: Failed to read channels: {:?}", err);
+                    return vec![];
+                }
+            };
+            let brightest = counts.iter().copied().max().unwrap_or_default();
+            if brightest >= SATURATION && self.gain > 0 {
+                debug!("AS7341: saturated at gain {}, lowering it", self.gain);
+                self.gain -= 1;
+                continue;
+            }
+            let gain_factor = 0.5 * 2f32.powi(self.gain as i32);
+            let basic = counts.map(|count| count as f32 / (gain_factor * INTEGRATION_MS));
+            // Dim light, the next round gets a better resolution
+            if brightest < SATURATION / 4 && self.gain < MAX_GAIN {
+                self.gain += 1;
+            }
+
+            let mut measurements: Vec<Measurement> = CHANNEL_NAMES
+                .iter()
+                .zip(basic)
+                .map(|(name, value)| Measurement {
+                    name: (*name).into(),
+                    value,
+                })
+                .collect();
+            let visible: [f32; 8] = basic[..8].try_into().expect("Eight visible channels");
+            if let Some(cct) = correlated_color_temperature(&visible) {
+                measurements.push(Measurement {
+                    name: "cct".into(),
+                    value: cct,
+                });
+            }
+            return measurements;
+        }
+        error!("AS7341: Still saturated after lowering the gain");
+        vec![]
+    }
+
+    fn memory(&self) -> Vec<(&'static str, u32)> {
+        vec![("gain", self.gain as u32)]
+    }
+}
+
+impl<'a> I2cSensor<'a> for As7341Sensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
+        println!("Initializing AS7341 spectral sensor");
+        let mut device = As7341 { i2c: i2c_device };
+        let id = device.id()
+            .map_err(sensor_init("as7341", "Failed to read ID - check I2C connection"))?;
+        if id != ID {
+            return Err(Error::sensor("as7341", Phase::SensorInit, format!("Unexpected ID {:#04x}", id)));
+        }
+        let gain = match config.sensor_memory.get("as7341", "gain") {
+            Some(gain) if gain <= MAX_GAIN as u32 => gain as u8,
+            _ => DEFAULT_GAIN,
+        };
+        device.configure(gain)
+            .map_err(sensor_init("as7341", "Failed to configure"))?;
+        Ok(As7341Sensor { device, gain })
+    }
+}
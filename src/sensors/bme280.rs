@@ -1,67 +1,207 @@
-use embedded_hal_bus::i2c::RcDevice;
+use std::time::Duration;
+
+use embedded_hal::spi::{Operation, SpiDevice as _};
 use esp_idf_svc::hal::delay::Delay;
-use esp_idf_svc::hal::i2c::I2cDriver;
 use log::error;
 use bme280_rs::{Bme280, Configuration as Bme280Configuration};
 
-use super::trait_def::{Measurement, Sensor};
+use crate::bme280_compensation::Calibration;
+use crate::climate::relative_humidity_at;
+use crate::config::Config;
+use crate::error::{sensor_init, Error, Phase, Result};
+use crate::metrics::MetricSpec;
 
-impl<'a> Sensor<'a> for Bme280<RcDevice<I2cDriver<'a>>, Delay> {
-    fn measure(&mut self) -> Vec<Measurement> {
-        let mut measurements: Vec<Measurement> = Vec::new();
-        if let Err(e) = self.take_forced_measurement() {
-            error!("BME280: Failed to trigger measurement: {:?}", e);
-            return vec![];
-        }
-        match self.read_sample() {
-            Ok(sample) => {
-                match sample.temperature {
-                    Some(value) => {
-                        measurements.push(Measurement {
-                            name: "temperature".to_string(),
-                            value: value,
-                        });
-                    }
-                    None => {
-                        error!("Temperature measurement is disabled");
-                    }
-                };
-                match sample.pressure {
-                    Some(value) => {
-                        measurements.push(Measurement {
-                            name: "pressure".to_string(),
-                            value: value * 0.0075,
-                        });
-                    }
-                    None => {
-                        error!("Pressure measurement is disabled");
-                    }
-                };
-                match sample.humidity {
-                    Some(value) => {
-                        measurements.push(Measurement {
-                            name: "humidity".to_string(),
-                            value: value,
-                        });
-                    }
-                    None => {
-                        error!("Humidity measurement is disabled");
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor, SpiDevice, SpiSensor};
+
+const REG_CALIBRATION_LOW: u8 = 0x88;
+const REG_ID: u8 = 0xD0;
+const REG_CALIBRATION_HIGH: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+const SPI_READ: u8 = 0x80;
+const SPI_WRITE: u8 = 0x7F;
+const ID: u8 = 0x60;
+const STATUS_MEASURING: u8 = 0x08;
+// 4x oversampling everywhere like the I2C setup, a forced measurement takes about 30 ms
+const CTRL_HUM_X4: u8 = 0x03;
+const CTRL_MEAS_X4_FORCED: u8 = 0x6D;
+
+/// BME280 with the board's calibration applied. The die runs warmer than the room, so the
+/// temperature offset is applied first and the humidity is re-computed for the corrected
+/// temperature, since the same air holds a higher relative humidity when it's cooler.
+pub struct Bme280Sensor<'a> {
+    bus: Bus<'a>,
+    temperature_offset: f32,
+}
+
+enum Bus<'a> {
+    I2c(Bme280<I2cDevice<'a>, Delay>),
+    Spi(SpiBme280<SpiDevice<'a>>),
+}
+
+/// Temperature in °C, pressure in Pa and humidity in %, `None` where it's switched off.
+type Sample = (Option<f32>, Option<f32>, Option<f32>);
+
+impl Bus<'_> {
+    fn sample(&mut self) -> Option<Sample> {
+        match self {
+            Bus::I2c(sensor) => {
+                if let Err(e) = sensor.take_forced_measurement() {
+                    error!("BME280: Failed to trigger measurement: {:?}", e);
+                    return None;
+                }
+                match sensor.read_sample() {
+                    Ok(sample) => Some((sample.temperature, sample.pressure, sample.humidity)),
+                    Err(err) => {
+                        error!("Error reading sample: {:?}", err);
+                        None
                     }
                 }
             }
-            Err(err) => {
-                error!("Error reading sample: {:?}", err);
+            Bus::Spi(sensor) => match sensor.sample() {
+                Ok(sample) => Some((Some(sample.temperature), Some(sample.pressure), Some(sample.humidity))),
+                Err(err) => {
+                    error!("BME280: Failed to read over SPI: {:?}", err);
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// The SPI variant, which the driver crate doesn't handle: forced measurements with 4x
+/// oversampling, compensated with the chip's trimming parameters.
+struct SpiBme280<SPI> {
+    spi: SPI,
+    calibration: Calibration,
+}
+
+impl<SPI: embedded_hal::spi::SpiDevice> SpiBme280<SPI> {
+    fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), SPI::Error> {
+        self.spi.transaction(&mut [Operation::Write(&[register | SPI_READ]), Operation::Read(buffer)])
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), SPI::Error> {
+        self.spi.write(&[register & SPI_WRITE, value])
+    }
+
+    fn id(&mut self) -> Result<u8, SPI::Error> {
+        let mut id = [0u8];
+        self.read(REG_ID, &mut id)?;
+        Ok(id[0])
+    }
+
+    fn load_calibration(&mut self) -> Result<(), SPI::Error> {
+        let (mut low, mut high) = ([0u8; 26], [0u8; 7]);
+        self.read(REG_CALIBRATION_LOW, &mut low)?;
+        self.read(REG_CALIBRATION_HIGH, &mut high)?;
+        self.calibration = Calibration::parse(&low, &high);
+        Ok(())
+    }
+
+    fn sample(&mut self) -> Result<crate::bme280_compensation::Sample, SPI::Error> {
+        // ctrl_hum only applies once ctrl_meas is written
+        self.write(REG_CTRL_HUM, CTRL_HUM_X4)?;
+        self.write(REG_CTRL_MEAS, CTRL_MEAS_X4_FORCED)?;
+        let mut status = [STATUS_MEASURING];
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(10));
+            self.read(REG_STATUS, &mut status)?;
+            if status[0] & STATUS_MEASURING == 0 {
+                break;
+            }
+        }
+        let mut data = [0u8; 8];
+        self.read(REG_DATA, &mut data)?;
+        let adc_p = (data[0] as u32) << 12 | (data[1] as u32) << 4 | (data[2] as u32) >> 4;
+        let adc_t = (data[3] as u32) << 12 | (data[4] as u32) << 4 | (data[5] as u32) >> 4;
+        let adc_h = u16::from_be_bytes([data[6], data[7]]);
+        Ok(self.calibration.compensate(adc_t, adc_p, adc_h))
+    }
+}
+
+impl<'a> Bme280Sensor<'a> {
+    fn new(bus: Bus<'a>, config: &Config) -> Self {
+        let temperature_offset = config.calibration.offset("bme280", "temperature");
+        if temperature_offset != 0.0 {
+            println!("BME280 temperature offset: {:+.2} C", temperature_offset);
+        }
+        Bme280Sensor { bus, temperature_offset }
+    }
+}
+
+const METRICS: [MetricSpec; 3] = [
+    MetricSpec::new("temperature", "°C", -40.0, 85.0),
+    MetricSpec::new("pressure", "mmHg", 225.0, 825.0),
+    MetricSpec::new("humidity", "%", 0.0, 100.0),
+];
+
+impl Sensor for Bme280Sensor<'_> {
+    fn name(&self) -> &'static str {
+        "bme280"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let mut measurements: Vec<Measurement> = Vec::new();
+        if let Some((temperature, pressure, humidity)) = self.bus.sample() {
+            let corrected = temperature.map(|value| value + self.temperature_offset);
+            match corrected {
+                Some(value) => {
+                    measurements.push(Measurement {
+                        name: "temperature".into(),
+                        value: value,
+                    });
+                }
+                None => {
+                    error!("Temperature measurement is disabled");
+                }
+            };
+            match pressure {
+                Some(value) => {
+                    measurements.push(Measurement {
+                        name: "pressure".into(),
+                        value: value * 0.0075,
+                    });
+                }
+                None => {
+                    error!("Pressure measurement is disabled");
+                }
+            };
+            match humidity {
+                Some(value) => {
+                    let value = match (temperature, corrected) {
+                        (Some(measured), Some(corrected)) if self.temperature_offset != 0.0 => {
+                            relative_humidity_at(value, measured, corrected)
+                        }
+                        _ => value,
+                    };
+                    measurements.push(Measurement {
+                        name: "humidity".into(),
+                        value: value,
+                    });
+                }
+                None => {
+                    error!("Humidity measurement is disabled");
+                }
             }
         }
         measurements
     }
+}
 
-    fn get_sensor(i2c_device: RcDevice<I2cDriver<'a>>) -> Self {
+impl<'a> I2cSensor<'a> for Bme280Sensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
         println!("Initializing BME280 sensor");
         let delay = Delay::new_default();
-        let mut sensor: Bme280<RcDevice<I2cDriver<'a>>, Delay> = Bme280::new(i2c_device, delay);
+        let mut sensor: Bme280<I2cDevice<'a>, Delay> = Bme280::new(i2c_device, delay);
         sensor.init()
-            .expect("Failed to initialize BME280 sensor - check I2C connection");
+            .map_err(sensor_init("bme280", "Failed to initialize - check I2C connection"))?;
         sensor
             .set_sampling_configuration(
                 Bme280Configuration::default()
@@ -70,10 +210,27 @@ impl<'a> Sensor<'a> for Bme280<RcDevice<I2cDriver<'a>>, Delay> {
                     .with_temperature_oversampling(bme280_rs::Oversampling::Oversample4)
                     .with_pressure_oversampling(bme280_rs::Oversampling::Oversample4)
             )
-            .expect("Failed to configure BME280 sensor");
+            .map_err(sensor_init("bme280", "Failed to configure"))?;
 
         delay.delay_ms(100);
+        Ok(Bme280Sensor::new(Bus::I2c(sensor), config))
+    }
+}
 
-        sensor
+impl<'a> SpiSensor<'a> for Bme280Sensor<'a> {
+    fn get_sensor(spi_device: SpiDevice<'a>, config: &Config) -> Result<Self> {
+        println!("Initializing BME280 sensor on SPI");
+        let mut sensor = SpiBme280 {
+            spi: spi_device,
+            calibration: Calibration::default(),
+        };
+        let id = sensor.id()
+            .map_err(sensor_init("bme280", "Failed to read ID - check SPI connection"))?;
+        if id != ID {
+            return Err(Error::sensor("bme280", Phase::SensorInit, format!("Unexpected ID {:#04x}", id)));
+        }
+        sensor.load_calibration()
+            .map_err(sensor_init("bme280", "Failed to read the calibration"))?;
+        Ok(Bme280Sensor::new(Bus::Spi(sensor), config))
     }
 }
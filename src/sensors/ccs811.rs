@@ -0,0 +1,237 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+use log::{error, info, warn};
+
+use crate::baseline::BaselineKeeper;
+use crate::config::Config;
+use crate::error::{sensor_init, Error, Phase, Result};
+use crate::metrics::MetricSpec;
+
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+const REG_STATUS: u8 = 0x00;
+const REG_MEAS_MODE: u8 = 0x01;
+const REG_ALG_RESULT_DATA: u8 = 0x02;
+const REG_ENV_DATA: u8 = 0x05;
+const REG_BASELINE: u8 = 0x11;
+const REG_HW_ID: u8 = 0x20;
+const REG_ERROR_ID: u8 = 0xE0;
+const APP_START: u8 = 0xF4;
+const HW_ID: u8 = 0x81;
+
+const STATUS_ERROR: u8 = 0x01;
+const STATUS_DATA_READY: u8 = 0x08;
+const STATUS_APP_VALID: u8 = 0x10;
+const STATUS_FW_MODE: u8 = 0x80;
+// Drive mode 1, a measurement every second
+const MEAS_MODE_1S: u8 = 0x10;
+// Drive mode 0, the heater is off
+const MEAS_MODE_IDLE: u8 = 0x00;
+
+// From the datasheet: the baseline is written back after 20 minutes of running, and the first
+// one learned is only worth keeping after a day
+const RESTORE_AFTER_SEC: u64 = 20 * 60;
+const BURN_IN_SEC: u64 = 24 * 60 * 60;
+const SAVE_EVERY_SEC: u64 = 60 * 60;
+// The readings settle within 20 minutes of every start
+const WARM_UP: Duration = Duration::from_secs(20 * 60);
+
+/// Register-level access to the CCS811, the driver crates are still on embedded-hal 0.2.
+struct Ccs811<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Ccs811<I2C> {
+    fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), I2C::Error> {
+        self.i2c.write_read(self.address, &[register], buffer)
+    }
+
+    fn write(&mut self, register: u8, data: &[u8]) -> Result<(), I2C::Error> {
+        let mut buffer = [0u8; 5];
+        buffer[0] = register;
+        buffer[1..=data.len()].copy_from_slice(data);
+        self.i2c.write(self.address, &buffer[..=data.len()])
+    }
+
+    fn status(&mut self) -> Result<u8, I2C::Error> {
+        let mut status = [0u8];
+        self.read(REG_STATUS, &mut status)?;
+        Ok(status[0])
+    }
+
+    fn error_id(&mut self) -> Result<u8, I2C::Error> {
+        let mut error_id = [0u8];
+        self.read(REG_ERROR_ID, &mut error_id)?;
+        Ok(error_id[0])
+    }
+
+    fn baseline(&mut self) -> Result<u16, I2C::Error> {
+        let mut baseline = [0u8; 2];
+        self.read(REG_BASELINE, &mut baseline)?;
+        Ok(u16::from_be_bytes(baseline))
+    }
+
+    /// eCO2 in ppm and TVOC in ppb, `None` if the sensor flags an error.
+    fn result(&mut self) -> Result<Option<(u16, u16)>, I2C::Error> {
+        let mut data = [0u8; 6];
+        self.read(REG_ALG_RESULT_DATA, &mut data)?;
+        if data[4] & STATUS_ERROR != 0 {
+            warn!("CCS811: error {:#04x}", data[5]);
+            return Ok(None);
+        }
+        Ok(Some((u16::from_be_bytes([data[0], data[1]]), u16::from_be_bytes([data[2], data[3]]))))
+    }
+}
+
+/// CCS811 metal-oxide gas sensor. It doesn't measure CO2, it estimates it from the volatile
+/// organic compounds it sees, so it reports `eco2` (ppm) and `tvoc` (ppb) and never `co2`, which
+/// stays the NDIR reading of the SCD4x. The estimate drifts with temperature and humidity, those
+/// of the sensors before it in the round are passed on, and with its baseline, which is kept in
+/// the sensor memory and written back after a reboot.
+pub struct Ccs811Sensor<'a> {
+    device: Ccs811<I2cDevice<'a>>,
+    started: Instant,
+    baseline: BaselineKeeper,
+}
+
+const METRICS: [MetricSpec; 2] = [
+    MetricSpec::new("eco2", "ppm", 400.0, 32_768.0),
+    MetricSpec::new("tvoc", "ppb", 0.0, 32_768.0),
+];
+
+impl Sensor for Ccs811Sensor<'_> {
+    fn name(&self) -> &'static str {
+        "ccs811"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let running_sec = self.started.elapsed().as_secs();
+        if let Some(baseline) = self.baseline.restore(running_sec) {
+            match self.device.write(REG_BASELINE, &(baseline as u16).to_be_bytes()) {
+                Ok(()) => info!("CCS811: baseline {:#06x} restored", baseline),
+                Err(err) => error!("CCS811: Failed to restore the baseline: {:?}", err),
+            }
+        }
+
+        let mut ready = false;
+        for _ in 0..12 {
+            match self.device.status() {
+                Ok(status) if status & STATUS_ERROR != 0 => {
+                    error!("CCS811: error {:?}", self.device.error_id());
+                    return vec![];
+                }
+                Ok(status) if status & STATUS_DATA_READY != 0 => {
+                    ready = true;
+                    break;
+                }
+                Ok(_) => thread::sleep(Duration::from_millis(100)),
+                Err(err) => {
+                    error!("CCS811: Failed to read the status: {:?}", err);
+                    return vec![];
+                }
+            }
+        }
+        if !ready {
+            warn!("CCS811: no data ready");
+            return vec![];
+        }
+        let (eco2, tvoc) = match self.device.result() {
+            Ok(Some(result)) => result,
+            Ok(None) => return vec![],
+            Err(err) => {
+                error!("CCS811: Failed to read the result: {:?}", err);
+                return vec![];
+            }
+        };
+
+        if self.baseline.save_due(running_sec) {
+            match self.device.baseline() {
+                Ok(baseline) => self.baseline.saved(running_sec, baseline as u32),
+                Err(err) => error!("CCS811: Failed to read the baseline: {:?}", err),
+            }
+        }
+        vec![
+            Measurement {
+                name: "eco2".into(),
+                value: eco2 as f32,
+            },
+            Measurement {
+                name: "tvoc".into(),
+                value: tvoc as f32,
+            },
+        ]
+    }
+
+    fn memory(&self) -> Vec<(&'static str, u32)> {
+        self.baseline.memory()
+    }
+
+    fn warm_up(&self) -> Duration {
+        WARM_UP
+    }
+
+    fn ambient(&mut self, measurements: &[Measurement]) {
+        let value = |name: &str| measurements.iter().find(|measurement| measurement.name == name);
+        let (Some(temperature), Some(humidity)) = (value("temperature"), value("humidity")) else {
+            return;
+        };
+        // Both in 1/512 steps, the temperature offset by 25 °C
+        let humidity = (humidity.value.clamp(0.0, 100.0) * 512.0) as u16;
+        let temperature = ((temperature.value.clamp(-25.0, 100.0) + 25.0) * 512.0) as u16;
+        let [humidity_high, humidity_low] = humidity.to_be_bytes();
+        let [temperature_high, temperature_low] = temperature.to_be_bytes();
+        let data = [humidity_high, humidity_low, temperature_high, temperature_low];
+        if let Err(err) = self.device.write(REG_ENV_DATA, &data) {
+            error!("CCS811: Failed to set the environment: {:?}", err);
+        }
+    }
+
+    fn power_down(&mut self) {
+        if let Err(err) = self.device.write(REG_MEAS_MODE, &[MEAS_MODE_IDLE]) {
+            error!("CCS811: Failed to switch the heater off: {:?}", err);
+        }
+    }
+}
+
+impl<'a> I2cSensor<'a> for Ccs811Sensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
+        println!("Initializing CCS811 sensor");
+        let mut device = Ccs811 {
+            i2c: i2c_device,
+            address: config.ccs811.address,
+        };
+        let mut id = [0u8];
+        device.read(REG_HW_ID, &mut id)
+            .map_err(sensor_init("ccs811", "Failed to read ID - check I2C connection"))?;
+        if id[0] != HW_ID {
+            return Err(Error::sensor("ccs811", Phase::SensorInit, format!("Unexpected ID {:#04x}", id[0])));
+        }
+        let status = device.status()
+            .map_err(sensor_init("ccs811", "Failed to read the status"))?;
+        // Comes up in boot mode, the application has to be started before anything else works
+        if status & STATUS_FW_MODE == 0 {
+            if status & STATUS_APP_VALID == 0 {
+                return Err(Error::sensor("ccs811", Phase::SensorInit, "No valid application firmware"));
+            }
+            device.i2c.write(device.address, &[APP_START])
+                .map_err(sensor_init("ccs811", "Failed to start the application"))?;
+            thread::sleep(Duration::from_millis(2));
+        }
+        device.write(REG_MEAS_MODE, &[MEAS_MODE_1S])
+            .map_err(sensor_init("ccs811", "Failed to set the drive mode"))?;
+
+        let stored = config.sensor_memory.get("ccs811", "baseline");
+        Ok(Ccs811Sensor {
+            device,
+            started: Instant::now(),
+            baseline: BaselineKeeper::new(stored, RESTORE_AFTER_SEC, BURN_IN_SEC, SAVE_EVERY_SEC),
+        })
+    }
+}
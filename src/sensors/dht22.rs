@@ -0,0 +1,153 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::{Ets, TickType};
+use esp_idf_svc::hal::gpio::InputPin;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::rmt::config::ReceiveConfig;
+use esp_idf_svc::hal::rmt::{PinState, Pulse, Receive, RmtChannel, RxRmtDriver};
+use esp_idf_svc::sys::{esp, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD, gpio_set_direction, gpio_set_level};
+use log::{error, warn};
+
+use crate::dht22;
+use crate::error::{Context, Phase, Result};
+use crate::metrics::MetricSpec;
+
+use super::trait_def::{Measurement, Sensor};
+
+// 80 MHz APB clock divided down to 1 µs ticks
+const CLOCK_DIVIDER: u8 = 80;
+// Longer than any pulse in a frame, the line idling high ends the capture
+const IDLE_THRESHOLD_US: u16 = 200;
+const RING_BUFFER_SIZE: usize = 1024;
+// At least 1 ms low wakes the sensor up
+const START_SIGNAL_US: u32 = 1_100;
+// The frame takes under 5 ms
+const RECEIVE_TIMEOUT_MS: u64 = 50;
+// The sensor needs 2 s between reads, sooner ones return the previous frame or nothing
+const MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The data line, an open-drain GPIO captured by an RMT receive channel.
+pub struct Dht22Line {
+    rx: RxRmtDriver<'static>,
+    pin: i32,
+    last_read: Option<Instant>,
+    last: Option<(f32, f32)>,
+}
+
+pub type SharedLine = Arc<Mutex<Dht22Line>>;
+
+impl Dht22Line {
+    /// Sets up the channel once, the sensor is re-initialized with the same line.
+    pub fn open<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'static,
+        pin: impl Peripheral<P = impl InputPin> + 'static,
+        pin_number: i32,
+    ) -> Result<SharedLine> {
+        let context = "Failed to set up the DHT22 line";
+        let config = ReceiveConfig::new().clock_divider(CLOCK_DIVIDER).idle_threshold(IDLE_THRESHOLD_US);
+        let rx = RxRmtDriver::new(channel, pin, &config, RING_BUFFER_SIZE).context(Phase::SensorInit, context)?;
+        // The receiver only configures the input, the start signal is driven on the same pin. Open
+        // drain, the pull-up on the sensor board keeps it high when released.
+        esp!(unsafe { gpio_set_direction(pin_number, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD) })
+            .context(Phase::SensorInit, context)?;
+        esp!(unsafe { gpio_set_level(pin_number, 1) }).context(Phase::SensorInit, context)?;
+        Ok(Arc::new(Mutex::new(Dht22Line {
+            rx,
+            pin: pin_number,
+            last_read: None,
+            last: None,
+        })))
+    }
+
+    /// Temperature in °C and relative humidity in %.
+    fn read(&mut self) -> Result<Option<(f32, f32)>> {
+        if self.last_read.is_some_and(|last| last.elapsed() < MIN_INTERVAL) {
+            return Ok(self.last);
+        }
+        self.last_read = Some(Instant::now());
+        self.last = None;
+
+        let context = "Failed to read the DHT22";
+        esp!(unsafe { gpio_set_level(self.pin, 0) }).context(Phase::Measure, context)?;
+        Ets::delay_us(START_SIGNAL_US);
+        self.rx.start().context(Phase::Measure, context)?;
+        esp!(unsafe { gpio_set_level(self.pin, 1) }).context(Phase::Measure, context)?;
+
+        let mut pulses = [(Pulse::zero(), Pulse::zero()); 64];
+        let received = self.rx.receive(&mut pulses, TickType::new_millis(RECEIVE_TIMEOUT_MS).ticks());
+        self.rx.stop().context(Phase::Measure, context)?;
+        let count = match received.context(Phase::Measure, context)? {
+            Receive::Read(count) => count,
+            Receive::Overflow(_) | Receive::Timeout => {
+                warn!("DHT22: incomplete frame, check the wiring and the pull-up");
+                return Ok(None);
+            }
+        };
+
+        let high_us: Vec<u16> = pulses[..count]
+            .iter()
+            .flat_map(|(first, second)| [first, second])
+            .filter(|pulse| pulse.pin_state == PinState::High && pulse.ticks.ticks() > 0)
+            .map(|pulse| pulse.ticks.ticks())
+            .collect();
+        let Some(frame) = dht22::frame(&high_us) else {
+            warn!("DHT22: {} bits received, expected 40", high_us.len());
+            return Ok(None);
+        };
+        self.last = dht22::decode(frame);
+        if self.last.is_none() {
+            warn!("DHT22: checksum mismatch");
+        }
+        Ok(self.last)
+    }
+}
+
+/// DHT22/AM2302 temperature and humidity sensor on a single-wire GPIO. The bit timings are
+/// captured by an RMT channel, they're too short to bit-bang reliably next to the Wi-Fi stack.
+pub struct Dht22Sensor {
+    line: SharedLine,
+}
+
+impl Dht22Sensor {
+    pub fn new(line: SharedLine) -> Self {
+        Dht22Sensor { line }
+    }
+}
+
+const METRICS: [MetricSpec; 2] = [
+    MetricSpec::new("temperature", "°C", -40.0, 80.0),
+    MetricSpec::new("humidity", "%", 0.0, 100.0),
+];
+
+impl Sensor for Dht22Sensor {
+    fn name(&self) -> &'static str {
+        "dht22"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let reading = self.line.lock().expect("DHT22 line lock poisoned").read();
+        let (temperature, humidity) = match reading {
+            Ok(Some(reading)) => reading,
+            Ok(None) => return vec![],
+            Err(err) => {
+                error!("DHT22: {}", err);
+                return vec![];
+            }
+        };
+        vec![
+            Measurement {
+                name: "temperature".into(),
+                value: temperature,
+            },
+            Measurement {
+                name: "humidity".into(),
+                value: humidity,
+            },
+        ]
+    }
+}
@@ -0,0 +1,152 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+use log::{error, info};
+
+use crate::climate::relative_humidity_at;
+use crate::config::{Config, Hdc1080Config};
+use crate::error::{sensor_init, Error, Phase, Result};
+use crate::metrics::MetricSpec;
+
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+const ADDRESS: u8 = 0x40;
+
+const REG_TEMPERATURE: u8 = 0x00;
+const REG_CONFIGURATION: u8 = 0x02;
+const REG_MANUFACTURER_ID: u8 = 0xFE;
+const MANUFACTURER_ID: u16 = 0x5449;
+
+// Temperature and humidity in one go at 14 bit, the heater bit on top
+const CONFIGURATION_SEQUENCE: u16 = 0x1000;
+const CONFIGURATION_HEATER: u16 = 0x2000;
+// 6.35 ms for the temperature and 6.5 ms for the humidity at 14 bit
+const CONVERSION_TIME: Duration = Duration::from_millis(15);
+
+/// Register-level access to the HDC1080, it's simple enough not to need a driver crate.
+struct Hdc1080<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Hdc1080<I2C> {
+    fn manufacturer_id(&mut self) -> Result<u16, I2C::Error> {
+        let mut id = [0u8; 2];
+        self.i2c.write_read(ADDRESS, &[REG_MANUFACTURER_ID], &mut id)?;
+        Ok(u16::from_be_bytes(id))
+    }
+
+    fn configure(&mut self, heater: bool) -> Result<(), I2C::Error> {
+        let configuration = CONFIGURATION_SEQUENCE | if heater { CONFIGURATION_HEATER } else { 0 };
+        let [high, low] = configuration.to_be_bytes();
+        self.i2c.write(ADDRESS, &[REG_CONFIGURATION, high, low])
+    }
+
+    /// Temperature in °C and relative humidity in %.
+    fn read(&mut self) -> Result<(f32, f32), I2C::Error> {
+        // Setting the pointer starts the conversion, the result is read without one
+        self.i2c.write(ADDRESS, &[REG_TEMPERATURE])?;
+        thread::sleep(CONVERSION_TIME);
+        let mut data = [0u8; 4];
+        self.i2c.read(ADDRESS, &mut data)?;
+        let temperature = u16::from_be_bytes([data[0], data[1]]) as f32 / 65536.0 * 165.0 - 40.0;
+        let humidity = u16::from_be_bytes([data[2], data[3]]) as f32 / 65536.0 * 100.0;
+        Ok((temperature, humidity))
+    }
+
+    /// The heater only heats while conversions run, so it keeps converting for `duration`.
+    fn heat(&mut self, duration: Duration) -> Result<(), I2C::Error> {
+        self.configure(true)?;
+        let start = Instant::now();
+        let mut result = Ok(());
+        while start.elapsed() < duration && result.is_ok() {
+            result = self.read().map(|_| ());
+        }
+        // Off again whatever happened, a heater left on skews every reading after it
+        self.configure(false)?;
+        result
+    }
+}
+
+/// HDC1080 temperature and humidity sensor with the BME280's calibration handling. Near
+/// saturation, condensation on the die makes it stick at 100% long after the air has dried, so
+/// after a round at `hdc1080.heater_rh` or above the built-in heater runs for `hdc1080.heater_sec`
+/// to drive it off. The round's values are taken before heating, the next round may read a
+/// little warm and dry while the die cools down.
+pub struct Hdc1080Sensor<'a> {
+    device: Hdc1080<I2cDevice<'a>>,
+    config: Hdc1080Config,
+    temperature_offset: f32,
+}
+
+const METRICS: [MetricSpec; 2] = [
+    MetricSpec::new("temperature", "°C", -40.0, 125.0),
+    MetricSpec::new("humidity", "%", 0.0, 100.0),
+];
+
+impl Sensor for Hdc1080Sensor<'_> {
+    fn name(&self) -> &'static str {
+        "hdc1080"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let (temperature, humidity) = match self.device.read() {
+            Ok(reading) => reading,
+            Err(err) => {
+                error!("HDC1080: Failed to read: {:?}", err);
+                return vec![];
+            }
+        };
+        if self.config.heater_rh.is_some_and(|limit| humidity >= limit) {
+            info!("HDC1080: {:.1}% RH, heating for {} s", humidity, self.config.heater_sec);
+            if let Err(err) = self.device.heat(Duration::from_secs(self.config.heater_sec as u64)) {
+                error!("HDC1080: Failed to run the heater: {:?}", err);
+            }
+        }
+
+        let corrected = temperature + self.temperature_offset;
+        let humidity = if self.temperature_offset != 0.0 {
+            relative_humidity_at(humidity, temperature, corrected)
+        } else {
+            humidity
+        };
+        vec![
+            Measurement {
+                name: "temperature".into(),
+                value: corrected,
+            },
+            Measurement {
+                name: "humidity".into(),
+                value: humidity,
+            },
+        ]
+    }
+}
+
+impl<'a> I2cSensor<'a> for Hdc1080Sensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
+        println!("Initializing HDC1080 sensor");
+        let mut device = Hdc1080 { i2c: i2c_device };
+        let id = device.manufacturer_id()
+            .map_err(sensor_init("hdc1080", "Failed to read ID - check I2C connection"))?;
+        if id != MANUFACTURER_ID {
+            return Err(Error::sensor("hdc1080", Phase::SensorInit, format!("Unexpected ID {:#06x}", id)));
+        }
+        device.configure(false)
+            .map_err(sensor_init("hdc1080", "Failed to configure"))?;
+
+        let temperature_offset = config.calibration.offset("hdc1080", "temperature");
+        if temperature_offset != 0.0 {
+            println!("HDC1080 temperature offset: {:+.2} C", temperature_offset);
+        }
+        Ok(Hdc1080Sensor {
+            device,
+            config: config.hdc1080.clone(),
+            temperature_offset,
+        })
+    }
+}
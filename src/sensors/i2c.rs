@@ -0,0 +1,107 @@
+use std::sync::{Mutex, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, Operation};
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::i2c::I2cDriver;
+use esp_idf_svc::sys::{i2c_reset_rx_fifo, i2c_reset_tx_fifo, EspError, ESP_ERR_TIMEOUT};
+use log::warn;
+
+use crate::config::I2cPins;
+use crate::registry::SharedSensorStates;
+
+const BUS_POLL: Duration = Duration::from_millis(1);
+
+#[derive(Debug)]
+pub enum BusError {
+    /// The bus stayed busy, or the device held the clock low, for longer than the timeout
+    Timeout,
+    Driver(EspError),
+}
+
+impl embedded_hal::i2c::Error for BusError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// The I2C bus shared by the sensors, the display and the background samplers. Timeouts count
+/// towards the errors of the sensor that ran into them.
+pub struct I2cBus<'a> {
+    driver: Mutex<I2cDriver<'a>>,
+    config: I2cPins,
+    states: SharedSensorStates,
+}
+
+impl<'a> I2cBus<'a> {
+    pub fn new(driver: I2cDriver<'a>, config: &I2cPins, states: SharedSensorStates) -> Self {
+        I2cBus {
+            driver: Mutex::new(driver),
+            config: config.clone(),
+            states,
+        }
+    }
+
+    /// A handle for `name`, with its timeout from the configuration.
+    pub fn device(&'a self, name: &'static str) -> I2cDevice<'a> {
+        let timeout_ms = self.config.sensor_timeout_ms.get(name).copied().unwrap_or(self.config.timeout_ms);
+        I2cDevice {
+            bus: self,
+            name,
+            timeout: Duration::from_millis(timeout_ms.into()),
+        }
+    }
+}
+
+/// Handle on the shared I2C bus, it can be used from any thread. The driver's own timeout covers
+/// a device stretching the clock, waiting for the bus while another thread is stuck on it is
+/// bounded too, so a wedged device can't hold up a round.
+pub struct I2cDevice<'a> {
+    bus: &'a I2cBus<'a>,
+    name: &'static str,
+    timeout: Duration,
+}
+
+impl I2cDevice<'_> {
+    fn timed_out(&self) -> BusError {
+        warn!("{}: I2C transaction timed out after {} ms", self.name, self.timeout.as_millis());
+        if let Some(state) = self.bus.states.lock().expect("Sensor state lock poisoned").get_mut(self.name) {
+            state.errors += 1;
+        }
+        BusError::Timeout
+    }
+}
+
+impl ErrorType for I2cDevice<'_> {
+    type Error = BusError;
+}
+
+impl I2c for I2cDevice<'_> {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), BusError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut driver = loop {
+            match self.bus.driver.try_lock() {
+                Ok(driver) => break driver,
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(BUS_POLL),
+                Err(TryLockError::WouldBlock) => return Err(self.timed_out()),
+                Err(TryLockError::Poisoned(_)) => panic!("I2C bus lock poisoned"),
+            }
+        };
+        let ticks = TickType::new_millis(self.timeout.as_millis() as u64).ticks();
+        match driver.transaction(address, operations, ticks) {
+            Ok(()) => Ok(()),
+            Err(err) if err.code() == ESP_ERR_TIMEOUT as i32 => {
+                // The driver resets the controller and clears the bus, whatever is left in the
+                // FIFOs would be read by the next transaction
+                unsafe {
+                    i2c_reset_tx_fifo(driver.port());
+                    i2c_reset_rx_fifo(driver.port());
+                }
+                drop(driver);
+                Err(self.timed_out())
+            }
+            Err(err) => Err(BusError::Driver(err)),
+        }
+    }
+}
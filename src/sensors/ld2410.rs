@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::uart::UartDriver;
+use log::{error, info};
+
+use crate::config::Ld2410Config;
+use crate::error::{Context, Phase, Result};
+use crate::metrics::MetricSpec;
+use crate::radar::{self, BreathingEstimator, FrameParser, Report};
+
+use super::trait_def::{Measurement, Sensor};
+
+const STACK_SIZE: usize = 6 * 1024;
+const READ_TIMEOUT_MS: u64 = 100;
+
+#[derive(Default)]
+pub struct RadarState {
+    last: Option<Report>,
+    breathing_rate: Option<f32>,
+}
+
+pub type SharedRadar = Arc<Mutex<RadarState>>;
+
+/// Presence and breathing rate from the latest LD2410 report.
+pub struct Ld2410Sensor {
+    state: SharedRadar,
+}
+
+impl Ld2410Sensor {
+    /// Starts a thread parsing the report stream, the radar reports about ten times a second, far
+    /// more often than the sensors are sampled.
+    pub fn spawn_reader(uart: UartDriver<'static>, config: &Ld2410Config) -> Result<SharedRadar> {
+        let state = SharedRadar::default();
+        let shared = state.clone();
+        let engineering_mode = config.engineering_mode;
+        let window_sec = config.breathing_window_sec;
+        thread::Builder::new()
+            .name("ld2410".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || read(uart, shared, engineering_mode, window_sec))
+            .context(Phase::SensorInit, "Failed to start the LD2410 thread")?;
+        Ok(state)
+    }
+
+    pub fn new(state: SharedRadar) -> Self {
+        Ld2410Sensor { state }
+    }
+}
+
+const METRICS: [MetricSpec; 4] = [
+    MetricSpec::new("presence_moving", "", 0.0, 1.0),
+    MetricSpec::new("presence_stationary", "", 0.0, 1.0),
+    MetricSpec::new("target_distance", "m", 0.0, 6.0),
+    MetricSpec::new("breathing_rate", "/min", 0.0, 60.0),
+];
+
+impl Sensor for Ld2410Sensor {
+    fn name(&self) -> &'static str {
+        "ld2410"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let state = self.state.lock().expect("Radar state lock poisoned");
+        let Some(report) = &state.last else {
+            error!("LD2410: No reports received, check the UART wiring");
+            return vec![];
+        };
+        let flag = |value: bool| if value { 1.0 } else { 0.0 };
+        let mut measurements = vec![
+            Measurement {
+                name: "presence_moving".into(),
+                value: flag(report.moving),
+            },
+            Measurement {
+                name: "presence_stationary".into(),
+                value: flag(report.stationary),
+            },
+        ];
+        if report.stationary {
+            measurements.push(Measurement {
+                name: "target_distance".into(),
+                value: report.stationary_distance_cm as f32 / 100.0,
+            });
+        }
+        if let Some(rate) = state.breathing_rate {
+            measurements.push(Measurement {
+                name: "breathing_rate".into(),
+                value: rate,
+            });
+        }
+        measurements
+    }
+}
+
+fn read(uart: UartDriver<'static>, state: SharedRadar, engineering_mode: bool, window_sec: u32) {
+    if engineering_mode {
+        // Per-gate energies are only reported in engineering mode, the breathing estimate needs them
+        for frame in [radar::ENABLE_CONFIGURATION, radar::ENABLE_ENGINEERING_MODE, radar::END_CONFIGURATION] {
+            if let Err(err) = uart.write(&radar::command(frame)) {
+                error!("LD2410: Failed to send a command: {:?}", err);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        info!("LD2410: engineering mode enabled");
+    }
+    let started = Instant::now();
+    let mut parser = FrameParser::default();
+    let mut estimator = BreathingEstimator::new(window_sec);
+    let mut buf = [0u8; 64];
+    loop {
+        let read = match uart.read(&mut buf, TickType::new_millis(READ_TIMEOUT_MS).ticks()) {
+            Ok(read) => read,
+            Err(err) => {
+                error!("LD2410: Failed to read: {:?}", err);
+                thread::sleep(Duration::from_millis(READ_TIMEOUT_MS));
+                continue;
+            }
+        };
+        for byte in &buf[..read] {
+            let Some(report) = parser.push(*byte) else {
+                continue;
+            };
+            estimator.push(started.elapsed().as_millis() as u64, &report);
+            let mut state = state.lock().expect("Radar state lock poisoned");
+            state.breathing_rate = estimator.rate();
+            state.last = Some(report);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::i2c::I2c;
+use log::{error, info};
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+
+use crate::actigraphy::ActivityCounter;
+use crate::config::ActigraphyConfig;
+use crate::error::{sensor_init, Context, Error, Phase, Result};
+use crate::pipeline::{now_ms, SharedBuffer};
+
+use super::trait_def::{I2cDevice, Measurement};
+
+const STACK_SIZE: usize = 4 * 1024;
+
+const REG_WHO_AM_I: u8 = 0x0F;
+const REG_CTRL1: u8 = 0x20;
+const REG_CTRL4: u8 = 0x23;
+const REG_OUT_X_L: u8 = 0x28;
+const AUTO_INCREMENT: u8 = 0x80;
+const ID: u8 = 0x33;
+
+// 25 Hz with all axes on, block data update and high resolution at ±2 g, where a count is 1 mg
+const CTRL1_25HZ_XYZ: u8 = 0x37;
+const CTRL4_BDU_HR: u8 = 0x88;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(40);
+
+struct Lis3dh<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Lis3dh<I2C> {
+    fn id(&mut self) -> Result<u8, I2C::Error> {
+        let mut id = [0u8];
+        self.i2c.write_read(self.address, &[REG_WHO_AM_I], &mut id)?;
+        Ok(id[0])
+    }
+
+    fn configure(&mut self) -> Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[REG_CTRL1, CTRL1_25HZ_XYZ])?;
+        self.i2c.write(self.address, &[REG_CTRL4, CTRL4_BDU_HR])
+    }
+
+    /// Acceleration in mg.
+    fn read(&mut self) -> Result<[f32; 3], I2C::Error> {
+        let mut data = [0u8; 6];
+        self.i2c.write_read(self.address, &[REG_OUT_X_L | AUTO_INCREMENT], &mut data)?;
+        // 12 bit values, left aligned
+        Ok([0, 2, 4].map(|i| (i16::from_le_bytes([data[i], data[i + 1]]) >> 4) as f32))
+    }
+}
+
+/// Samples a LIS3DH on its own thread and collects `activity_count` epochs in a buffer of their
+/// own, which the uploader sends along with the environmental measurements. Needs the clock to
+/// be synced, the epochs carry their start time to the ms.
+pub fn spawn_actigraphy(i2c_device: I2cDevice<'static>, config: &ActigraphyConfig) -> Result<SharedBuffer> {
+    let mut device = Lis3dh {
+        i2c: i2c_device,
+        address: config.address,
+    };
+    let id = device.id()
+        .map_err(sensor_init("lis3dh", "Failed to read ID - check I2C connection"))?;
+    if id != ID {
+        return Err(Error::sensor("lis3dh", Phase::SensorInit, format!("Unexpected ID {:#04x}", id)));
+    }
+    device.configure()
+        .map_err(sensor_init("lis3dh", "Failed to configure"))?;
+
+    // A day of epochs
+    let capacity = (24 * 60 * 60 / config.epoch_sec.max(1)) as usize;
+    let epochs: SharedBuffer = Arc::new(Mutex::new(AllocRingBuffer::new(capacity)));
+    let buffer = epochs.clone();
+    let counter = ActivityCounter::new(config.epoch_sec, config.threshold_mg);
+    thread::Builder::new()
+        .name("actigraphy".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || sample(device, counter, buffer))
+        .context(Phase::SensorInit, "Failed to start the actigraphy thread")?;
+    info!("Actigraphy started, {} s epochs", config.epoch_sec);
+    Ok(epochs)
+}
+
+fn sample(mut device: Lis3dh<I2cDevice<'static>>, mut counter: ActivityCounter, epochs: SharedBuffer) {
+    loop {
+        thread::sleep(SAMPLE_INTERVAL);
+        let acceleration = match device.read() {
+            Ok(acceleration) => acceleration,
+            Err(err) => {
+                error!("LIS3DH: Failed to read: {:?}", err);
+                continue;
+            }
+        };
+        if let Some((start_ms, count)) = counter.push(now_ms(), acceleration) {
+            let epoch = Measurement {
+                name: "activity_count".into(),
+                value: count,
+            };
+            epochs
+                .lock()
+                .expect("Epoch buffer lock poisoned")
+                .push((start_ms, vec![epoch]).into());
+        }
+    }
+}
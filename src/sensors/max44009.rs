@@ -0,0 +1,99 @@
+use embedded_hal::i2c::{I2c, Operation};
+use log::{error, warn};
+
+use crate::config::Config;
+use crate::error::{sensor_init, Result};
+use crate::light::max44009_lux;
+use crate::metrics::MetricSpec;
+
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+const REG_CONFIGURATION: u8 = 0x02;
+const REG_LUX_HIGH: u8 = 0x03;
+const REG_LUX_LOW: u8 = 0x04;
+
+/// Register-level access to the MAX44009, it's a couple of registers.
+struct Max44009<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Max44009<I2C> {
+    /// Lux high and low registers. The chip doesn't auto-increment, both are read in one
+    /// transaction so they come from the same conversion.
+    fn read(&mut self) -> Result<(u8, u8), I2C::Error> {
+        let (mut high, mut low) = ([0u8], [0u8]);
+        self.i2c.transaction(
+            self.address,
+            &mut [
+                Operation::Write(&[REG_LUX_HIGH]),
+                Operation::Read(&mut high),
+                Operation::Write(&[REG_LUX_LOW]),
+                Operation::Read(&mut low),
+            ],
+        )?;
+        Ok((high[0], low[0]))
+    }
+
+    fn configuration(&mut self) -> Result<u8, I2C::Error> {
+        let mut configuration = [0u8];
+        self.i2c.write_read(self.address, &[REG_CONFIGURATION], &mut configuration)?;
+        Ok(configuration[0])
+    }
+}
+
+/// MAX44009 ambient light sensor, 0.045 to 188 000 lx in its default automatic mode, where it
+/// picks the integration time and range on its own. A simpler alternative to the TSL2591 for
+/// boards that only need `lux`.
+pub struct Max44009Sensor<'a> {
+    device: Max44009<I2cDevice<'a>>,
+}
+
+const METRICS: [MetricSpec; 1] = [
+    MetricSpec::new("lux", "lx", 0.0, 188_000.0),
+];
+
+impl Sensor for Max44009Sensor<'_> {
+    fn name(&self) -> &'static str {
+        "max44009"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let (high, low) = match self.device.read() {
+            Ok(registers) => registers,
+            Err(err) => {
+                error!("MAX44009: Failed to read: {:?}", err);
+                return vec![];
+            }
+        };
+        match max44009_lux(high, low) {
+            Some(lux) => vec![Measurement {
+                name: "lux".into(),
+                value: lux,
+            }],
+            None => {
+                warn!("MAX44009: over range");
+                vec![]
+            }
+        }
+    }
+}
+
+impl<'a> I2cSensor<'a> for Max44009Sensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
+        println!("Initializing MAX44009 light sensor");
+        let mut device = Max44009 {
+            i2c: i2c_device,
+            address: config.max44009.address,
+        };
+        // There's no ID register, the configuration one answering is all there is to check
+        let configuration = device.configuration()
+            .map_err(sensor_init("max44009", "Failed to read the configuration - check I2C connection"))?;
+        println!("MAX44009 configuration: {:#04x}", configuration);
+        Ok(Max44009Sensor { device })
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2s::config::{
+    Config as I2sConfig, DataBitWidth, SlotMode, StdClkConfig, StdConfig, StdGpioConfig, StdSlotConfig,
+};
+use esp_idf_svc::hal::i2s::{I2sDriver, I2sRx, I2S0};
+use log::error;
+
+use crate::config::SnoreConfig;
+use crate::error::{Context, Phase, Result};
+use crate::metrics::MetricSpec;
+use crate::snore::{Model, SnoreDetector, FRAME_LEN, SAMPLE_RATE_HZ};
+
+use super::trait_def::{Measurement, Sensor};
+
+// The FFT and the network run on this thread
+const STACK_SIZE: usize = 8 * 1024;
+const READ_TIMEOUT_MS: u64 = 100;
+
+#[derive(Default)]
+pub struct SnoreState {
+    events: u32,
+    frames: u64,
+}
+
+pub type SharedSnore = Arc<Mutex<SnoreState>>;
+
+/// Snore events counted by the capture thread since the last round.
+pub struct SnoreSensor {
+    state: SharedSnore,
+}
+
+impl SnoreSensor {
+    /// Starts a thread capturing and classifying the audio, the samples never leave it.
+    pub fn spawn_capture(
+        i2s: I2S0,
+        bclk: AnyIOPin,
+        ws: AnyIOPin,
+        din: AnyIOPin,
+        config: &SnoreConfig,
+    ) -> Result<SharedSnore> {
+        let model = Model::load(&config.model_path)?;
+        let i2s = I2sDriver::new_std_rx(i2s, &i2s_config(), bclk, din, Option::<AnyIOPin>::None, ws)
+            .context(Phase::SensorInit, "Failed to set up the I2S microphone")?;
+        let detector = SnoreDetector::new(model, config.threshold, config.min_event_ms, config.min_level_db);
+        let state = SharedSnore::default();
+        let shared = state.clone();
+        thread::Builder::new()
+            .name("snore".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || capture(i2s, detector, shared))
+            .context(Phase::SensorInit, "Failed to start the snore thread")?;
+        Ok(state)
+    }
+
+    pub fn new(state: SharedSnore) -> Self {
+        SnoreSensor { state }
+    }
+}
+
+/// Mono 32-bit slots, the INMP441 sends 24 bits on the left channel with L/R tied low.
+fn i2s_config() -> StdConfig {
+    StdConfig::new(
+        I2sConfig::default(),
+        StdClkConfig::from_sample_rate_hz(SAMPLE_RATE_HZ),
+        StdSlotConfig::philips_slot_default(DataBitWidth::Bits32, SlotMode::Mono),
+        StdGpioConfig::default(),
+    )
+}
+
+const METRICS: [MetricSpec; 1] = [MetricSpec::new("snore_events", "", 0.0, 10_000.0)];
+
+impl Sensor for SnoreSensor {
+    fn name(&self) -> &'static str {
+        "snore"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let mut state = self.state.lock().expect("Snore state lock poisoned");
+        if std::mem::take(&mut state.frames) == 0 {
+            error!("Snore: No audio captured, check the I2S wiring");
+            return vec![];
+        }
+        vec![Measurement {
+            name: "snore_events".into(),
+            value: std::mem::take(&mut state.events) as f32,
+        }]
+    }
+}
+
+fn capture(mut i2s: I2sDriver<'static, I2sRx>, mut detector: SnoreDetector, state: SharedSnore) {
+    if let Err(err) = i2s.rx_enable() {
+        error!("Snore: Failed to start the I2S capture: {:?}", err);
+        return;
+    }
+    let mut buf = [0u8; FRAME_LEN * 4];
+    let mut frame = Vec::with_capacity(FRAME_LEN);
+    loop {
+        let read = match i2s.read(&mut buf, TickType::new_millis(READ_TIMEOUT_MS).ticks()) {
+            Ok(read) => read,
+            Err(err) => {
+                error!("Snore: Failed to read: {:?}", err);
+                thread::sleep(Duration::from_millis(READ_TIMEOUT_MS));
+                continue;
+            }
+        };
+        // The top 16 of the 24 bits are plenty for the band energies
+        for sample in buf[..read].chunks_exact(4) {
+            frame.push((i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) >> 16) as i16);
+            if frame.len() < FRAME_LEN {
+                continue;
+            }
+            detector.push(&frame);
+            frame.clear();
+            let mut state = state.lock().expect("Snore state lock poisoned");
+            state.frames += 1;
+            state.events += detector.take_events();
+        }
+    }
+}
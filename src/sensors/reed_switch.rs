@@ -0,0 +1,124 @@
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::task::notification::Notification;
+use log::{error, info};
+
+use crate::config::ReedSwitchConfig;
+use crate::disturbances::{self, DisturbanceLog};
+use crate::error::{Context, Phase, Result};
+use crate::metrics::MetricSpec;
+use crate::pipeline::{now_ms, Alerts};
+
+use super::trait_def::{Measurement, Sensor};
+
+const STACK_SIZE: usize = 4 * 1024;
+
+/// Whether the window is open, kept up to date by the interrupt thread.
+pub type SharedContact = Arc<AtomicBool>;
+
+/// Door or window contact, a reed switch on a GPIO. Every change is a `window` disturbance (1
+/// opened, 0 closed) and an alert as it happens, and every round has the current `window_open`
+/// state, an open window explains most overnight CO2 and temperature swings.
+pub struct ReedSwitchSensor {
+    open: SharedContact,
+}
+
+impl ReedSwitchSensor {
+    pub fn new(open: SharedContact) -> Self {
+        ReedSwitchSensor { open }
+    }
+}
+
+const METRICS: [MetricSpec; 1] = [
+    MetricSpec::new("window_open", "", 0.0, 1.0),
+];
+
+impl Sensor for ReedSwitchSensor {
+    fn name(&self) -> &'static str {
+        "reed_switch"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        vec![Measurement {
+            name: "window_open".into(),
+            value: if self.open.load(Ordering::Relaxed) { 1.0 } else { 0.0 },
+        }]
+    }
+}
+
+/// Starts a thread waiting for the switch to change, each change is taken once the contact has
+/// settled for `reed_switch.debounce_ms`.
+pub fn spawn_reed_switch(config: &ReedSwitchConfig, log: DisturbanceLog, alerts: Alerts) -> Result<SharedContact> {
+    let context = "Failed to set up the reed switch pin";
+    let mut pin = PinDriver::input(unsafe { AnyIOPin::new(config.pin) }).context(Phase::SensorInit, context)?;
+    // The switch closes to ground
+    pin.set_pull(Pull::Up).context(Phase::SensorInit, context)?;
+    pin.set_interrupt_type(InterruptType::AnyEdge).context(Phase::SensorInit, context)?;
+
+    let open_when_high = config.open_when_high;
+    let open = Arc::new(AtomicBool::new(pin.is_high() == open_when_high));
+    let shared = open.clone();
+    let debounce = Duration::from_millis(config.debounce_ms as u64);
+    thread::Builder::new()
+        .name("reed_switch".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || wait(pin, open_when_high, debounce, shared, log, alerts))
+        .context(Phase::SensorInit, "Failed to start the reed switch thread")?;
+    info!("Reed switch on GPIO{}, window {}", config.pin, if open.load(Ordering::Relaxed) { "open" } else { "closed" });
+    Ok(open)
+}
+
+fn wait(
+    mut pin: PinDriver<'static, AnyIOPin, Input>,
+    open_when_high: bool,
+    debounce: Duration,
+    open: SharedContact,
+    log: DisturbanceLog,
+    alerts: Alerts,
+) {
+    let notification = Notification::new();
+    let notifier = notification.notifier();
+    let subscribed = unsafe {
+        pin.subscribe(move || {
+            notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+        })
+    };
+    if let Err(err) = subscribed {
+        error!("Reed switch: Failed to subscribe to the interrupt: {:?}", err);
+        return;
+    }
+    if let Err(err) = pin.enable_interrupt() {
+        error!("Reed switch: Failed to enable the interrupt: {:?}", err);
+        return;
+    }
+    loop {
+        notification.wait(BLOCK);
+        let timestamp_ms = now_ms();
+        // The interrupt stays off until it's enabled again, the bounces in between are ignored.
+        // Enabled before reading the level, so a change right after it isn't missed.
+        thread::sleep(debounce);
+        if let Err(err) = pin.enable_interrupt() {
+            error!("Reed switch: Failed to enable the interrupt: {:?}", err);
+            return;
+        }
+        let now_open = pin.is_high() == open_when_high;
+        if open.swap(now_open, Ordering::Relaxed) != now_open {
+            let value = if now_open { 1.0 } else { 0.0 };
+            disturbances::record(&log, "window", timestamp_ms, value);
+            alerts.raise(vec![Measurement {
+                name: "window_open".into(),
+                value,
+            }]);
+        }
+    }
+}
@@ -1,65 +1,201 @@
 use std::time::Duration;
-use embedded_hal_bus::i2c::RcDevice;
 use esp_idf_svc::hal::delay::Delay;
-use esp_idf_svc::hal::i2c::I2cDriver;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use scd4x::Scd4x;
 
-use super::trait_def::{Measurement, Sensor};
+use crate::config::{Config, Scd4xMode};
+use crate::error::{sensor_init, Result};
+use crate::metrics::MetricSpec;
 
-impl<'a> Sensor<'a> for Scd4x<RcDevice<I2cDriver<'a>>, Delay> {
-    fn measure(&mut self) -> Vec<Measurement> {
-        self.wake_up();
-        self.wake_up(); // For some reason if you just do the one wakeup it doesn't work, need to check it with an LA or scope
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+// Wake-up and the discarded 5 s shot, done while the sampler waits for the round
+const WAKE_LEAD: Duration = Duration::from_secs(6);
+const SINGLE_SHOT_TIME: Duration = Duration::from_millis(5_000);
+
+/// SCD4x CO2 sensor, either woken up ahead of every round for a single shot or left running in
+/// low-power periodic mode, which measures every 30 s on its own.
+pub struct Scd4xSensor<'a> {
+    sensor: Scd4x<I2cDevice<'a>, Delay>,
+    mode: Scd4xMode,
+    // Woken up ahead of the round, the first shot is already discarded
+    awake: bool,
+    // The round's shot was started along with the other sensors
+    shot_started: bool,
+}
+
+impl Scd4xSensor<'_> {
+    fn power_up(&mut self) {
+        self.sensor.wake_up();
+        self.sensor.wake_up(); // For some reason if you just do the one wakeup it doesn't work, need to check it with an LA or scope
         std::thread::sleep(Duration::from_millis(200)); // according to spec should not take more than 20msec, since wake_up doesn't get an ACK, so we are waiting 10x
 
-        let _ = self.measure_single_shot(); // Discarding the first reading after waking up, according to the spec
-        let result = self.measure_single_shot();
-        let measurements: Vec<Measurement> = match result {
-            Ok(_) => match self.measurement() {
-                Ok(measurement) => {
-                    info!(
-                        "CO2: {:?}, Humidity: {} RH, Temperature: {} C",
-                        measurement.co2, measurement.humidity, measurement.temperature
-                    );
-                    vec![
-                        Measurement {
-                            name: "co2".to_string(),
-                            value: measurement.co2 as f32,
-                        },
-                        Measurement {
-                            name: "humidity".to_string(),
-                            value: measurement.humidity,
-                        },
-                        Measurement {
-                            name: "temperature".to_string(),
-                            value: measurement.temperature,
-                        },
-                    ]
-                }
-                Err(error) => {
-                    error!("Error trying to measure co2: {:?}", error);
-                    vec![]
-                }
-            },
-            Err(_) => vec![],
+        // Discarding the first reading after waking up, according to the spec
+        if let Err(error) = self.sensor.measure_single_shot() {
+            debug!("SCD4x: first reading after wake-up failed: {:?}", error);
+        }
+        self.awake = true;
+    }
+
+    fn measure_single_shot(&mut self) -> Vec<Measurement> {
+        let shot = if std::mem::take(&mut self.shot_started) {
+            Ok(())
+        } else {
+            if !self.awake {
+                self.power_up();
+            }
+            self.sensor.measure_single_shot()
+        };
+        let measurements = match shot {
+            Ok(_) => self.read(),
+            Err(error) => {
+                error!("Error trying to trigger a co2 measurement: {:?}", error);
+                vec![]
+            }
         };
-        let _ = self.power_down();
+        if let Err(error) = self.sensor.power_down() {
+            warn!("SCD4x: failed to power down: {:?}", error);
+        }
+        self.awake = false;
         measurements
     }
 
-    fn get_sensor(i2c_device: RcDevice<I2cDriver<'a>>) -> Self {
+    fn measure_periodic(&mut self) -> Vec<Measurement> {
+        match self.sensor.data_ready_status() {
+            Ok(true) => self.read(),
+            // Sampling faster than the sensor's 30 s cadence, nothing new yet
+            Ok(false) => {
+                debug!("SCD4x: no new data");
+                vec![]
+            }
+            Err(error) => {
+                error!("Error trying to read the co2 data ready status: {:?}", error);
+                vec![]
+            }
+        }
+    }
+
+    fn read(&mut self) -> Vec<Measurement> {
+        match self.sensor.measurement() {
+            Ok(measurement) => {
+                info!(
+                    "CO2: {:?}, Humidity: {} RH, Temperature: {} C",
+                    measurement.co2, measurement.humidity, measurement.temperature
+                );
+                vec![
+                    Measurement {
+                        name: "co2".into(),
+                        value: measurement.co2 as f32,
+                    },
+                    Measurement {
+                        name: "humidity".into(),
+                        value: measurement.humidity,
+                    },
+                    Measurement {
+                        name: "temperature".into(),
+                        value: measurement.temperature,
+                    },
+                ]
+            }
+            Err(error) => {
+                error!("Error trying to measure co2: {:?}", error);
+                vec![]
+            }
+        }
+    }
+}
+
+const METRICS: [MetricSpec; 3] = [
+    MetricSpec::new("co2", "ppm", 0.0, 40_000.0),
+    MetricSpec::new("humidity", "%", 0.0, 100.0),
+    MetricSpec::new("temperature", "°C", -10.0, 60.0),
+];
+
+impl Sensor for Scd4xSensor<'_> {
+    fn name(&self) -> &'static str {
+        "scd4x"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        match self.mode {
+            Scd4xMode::SingleShot => self.measure_single_shot(),
+            Scd4xMode::LowPowerPeriodic => self.measure_periodic(),
+        }
+    }
+
+    fn start(&mut self) -> Duration {
+        if self.mode != Scd4xMode::SingleShot {
+            return Duration::ZERO;
+        }
+        if !self.awake {
+            self.power_up();
+        }
+        match self.sensor.measure_single_shot_non_blocking() {
+            Ok(()) => {
+                self.shot_started = true;
+                SINGLE_SHOT_TIME
+            }
+            Err(error) => {
+                error!("Error trying to trigger a co2 measurement: {:?}", error);
+                Duration::ZERO
+            }
+        }
+    }
+
+    fn wake_lead(&self) -> Duration {
+        match self.mode {
+            Scd4xMode::SingleShot => WAKE_LEAD,
+            Scd4xMode::LowPowerPeriodic => Duration::ZERO,
+        }
+    }
+
+    fn wake(&mut self) {
+        self.power_up();
+    }
+
+    fn power_down(&mut self) {
+        if self.mode == Scd4xMode::LowPowerPeriodic {
+            if let Err(error) = self.sensor.stop_periodic_measurement() {
+                warn!("SCD4x: failed to stop periodic measurement: {:?}", error);
+            }
+        }
+        if let Err(error) = self.sensor.power_down() {
+            warn!("SCD4x: failed to power down: {:?}", error);
+        }
+    }
+}
+
+impl<'a> I2cSensor<'a> for Scd4xSensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
         println!("Setting up SCD4x sensor");
         let mut sensor = Scd4x::new(i2c_device, Delay::new_default());
         println!("Stopping periodic measurement in SCD4x sensor");
-        _ = sensor.stop_periodic_measurement();
+        if let Err(error) = sensor.stop_periodic_measurement() {
+            debug!("SCD4x: stopping periodic measurement failed: {:?}", error);
+        }
         println!("Re-initializing SCD4x sensor");
         sensor.reinit()
-            .expect("Failed to reinitialize SCD4x sensor - check I2C connection");
+            .map_err(sensor_init("scd4x", "Failed to reinitialize - check I2C connection"))?;
 
         let serial = sensor.serial_number()
-            .expect("Failed to read SCD4x serial number");
+            .map_err(sensor_init("scd4x", "Failed to read serial number"))?;
         println!("SCD4x serial: {:#04x}", serial);
-        sensor
+
+        let mode = config.scd4x.mode;
+        if mode == Scd4xMode::LowPowerPeriodic {
+            println!("Starting low-power periodic measurement in SCD4x sensor");
+            sensor.start_low_power_periodic_measurement()
+                .map_err(sensor_init("scd4x", "Failed to start low-power periodic measurement"))?;
+        }
+        Ok(Scd4xSensor {
+            sensor,
+            mode,
+            awake: false,
+            shot_started: false,
+        })
     }
 }
@@ -0,0 +1,232 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+use log::{error, info};
+
+use crate::baseline::BaselineKeeper;
+use crate::climate::absolute_humidity;
+use crate::config::Config;
+use crate::error::{sensor_init, Context, Error, Phase, Result};
+use crate::metrics::MetricSpec;
+
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+const ADDRESS: u8 = 0x58;
+const STACK_SIZE: usize = 4 * 1024;
+
+const IAQ_INIT: u16 = 0x2003;
+const MEASURE_IAQ: u16 = 0x2008;
+const GET_IAQ_BASELINE: u16 = 0x2015;
+const SET_IAQ_BASELINE: u16 = 0x201E;
+const SET_ABSOLUTE_HUMIDITY: u16 = 0x2061;
+const GET_FEATURE_SET: u16 = 0x202F;
+// Upper nibble of the feature set, 0 for the SGP30
+const PRODUCT_TYPE: u16 = 0x0000;
+
+// The on-chip baseline compensation expects a measurement every second
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+const MEASURE_TIME: Duration = Duration::from_millis(12);
+// Fixed at 400 ppm and 0 ppb while the sensor initializes
+const INIT_TIME: Duration = Duration::from_secs(15);
+// From the datasheet: a stored baseline is restored right after the init, without one the first
+// is worth keeping after 12 hours
+const BURN_IN_SEC: u64 = 12 * 60 * 60;
+const SAVE_EVERY_SEC: u64 = 60 * 60;
+
+/// Register-level access to the SGP30, with Sensirion's CRC on every word.
+struct Sgp30<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Sgp30<I2C> {
+    fn command(&mut self, command: u16, words: &[u16]) -> Result<(), I2C::Error> {
+        let mut buffer = [0u8; 8];
+        buffer[..2].copy_from_slice(&command.to_be_bytes());
+        for (i, word) in words.iter().enumerate() {
+            let bytes = word.to_be_bytes();
+            buffer[2 + 3 * i..4 + 3 * i].copy_from_slice(&bytes);
+            buffer[4 + 3 * i] = crc8(&bytes);
+        }
+        self.i2c.write(ADDRESS, &buffer[..2 + 3 * words.len()])
+    }
+
+    /// Two words, `None` if a CRC doesn't match.
+    fn read_words(&mut self, command: u16, delay: Duration) -> Result<Option<[u16; 2]>, I2C::Error> {
+        self.command(command, &[])?;
+        thread::sleep(delay);
+        let mut data = [0u8; 6];
+        self.i2c.read(ADDRESS, &mut data)?;
+        if crc8(&data[0..2]) != data[2] || crc8(&data[3..5]) != data[5] {
+            return Ok(None);
+        }
+        Ok(Some([u16::from_be_bytes([data[0], data[1]]), u16::from_be_bytes([data[3], data[4]])]))
+    }
+
+    fn feature_set(&mut self) -> Result<Option<u16>, I2C::Error> {
+        self.command(GET_FEATURE_SET, &[])?;
+        thread::sleep(Duration::from_millis(10));
+        let mut data = [0u8; 3];
+        self.i2c.read(ADDRESS, &mut data)?;
+        Ok(Some(u16::from_be_bytes([data[0], data[1]])).filter(|_| crc8(&data[0..2]) == data[2]))
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0xFF, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 })
+    })
+}
+
+struct State {
+    // eCO2 in ppm and TVOC in ppb
+    last: Option<(u16, u16)>,
+    baseline: BaselineKeeper,
+    // In 8.8 fixed point g/m³, set on the sensor by the measuring thread
+    absolute_humidity: Option<u16>,
+}
+
+/// SGP30 metal-oxide gas sensor. Its baseline compensation needs a measurement every second, so
+/// it's measured on its own thread and the rounds report the latest `eco2` (ppm, an estimate,
+/// not the NDIR `co2`) and `tvoc` (ppb). The absolute humidity from the sensors before it in
+/// the round is passed on. The baseline is kept in the sensor memory, read out hourly and
+/// restored after a reboot, without it every reboot starts the learning over.
+pub struct Sgp30Sensor {
+    state: Arc<Mutex<State>>,
+}
+
+const METRICS: [MetricSpec; 2] = [
+    MetricSpec::new("eco2", "ppm", 400.0, 60_000.0),
+    MetricSpec::new("tvoc", "ppb", 0.0, 60_000.0),
+];
+
+impl Sensor for Sgp30Sensor {
+    fn name(&self) -> &'static str {
+        "sgp30"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let state = self.state.lock().expect("SGP30 state lock poisoned");
+        let Some((eco2, tvoc)) = state.last else {
+            info!("SGP30: no reading yet");
+            return vec![];
+        };
+        vec![
+            Measurement {
+                name: "eco2".into(),
+                value: eco2 as f32,
+            },
+            Measurement {
+                name: "tvoc".into(),
+                value: tvoc as f32,
+            },
+        ]
+    }
+
+    fn memory(&self) -> Vec<(&'static str, u32)> {
+        self.state.lock().expect("SGP30 state lock poisoned").baseline.memory()
+    }
+
+    fn warm_up(&self) -> Duration {
+        INIT_TIME
+    }
+
+    fn ambient(&mut self, measurements: &[Measurement]) {
+        let value = |name: &str| measurements.iter().find(|measurement| measurement.name == name);
+        let (Some(temperature), Some(humidity)) = (value("temperature"), value("humidity")) else {
+            return;
+        };
+        let grams = absolute_humidity(temperature.value, humidity.value);
+        // 0 switches the compensation off, the smallest value is 1/256 g/m³
+        let fixed = (grams.clamp(0.0, 255.0) * 256.0).max(1.0) as u16;
+        self.state.lock().expect("SGP30 state lock poisoned").absolute_humidity = Some(fixed);
+    }
+}
+
+impl I2cSensor<'static> for Sgp30Sensor {
+    fn get_sensor(i2c_device: I2cDevice<'static>, config: &Config) -> Result<Self> {
+        println!("Initializing SGP30 sensor");
+        let mut device = Sgp30 { i2c: i2c_device };
+        let feature_set = device.feature_set()
+            .map_err(sensor_init("sgp30", "Failed to read the feature set - check I2C connection"))?;
+        match feature_set {
+            Some(features) if features & 0xF000 == PRODUCT_TYPE => {}
+            other => {
+                return Err(Error::sensor("sgp30", Phase::SensorInit, format!("Unexpected feature set {:?}", other)));
+            }
+        }
+        device.command(IAQ_INIT, &[])
+            .map_err(sensor_init("sgp30", "Failed to start the IAQ algorithm"))?;
+        thread::sleep(Duration::from_millis(10));
+
+        let stored = config.sensor_memory.get("sgp30", "baseline");
+        let mut baseline = BaselineKeeper::new(stored, 0, BURN_IN_SEC, SAVE_EVERY_SEC);
+        if let Some(stored) = baseline.restore(0) {
+            // The TVOC baseline goes first
+            let (eco2, tvoc) = ((stored >> 16) as u16, stored as u16);
+            device.command(SET_IAQ_BASELINE, &[tvoc, eco2])
+                .map_err(sensor_init("sgp30", "Failed to restore the baseline"))?;
+            info!("SGP30: baseline {:#010x} restored", stored);
+        }
+        let state = Arc::new(Mutex::new(State {
+            last: None,
+            baseline,
+            absolute_humidity: None,
+        }));
+        let shared = Arc::downgrade(&state);
+        thread::Builder::new()
+            .name("sgp30".to_string())
+            .stack_size(STACK_SIZE)
+            .spawn(move || sample(device, shared))
+            .context(Phase::SensorInit, "Failed to start the SGP30 thread")?;
+        Ok(Sgp30Sensor { state })
+    }
+}
+
+/// Measures every second until the sensor is dropped, e.g. when it's re-initialized.
+fn sample(mut device: Sgp30<I2cDevice<'static>>, state: Weak<Mutex<State>>) {
+    let started = Instant::now();
+    let mut humidity_set = None;
+    loop {
+        thread::sleep(MEASURE_INTERVAL);
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let absolute_humidity = state.lock().expect("SGP30 state lock poisoned").absolute_humidity;
+        if absolute_humidity != humidity_set {
+            if let Some(value) = absolute_humidity {
+                match device.command(SET_ABSOLUTE_HUMIDITY, &[value]) {
+                    Ok(()) => humidity_set = absolute_humidity,
+                    Err(err) => error!("SGP30: Failed to set the humidity: {:?}", err),
+                }
+            }
+        }
+
+        let reading = match device.read_words(MEASURE_IAQ, MEASURE_TIME) {
+            Ok(Some([eco2, tvoc])) => Some((eco2, tvoc)),
+            Ok(None) => {
+                error!("SGP30: CRC mismatch");
+                None
+            }
+            Err(err) => {
+                error!("SGP30: Failed to measure: {:?}", err);
+                None
+            }
+        };
+        let running_sec = started.elapsed().as_secs();
+        let mut state = state.lock().expect("SGP30 state lock poisoned");
+        state.last = reading;
+        if state.baseline.save_due(running_sec) {
+            match device.read_words(GET_IAQ_BASELINE, Duration::from_millis(10)) {
+                Ok(Some([eco2, tvoc])) => state.baseline.saved(running_sec, (eco2 as u32) << 16 | tvoc as u32),
+                Ok(None) => error!("SGP30: CRC mismatch in the baseline"),
+                Err(err) => error!("SGP30: Failed to read the baseline: {:?}", err),
+            }
+        }
+    }
+}
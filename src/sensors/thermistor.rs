@@ -0,0 +1,67 @@
+use log::{error, warn};
+
+use crate::analog::{divider_resistance, thermistor_temperature};
+use crate::config::{Config, ThermistorConfig};
+use crate::error::Result;
+use crate::metrics::MetricSpec;
+
+use super::adc::AdcInput;
+use super::trait_def::{Measurement, Sensor};
+
+/// NTC thermistors on ADC pins, e.g. one taped to the mattress surface. Each is its own
+/// `<name>.temperature` channel, apart from the room `temperature` the derived metrics use.
+pub struct ThermistorSensor {
+    inputs: Vec<(ThermistorConfig, AdcInput)>,
+}
+
+impl ThermistorSensor {
+    pub fn new(config: &Config) -> Result<Self> {
+        println!("Initializing {} thermistor(s)", config.thermistors.len());
+        let inputs = config
+            .thermistors
+            .iter()
+            .map(|thermistor| Ok((thermistor.clone(), AdcInput::new(thermistor.pin)?)))
+            .collect::<Result<_>>()?;
+        Ok(ThermistorSensor { inputs })
+    }
+}
+
+impl Sensor for ThermistorSensor {
+    fn name(&self) -> &'static str {
+        "thermistor"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        self.inputs
+            .iter()
+            .map(|(config, _)| MetricSpec {
+                name: format!("{}.temperature", config.name).into(),
+                unit: "°C",
+                min: -55.0,
+                max: 150.0,
+            })
+            .collect()
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let mut measurements = Vec::new();
+        for (config, input) in &mut self.inputs {
+            let mv = match input.read_mv() {
+                Ok(mv) => mv,
+                Err(err) => {
+                    error!("Thermistor {}: {}", config.name, err);
+                    continue;
+                }
+            };
+            let Some(ohm) = divider_resistance(mv, config.supply_mv, config.series_ohm, config.to_ground) else {
+                warn!("Thermistor {}: {:.0} mV, disconnected or shorted", config.name, mv);
+                continue;
+            };
+            measurements.push(Measurement {
+                name: format!("{}.temperature", config.name).into(),
+                value: thermistor_temperature(ohm, &config.model),
+            });
+        }
+        measurements
+    }
+}
@@ -1,15 +1,88 @@
-use embedded_hal_bus::i2c::RcDevice;
-use esp_idf_svc::hal::i2c::I2cDriver;
+use std::borrow::Cow;
+use std::time::Duration;
 
-#[derive(Debug)]
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::spi::{SpiDeviceDriver, SpiDriver};
+
+use crate::metrics::MetricSpec;
+#[cfg(target_os = "espidf")]
+use crate::config::Config;
+#[cfg(target_os = "espidf")]
+use crate::error::Result;
+
+#[cfg(target_os = "espidf")]
+pub use super::i2c::I2cDevice;
+
+#[derive(Debug, Clone)]
 pub struct Measurement {
-    pub name: String,
+    /// Borrowed for the fixed metric names, so a round doesn't allocate a string per value
+    pub name: Cow<'static, str>,
     pub value: f32,
 }
 
-pub trait Sensor<'a> {
+pub trait Sensor {
+    /// Short identifier used in logs and reports, e.g. `bme280`.
+    fn name(&self) -> &'static str;
     fn measure(&mut self) -> Vec<Measurement>;
-    fn get_sensor(i2c_device: RcDevice<I2cDriver<'a>>) -> Self
+
+    /// The metrics it reports with their unit and range, collected by the registry once the
+    /// sensor is set up. Values outside the range are dropped before they're buffered.
+    fn metrics(&self) -> Vec<MetricSpec> {
+        Vec::new()
+    }
+
+    /// Starts a conversion for [`Sensor::measure`] to collect and returns how long it takes. The
+    /// registry starts every sensor before measuring the first, so their waits overlap and a round
+    /// takes about as long as its slowest sensor. `measure` still works without it, it then waits
+    /// for the conversion itself.
+    fn start(&mut self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Runtime state worth keeping across a reboot as `(field, value)`, it's handed back through
+    /// `Config::sensor_memory` when the sensor is set up again.
+    fn memory(&self) -> Vec<(&'static str, u32)> {
+        Vec::new()
+    }
+
+    /// Gets what the sensors before it measured this round ahead of its own measurement, for gas
+    /// sensors compensating for temperature and humidity.
+    fn ambient(&mut self, _measurements: &[Measurement]) {}
+
+    /// How long after the setup the readings aren't valid yet, e.g. while a heater or a fan
+    /// settles. The sensor is still measured every round, so it keeps running, and the registry
+    /// discards what it reports.
+    fn warm_up(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// How long ahead of a round the sensor wants [`Sensor::wake`] called, for sensors powered down
+    /// between rounds that take a while to come up.
+    fn wake_lead(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn wake(&mut self) {}
+
+    /// Called once before the node shuts down, for sensors that keep a heater or a measurement
+    /// running on their own.
+    fn power_down(&mut self) {}
+}
+
+#[cfg(target_os = "espidf")]
+pub trait I2cSensor<'a>: Sensor {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self>
+    where
+        Self: Sized;
+}
+
+/// Device on the shared SPI bus with its own chip select, for sensors in their SPI variant.
+#[cfg(target_os = "espidf")]
+pub type SpiDevice<'a> = SpiDeviceDriver<'a, &'a SpiDriver<'a>>;
+
+#[cfg(target_os = "espidf")]
+pub trait SpiSensor<'a>: Sensor {
+    fn get_sensor(spi_device: SpiDevice<'a>, config: &Config) -> Result<Self>
     where
         Self: Sized;
 }
@@ -1,15 +1,137 @@
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
-use embedded_hal_bus::i2c::RcDevice;
-use esp_idf_svc::hal::i2c::I2cDriver;
+
+use embedded_hal::i2c::I2c;
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::task::notification::Notification;
 use log::{error, info, warn};
 use tsl2591_eh_driver;
 
-use super::trait_def::{Measurement, Sensor};
+use crate::config::{Config, Tsl2591Config};
+use crate::disturbances::{self, DisturbanceLog};
+use crate::error::{sensor_init, Context, Phase, Result};
+use crate::light;
+use crate::metrics::MetricSpec;
+use crate::pipeline::now_ms;
+
+use super::trait_def::{I2cDevice, I2cSensor, Measurement, Sensor};
+
+const ADDRESS: u8 = 0x29;
+const COMMAND: u8 = 0xA0;
+const REG_ENABLE: u8 = 0x00;
+const REG_AILTL: u8 = 0x04;
+const REG_PERSIST: u8 = 0x0C;
+const REG_C0DATAL: u8 = 0x14;
+// Special function clearing the ALS interrupt
+const CLEAR_INTERRUPT: u8 = 0xE7;
+const ENABLE_PON_AEN_AIEN: u8 = 0x13;
+// Three integration periods out of the band before INT is asserted, skips flicker
+const PERSIST_3: u8 = 0x03;
+const INTEGRATION_MS: f32 = 100.0;
+// One integration and a little margin, the status is polled after it anyway
+const INTEGRATION_TIME: Duration = Duration::from_millis(120);
+// In the dark a couple of counts of noise would be a big change
+const MIN_BAND_COUNTS: u16 = 10;
+const STACK_SIZE: usize = 4 * 1024;
+
+/// TSL2591 that remembers the gain of its last usable reading, across reboots as well. Light
+/// levels rarely jump between rounds, so starting the search there usually needs a single
+/// integration period, while always starting at MED costs up to three at night.
+pub struct Tsl2591Sensor<'a> {
+    sensor: tsl2591_eh_driver::Driver<I2cDevice<'a>>,
+    gain: tsl2591_eh_driver::Gain,
+    raw_channels: bool,
+    watch: Option<LightWatch>,
+    // Started integrating ahead of the measurement
+    integrating: bool,
+}
+
+impl Tsl2591Sensor<'_> {
+    /// Keeps the sensor integrating between rounds with the ALS interrupt armed around the last
+    /// reading, see [`spawn_light_events`].
+    pub fn with_watch(mut self, watch: LightWatch) -> Self {
+        self.watch = Some(watch);
+        self
+    }
+
+    /// Starts integrating at `gain`, false if that failed.
+    fn begin(&mut self, gain: tsl2591_eh_driver::Gain) -> bool {
+        if let Err(e) = self.sensor.set_gain(gain) {
+            error!("TSL2591: Failed to set gain: {:?}", e);
+            return false;
+        }
+        if let Err(e) = self.sensor.set_timing(tsl2591_eh_driver::IntegrationTimes::_100MS) {
+            error!("TSL2591: Failed to set timing: {:?}", e);
+            return false;
+        }
+        if let Err(e) = self.sensor.enable() {
+            error!("TSL2591: Failed to enable sensor: {:?}", e);
+            return false;
+        }
+        true
+    }
+
+    fn finish(&mut self, lux: f32, ch0: u16, ch1: u16, gain: tsl2591_eh_driver::Gain) -> Vec<Measurement> {
+        self.gain = gain;
+        if let Some(watch) = &self.watch {
+            watch.arm(gain_factor(gain), ch0);
+        }
+        self.readings(lux, ch0, ch1, gain)
+    }
+
+    fn readings(&self, lux: f32, ch0: u16, ch1: u16, gain: tsl2591_eh_driver::Gain) -> Vec<Measurement> {
+        let mut measurements = vec![Measurement {
+            name: "lux".into(),
+            value: lux,
+        }];
+        if !self.raw_channels {
+            return measurements;
+        }
+        // Scaled to low gain so the values stay comparable when the gain changes
+        let scale = gain_factor(gain);
+        let (full, ir) = (ch0 as f32 / scale, ch1 as f32 / scale);
+        measurements.push(Measurement {
+            name: "light_full".into(),
+            value: full,
+        });
+        measurements.push(Measurement {
+            name: "light_ir".into(),
+            value: ir,
+        });
+        // Daylight and incandescent light carry a lot more IR than LEDs
+        if ch1 > 0 {
+            measurements.push(Measurement {
+                name: "light_visible_ir_ratio".into(),
+                value: (full - ir).max(0.0) / ir,
+            });
+        }
+        measurements
+    }
+}
+
+const METRICS: [MetricSpec; 1] = [
+    MetricSpec::new("lux", "lx", 0.0, 88_000.0),
+];
+
+impl Sensor for Tsl2591Sensor<'_> {
+    fn name(&self) -> &'static str {
+        "tsl2591"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        METRICS.to_vec()
+    }
 
-impl<'a> Sensor<'a> for tsl2591_eh_driver::Driver<RcDevice<I2cDriver<'a>>> {
     fn measure(&mut self) -> Vec<Measurement> {
-        let mut current_gain = tsl2591_eh_driver::Gain::MED;
-        let current_scan = tsl2591_eh_driver::IntegrationTimes::_100MS;
+        // The gain search below would trip the interrupt
+        if let Some(watch) = &self.watch {
+            watch.pause();
+        }
+        let mut current_gain = self.gain;
+        let integrating = std::mem::take(&mut self.integrating);
         let max_iterations = 10; // Prevent infinite loop
         let mut iteration = 0;
 
@@ -20,23 +142,14 @@ impl<'a> Sensor<'a> for tsl2591_eh_driver::Driver<RcDevice<I2cDriver<'a>>> {
             }
             iteration += 1;
 
-            if let Err(e) = self.set_gain(current_gain) {
-                error!("TSL2591: Failed to set gain: {:?}", e);
-                return vec![];
-            }
-            if let Err(e) = self.set_timing(current_scan) {
-                error!("TSL2591: Failed to set timing: {:?}", e);
-                return vec![];
-            }
-
-            if let Err(e) = self.enable() {
-                error!("TSL2591: Failed to enable sensor: {:?}", e);
+            // The first integration was started along with the other sensors
+            if !(integrating && iteration == 1) && !self.begin(current_gain) {
                 return vec![];
             }
 
             let mut loop_count = 0;
             while loop_count < 10 {
-                let lux_sensor_status = match self.get_status() {
+                let lux_sensor_status = match self.sensor.get_status() {
                     Ok(status) => status,
                     Err(e) => {
                         error!("TSL2591: Failed to get status: {:?}", e);
@@ -52,7 +165,7 @@ impl<'a> Sensor<'a> for tsl2591_eh_driver::Driver<RcDevice<I2cDriver<'a>>> {
                 }
             }
 
-            let (ch0, ch1) = match self.get_channel_data() {
+            let (ch0, ch1) = match self.sensor.get_channel_data() {
                 Ok(data) => data,
                 Err(e) => {
                     error!("TSL2591: Failed to get channel data: {:?}", e);
@@ -60,11 +173,13 @@ impl<'a> Sensor<'a> for tsl2591_eh_driver::Driver<RcDevice<I2cDriver<'a>>> {
                 }
             };
 
-            if let Err(e) = self.disable() {
-                warn!("TSL2591: Failed to disable sensor: {:?}", e);
+            if self.watch.is_none() {
+                if let Err(e) = self.sensor.disable() {
+                    warn!("TSL2591: Failed to disable sensor: {:?}", e);
+                }
             }
 
-            match self.calculate_lux(ch0, ch1) {
+            match self.sensor.calculate_lux(ch0, ch1) {
                 Ok(lux) => {
                     if lux.is_nan() {
                         // Basically we got an underflow
@@ -74,20 +189,14 @@ impl<'a> Sensor<'a> for tsl2591_eh_driver::Driver<RcDevice<I2cDriver<'a>>> {
                             }
                             // We are already at max gain, we can consider this to be pitch-black
                             Err(_) => {
-                                return vec![Measurement {
-                                    name: "lux".to_string(),
-                                    value: 0.0,
-                                }]
+                                return self.finish(0.0, ch0, ch1, current_gain);
                             }
                         }
                     } else if lux.is_infinite() {
                         return vec![];
                     } else {
                         info!("Lux: {} lx", lux);
-                        return vec![Measurement {
-                            name: "lux".to_string(),
-                            value: lux,
-                        }];
+                        return self.finish(lux, ch0, ch1, current_gain);
                     }
                 }
                 // We have an overflow
@@ -97,27 +206,180 @@ impl<'a> Sensor<'a> for tsl2591_eh_driver::Driver<RcDevice<I2cDriver<'a>>> {
                             current_gain = gain;
                         }
                         // If we are at the lowest gain already and are still getting an overflow we can return the brightest sunlight levels
-                        Err(_) => return vec![],
+                        Err(_) => {
+                            self.gain = current_gain;
+                            return vec![];
+                        }
                     }
                 }
             }
         }
     }
 
-    fn get_sensor(i2c_device: RcDevice<I2cDriver<'a>>) -> Self {
+    fn start(&mut self) -> Duration {
+        if let Some(watch) = &self.watch {
+            watch.pause();
+        }
+        self.integrating = self.begin(self.gain);
+        if self.integrating {
+            INTEGRATION_TIME
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    fn memory(&self) -> Vec<(&'static str, u32)> {
+        vec![("gain", gain_index(self.gain))]
+    }
+}
+
+impl<'a> I2cSensor<'a> for Tsl2591Sensor<'a> {
+    fn get_sensor(i2c_device: I2cDevice<'a>, config: &Config) -> Result<Self> {
         println!("Initializing TSL2591 light sensor");
         let mut lux_sensor = tsl2591_eh_driver::Driver::new(i2c_device)
-            .expect("Failed to create TSL2591 sensor - check I2C connection");
+            .map_err(sensor_init("tsl2591", "Failed to create - check I2C connection"))?;
         lux_sensor.enable()
-            .expect("Failed to enable TSL2591 sensor");
+            .map_err(sensor_init("tsl2591", "Failed to enable"))?;
         std::thread::sleep(Duration::from_millis(1000));
 
         let status = lux_sensor.get_status()
-            .expect("Failed to read TSL2591 status");
+            .map_err(sensor_init("tsl2591", "Failed to read status"))?;
         println!("TSL2591 status: {:?}", status);
         lux_sensor.disable()
-            .expect("Failed to disable TSL2591 sensor");
-        lux_sensor
+            .map_err(sensor_init("tsl2591", "Failed to disable"))?;
+        let gain = config.sensor_memory.get("tsl2591", "gain").and_then(gain_from_index);
+        Ok(Tsl2591Sensor {
+            sensor: lux_sensor,
+            gain: gain.unwrap_or(tsl2591_eh_driver::Gain::MED),
+            raw_channels: config.tsl2591.raw_channels,
+            watch: None,
+            integrating: false,
+        })
+    }
+}
+
+struct Watch<I2C> {
+    i2c: I2C,
+    change_percent: f32,
+    // Multiplier of the gain the thresholds were set at
+    gain: f32,
+    paused: bool,
+}
+
+impl<I2C: I2c> Watch<I2C> {
+    fn write(&mut self, register: u8, data: &[u8]) -> Result<(), I2C::Error> {
+        let mut buffer = [0u8; 5];
+        buffer[0] = COMMAND | register;
+        buffer[1..=data.len()].copy_from_slice(data);
+        self.i2c.write(ADDRESS, &buffer[..=data.len()])
+    }
+
+    fn arm(&mut self, gain: f32, ch0: u16) -> Result<(), I2C::Error> {
+        let (low, high) = light::threshold_band(ch0, self.change_percent, MIN_BAND_COUNTS);
+        let [low_l, low_h] = low.to_le_bytes();
+        let [high_l, high_h] = high.to_le_bytes();
+        self.write(REG_AILTL, &[low_l, low_h, high_l, high_h])?;
+        self.write(REG_PERSIST, &[PERSIST_3])?;
+        self.i2c.write(ADDRESS, &[CLEAR_INTERRUPT])?;
+        self.write(REG_ENABLE, &[ENABLE_PON_AEN_AIEN])?;
+        self.gain = gain;
+        self.paused = false;
+        Ok(())
+    }
+
+    fn channels(&mut self) -> Result<(u16, u16), I2C::Error> {
+        let mut data = [0u8; 4];
+        self.i2c.write_read(ADDRESS, &[COMMAND | REG_C0DATAL], &mut data)?;
+        Ok((u16::from_le_bytes([data[0], data[1]]), u16::from_le_bytes([data[2], data[3]])))
+    }
+}
+
+/// The ALS interrupt state shared by the sampled sensor and the thread waiting on INT.
+#[derive(Clone)]
+pub struct LightWatch(Arc<Mutex<Watch<I2cDevice<'static>>>>);
+
+impl LightWatch {
+    fn pause(&self) {
+        self.0.lock().expect("Light watch lock poisoned").paused = true;
+    }
+
+    fn arm(&self, gain: f32, ch0: u16) {
+        if let Err(err) = self.0.lock().expect("Light watch lock poisoned").arm(gain, ch0) {
+            error!("TSL2591: Failed to arm the interrupt: {:?}", err);
+        }
+    }
+}
+
+/// Waits on the TSL2591 INT pin on its own thread and records a `light` disturbance with the new
+/// lux level whenever the light leaves the band around the last reading, e.g. a lamp switched on
+/// at night, stamped with the time of the interrupt rather than the round. The band follows the
+/// light, it's armed again around every reading, so a new level is reported once.
+pub fn spawn_light_events(
+    i2c_device: I2cDevice<'static>,
+    config: &Tsl2591Config,
+    pin: i32,
+    log: DisturbanceLog,
+) -> Result<LightWatch> {
+    let watch = LightWatch(Arc::new(Mutex::new(Watch {
+        i2c: i2c_device,
+        change_percent: config.change_percent,
+        gain: gain_factor(tsl2591_eh_driver::Gain::MED),
+        // Until the first round has found the gain
+        paused: true,
+    })));
+    let context = "Failed to set up the TSL2591 interrupt pin";
+    let mut interrupt = PinDriver::input(unsafe { AnyIOPin::new(pin) }).context(Phase::SensorInit, context)?;
+    // INT is open drain, active low
+    interrupt.set_pull(Pull::Up).context(Phase::SensorInit, context)?;
+    interrupt.set_interrupt_type(InterruptType::NegEdge).context(Phase::SensorInit, context)?;
+
+    let thread_watch = watch.clone();
+    thread::Builder::new()
+        .name("light_events".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || wait(interrupt, thread_watch, log))
+        .context(Phase::SensorInit, "Failed to start the light event thread")?;
+    info!("TSL2591 interrupt on GPIO{}, {}% change", pin, config.change_percent);
+    Ok(watch)
+}
+
+fn wait(mut interrupt: PinDriver<'static, AnyIOPin, Input>, watch: LightWatch, log: DisturbanceLog) {
+    let notification = Notification::new();
+    let notifier = notification.notifier();
+    // Only wakes this thread up, the I2C work is done here
+    let subscribed = unsafe {
+        interrupt.subscribe(move || {
+            notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+        })
+    };
+    if let Err(err) = subscribed {
+        error!("TSL2591: Failed to subscribe to the interrupt: {:?}", err);
+        return;
+    }
+    loop {
+        if let Err(err) = interrupt.enable_interrupt() {
+            error!("TSL2591: Failed to enable the interrupt: {:?}", err);
+            return;
+        }
+        notification.wait(BLOCK);
+        let timestamp_ms = now_ms();
+        let mut watch = watch.0.lock().expect("Light watch lock poisoned");
+        // The sampler is in the middle of a gain search and arms it again once it's done
+        if watch.paused {
+            continue;
+        }
+        let (ch0, ch1) = match watch.channels() {
+            Ok(channels) => channels,
+            Err(err) => {
+                error!("TSL2591: Failed to read channels: {:?}", err);
+                continue;
+            }
+        };
+        let gain = watch.gain;
+        if let Err(err) = watch.arm(gain, ch0) {
+            error!("TSL2591: Failed to arm the interrupt: {:?}", err);
+        }
+        disturbances::record(&log, "light", timestamp_ms, light::tsl2591_lux(ch0, ch1, gain, INTEGRATION_MS));
     }
 }
 
@@ -138,3 +400,33 @@ fn decrement_gain(gain: tsl2591_eh_driver::Gain) -> Result<tsl2591_eh_driver::Ga
         tsl2591_eh_driver::Gain::MAX => Ok(tsl2591_eh_driver::Gain::HIGH),
     }
 }
+
+// Kept in the sensor memory
+fn gain_index(gain: tsl2591_eh_driver::Gain) -> u32 {
+    match gain {
+        tsl2591_eh_driver::Gain::LOW => 0,
+        tsl2591_eh_driver::Gain::MED => 1,
+        tsl2591_eh_driver::Gain::HIGH => 2,
+        tsl2591_eh_driver::Gain::MAX => 3,
+    }
+}
+
+fn gain_from_index(index: u32) -> Option<tsl2591_eh_driver::Gain> {
+    match index {
+        0 => Some(tsl2591_eh_driver::Gain::LOW),
+        1 => Some(tsl2591_eh_driver::Gain::MED),
+        2 => Some(tsl2591_eh_driver::Gain::HIGH),
+        3 => Some(tsl2591_eh_driver::Gain::MAX),
+        _ => None,
+    }
+}
+
+// Typical gain multipliers from the datasheet
+fn gain_factor(gain: tsl2591_eh_driver::Gain) -> f32 {
+    match gain {
+        tsl2591_eh_driver::Gain::LOW => 1.0,
+        tsl2591_eh_driver::Gain::MED => 25.0,
+        tsl2591_eh_driver::Gain::HIGH => 428.0,
+        tsl2591_eh_driver::Gain::MAX => 9876.0,
+    }
+}
@@ -0,0 +1,140 @@
+//! Orderly shutdown, for maintenance power-offs and a flat battery: sampling stops, what's still
+//! buffered goes out over the network if it can and onto flash otherwise, and the sensors are
+//! powered down before the node halts.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use log::warn;
+
+use crate::config::ShutdownConfig;
+use crate::sensors::Measurement;
+
+/// Set from the console, MQTT or the battery check, the sampler picks it up between rounds.
+/// `wake` cuts the wait for the next round short instead, for a measurement on demand. Clones
+/// share the requests.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<(Mutex<Requests>, Condvar)>);
+
+#[derive(Default)]
+struct Requests {
+    shutdown: Option<String>,
+    wake: bool,
+}
+
+impl Shutdown {
+    pub fn request(&self, reason: &str) {
+        let (requests, changed) = &*self.0;
+        let mut requests = requests.lock().expect("Shutdown lock poisoned");
+        if requests.shutdown.is_none() {
+            warn!("Shutting down: {}", reason);
+            requests.shutdown = Some(reason.to_string());
+            changed.notify_all();
+        }
+    }
+
+    pub fn requested(&self) -> bool {
+        let (requests, _) = &*self.0;
+        requests.lock().expect("Shutdown lock poisoned").shutdown.is_some()
+    }
+
+    pub fn wake(&self) {
+        let (requests, changed) = &*self.0;
+        requests.lock().expect("Shutdown lock poisoned").wake = true;
+        changed.notify_all();
+    }
+
+    /// Sleeps for `duration`, or until a shutdown is requested or the sleeper woken. True if a
+    /// shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (requests, changed) = &*self.0;
+        let requests = requests.lock().expect("Shutdown lock poisoned");
+        let (mut requests, _) = changed
+            .wait_timeout_while(requests, duration, |requests| requests.shutdown.is_none() && !requests.wake)
+            .expect("Shutdown lock poisoned");
+        requests.wake = false;
+        requests.shutdown.is_some()
+    }
+}
+
+/// Tells when the battery has been low for long enough to shut down, a single dip under load
+/// doesn't count.
+#[derive(Default)]
+pub struct BatteryCheck {
+    low_rounds: u32,
+}
+
+impl BatteryCheck {
+    /// Goes through a round, true once the node should shut down.
+    pub fn check(&mut self, config: &ShutdownConfig, measurements: &[Measurement]) -> bool {
+        if config.battery_metric.is_empty() {
+            return false;
+        }
+        let Some(level) = measurements.iter().find(|measurement| measurement.name == config.battery_metric) else {
+            return false;
+        };
+        if level.value < config.low_battery_below {
+            self.low_rounds += 1;
+        } else {
+            self.low_rounds = 0;
+        }
+        self.low_rounds >= config.low_battery_rounds.max(1)
+    }
+}
+
+/// Deep sleep without a wake-up source, the node stays down until it's reset or powered up again.
+#[cfg(target_os = "espidf")]
+pub fn halt() -> ! {
+    warn!("Halted, reset or power cycle to start again");
+    unsafe { esp_idf_svc::sys::esp_deep_sleep_start() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    fn battery(value: f32) -> Vec<Measurement> {
+        vec![Measurement {
+            name: "battery_mv".into(),
+            value,
+        }]
+    }
+
+    #[test]
+    fn wakes_a_sleeper_on_request() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.sleep(Duration::from_millis(1)));
+
+        let started = Instant::now();
+        shutdown.wake();
+        assert!(!shutdown.sleep(Duration::from_secs(10)));
+
+        let requester = shutdown.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            requester.request("test");
+        });
+        assert!(shutdown.sleep(Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(shutdown.requested());
+    }
+
+    #[test]
+    fn shuts_down_after_consecutive_low_rounds() {
+        let config = ShutdownConfig {
+            battery_metric: "battery_mv".to_string(),
+            low_battery_below: 1700.0,
+            low_battery_rounds: 2,
+        };
+        let mut check = BatteryCheck::default();
+        assert!(!check.check(&config, &battery(1650.0)));
+        assert!(!check.check(&config, &battery(1750.0)));
+        assert!(!check.check(&config, &battery(1650.0)));
+        // Rounds without the metric don't reset the count
+        assert!(!check.check(&config, &[]));
+        assert!(check.check(&config, &battery(1600.0)));
+    }
+}
@@ -0,0 +1,153 @@
+use std::f32::consts::PI;
+
+use rand::prelude::*;
+
+use crate::config::Config;
+use crate::error::{Error, Phase, Result};
+use crate::metrics::MetricSpec;
+use crate::pipeline::{self, Network};
+use crate::sensors::{Measurement, Sensor};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Hour of the day (UTC) with fractions, drives the simulated daily cycles.
+fn hour_of_day() -> f32 {
+    (pipeline::now() % SECONDS_PER_DAY) as f32 / 3600.0
+}
+
+fn is_night(hour: f32) -> bool {
+    !(7.0..22.0).contains(&hour)
+}
+
+fn noise(rng: &mut impl Rng, amplitude: f32) -> f32 {
+    rng.random_range(-amplitude..=amplitude)
+}
+
+/// Bedroom climate as the BME280 and SCD4x would see it: temperature peaking in the afternoon,
+/// humidity moving against it, slowly drifting pressure and CO2 building up overnight.
+pub struct SimulatedClimate {
+    co2: f32,
+    pressure: f32,
+}
+
+impl SimulatedClimate {
+    pub fn new() -> Self {
+        SimulatedClimate {
+            co2: 450.0,
+            pressure: 750.0,
+        }
+    }
+}
+
+impl Default for SimulatedClimate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sensor for SimulatedClimate {
+    fn name(&self) -> &'static str {
+        "climate"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        vec![
+            MetricSpec::new("temperature", "°C", -40.0, 85.0),
+            MetricSpec::new("humidity", "%", 0.0, 100.0),
+            MetricSpec::new("pressure", "mmHg", 225.0, 825.0),
+            MetricSpec::new("co2", "ppm", 0.0, 40_000.0),
+        ]
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let mut rng = rand::rng();
+        let hour = hour_of_day();
+        let daily = ((hour - 9.0) / 24.0 * 2.0 * PI).sin();
+
+        let temperature = 21.0 + 1.5 * daily + noise(&mut rng, 0.1);
+        let humidity = 45.0 - 5.0 * daily + noise(&mut rng, 0.5);
+        self.pressure = (self.pressure + noise(&mut rng, 0.2)).clamp(735.0, 765.0);
+
+        // Someone sleeping adds CO2, the ventilated room decays back towards outdoor levels
+        let target = if is_night(hour) { 1400.0 } else { 450.0 };
+        self.co2 += (target - self.co2) * 0.05 + noise(&mut rng, 10.0);
+
+        vec![
+            Measurement {
+                name: "temperature".into(),
+                value: temperature,
+            },
+            Measurement {
+                name: "humidity".into(),
+                value: humidity,
+            },
+            Measurement {
+                name: "pressure".into(),
+                value: self.pressure,
+            },
+            Measurement {
+                name: "co2".into(),
+                value: self.co2.max(400.0).round(),
+            },
+        ]
+    }
+}
+
+/// Ambient light like the TSL2591 reports it: daylight curve, darkness at night with the
+/// occasional lamp switched on.
+pub struct SimulatedLight;
+
+impl Sensor for SimulatedLight {
+    fn name(&self) -> &'static str {
+        "light"
+    }
+
+    fn metrics(&self) -> Vec<MetricSpec> {
+        vec![MetricSpec::new("lux", "lx", 0.0, 88_000.0)]
+    }
+
+    fn measure(&mut self) -> Vec<Measurement> {
+        let mut rng = rand::rng();
+        let hour = hour_of_day();
+        let lux = if is_night(hour) {
+            if rng.random_bool(0.05) {
+                rng.random_range(50.0..150.0)
+            } else {
+                0.0
+            }
+        } else {
+            let daylight = ((hour - 7.0) / 15.0 * PI).sin().max(0.0);
+            400.0 * daylight + noise(&mut rng, 5.0).abs()
+        };
+        vec![Measurement {
+            name: "lux".into(),
+            value: lux,
+        }]
+    }
+}
+
+/// Network that fails to come up with the given probability, to exercise buffering.
+pub struct SimulatedNetwork {
+    failure_rate: f64,
+}
+
+impl SimulatedNetwork {
+    pub fn new(failure_rate: f64) -> Self {
+        SimulatedNetwork {
+            failure_rate: failure_rate.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Network for SimulatedNetwork {
+    fn connect(&mut self, _config: &Config) -> Result<()> {
+        if rand::rng().random_bool(self.failure_rate) {
+            return Err(Error::failed(Phase::Connect, "Simulated network outage"));
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
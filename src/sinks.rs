@@ -0,0 +1,13 @@
+mod coap;
+mod compression;
+mod datadog;
+mod graphite;
+mod mqtt;
+mod units;
+
+pub use coap::CoapSink;
+pub use compression::{deflate, DEFLATE};
+pub use datadog::DatadogSink;
+pub use graphite::GraphiteSink;
+pub use mqtt::{base_topic, command_topic, heartbeat_topic, status_topic, MqttSink, Publisher, OFFLINE, ONLINE};
+pub use units::convert;
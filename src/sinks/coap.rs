@@ -0,0 +1,203 @@
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::error::{Context, Error, Phase, Result};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+use super::mqtt::write_state;
+
+const VERSION: u8 = 1;
+const TYPE_CONFIRMABLE: u8 = 0;
+const TYPE_NON_CONFIRMABLE: u8 = 1;
+const TYPE_ACKNOWLEDGEMENT: u8 = 2;
+const CODE_POST: u8 = 0x02;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+const CONTENT_FORMAT_JSON: u8 = 50;
+const PAYLOAD_MARKER: u8 = 0xFF;
+// RFC 7252's defaults, short of the random factor
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+/// POSTs every round as the JSON object MQTT gets to `coap.path` over CoAP, e.g. to a border
+/// router's proxy from a Thread network. Confirmable requests are resent until they're
+/// acknowledged, a 4.xx response turns the batch down.
+#[derive(Default)]
+pub struct CoapSink {
+    message_id: u16,
+    bytes_written: u64,
+    // Kept between batches, so its capacity is reused
+    payload: String,
+}
+
+/// A POST with the path split into Uri-Path options.
+pub fn request(confirmable: bool, message_id: u16, token: &[u8], path: &str, payload: &[u8]) -> Vec<u8> {
+    let kind = if confirmable { TYPE_CONFIRMABLE } else { TYPE_NON_CONFIRMABLE };
+    let mut message = vec![VERSION << 6 | kind << 4 | token.len() as u8, CODE_POST];
+    message.extend_from_slice(&message_id.to_be_bytes());
+    message.extend_from_slice(token);
+    let mut last = 0;
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        option(&mut message, &mut last, OPTION_URI_PATH, segment.as_bytes());
+    }
+    option(&mut message, &mut last, OPTION_CONTENT_FORMAT, &[CONTENT_FORMAT_JSON]);
+    message.push(PAYLOAD_MARKER);
+    message.extend_from_slice(payload);
+    message
+}
+
+fn option(message: &mut Vec<u8>, last: &mut u16, number: u16, value: &[u8]) {
+    let nibble = |value: usize| match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, ((value - 269) as u16).to_be_bytes().to_vec()),
+    };
+    let (delta, delta_extended) = nibble((number - *last) as usize);
+    let (length, length_extended) = nibble(value.len());
+    message.push(delta << 4 | length);
+    message.extend(delta_extended);
+    message.extend(length_extended);
+    message.extend_from_slice(value);
+    *last = number;
+}
+
+/// The code of a piggybacked response to the request, e.g. 0x44 for 2.04 Changed. None for
+/// anything else, an empty acknowledgement as well.
+pub fn response_code(datagram: &[u8], message_id: u16, token: &[u8]) -> Option<u8> {
+    let [header, code, id_high, id_low, rest @ ..] = datagram else {
+        return None;
+    };
+    let token_len = (header & 0x0F) as usize;
+    if header >> 6 != VERSION
+        || (header >> 4) & 0x03 != TYPE_ACKNOWLEDGEMENT
+        || u16::from_be_bytes([*id_high, *id_low]) != message_id
+        || *code == 0
+        || rest.get(..token_len) != Some(token)
+    {
+        return None;
+    }
+    Some(*code)
+}
+
+impl CoapSink {
+    fn exchange(&mut self, socket: &UdpSocket, config: &Config, path: &str) -> Result<()> {
+        self.message_id = self.message_id.wrapping_add(1);
+        let token = rand::random::<u32>().to_be_bytes();
+        let confirmable = config.coap.confirmable;
+        let message = request(confirmable, self.message_id, &token, path, self.payload.as_bytes());
+        let address = config.coap.address();
+        let mut timeout = ACK_TIMEOUT;
+        for _ in 0..=MAX_RETRANSMIT {
+            socket.send(&message).with_context(Phase::Upload, || format!("Failed to send to {}", address))?;
+            self.bytes_written += message.len() as u64;
+            if !confirmable {
+                return Ok(());
+            }
+            socket.set_read_timeout(Some(timeout)).context(Phase::Upload, "Failed to set the CoAP timeout")?;
+            let mut datagram = [0; 256];
+            while let Ok(len) = socket.recv(&mut datagram) {
+                let Some(code) = response_code(&datagram[..len], self.message_id, &token) else {
+                    continue;
+                };
+                let status = format!("{}.{:02}", code >> 5, code & 0x1F);
+                return match code >> 5 {
+                    2 => Ok(()),
+                    4 => Err(Error::rejected(Phase::Upload, format!("{} answered {}", address, status))),
+                    _ => Err(Error::failed(Phase::Upload, format!("{} answered {}", address, status))),
+                };
+            }
+            timeout *= 2;
+        }
+        Err(Error::failed(Phase::Upload, format!("No answer from {}", address)))
+    }
+}
+
+impl Sink for CoapSink {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let address = config.coap.address();
+        let remote = (config.coap.host.as_str(), config.coap.port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| Error::failed(Phase::Upload, format!("Failed to resolve {}", address)))?;
+        let local: SocketAddr = if remote.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse().expect("Valid address");
+        let socket = UdpSocket::bind(local).context(Phase::Upload, "Failed to open a UDP socket")?;
+        socket.connect(remote).with_context(Phase::Upload, || format!("Failed to connect to {}", address))?;
+        if self.message_id == 0 {
+            self.message_id = rand::random();
+        }
+
+        write_state(&mut self.payload, timestamp_ms, measurements)?;
+        let path = config.coap.path.replace("{id}", config.device_id());
+        self.exchange(&socket, config, &path)
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    fn name(&self) -> &'static str {
+        "coap"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn encodes_the_request() {
+        let message = request(true, 0x1234, &[0xAB], "/sleep/bedroom", b"{}");
+        assert_eq!(
+            message,
+            [
+                0x41, 0x02, 0x12, 0x34, 0xAB, // CON POST with a one byte token
+                0xB5, b's', b'l', b'e', b'e', b'p', // Uri-Path
+                0x07, b'b', b'e', b'd', b'r', b'o', b'o', b'm', // Uri-Path again
+                0x11, 50, // Content-Format
+                0xFF, b'{', b'}',
+            ]
+        );
+        let long = request(false, 1, &[], "a-rather-long-path-segment", b"");
+        assert_eq!(long[0], 0x50);
+        assert_eq!(&long[4..6], &[0xBD, 26 - 13]);
+    }
+
+    #[test]
+    fn posts_confirmable_rounds_until_acknowledged() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        config.coap.host = "127.0.0.1".to_string();
+        config.coap.port = server.local_addr().unwrap().port();
+        let acknowledge = thread::spawn(move || {
+            let mut datagram = [0; 256];
+            // The first one is lost
+            server.recv_from(&mut datagram).unwrap();
+            let (len, client) = server.recv_from(&mut datagram).unwrap();
+            let token_len = (datagram[0] & 0x0F) as usize;
+            let mut ack = vec![0x60 | token_len as u8, 0x44, datagram[2], datagram[3]];
+            ack.extend_from_slice(&datagram[4..4 + token_len]);
+            // A stray acknowledgement of another request first
+            server.send_to(&[0x60, 0x44, 0, 0], client).unwrap();
+            server.send_to(&ack, client).unwrap();
+            String::from_utf8_lossy(&datagram[..len]).into_owned()
+        });
+
+        let measurements = [Measurement {
+            name: "co2".into(),
+            value: 612.0,
+        }];
+        let mut sink = CoapSink::default();
+        sink.send(&config, 1_700_000_000_000, &measurements).unwrap();
+        let request = acknowledge.join().unwrap();
+        assert!(request.ends_with(r#"{"timestamp":1700000000,"co2":612}"#));
+        assert!(request.contains("bedroom"));
+    }
+}
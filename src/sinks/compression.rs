@@ -0,0 +1,34 @@
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+/// `Content-Encoding` of a body from [`deflate`], zlib framed as HTTP expects it.
+pub const DEFLATE: &str = "deflate";
+
+// Smaller bodies barely shrink, it's not worth the heap
+const MIN_LEN: usize = 1024;
+// Most of the gain of the higher levels for a fraction of the time
+const LEVEL: u8 = 3;
+
+/// Compresses a request body, None when it's too small to gain anything.
+pub fn deflate(body: &[u8]) -> Option<Vec<u8>> {
+    if body.len() < MIN_LEN {
+        return None;
+    }
+    let compressed = compress_to_vec_zlib(body, LEVEL);
+    (compressed.len() < body.len()).then_some(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    use super::*;
+
+    #[test]
+    fn deflates_large_bodies_only() {
+        assert_eq!(deflate(b"{\"series\":[]}"), None);
+        let body = r#"{"metric":"sleep_thing.co2","type":3,"tags":["device:bedroom"]},"#.repeat(40);
+        let compressed = deflate(body.as_bytes()).unwrap();
+        assert!(compressed.len() < body.len() / 4);
+        assert_eq!(decompress_to_vec_zlib(&compressed).unwrap(), body.as_bytes());
+    }
+}
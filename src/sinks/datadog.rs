@@ -0,0 +1,138 @@
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::error::{Error, Phase, Result};
+use crate::pipeline::{HttpClient, Sink};
+use crate::sensors::Measurement;
+
+use super::{convert, deflate, DEFLATE};
+
+// Gauge in the v2 series API
+const GAUGE: u8 = 3;
+
+/// Submits measurements to Datadog's series API, one request per batch, tagged with the device ID
+/// and `datadog.tags`.
+pub struct DatadogSink<'a> {
+    client: Box<dyn HttpClient + Send + 'a>,
+    bytes_written: u64,
+}
+
+impl<'a> DatadogSink<'a> {
+    pub fn new(client: Box<dyn HttpClient + Send + 'a>) -> Self {
+        DatadogSink {
+            client,
+            bytes_written: 0,
+        }
+    }
+}
+
+fn series(config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Value {
+    let mut tags = vec![format!("device:{}", config.device_id())];
+    tags.extend(config.datadog.tags.iter().cloned());
+    let series: Vec<Value> = measurements
+        .iter()
+        .filter(|measurement| measurement.value.is_finite())
+        .map(|measurement| {
+            json!({
+                "metric": format!("{}{}", config.datadog.prefix, measurement.name),
+                "type": GAUGE,
+                "points": [{
+                    "timestamp": timestamp_ms / 1000,
+                    "value": convert(config.datadog.units, &measurement.name, measurement.value),
+                }],
+                "tags": tags,
+            })
+        })
+        .collect();
+    json!({ "series": series })
+}
+
+impl Sink for DatadogSink<'_> {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let mut body = serde_json::to_vec(&series(config, timestamp_ms, measurements))?;
+        let url = format!("https://api.{}/api/v2/series", config.datadog.site);
+        let mut headers = vec![("Content-Type", "application/json"), ("DD-API-KEY", config.datadog.api_key.as_str())];
+        if config.datadog.compress {
+            if let Some(compressed) = deflate(&body) {
+                body = compressed;
+                headers.push(("Content-Encoding", DEFLATE));
+            }
+        }
+        let status = self.client.post(&url, &headers, &body)?;
+        self.bytes_written += body.len() as u64;
+        let message = format!("Datadog answered {}", status);
+        match status {
+            200..=299 => Ok(()),
+            // A timeout or rate limiting, the batch is fine
+            408 | 429 => Err(Error::failed(Phase::Upload, message)),
+            // Bad data, a bad API key or a payload that's too large, it won't get any better
+            400..=499 => Err(Error::rejected(Phase::Upload, message)),
+            _ => Err(Error::failed(Phase::Upload, message)),
+        }
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    fn name(&self) -> &'static str {
+        "datadog"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockClient {
+        requests: Arc<Mutex<Vec<(String, Value)>>>,
+    }
+
+    impl HttpClient for MockClient {
+        fn post(&mut self, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<u16> {
+            assert!(headers.contains(&("DD-API-KEY", "secret")));
+            let body = serde_json::from_slice(body).unwrap();
+            self.requests.lock().unwrap().push((url.to_string(), body));
+            Ok(202)
+        }
+    }
+
+    #[test]
+    fn submits_tagged_gauges() {
+        let mut config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        config.datadog.api_key = "secret".to_string();
+        config.datadog.tags = vec!["room:bedroom".to_string()];
+        let measurements = [
+            Measurement {
+                name: "co2".into(),
+                value: 612.0,
+            },
+            Measurement {
+                name: "lux".into(),
+                value: f32::NAN,
+            },
+        ];
+
+        let client = MockClient::default();
+        let mut sink = DatadogSink::new(Box::new(client.clone()));
+        sink.send(&config, 1_700_000_000_999, &measurements).unwrap();
+        let expected = json!({
+            "series": [{
+                "metric": "sleep_thing.co2",
+                "type": 3,
+                "points": [{"timestamp": 1_700_000_000, "value": 612.0}],
+                "tags": ["device:bedroom", "room:bedroom"],
+            }]
+        });
+        assert_eq!(
+            *client.requests.lock().unwrap(),
+            vec![("https://api.datadoghq.com/api/v2/series".to_string(), expected)]
+        );
+    }
+}
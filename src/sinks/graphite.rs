@@ -0,0 +1,93 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::TcpStream;
+
+use crate::config::Config;
+use crate::error::{Context, Phase, Result};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+use super::convert;
+
+/// Sends measurements to Carbon using the Graphite plaintext protocol, one connection per batch.
+#[derive(Default)]
+pub struct GraphiteSink {
+    bytes_written: u64,
+    // Kept between batches, so its capacity is reused
+    lines: String,
+}
+
+impl Sink for GraphiteSink {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let address = config.graphite.address();
+        // Resolves names to either family, and takes IPv6 literals without the brackets
+        let mut stream = TcpStream::connect((config.graphite.host.as_str(), config.graphite.port))
+            .with_context(Phase::Upload, || format!("Failed to connect to {}", address))?;
+
+        let pattern = config.metric_pattern();
+        self.lines.clear();
+        for measurement in measurements {
+            for (i, part) in pattern.split("{name}").enumerate() {
+                if i > 0 {
+                    self.lines.push_str(&measurement.name);
+                }
+                self.lines.push_str(part);
+            }
+            let value = convert(config.graphite.units, &measurement.name, measurement.value);
+            // Writing to a String can't fail
+            let _ = writeln!(self.lines, " {} {}", value, timestamp_ms / 1000);
+        }
+        stream.write_all(self.lines.as_bytes())
+            .with_context(Phase::Upload, || format!("Failed to write to {}", address))?;
+        self.bytes_written += self.lines.len() as u64;
+
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn writes_one_line_per_measurement() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.graphite.host = "127.0.0.1".to_string();
+        config.graphite.port = listener.local_addr().unwrap().port();
+        config.graphite.prefix = "room.".to_string();
+        config.metric_template = "{prefix}{name}.{name}".to_string();
+        let measurements = [
+            Measurement {
+                name: "co2".into(),
+                value: 612.0,
+            },
+            Measurement {
+                name: format!("{}_on", "ac").into(),
+                value: 1.0,
+            },
+        ];
+
+        let mut sink = GraphiteSink::default();
+        sink.send(&config, 1_700_000_000_999, &measurements).unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "room.co2.co2 612 1700000000\nroom.ac_on.ac_on 1 1700000000\n");
+        assert_eq!(sink.bytes_written(), received.len() as u64);
+
+        config.graphite.host = "2001:db8::1".to_string();
+        config.graphite.port = 2003;
+        assert_eq!(config.graphite.address(), "[2001:db8::1]:2003");
+    }
+}
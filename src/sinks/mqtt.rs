@@ -0,0 +1,173 @@
+use std::fmt::Write as _;
+
+use crate::config::{Config, MqttLayout};
+use crate::error::Result;
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+/// Retained on the status topic, `offline` is the Last Will the broker publishes when the node
+/// stops answering, for Home Assistant's `availability_topic`.
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Hands a message to the MQTT client, which delivers it in the background. `qos` is 0, 1 or 2.
+pub trait Publisher {
+    fn publish(&mut self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()>;
+}
+
+/// `mqtt.base_topic` with the device ID filled in.
+pub fn base_topic(config: &Config) -> String {
+    config.mqtt.base_topic.replace("{id}", config.device_id())
+}
+
+pub fn status_topic(config: &Config) -> String {
+    format!("{}/status", base_topic(config))
+}
+
+pub fn heartbeat_topic(config: &Config) -> String {
+    format!("{}/heartbeat", base_topic(config))
+}
+
+/// Subscribed to for commands to the node, `shutdown` so far.
+pub fn command_topic(config: &Config) -> String {
+    format!("{}/command", base_topic(config))
+}
+
+/// Publishes every round in the `mqtt.layout`: one JSON object on `<base_topic>/state`, e.g.
+/// `{"timestamp":1700000000,"co2":612}`, or every value on `<base_topic>/<name>`. A value that
+/// isn't a number is `null`.
+pub struct MqttSink<'a> {
+    publisher: Box<dyn Publisher + 'a>,
+    // Kept between rounds, so its capacity is reused
+    payload: String,
+}
+
+impl<'a> MqttSink<'a> {
+    pub fn new(publisher: Box<dyn Publisher + 'a>) -> Self {
+        MqttSink {
+            publisher,
+            payload: String::new(),
+        }
+    }
+}
+
+impl Sink for MqttSink<'_> {
+    fn send(&mut self, config: &Config, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let base = base_topic(config);
+        let (qos, retain) = (config.mqtt.qos, config.mqtt.retain);
+        // Writing to a String can't fail
+        match config.mqtt.layout {
+            MqttLayout::State => {
+                write_state(&mut self.payload, timestamp_ms, measurements)?;
+                self.publisher.publish(&format!("{}/state", base), self.payload.as_bytes(), qos, retain)
+            }
+            MqttLayout::PerMetric => {
+                for measurement in measurements {
+                    self.payload.clear();
+                    write_value(&mut self.payload, measurement.value);
+                    let topic = format!("{}/{}", base, measurement.name);
+                    self.publisher.publish(&topic, self.payload.as_bytes(), qos, retain)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+}
+
+/// The round as one JSON object, e.g. `{"timestamp":1700000000,"co2":612}`.
+pub(super) fn write_state(payload: &mut String, timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+    payload.clear();
+    let _ = write!(payload, "{{\"timestamp\":{}", timestamp_ms / 1000);
+    for measurement in measurements {
+        let name = serde_json::to_string(measurement.name.as_ref())?;
+        let _ = write!(payload, ",{}:", name);
+        write_value(payload, measurement.value);
+    }
+    payload.push('}');
+    Ok(())
+}
+
+fn write_value(payload: &mut String, value: f32) {
+    if value.is_finite() {
+        let _ = write!(payload, "{}", value);
+    } else {
+        payload.push_str("null");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // Topic, payload, QoS and retain flag
+    type Message = (String, String, u8, bool);
+
+    #[derive(Clone, Default)]
+    struct MockPublisher {
+        published: Arc<Mutex<Vec<Message>>>,
+    }
+
+    impl Publisher for MockPublisher {
+        fn publish(&mut self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()> {
+            let payload = String::from_utf8(payload.to_vec()).unwrap();
+            self.published.lock().unwrap().push((topic.to_string(), payload, qos, retain));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn publishes_a_json_state_or_a_topic_per_metric() {
+        let mut config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        let measurements = [
+            Measurement {
+                name: "co2".into(),
+                value: 612.0,
+            },
+            Measurement {
+                name: "temperature".into(),
+                value: 21.5,
+            },
+            Measurement {
+                name: "lux".into(),
+                value: f32::NAN,
+            },
+        ];
+
+        let publisher = MockPublisher::default();
+        let mut sink = MqttSink::new(Box::new(publisher.clone()));
+        sink.send(&config, 1_700_000_000_999, &measurements).unwrap();
+        assert_eq!(
+            *publisher.published.lock().unwrap(),
+            vec![(
+                "sleep_thing/bedroom/state".to_string(),
+                r#"{"timestamp":1700000000,"co2":612,"temperature":21.5,"lux":null}"#.to_string(),
+                0,
+                false
+            )]
+        );
+        assert_eq!(status_topic(&config), "sleep_thing/bedroom/status");
+
+        config.mqtt.layout = MqttLayout::PerMetric;
+        config.mqtt.base_topic = "home/{id}/climate".to_string();
+        config.mqtt.qos = 1;
+        config.mqtt.retain = true;
+        publisher.published.lock().unwrap().clear();
+        sink.send(&config, 1_700_000_000_999, &measurements[..2]).unwrap();
+        assert_eq!(
+            *publisher.published.lock().unwrap(),
+            vec![
+                ("home/bedroom/climate/co2".to_string(), "612".to_string(), 1, true),
+                ("home/bedroom/climate/temperature".to_string(), "21.5".to_string(), 1, true),
+            ]
+        );
+    }
+}
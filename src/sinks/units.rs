@@ -0,0 +1,30 @@
+use crate::config::Units;
+
+const TEMPERATURES: &[&str] = &["temperature", "dew_point", "heat_index"];
+const PRESSURES: &[&str] = &["pressure"];
+
+/// Converts a metric value for a sink that reports in `units`, `mattress.temperature` like
+/// `temperature`.
+pub fn convert(units: Units, name: &str, value: f32) -> f32 {
+    let name = name.rsplit('.').next().unwrap_or(name);
+    match units {
+        Units::Metric => value,
+        Units::Imperial if TEMPERATURES.contains(&name) => value * 9.0 / 5.0 + 32.0,
+        Units::Imperial if PRESSURES.contains(&name) => value / 25.4,
+        Units::Imperial => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_only_known_metrics() {
+        assert_eq!(convert(Units::Imperial, "temperature", 20.0), 68.0);
+        assert_eq!(convert(Units::Imperial, "pressure", 762.0), 30.0);
+        assert_eq!(convert(Units::Imperial, "co2", 600.0), 600.0);
+        assert_eq!(convert(Units::Imperial, "mattress.temperature", 30.0), 86.0);
+        assert_eq!(convert(Units::Metric, "temperature", 20.0), 20.0);
+    }
+}
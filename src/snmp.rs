@@ -0,0 +1,428 @@
+//! Minimal SNMP v2c agent, so network monitoring can poll the node like any other appliance.
+//! Read-only: get, get-next and get-bulk over sysDescr, sysUpTime, sysName and a table of the
+//! last round under the private enterprise subtree:
+//!
+//! - `1.3.6.1.4.1.<enterprise>.1.1.1.<i>` metric name
+//! - `1.3.6.1.4.1.<enterprise>.1.1.2.<i>` value × 100, as Integer32
+//!
+//! Health (heap, buffer depth, uplink failures) is in the table like any other metric.
+
+use std::collections::BTreeMap;
+use std::net::{Ipv6Addr, UdpSocket};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use log::{error, info};
+
+use crate::config::Config;
+use crate::error::{Context, Phase, Result};
+use crate::pipeline::Sink;
+use crate::sensors::Measurement;
+
+const STACK_SIZE: usize = 6 * 1024;
+// Keeps a get-bulk response within a single unfragmented datagram
+const MAX_VARBINDS: usize = 32;
+const MAX_PACKET: usize = 1500;
+
+const VERSION_2C: i64 = 1;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const TIME_TICKS: u8 = 0x43;
+const NO_SUCH_OBJECT: u8 = 0x80;
+const END_OF_MIB_VIEW: u8 = 0x82;
+
+const GET_REQUEST: u8 = 0xA0;
+const GET_NEXT_REQUEST: u8 = 0xA1;
+const RESPONSE: u8 = 0xA2;
+const GET_BULK_REQUEST: u8 = 0xA5;
+
+const SYS_DESCR: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 1, 0];
+const SYS_UPTIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+const SYS_NAME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 5, 0];
+const ENTERPRISES: &[u32] = &[1, 3, 6, 1, 4, 1];
+
+pub type Oid = Vec<u32>;
+
+/// Ordered by OID, which is the order get-next walks in.
+pub type SharedMib = Arc<Mutex<BTreeMap<Oid, Value>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i32),
+    String(String),
+    TimeTicks(u32),
+    Null,
+    NoSuchObject,
+    EndOfMibView,
+}
+
+/// Archive sink keeping the MIB up to date, every round replaces the table.
+#[derive(Default)]
+pub struct SnmpFeed {
+    mib: SharedMib,
+}
+
+impl SnmpFeed {
+    pub fn mib(&self) -> SharedMib {
+        self.mib.clone()
+    }
+}
+
+impl Sink for SnmpFeed {
+    fn send(&mut self, config: &Config, _timestamp_ms: u64, measurements: &[Measurement]) -> Result<()> {
+        let mut mib = BTreeMap::new();
+        let description = format!("Sleep Thing {}", env!("CARGO_PKG_VERSION"));
+        mib.insert(SYS_DESCR.to_vec(), Value::String(description));
+        mib.insert(SYS_NAME.to_vec(), Value::String(config.device_id().to_string()));
+        let table = [ENTERPRISES, &[config.snmp.enterprise, 1, 1]].concat();
+        let mut measurements: Vec<&Measurement> = measurements.iter().filter(|m| m.value.is_finite()).collect();
+        measurements.sort_by(|a, b| a.name.cmp(&b.name));
+        for (i, measurement) in measurements.into_iter().enumerate() {
+            let index = i as u32 + 1;
+            let value = (measurement.value as f64 * 100.0).round().clamp(i32::MIN as f64, i32::MAX as f64);
+            mib.insert([&table[..], &[1, index]].concat(), Value::String(measurement.name.to_string()));
+            mib.insert([&table[..], &[2, index]].concat(), Value::Integer(value as i32));
+        }
+        *self.mib.lock().expect("SNMP MIB lock poisoned") = mib;
+        Ok(())
+    }
+}
+
+/// Starts the agent thread on `snmp.port`.
+pub fn spawn(config: &Config, mib: SharedMib) -> Result<()> {
+    let port = config.snmp.port;
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+        .with_context(Phase::Output, || format!("Failed to listen for SNMP on port {}", port))?;
+    let community = config.snmp.community.clone();
+    thread::Builder::new()
+        .name("snmp".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(move || serve(socket, &community, mib))
+        .context(Phase::Output, "Failed to start the SNMP thread")?;
+    info!("SNMP agent listening on port {}", port);
+    Ok(())
+}
+
+fn serve(socket: UdpSocket, community: &str, mib: SharedMib) {
+    let started = Instant::now();
+    let mut packet = [0u8; MAX_PACKET];
+    loop {
+        let (length, peer) = match socket.recv_from(&mut packet) {
+            Ok(received) => received,
+            Err(err) => {
+                error!("Failed to receive an SNMP request: {:?}", err);
+                continue;
+            }
+        };
+        let mut mib = mib.lock().expect("SNMP MIB lock poisoned").clone();
+        let uptime = (started.elapsed().as_millis() / 10) as u32;
+        mib.insert(SYS_UPTIME.to_vec(), Value::TimeTicks(uptime));
+        // Malformed requests and wrong communities get no answer, as with any agent
+        if let Some(response) = respond(&mib, community, &packet[..length]) {
+            if let Err(err) = socket.send_to(&response, peer) {
+                error!("Failed to answer {}: {:?}", peer, err);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    community: Vec<u8>,
+    pdu: u8,
+    request_id: i32,
+    // Error status and index, non-repeaters and max-repetitions in a get-bulk
+    fields: (i32, i32),
+    varbinds: Vec<(Oid, Value)>,
+}
+
+fn respond(mib: &BTreeMap<Oid, Value>, community: &str, packet: &[u8]) -> Option<Vec<u8>> {
+    let request = Message::parse(packet)?;
+    if request.community != community.as_bytes() {
+        return None;
+    }
+    let exact = |oid: &Oid| (oid.clone(), mib.get(oid).cloned().unwrap_or(Value::NoSuchObject));
+    let next = |oid: &Oid| match mib.range::<Oid, _>((Bound::Excluded(oid), Bound::Unbounded)).next() {
+        Some((oid, value)) => (oid.clone(), value.clone()),
+        None => (oid.clone(), Value::EndOfMibView),
+    };
+    let varbinds = match request.pdu {
+        GET_REQUEST => request.varbinds.iter().map(|(oid, _)| exact(oid)).collect(),
+        GET_NEXT_REQUEST => request.varbinds.iter().map(|(oid, _)| next(oid)).collect(),
+        GET_BULK_REQUEST => {
+            let non_repeaters = (request.fields.0.max(0) as usize).min(request.varbinds.len());
+            let (singles, repeated) = request.varbinds.split_at(non_repeaters);
+            let mut varbinds: Vec<(Oid, Value)> = singles.iter().map(|(oid, _)| next(oid)).collect();
+            let mut cursors: Vec<Oid> = repeated.iter().map(|(oid, _)| oid.clone()).collect();
+            for _ in 0..request.fields.1.max(0) {
+                if cursors.is_empty() || varbinds.len() + cursors.len() > MAX_VARBINDS {
+                    break;
+                }
+                for cursor in &mut cursors {
+                    let (oid, value) = next(cursor);
+                    *cursor = oid.clone();
+                    varbinds.push((oid, value));
+                }
+                if varbinds.iter().rev().take(cursors.len()).all(|(_, value)| *value == Value::EndOfMibView) {
+                    break;
+                }
+            }
+            varbinds
+        }
+        _ => return None,
+    };
+    let response = Message {
+        community: request.community,
+        pdu: RESPONSE,
+        request_id: request.request_id,
+        fields: (0, 0),
+        varbinds,
+    };
+    Some(response.encode())
+}
+
+impl Message {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let (tag, message, _) = read_tlv(packet)?;
+        if tag != SEQUENCE {
+            return None;
+        }
+        let (version, rest) = read_integer(message)?;
+        if version != VERSION_2C {
+            return None;
+        }
+        let (tag, community, rest) = read_tlv(rest)?;
+        if tag != OCTET_STRING {
+            return None;
+        }
+        let (pdu, body, _) = read_tlv(rest)?;
+        let (request_id, rest) = read_integer(body)?;
+        let (first, rest) = read_integer(rest)?;
+        let (second, rest) = read_integer(rest)?;
+        let (tag, mut list, _) = read_tlv(rest)?;
+        if tag != SEQUENCE {
+            return None;
+        }
+        let mut varbinds = Vec::new();
+        while !list.is_empty() {
+            let (tag, varbind, rest) = read_tlv(list)?;
+            if tag != SEQUENCE {
+                return None;
+            }
+            let (tag, oid, value) = read_tlv(varbind)?;
+            if tag != OBJECT_IDENTIFIER {
+                return None;
+            }
+            varbinds.push((decode_oid(oid)?, read_value(value)?));
+            list = rest;
+        }
+        Some(Message {
+            community: community.to_vec(),
+            pdu,
+            request_id: request_id as i32,
+            fields: (first as i32, second as i32),
+            varbinds,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (oid, value) in &self.varbinds {
+            let mut varbind = Vec::new();
+            write_tlv(&mut varbind, OBJECT_IDENTIFIER, &encode_oid(oid));
+            write_value(&mut varbind, value);
+            write_tlv(&mut list, SEQUENCE, &varbind);
+        }
+        let mut pdu = Vec::new();
+        write_integer(&mut pdu, INTEGER, self.request_id as i64);
+        write_integer(&mut pdu, INTEGER, self.fields.0 as i64);
+        write_integer(&mut pdu, INTEGER, self.fields.1 as i64);
+        write_tlv(&mut pdu, SEQUENCE, &list);
+        let mut message = Vec::new();
+        write_integer(&mut message, INTEGER, VERSION_2C);
+        write_tlv(&mut message, OCTET_STRING, &self.community);
+        write_tlv(&mut message, self.pdu, &pdu);
+        let mut packet = Vec::new();
+        write_tlv(&mut packet, SEQUENCE, &message);
+        packet
+    }
+}
+
+/// Tag, content and what follows.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = match first {
+        0..=0x7F => (first as usize, rest),
+        0x81 => (*rest.first()? as usize, &rest[1..]),
+        0x82 if rest.len() >= 2 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..]),
+        _ => return None,
+    };
+    (rest.len() >= length).then(|| (tag, &rest[..length], &rest[length..]))
+}
+
+fn read_integer(data: &[u8]) -> Option<(i64, &[u8])> {
+    let (tag, content, rest) = read_tlv(data)?;
+    (tag == INTEGER).then_some(())?;
+    Some((decode_integer(content)?, rest))
+}
+
+fn decode_integer(content: &[u8]) -> Option<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return None;
+    }
+    let sign = if content[0] & 0x80 != 0 { -1i64 } else { 0 };
+    Some(content.iter().fold(sign, |value, byte| (value << 8) | *byte as i64))
+}
+
+fn read_value(data: &[u8]) -> Option<Value> {
+    let (tag, content, _) = read_tlv(data)?;
+    Some(match tag {
+        INTEGER => Value::Integer(decode_integer(content)? as i32),
+        OCTET_STRING => Value::String(String::from_utf8_lossy(content).into_owned()),
+        TIME_TICKS => Value::TimeTicks(decode_integer(content)? as u32),
+        NULL => Value::Null,
+        NO_SUCH_OBJECT => Value::NoSuchObject,
+        END_OF_MIB_VIEW => Value::EndOfMibView,
+        _ => return None,
+    })
+}
+
+fn decode_oid(content: &[u8]) -> Option<Oid> {
+    let (&first, rest) = content.split_first()?;
+    let mut oid = vec![(first / 40) as u32, (first % 40) as u32];
+    let mut sub_id: u32 = 0;
+    for byte in rest {
+        sub_id = sub_id.checked_mul(128)? | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            oid.push(sub_id);
+            sub_id = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn write_tlv(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+    match content.len() {
+        length @ 0..=0x7F => out.push(length as u8),
+        length @ 0x80..=0xFF => out.extend_from_slice(&[0x81, length as u8]),
+        length => {
+            out.push(0x82);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(content);
+}
+
+fn write_integer(out: &mut Vec<u8>, tag: u8, value: i64) {
+    let bytes = value.to_be_bytes();
+    // Shortest two's complement that keeps the sign
+    let mut start = 0;
+    while start < 7 {
+        let redundant = match bytes[start] {
+            0x00 => bytes[start + 1] & 0x80 == 0,
+            0xFF => bytes[start + 1] & 0x80 != 0,
+            _ => false,
+        };
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    write_tlv(out, tag, &bytes[start..]);
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Integer(value) => write_integer(out, INTEGER, *value as i64),
+        Value::String(value) => write_tlv(out, OCTET_STRING, value.as_bytes()),
+        Value::TimeTicks(value) => write_integer(out, TIME_TICKS, *value as i64),
+        Value::Null => write_tlv(out, NULL, &[]),
+        Value::NoSuchObject => write_tlv(out, NO_SUCH_OBJECT, &[]),
+        Value::EndOfMibView => write_tlv(out, END_OF_MIB_VIEW, &[]),
+    }
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut content = vec![(oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0)) as u8];
+    for sub_id in oid.iter().skip(2) {
+        let groups = (32 - sub_id.leading_zeros()).div_ceil(7).max(1);
+        for group in (0..groups).rev() {
+            let byte = (sub_id >> (group * 7)) as u8 & 0x7F;
+            content.push(if group > 0 { byte | 0x80 } else { byte });
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(pdu: u8, fields: (i32, i32), oids: &[&[u32]]) -> Vec<u8> {
+        Message {
+            community: b"public".to_vec(),
+            pdu,
+            request_id: 0x1234,
+            fields,
+            varbinds: oids.iter().map(|oid| (oid.to_vec(), Value::Null)).collect(),
+        }
+        .encode()
+    }
+
+    fn answer(mib: &BTreeMap<Oid, Value>, packet: &[u8]) -> Vec<(Oid, Value)> {
+        let response = Message::parse(&respond(mib, "public", packet).unwrap()).unwrap();
+        assert_eq!((response.pdu, response.request_id), (RESPONSE, 0x1234));
+        response.varbinds
+    }
+
+    #[test]
+    fn walks_the_readings_table() {
+        let config = Config {
+            device_id: "bedroom".to_string(),
+            ..Config::default()
+        };
+        let mut feed = SnmpFeed::default();
+        let measurements = [
+            Measurement {
+                name: "temperature".into(),
+                value: -1.5,
+            },
+            Measurement {
+                name: "co2".into(),
+                value: 612.0,
+            },
+        ];
+        feed.send(&config, 0, &measurements).unwrap();
+        let mib = feed.mib().lock().unwrap().clone();
+        let table = [ENTERPRISES, &[config.snmp.enterprise, 1, 1]].concat();
+        let oid = |column: u32, index: u32| [&table[..], &[column, index]].concat();
+
+        assert_eq!(
+            answer(&mib, &request(GET_REQUEST, (0, 0), &[SYS_NAME, &oid(2, 9)])),
+            vec![(SYS_NAME.to_vec(), Value::String("bedroom".to_string())), (oid(2, 9), Value::NoSuchObject)]
+        );
+        assert_eq!(
+            answer(&mib, &request(GET_NEXT_REQUEST, (0, 0), &[&oid(1, 2)])),
+            vec![(oid(2, 1), Value::Integer(61200))]
+        );
+        assert_eq!(
+            answer(&mib, &request(GET_BULK_REQUEST, (0, 5), &[&oid(2, 0)])),
+            vec![
+                (oid(2, 1), Value::Integer(61200)),
+                (oid(2, 2), Value::Integer(-150)),
+                (oid(2, 2), Value::EndOfMibView),
+            ]
+        );
+        let mut wrong_community = request(GET_REQUEST, (0, 0), &[SYS_NAME]);
+        wrong_community[7] = b'P';
+        assert_eq!(respond(&mib, "public", &wrong_community), None);
+    }
+}
@@ -0,0 +1,394 @@
+//! Snore detection on the node, kept apart from the I2S driver so it can be tested on the host.
+//! Frames of microphone samples are turned into band energies and scored by a small network, runs
+//! of snoring frames count as snore events. Only the count leaves the node, never the audio.
+//!
+//! The network is trained elsewhere and loaded from `snore.model_path` as JSON,
+//! `{"mean": [..], "std": [..], "layers": [{"weights": [[..], ..], "biases": [..]}, ..]}`. The
+//! features are normalized with `mean` and `std`, the layers have a ReLU in between and the last
+//! one a single output, the snoring probability after a sigmoid.
+
+use std::f32::consts::PI;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::error::{Context, Error, Phase, Result};
+
+// Snoring sits well below 4 kHz, and without an FPU on the C3 and the C6 a low rate keeps the FFT
+// affordable
+pub const SAMPLE_RATE_HZ: u32 = 8000;
+pub const FRAME_LEN: usize = 256;
+pub const FRAME_MS: u32 = FRAME_LEN as u32 * 1000 / SAMPLE_RATE_HZ;
+pub const BANDS: usize = 16;
+
+const MIN_HZ: f32 = 60.0;
+const MAX_HZ: f32 = 4000.0;
+// Pauses within a snore shorter than this don't end it
+const GAP_MS: u32 = 1000;
+
+/// Log energies of [`BANDS`] bands spaced evenly on a log scale between 60 Hz and 4 kHz.
+pub struct Features {
+    window: Vec<f32>,
+    twiddles: Vec<(f32, f32)>,
+    edges: [usize; BANDS + 1],
+}
+
+impl Features {
+    pub fn new() -> Self {
+        let window = (0..FRAME_LEN)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME_LEN as f32).cos())
+            .collect();
+        let twiddles = (0..FRAME_LEN / 2)
+            .map(|k| {
+                let (sin, cos) = (-2.0 * PI * k as f32 / FRAME_LEN as f32).sin_cos();
+                (cos, sin)
+            })
+            .collect();
+        let bin_hz = SAMPLE_RATE_HZ as f32 / FRAME_LEN as f32;
+        let edges = std::array::from_fn(|band| {
+            let hz = MIN_HZ * (MAX_HZ / MIN_HZ).powf(band as f32 / BANDS as f32);
+            ((hz / bin_hz).round() as usize).clamp(1, FRAME_LEN / 2)
+        });
+        Features {
+            window,
+            twiddles,
+            edges,
+        }
+    }
+
+    /// The features of a frame of [`FRAME_LEN`] samples.
+    pub fn extract(&self, frame: &[i16]) -> [f32; BANDS] {
+        let mut re: Vec<f32> = frame
+            .iter()
+            .zip(&self.window)
+            .map(|(sample, weight)| *sample as f32 / 32768.0 * weight)
+            .collect();
+        let mut im = vec![0.0; FRAME_LEN];
+        self.fft(&mut re, &mut im);
+        std::array::from_fn(|band| {
+            // The lowest bands are narrower than a bin
+            let bins = self.edges[band]..self.edges[band + 1].max(self.edges[band] + 1);
+            let power: f32 = bins.map(|bin| re[bin] * re[bin] + im[bin] * im[bin]).sum();
+            (power + 1e-10).log10()
+        })
+    }
+
+    /// Radix-2 in place.
+    fn fft(&self, re: &mut [f32], im: &mut [f32]) {
+        let mut j = 0;
+        for i in 1..FRAME_LEN {
+            let mut bit = FRAME_LEN >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= FRAME_LEN {
+            let stride = FRAME_LEN / len;
+            for start in (0..FRAME_LEN).step_by(len) {
+                for k in 0..len / 2 {
+                    let (cos, sin) = self.twiddles[k * stride];
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let t_re = re[b] * cos - im[b] * sin;
+                    let t_im = re[b] * sin + im[b] * cos;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            len <<= 1;
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features::new()
+    }
+}
+
+/// RMS level of a frame in dBFS.
+pub fn level_db(frame: &[i16]) -> f32 {
+    let power = frame.iter().map(|sample| (*sample as f32 / 32768.0).powi(2)).sum::<f32>() / frame.len() as f32;
+    10.0 * (power + 1e-12).log10()
+}
+
+#[derive(Deserialize, Debug)]
+struct Layer {
+    weights: Vec<Vec<f32>>,
+    biases: Vec<f32>,
+}
+
+/// Dense network scoring the features of a frame.
+#[derive(Deserialize, Debug)]
+pub struct Model {
+    mean: Vec<f32>,
+    std: Vec<f32>,
+    layers: Vec<Layer>,
+}
+
+impl Model {
+    pub fn load(path: &str) -> Result<Self> {
+        let json = fs::read_to_string(path).with_context(Phase::SensorInit, || format!("Failed to read {}", path))?;
+        Model::parse(&json)
+    }
+
+    pub fn parse(json: &str) -> Result<Self> {
+        let model: Model = serde_json::from_str(json)?;
+        let invalid = |message: &str| Err(Error::sensor("snore", Phase::SensorInit, message));
+        if model.mean.len() != BANDS || model.std.len() != BANDS {
+            return invalid("The model needs a mean and a std for every band");
+        }
+        if model.std.iter().any(|std| *std <= 0.0) {
+            return invalid("The model's std must be positive");
+        }
+        let mut inputs = BANDS;
+        for layer in &model.layers {
+            if layer.weights.len() != layer.biases.len() || layer.weights.iter().any(|row| row.len() != inputs) {
+                return invalid("The model's layers don't fit together");
+            }
+            inputs = layer.biases.len();
+        }
+        if model.layers.is_empty() || inputs != 1 {
+            return invalid("The model's last layer needs a single output");
+        }
+        Ok(model)
+    }
+
+    /// Probability that the frame is snoring.
+    pub fn score(&self, features: &[f32; BANDS]) -> f32 {
+        let mut values: Vec<f32> = features
+            .iter()
+            .zip(self.mean.iter().zip(&self.std))
+            .map(|(value, (mean, std))| (value - mean) / std)
+            .collect();
+        for (index, layer) in self.layers.iter().enumerate() {
+            values = layer
+                .weights
+                .iter()
+                .zip(&layer.biases)
+                .map(|(row, bias)| row.iter().zip(&values).map(|(weight, value)| weight * value).sum::<f32>() + bias)
+                .collect();
+            if index + 1 < self.layers.len() {
+                values.iter_mut().for_each(|value| *value = value.max(0.0));
+            }
+        }
+        1.0 / (1.0 + (-values[0]).exp())
+    }
+}
+
+/// Counts snore events in the per-frame decisions: one starts after `min_event_ms` of snoring
+/// frames and lasts until the snoring stopped for a second.
+pub struct EventCounter {
+    min_frames: u32,
+    gap_frames: u32,
+    run: u32,
+    quiet: u32,
+    active: bool,
+    events: u32,
+}
+
+impl EventCounter {
+    pub fn new(min_event_ms: u32) -> Self {
+        EventCounter {
+            min_frames: min_event_ms.div_ceil(FRAME_MS).max(1),
+            gap_frames: GAP_MS.div_ceil(FRAME_MS),
+            run: 0,
+            quiet: 0,
+            active: false,
+            events: 0,
+        }
+    }
+
+    pub fn push(&mut self, snoring: bool) {
+        if snoring {
+            self.quiet = 0;
+            self.run += 1;
+            if !self.active && self.run >= self.min_frames {
+                self.active = true;
+                self.events += 1;
+            }
+        } else {
+            self.quiet += 1;
+            if self.quiet >= self.gap_frames {
+                self.run = 0;
+                self.active = false;
+            }
+        }
+    }
+
+    /// The events started since the last call.
+    pub fn take(&mut self) -> u32 {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// Features, model and event counter together, fed frame by frame from the microphone.
+pub struct SnoreDetector {
+    features: Features,
+    model: Model,
+    counter: EventCounter,
+    threshold: f32,
+    min_level_db: f32,
+}
+
+impl SnoreDetector {
+    pub fn new(model: Model, threshold: f32, min_event_ms: u32, min_level_db: f32) -> Self {
+        SnoreDetector {
+            features: Features::new(),
+            model,
+            counter: EventCounter::new(min_event_ms),
+            threshold,
+            min_level_db,
+        }
+    }
+
+    pub fn push(&mut self, frame: &[i16]) {
+        // Most of the night is quiet, those frames aren't worth the FFT
+        let snoring = level_db(frame) >= self.min_level_db
+            && self.model.score(&self.features.extract(frame)) >= self.threshold;
+        self.counter.push(snoring);
+    }
+
+    pub fn take_events(&mut self) -> u32 {
+        self.counter.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(hz: f32, amplitude: f32) -> Vec<i16> {
+        (0..FRAME_LEN)
+            .map(|i| (amplitude * (2.0 * PI * hz * i as f32 / SAMPLE_RATE_HZ as f32).sin() * 32767.0) as i16)
+            .collect()
+    }
+
+    fn band_of(hz: f32) -> usize {
+        (0..BANDS)
+            .rev()
+            .find(|band| MIN_HZ * (MAX_HZ / MIN_HZ).powf(*band as f32 / BANDS as f32) <= hz)
+            .unwrap()
+    }
+
+    // Snores when the energy in the band of 250 Hz is up
+    fn model() -> Model {
+        let mut weights = vec![0.0; BANDS];
+        weights[band_of(250.0)] = 1.0;
+        Model::parse(
+            &serde_json::json!({
+                "mean": vec![-2.0; BANDS],
+                "std": vec![1.0; BANDS],
+                "layers": [
+                    {"weights": [weights], "biases": [0.0]},
+                ],
+            })
+            .to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn puts_a_tone_into_its_band() {
+        let features = Features::new();
+        for hz in [100.0, 250.0, 1000.0, 3000.0] {
+            let energies = features.extract(&tone(hz, 0.5));
+            let loudest = (0..BANDS).max_by(|a, b| energies[*a].total_cmp(&energies[*b])).unwrap();
+            assert!(loudest.abs_diff(band_of(hz)) <= 1, "{} Hz in band {}", hz, loudest);
+        }
+    }
+
+    #[test]
+    fn measures_the_level() {
+        assert!((level_db(&tone(250.0, 1.0)) + 3.0).abs() < 0.5);
+        assert!(level_db(&[0; FRAME_LEN]) < -100.0);
+    }
+
+    #[test]
+    fn scores_with_the_network() {
+        let model = model();
+        let features = Features::new();
+        assert!(model.score(&features.extract(&tone(250.0, 0.5))) > 0.9);
+        assert!(model.score(&features.extract(&tone(3000.0, 0.5))) < 0.1);
+    }
+
+    #[test]
+    fn runs_the_hidden_layers_through_a_relu() {
+        let mut model = model();
+        model.mean = vec![0.0; BANDS];
+        model.layers = vec![
+            Layer {
+                weights: vec![vec![1.0; BANDS], vec![-1.0; BANDS]],
+                biases: vec![0.0, 0.0],
+            },
+            Layer {
+                weights: vec![vec![1.0, 1.0]],
+                biases: vec![0.0],
+            },
+        ];
+        // The negative unit is cut off, without the ReLU the two would cancel out
+        let expected = 1.0 / (1.0 + (-16.0f32).exp());
+        assert!((model.score(&[1.0; BANDS]) - expected).abs() < 1e-6);
+        assert!((model.score(&[-1.0; BANDS]) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_models_that_dont_fit() {
+        let layer = |inputs: usize, outputs: usize| {
+            serde_json::json!({"weights": vec![vec![0.0; inputs]; outputs], "biases": vec![0.0; outputs]})
+        };
+        let models = [
+            serde_json::json!({"mean": [0.0], "std": vec![1.0; BANDS], "layers": [layer(BANDS, 1)]}),
+            serde_json::json!({"mean": vec![0.0; BANDS], "std": vec![0.0; BANDS], "layers": [layer(BANDS, 1)]}),
+            serde_json::json!({"mean": vec![0.0; BANDS], "std": vec![1.0; BANDS], "layers": []}),
+            serde_json::json!({"mean": vec![0.0; BANDS], "std": vec![1.0; BANDS], "layers": [layer(BANDS, 2)]}),
+            serde_json::json!({"mean": vec![0.0; BANDS], "std": vec![1.0; BANDS], "layers": [layer(8, 1)]}),
+        ];
+        for model in models {
+            assert!(Model::parse(&model.to_string()).is_err(), "{} was accepted", model);
+        }
+        let valid = serde_json::json!({
+            "mean": vec![0.0; BANDS],
+            "std": vec![1.0; BANDS],
+            "layers": [layer(BANDS, 8), layer(8, 1)],
+        });
+        assert!(Model::parse(&valid.to_string()).is_ok());
+    }
+
+    #[test]
+    fn counts_snoring_that_lasts() {
+        let mut counter = EventCounter::new(300);
+        let frames = |ms: u32| ms / FRAME_MS;
+        // Too short
+        (0..frames(200)).for_each(|_| counter.push(true));
+        (0..frames(2000)).for_each(|_| counter.push(false));
+        assert_eq!(counter.take(), 0);
+        // A short pause doesn't end a snore, a long one does
+        for _ in 0..2 {
+            (0..frames(800)).for_each(|_| counter.push(true));
+            (0..frames(300)).for_each(|_| counter.push(false));
+            (0..frames(800)).for_each(|_| counter.push(true));
+            (0..frames(2000)).for_each(|_| counter.push(false));
+        }
+        assert_eq!(counter.take(), 2);
+        assert_eq!(counter.take(), 0);
+    }
+
+    #[test]
+    fn skips_quiet_frames() {
+        let mut detector = SnoreDetector::new(model(), 0.5, 300, -40.0);
+        let (loud, quiet) = (tone(250.0, 0.5), tone(250.0, 0.001));
+        (0..20).for_each(|_| detector.push(&quiet));
+        assert_eq!(detector.take_events(), 0);
+        (0..20).for_each(|_| detector.push(&loud));
+        assert_eq!(detector.take_events(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+//! Batches still buffered at a shutdown, kept on flash and handed back to the buffer at the next
+//! boot. One JSON line per batch, `[timestamp_ms, [[name, value], ...]]`, a value that isn't a
+//! number is `null`.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::compact::CompactBatch;
+use crate::sensors::Measurement;
+
+/// On the storage partition, next to the configuration.
+pub const SPOOL_PATH: &str = "/storage/spool.jsonl";
+
+/// Measurements as written to flash, a value that isn't a number is `None`.
+pub type Values = Vec<(String, Option<f32>)>;
+
+type Line = (u64, Values);
+
+pub fn save(path: &Path, batches: &[CompactBatch]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for batch in batches {
+        let line: Line = (batch.timestamp_ms, values(batch));
+        serde_json::to_writer(&mut file, &line)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()
+}
+
+/// Reads the batches and removes the file, so they're only restored once. Nothing without a file,
+/// a line that can't be read is skipped.
+pub fn take(path: &Path) -> io::Result<Vec<CompactBatch>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut batches = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let Ok((timestamp_ms, values)) = serde_json::from_str::<Line>(&line?) else {
+            continue;
+        };
+        batches.push(CompactBatch::encode(timestamp_ms, &measurements(values)));
+    }
+    fs::remove_file(path)?;
+    Ok(batches)
+}
+
+pub fn values(batch: &CompactBatch) -> Values {
+    batch
+        .decode()
+        .into_iter()
+        .map(|measurement| (measurement.name.into_owned(), Some(measurement.value).filter(|value| !value.is_nan())))
+        .collect()
+}
+
+pub fn measurements(values: Values) -> Vec<Measurement> {
+    values
+        .into_iter()
+        .map(|(name, value)| Measurement {
+            name: name.into(),
+            value: value.unwrap_or(f32::NAN),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let path = std::env::temp_dir().join(format!("spool-{}.jsonl", std::process::id()));
+        let batch = |timestamp_ms, co2| {
+            let measurements = [
+                Measurement {
+                    name: "co2".into(),
+                    value: co2,
+                },
+                Measurement {
+                    name: "lux".into(),
+                    value: f32::NAN,
+                },
+            ];
+            CompactBatch::encode(timestamp_ms, &measurements)
+        };
+        save(&path, &[batch(1_000, 500.0), batch(2_000, 612.5)]).unwrap();
+
+        let restored = take(&path).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[1].timestamp_ms, 2_000);
+        let measurements = restored[1].decode();
+        assert_eq!((measurements[0].name.as_ref(), measurements[0].value), ("co2", 612.5));
+        assert!(measurements[1].value.is_nan());
+        assert!(take(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+//! Where the long-running threads run. On the dual-core S3 sampling gets core 1 to itself, while
+//! the uplink stays on core 0 with the Wi-Fi driver and lwIP, so a burst of Wi-Fi traffic can't
+//! hold up the accelerometer and a slow sensor can't hold up an upload. The single-core chips
+//! only get the priorities.
+//!
+//! The measurement loop runs on the main task, `sdkconfig.defaults.esp32s3` puts that on core 1.
+
+use crate::config::TaskConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    /// The measurement loop and the sensors sampled on threads of their own
+    Sensing,
+    /// The uplink
+    Network,
+}
+
+impl Task {
+    fn priority(self, config: &TaskConfig) -> u8 {
+        match self {
+            Task::Sensing => config.sensing_priority,
+            Task::Network => config.network_priority,
+        }
+    }
+}
+
+/// Runs `spawn` with the threads it starts set up for `task`, threads they start in turn get the
+/// defaults again.
+pub fn spawning<T>(config: &TaskConfig, task: Task, spawn: impl FnOnce() -> T) -> T {
+    #[cfg(target_os = "espidf")]
+    {
+        use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+
+        #[cfg(esp32s3)]
+        let core = config.pinned.then_some(match task {
+            Task::Sensing => esp_idf_svc::hal::cpu::Core::Core1,
+            Task::Network => esp_idf_svc::hal::cpu::Core::Core0,
+        });
+        #[cfg(not(esp32s3))]
+        let core = None;
+        let configured = ThreadSpawnConfiguration {
+            priority: task.priority(config),
+            pin_to_core: core,
+            ..Default::default()
+        }
+        .set();
+        if let Err(err) = configured {
+            log::error!("Failed to set up the {:?} task: {:?}", task, err);
+        }
+        let spawned = spawn();
+        if let Err(err) = ThreadSpawnConfiguration::default().set() {
+            log::error!("Failed to restore the task defaults: {:?}", err);
+        }
+        spawned
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        let _ = (config, task);
+        spawn()
+    }
+}
+
+/// Gives the calling thread the priority of `task`, it stays on its core.
+pub fn prioritize(config: &TaskConfig, task: Task) {
+    let priority = task.priority(config);
+    #[cfg(target_os = "espidf")]
+    unsafe {
+        esp_idf_svc::sys::vTaskPrioritySet(std::ptr::null_mut(), priority as u32)
+    };
+    #[cfg(not(target_os = "espidf"))]
+    let _ = priority;
+}
@@ -0,0 +1,50 @@
+//! SNTP against the servers in `sntp.servers`, the pool by default.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+
+use log::info;
+
+use crate::clock::ClockSync;
+use crate::config::SntpConfig;
+use crate::error::{Context, Error, Phase, Result};
+
+/// Called with the time after every sync.
+pub type OnSync = Box<dyn FnMut(Duration) + Send>;
+
+/// Starts syncing, it carries on in the background for as long as the client is kept. `clock`
+/// is set with the first sync.
+pub fn start(config: &SntpConfig, clock: ClockSync, mut on_sync: Option<OnSync>) -> Result<EspSntp<'static>> {
+    let mut conf = SntpConf::default();
+    // Every slot is queried, the spare ones repeat the configured servers
+    for (slot, server) in conf.servers.iter_mut().zip(config.servers.iter().cycle()) {
+        *slot = server;
+    }
+    EspSntp::new_with_callback(&conf, move |time| {
+        if !clock.is_synced() {
+            info!("SNTP synced");
+            clock.set();
+        }
+        if let Some(on_sync) = &mut on_sync {
+            on_sync(time);
+        }
+    })
+    .context(Phase::TimeSync, "Failed to start SNTP")
+}
+
+/// Waits for the first sync for up to `sntp.timeout_sec`, returns how long it took.
+pub fn wait(sntp: &EspSntp, config: &SntpConfig) -> Result<Duration> {
+    let started = Instant::now();
+    let timeout = Duration::from_secs(config.timeout_sec as u64);
+    while sntp.get_sync_status() != SyncStatus::Completed {
+        if started.elapsed() > timeout {
+            return Err(Error::failed(
+                Phase::TimeSync,
+                format!("No time from {} within {} s", config.servers.join(", "), config.timeout_sec),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(started.elapsed())
+}
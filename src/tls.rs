@@ -0,0 +1,111 @@
+//! Extra trust for the TLS sinks (HTTPS and `mqtts://`): a custom CA, or a self-signed server
+//! certificate that's then the only one accepted. It's kept in NVS, like the calibration, so a
+//! configuration upload doesn't lose it.
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::tls::X509;
+#[cfg(target_os = "espidf")]
+use log::error;
+
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase, Result};
+
+#[cfg(target_os = "espidf")]
+const NVS_NAMESPACE: &str = "tls";
+#[cfg(target_os = "espidf")]
+const NVS_CERTIFICATE_KEY: &str = "certificate";
+// A CA plus an intermediate or two
+#[cfg(target_os = "espidf")]
+const MAX_PEM_LEN: usize = 6 * 1024;
+
+const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const END: &str = "-----END CERTIFICATE-----";
+
+/// Rebuilds PEM pasted into the console, where the line breaks come out as spaces or `\n`.
+/// None unless it's one or more complete certificates.
+pub fn normalize_pem(input: &str) -> Option<String> {
+    let input = input.replace("\\n", "\n");
+    let mut pem = String::new();
+    let mut rest = input.trim();
+    while !rest.is_empty() {
+        let body = rest.strip_prefix(BEGIN)?;
+        let (body, after) = body.split_once(END)?;
+        let body: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+        let base64 = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=');
+        if body.is_empty() || !body.chars().all(base64) {
+            return None;
+        }
+        pem.push_str(BEGIN);
+        pem.push('\n');
+        for line in body.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).ok()?);
+            pem.push('\n');
+        }
+        pem.push_str(END);
+        pem.push('\n');
+        rest = after.trim_start();
+    }
+    (!pem.is_empty()).then_some(pem)
+}
+
+/// Reads the certificate from NVS, None to use the built-in CA bundle.
+#[cfg(target_os = "espidf")]
+pub fn load(nvs: &EspDefaultNvsPartition) -> Option<String> {
+    let nvs = match EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, err);
+            return None;
+        }
+    };
+    let mut buf = vec![0u8; MAX_PEM_LEN];
+    match nvs.get_str(NVS_CERTIFICATE_KEY, &mut buf) {
+        Ok(pem) => pem.map(str::to_string),
+        Err(err) => {
+            error!("Failed to read the TLS certificate from NVS: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Stores the certificate, None goes back to the built-in CA bundle.
+#[cfg(target_os = "espidf")]
+pub fn store(nvs: &EspDefaultNvsPartition, pem: Option<&str>) -> Result<()> {
+    let mut nvs = EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true)
+        .context(Phase::Config, "Failed to open the NVS namespace")?;
+    match pem {
+        Some(pem) => nvs
+            .set_str(NVS_CERTIFICATE_KEY, pem)
+            .context(Phase::Config, "Failed to store the TLS certificate")?,
+        None => {
+            nvs.remove(NVS_CERTIFICATE_KEY)
+                .context(Phase::Config, "Failed to remove the TLS certificate")?;
+        }
+    }
+    Ok(())
+}
+
+/// The clients keep the certificate by reference for as long as they live, so it's leaked. Only
+/// done again when the certificate changes.
+#[cfg(target_os = "espidf")]
+pub fn x509(pem: &str) -> X509<'static> {
+    let bytes: &'static [u8] = Box::leak(format!("{}\0", pem).into_bytes().into_boxed_slice());
+    X509::pem_until_nul(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewraps_pasted_certificates() {
+        let body = "A".repeat(70);
+        let pasted = format!("{} {} {} {}\\n{} {} {}", BEGIN, &body[..30], &body[30..], END, BEGIN, "QUJD", END);
+        let expected = format!("{}\n{}\n{}\n{}\n{}\nQUJD\n{}\n", BEGIN, &body[..64], &body[64..], END, BEGIN, END);
+        assert_eq!(normalize_pem(&pasted), Some(expected));
+        assert_eq!(normalize_pem(&format!("{} not base64! {}", BEGIN, END)), None);
+        assert_eq!(normalize_pem("QUJD"), None);
+    }
+}
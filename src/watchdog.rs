@@ -0,0 +1,101 @@
+//! Delivery watchdog for nodes that stop getting data out and stay that way, e.g. with the Wi-Fi
+//! driver stuck in a state only a power cycle fixes.
+
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(target_os = "espidf")]
+use log::error;
+
+#[cfg(target_os = "espidf")]
+use crate::error::{Context, Phase, Result};
+
+#[cfg(target_os = "espidf")]
+const NVS_NAMESPACE: &str = "watchdog";
+#[cfg(target_os = "espidf")]
+const NVS_REBOOTS_KEY: &str = "reboots";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    RestartNetwork,
+    Reboot,
+}
+
+/// Escalates while nothing gets delivered: the network is restarted once `hours` have passed
+/// since the last successful upload, the node reboots after as long again. A reboot loses
+/// whatever is buffered in RAM, so it's only the last resort.
+#[derive(Default)]
+pub struct DeliveryWatchdog {
+    network_restarted: bool,
+}
+
+impl DeliveryWatchdog {
+    pub fn delivered(&mut self) {
+        self.network_restarted = false;
+    }
+
+    /// What to do after a failed upload, `hours` 0 disables the watchdog.
+    pub fn check(&mut self, hours: u32, since_delivery: Duration) -> Option<Recovery> {
+        if hours == 0 {
+            return None;
+        }
+        let limit = Duration::from_secs(hours as u64 * 60 * 60);
+        if since_delivery >= limit * 2 {
+            Some(Recovery::Reboot)
+        } else if since_delivery >= limit && !self.network_restarted {
+            self.network_restarted = true;
+            Some(Recovery::RestartNetwork)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reboots done by the watchdog so far, kept in NVS so they show up after the fact.
+#[cfg(target_os = "espidf")]
+pub fn reboots(nvs: &EspDefaultNvsPartition) -> u32 {
+    let nvs = match EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(err) => {
+            error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, err);
+            return 0;
+        }
+    };
+    match nvs.get_u32(NVS_REBOOTS_KEY) {
+        Ok(count) => count.unwrap_or(0),
+        Err(err) => {
+            error!("Failed to read the watchdog reboots from NVS: {:?}", err);
+            0
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn record_reboot(nvs: &EspDefaultNvsPartition) -> Result<()> {
+    let count = reboots(nvs) + 1;
+    let mut nvs = EspNvs::<NvsDefault>::new(nvs.clone(), NVS_NAMESPACE, true)
+        .context(Phase::Config, "Failed to open the NVS namespace")?;
+    nvs.set_u32(NVS_REBOOTS_KEY, count)
+        .context(Phase::Config, "Failed to record the watchdog reboot")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_the_network_once_before_rebooting() {
+        let mut watchdog = DeliveryWatchdog::default();
+        let hours = |hours: u64| Duration::from_secs(hours * 60 * 60);
+        assert_eq!(watchdog.check(0, hours(100)), None);
+        assert_eq!(watchdog.check(6, hours(5)), None);
+        assert_eq!(watchdog.check(6, hours(6)), Some(Recovery::RestartNetwork));
+        assert_eq!(watchdog.check(6, hours(7)), None);
+        assert_eq!(watchdog.check(6, hours(12)), Some(Recovery::Reboot));
+
+        watchdog.delivered();
+        assert_eq!(watchdog.check(6, hours(6)), Some(Recovery::RestartNetwork));
+    }
+}
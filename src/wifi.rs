@@ -0,0 +1,146 @@
+use std::ffi::CString;
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::netif::EspNetif;
+use esp_idf_svc::sys::{
+    esp, esp_ip6_addr_t, esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL, esp_netif_create_ip6_linklocal,
+    esp_netif_get_all_ip6, esp_netif_ip6_get_addr_type, esp_wifi_set_country_code, CONFIG_LWIP_IPV6_NUM_ADDRESSES,
+};
+use esp_idf_svc::wifi::PmfConfiguration::NotCapable;
+use esp_idf_svc::wifi::ScanMethod::FastScan;
+use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use log::info;
+
+use crate::config::{Config, WifiConfig};
+use crate::error::{Context, Error, Phase, Result};
+use crate::pipeline::Network;
+
+// For DHCP, or router advertisements on an IPv6-only network
+const ADDRESS_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Wi-Fi station that is only brought up for uploads and shut down in between to save power,
+/// unless `wifi.always_on` keeps it connected.
+pub struct WifiNetwork<'d> {
+    wifi: BlockingWifi<EspWifi<'d>>,
+    always_on: bool,
+}
+
+impl<'d> WifiNetwork<'d> {
+    pub fn new(wifi: BlockingWifi<EspWifi<'d>>) -> Self {
+        WifiNetwork { wifi, always_on: false }
+    }
+}
+
+impl Network for WifiNetwork<'_> {
+    fn connect(&mut self, config: &Config) -> Result<()> {
+        self.always_on = config.wifi.always_on;
+        // A dropped link is brought up again like any other, one that's still up from the boot is
+        // used as it is
+        if self.wifi.is_connected().unwrap_or(false) {
+            return Ok(());
+        }
+        connect_wifi(&mut self.wifi, &config.wifi)
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        if self.always_on {
+            return Ok(());
+        }
+        // Gives the TCP stack time to get the last batch out before the link goes away
+        std::thread::sleep(Duration::from_millis(5000));
+        disconnect_wifi(&mut self.wifi)
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        // Stopping takes the radio down as well, the next connect starts it from scratch
+        disconnect_wifi(&mut self.wifi)?;
+        std::thread::sleep(Duration::from_secs(5));
+        Ok(())
+    }
+}
+
+pub fn connect_wifi(wifi: &mut BlockingWifi<EspWifi>, config: &WifiConfig) -> Result<()> {
+    disconnect_wifi(wifi)?;
+
+    let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
+        ssid: config.ssid.as_str().try_into()
+            .map_err(|_| Error::failed(Phase::Config, "SSID is too long"))?,
+        // Pinned, the fast scan stops at the first match on that channel
+        bssid: config.bssid_bytes(),
+        auth_method: AuthMethod::WPA2Personal,
+        password: config.password.as_str().try_into()
+            .map_err(|_| Error::failed(Phase::Config, "Wi-Fi password is too long"))?,
+        channel: config.channel,
+        scan_method: FastScan,
+        pmf_cfg: NotCapable,
+    });
+
+    wifi.set_configuration(&wifi_configuration)
+        .context(Phase::Connect, "Failed to configure Wi-Fi")?;
+    if !config.country.is_empty() {
+        let country = CString::new(config.country.as_str())
+            .map_err(|_| Error::failed(Phase::Config, "Invalid Wi-Fi country code"))?;
+        // Without 802.11d, the access point's country doesn't override the configured one
+        esp!(unsafe { esp_wifi_set_country_code(country.as_ptr(), false) })
+            .with_context(Phase::Connect, || format!("Failed to set the Wi-Fi country to {}", config.country))?;
+    }
+    wifi.start().context(Phase::Connect, "Failed to start Wi-Fi")?;
+    wifi.connect()
+        .with_context(Phase::Connect, || format!("Failed to connect to {}", config.ssid))?;
+    let netif = wifi.wifi().sta_netif();
+    // Starts SLAAC, the router's advertisements bring the global addresses
+    esp!(unsafe { esp_netif_create_ip6_linklocal(netif.handle()) })
+        .context(Phase::Connect, "Failed to enable IPv6")?;
+    wait_for_address(netif)
+}
+
+/// Waits for an IPv4 or a global IPv6 address, either is enough to reach the sinks.
+fn wait_for_address(netif: &EspNetif) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        let ipv4 = netif.get_ip_info().map(|info| info.ip).ok().filter(|ip| !ip.is_unspecified());
+        let ipv6 = global_ipv6(netif);
+        if ipv4.is_some() || !ipv6.is_empty() {
+            info!("Addresses: {:?} {:?}", ipv4, ipv6);
+            return Ok(());
+        }
+        if started.elapsed() > ADDRESS_TIMEOUT {
+            return Err(Error::failed(Phase::Connect, "No IP address"));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn global_ipv6(netif: &EspNetif) -> Vec<Ipv6Addr> {
+    let mut addresses = [esp_ip6_addr_t::default(); CONFIG_LWIP_IPV6_NUM_ADDRESSES as usize];
+    let count = unsafe { esp_netif_get_all_ip6(netif.handle(), addresses.as_mut_ptr()) };
+    addresses
+        .iter()
+        .take(count.max(0) as usize)
+        // Only reads the address
+        .filter(|&address| {
+            let address = address as *const esp_ip6_addr_t as *mut esp_ip6_addr_t;
+            unsafe { esp_netif_ip6_get_addr_type(address) == esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL }
+        })
+        .map(|address| {
+            // The words hold the address in network order
+            let mut bytes = [0u8; 16];
+            for (chunk, word) in bytes.chunks_exact_mut(4).zip(address.addr) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            Ipv6Addr::from(bytes)
+        })
+        .collect()
+}
+
+pub fn disconnect_wifi(wifi: &mut BlockingWifi<EspWifi>) -> Result<()> {
+    let context = "Failed to shut down Wi-Fi";
+    if wifi.is_started().context(Phase::Connect, context)? {
+        if wifi.is_connected().context(Phase::Connect, context)? {
+            wifi.disconnect().context(Phase::Connect, context)?;
+        }
+        wifi.stop().context(Phase::Connect, context)?;
+    }
+    Ok(())
+}